@@ -0,0 +1,18 @@
+fn main() {
+    // The gRPC service is generated from its proto with the vendored protoc, so builds do not
+    // need one installed.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/payment_engine.proto");
+        std::env::set_var(
+            "PROTOC",
+            protoc_bin_vendored::protoc_bin_path().expect("Vendored protoc is available"),
+        );
+        // Without the transport helpers, whose `connect` needs the 2021 prelude; clients
+        // connect a `Channel` themselves.
+        tonic_build::configure()
+            .build_transport(false)
+            .compile_protos(&["proto/payment_engine.proto"], &["proto"])
+            .expect("Proto of the gRPC service compiles");
+    }
+}
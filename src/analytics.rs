@@ -0,0 +1,192 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{self, TransactionType};
+use crate::sink;
+use csv::WriterBuilder;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// Upper bounds of the amount buckets, each ten times the one before. Amounts of at least the
+/// last bound fall into one more, open-ended bucket.
+const BUCKET_BOUNDS: [i64; 7] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000];
+const BUCKETS: usize = BUCKET_BOUNDS.len() + 1;
+const DECILES: usize = 10;
+
+/// Number and sum of amounts in one bucket of a histogram. `upper` is exclusive and empty for
+/// the last bucket.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistogramRow {
+    pub dimension: &'static str,
+    pub group: String,
+    pub lower: Decimal,
+    pub upper: Option<Decimal>,
+    pub count: u64,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS],
+    amounts: [Decimal; BUCKETS],
+}
+
+/// Amount distributions of the transactions applied in a run, per transaction type and per
+/// decile of clients ranked by the total amount they moved.
+#[derive(Debug, Default)]
+pub struct AmountAnalytics {
+    types: BTreeMap<&'static str, Histogram>,
+    clients: HashMap<u16, (Decimal, Histogram)>,
+}
+
+impl Histogram {
+    fn record(&mut self, amount: Decimal) -> PaymentEngineResult<()> {
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| amount.abs() < Decimal::from(*bound))
+            .unwrap_or(BUCKETS - 1);
+
+        self.counts[bucket] += 1;
+        self.amounts[bucket] = model::checked_add(self.amounts[bucket], amount)?;
+
+        Ok(())
+    }
+
+    fn merge(&mut self, other: &Histogram) -> PaymentEngineResult<()> {
+        for bucket in 0..BUCKETS {
+            self.counts[bucket] += other.counts[bucket];
+            self.amounts[bucket] = model::checked_add(self.amounts[bucket], other.amounts[bucket])?;
+        }
+
+        Ok(())
+    }
+
+    fn rows(&self, dimension: &'static str, group: String) -> Vec<HistogramRow> {
+        (0..BUCKETS)
+            .map(|bucket| HistogramRow {
+                dimension,
+                group: group.clone(),
+                lower: match bucket {
+                    0 => Decimal::ZERO,
+                    _ => Decimal::from(BUCKET_BOUNDS[bucket - 1]),
+                },
+                upper: BUCKET_BOUNDS.get(bucket).map(|bound| Decimal::from(*bound)),
+                count: self.counts[bucket],
+                amount: self.amounts[bucket],
+            })
+            .collect()
+    }
+}
+
+impl AmountAnalytics {
+    pub fn record(
+        &mut self,
+        client_id: u16,
+        r#type: &TransactionType,
+        amount: Decimal,
+    ) -> PaymentEngineResult<()> {
+        self.types
+            .entry(r#type.name())
+            .or_default()
+            .record(amount)?;
+
+        let (volume, histogram) = self.clients.entry(client_id).or_default();
+
+        *volume = model::checked_add(*volume, amount.abs())?;
+        histogram.record(amount)
+    }
+
+    /// Histogram rows of every transaction type, then of every client decile, decile 1 holding
+    /// the clients which moved the least.
+    pub fn rows(&self) -> PaymentEngineResult<Vec<HistogramRow>> {
+        let mut rows = vec![];
+
+        for (name, histogram) in &self.types {
+            rows.extend(histogram.rows("type", name.to_string()));
+        }
+
+        let mut clients: Vec<(&u16, &(Decimal, Histogram))> = self.clients.iter().collect();
+        let mut deciles = vec![Histogram::default(); DECILES];
+
+        clients.sort_by_key(|(client_id, (volume, _))| (*volume, **client_id));
+
+        for (rank, (_, (_, histogram))) in clients.iter().enumerate() {
+            deciles[rank * DECILES / clients.len()].merge(histogram)?;
+        }
+
+        for (decile, histogram) in deciles.iter().enumerate() {
+            rows.extend(histogram.rows("client_decile", (decile + 1).to_string()));
+        }
+
+        Ok(rows)
+    }
+
+    /// Writes the rows as a JSON array when the path ends in `.json`, as CSV otherwise.
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        let rows = self.rows()?;
+
+        sink::write_atomically(path, |sink| {
+            if path.extension() == Some(OsStr::new("json")) {
+                serde_json::to_writer_pretty(sink, &rows)?;
+            } else {
+                let mut writer = WriterBuilder::new().from_writer(sink);
+
+                for row in rows {
+                    writer.serialize(row)?;
+                }
+
+                writer.flush()?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analytics::AmountAnalytics;
+    use crate::model::TransactionType;
+    use rust_decimal::Decimal;
+
+    #[test]
+    pub fn should_bucket_amounts_by_type_and_client_decile() {
+        let mut analytics = AmountAnalytics::default();
+
+        for client_id in 1..=20u16 {
+            analytics
+                .record(
+                    client_id,
+                    &TransactionType::Deposit,
+                    Decimal::from(client_id) * Decimal::from(100),
+                )
+                .unwrap();
+        }
+        analytics
+            .record(1, &TransactionType::Withdrawal, Decimal::new(5, 1))
+            .unwrap();
+
+        let rows = analytics.rows().unwrap();
+        let row = |dimension: &str, group: &str, lower: i64| {
+            rows.iter()
+                .find(|row| {
+                    row.dimension == dimension
+                        && row.group == group
+                        && row.lower == Decimal::from(lower)
+                })
+                .unwrap()
+        };
+
+        assert_eq!(rows.len(), 12 * 8);
+        assert_eq!(row("type", "deposit", 100).count, 9);
+        assert_eq!(row("type", "deposit", 1_000).count, 11);
+        assert_eq!(row("type", "withdrawal", 0).amount, Decimal::new(5, 1));
+        assert_eq!(row("client_decile", "1", 0).count, 1);
+        assert_eq!(
+            row("client_decile", "10", 1_000).amount,
+            Decimal::from(3_900)
+        );
+        assert_eq!(row("client_decile", "10", 1_000_000).upper, None);
+    }
+}
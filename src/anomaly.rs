@@ -0,0 +1,211 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{self, Account};
+use crate::sink;
+use csv::WriterBuilder;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::path::Path;
+
+/// Balance changes of recent runs by client, kept between runs.
+pub const BALANCE_HISTORY_PATH: &str = "pe_balance_history.json";
+/// Accounts flagged by the last run with anomalies.
+pub const BALANCE_ANOMALIES_PATH: &str = "pe_balance_anomalies.csv";
+
+/// Detection of unusual balance changes, the `[anomaly]` table of the configuration. An account
+/// is flagged when the change of its total balance in a run is more than `sigmas` standard
+/// deviations away from the mean of its changes in the last `window` runs. Clients need
+/// `min_runs` earlier changes before they are judged. With `fail_run` the run fails before the
+/// report is written or delivered.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalyConfig {
+    pub sigmas: f64,
+    pub window: usize,
+    pub min_runs: usize,
+    pub fail_run: bool,
+}
+
+/// Total balance reported by the last run which changed the account, and the changes of the
+/// runs before, oldest first.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct ClientHistory {
+    total: Decimal,
+    changes: VecDeque<f64>,
+}
+
+/// Recent balance changes of every client seen so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BalanceHistory {
+    clients: BTreeMap<u16, ClientHistory>,
+}
+
+/// Account whose balance changed unusually in the run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceAnomaly {
+    pub client: u16,
+    pub change: Decimal,
+    pub mean: f64,
+    pub std_dev: f64,
+    /// Distance of the change from the mean, in standard deviations.
+    pub deviations: f64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            sigmas: 3.0,
+            window: 20,
+            min_runs: 5,
+            fail_run: false,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn is_valid(&self) -> bool {
+        self.sigmas > 0.0 && self.min_runs >= 2 && self.min_runs <= self.window
+    }
+}
+
+impl BalanceHistory {
+    /// History saved at `path`, or an empty one before the first run.
+    pub fn load(path: &Path) -> PaymentEngineResult<Self> {
+        match std::fs::read(path) {
+            Ok(json) => Ok(serde_json::from_slice(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BalanceHistory::default()),
+            Err(source) => Err(PaymentEngineError::BalanceHistory { source }),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |file| {
+            file.write_all(&serde_json::to_vec(self)?)?;
+
+            Ok(())
+        })
+    }
+
+    /// Compares the total balance of each account changed by the run with the one reported by
+    /// the last run changing it, and records the change. Returns the accounts whose change is
+    /// unusual. Clients seen for the first time only have their total recorded, and clients
+    /// whose earlier changes were all the same are not judged, as they have no spread.
+    pub fn observe<'a>(
+        &mut self,
+        accounts: impl IntoIterator<Item = &'a Account>,
+        config: &AnomalyConfig,
+    ) -> PaymentEngineResult<Vec<BalanceAnomaly>> {
+        let mut anomalies = vec![];
+
+        for account in accounts {
+            let history = match self.clients.get_mut(&account.client_id) {
+                Some(history) => history,
+                None => {
+                    self.clients.insert(
+                        account.client_id,
+                        ClientHistory {
+                            total: account.total,
+                            changes: VecDeque::new(),
+                        },
+                    );
+                    continue;
+                }
+            };
+            let change = model::checked_sub(account.total, history.total)?;
+            let value = change.to_f64().unwrap_or_default();
+
+            if history.changes.len() >= config.min_runs {
+                let (mean, std_dev) = mean_and_std_dev(&history.changes);
+                let deviations = (value - mean).abs() / std_dev;
+
+                if std_dev > 0.0 && deviations > config.sigmas {
+                    anomalies.push(BalanceAnomaly {
+                        client: account.client_id,
+                        change,
+                        mean,
+                        std_dev,
+                        deviations,
+                    });
+                }
+            }
+
+            history.total = account.total;
+            history.changes.push_back(value);
+
+            while history.changes.len() > config.window {
+                history.changes.pop_front();
+            }
+        }
+
+        Ok(anomalies)
+    }
+}
+
+fn mean_and_std_dev(values: &VecDeque<f64>) -> (f64, f64) {
+    let count = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / count;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / (count - 1.0);
+
+    (mean, variance.sqrt())
+}
+
+pub fn write_anomalies(path: &Path, anomalies: &[BalanceAnomaly]) -> PaymentEngineResult<()> {
+    sink::write_atomically(path, |sink| {
+        let mut writer = WriterBuilder::new().from_writer(sink);
+
+        for anomaly in anomalies {
+            writer.serialize(anomaly)?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::anomaly::{AnomalyConfig, BalanceHistory};
+    use crate::model::Account;
+    use rust_decimal::Decimal;
+
+    #[test]
+    pub fn should_flag_balance_changes_far_from_recent_runs() {
+        let config = AnomalyConfig::default();
+        let mut history = BalanceHistory::default();
+        let mut total = Decimal::ZERO;
+        let mut run = |history: &mut BalanceHistory, change: i64| {
+            total += Decimal::from(change);
+
+            history
+                .observe(
+                    &[Account {
+                        total,
+                        ..Account::new(1)
+                    }],
+                    &config,
+                )
+                .unwrap()
+        };
+
+        // The first run only records the total, the next ones the usual changes.
+        for change in [500, 9, 11, 10, 9, 11] {
+            assert!(run(&mut history, change).is_empty());
+        }
+
+        assert!(run(&mut history, 12).is_empty());
+
+        let anomalies = run(&mut history, 1_000);
+
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].client, 1);
+        assert_eq!(anomalies[0].change, Decimal::from(1_000));
+        assert!(anomalies[0].deviations > config.sigmas);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use chrono::{DateTime, Utc};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+pub const APPROVALS_DB_PATH: &str = "pe_approvals.db";
+
+/// Administrative operation which a second principal has to confirm under dual control.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum AdminAction {
+    ApprovePending { transaction_id: u32 },
+    RejectPending { transaction_id: u32 },
+}
+
+/// Action requested by one principal and waiting for another one to confirm it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovalRequest {
+    #[serde(flatten)]
+    pub action: AdminAction,
+    pub requested_by: String,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// Open approval requests by action, optionally persisted so the second principal can confirm
+/// in a later run.
+pub struct ApprovalBook {
+    db: Option<PickleDb>,
+    requests: BTreeMap<String, ApprovalRequest>,
+}
+
+impl fmt::Display for AdminAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminAction::ApprovePending { transaction_id } => {
+                write!(f, "approve-pending:{}", transaction_id)
+            }
+            AdminAction::RejectPending { transaction_id } => {
+                write!(f, "reject-pending:{}", transaction_id)
+            }
+        }
+    }
+}
+
+impl ApprovalBook {
+    pub fn open(path: Option<&Path>) -> Self {
+        let db = path.map(|path| {
+            PickleDb::load(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                .unwrap_or_else(|_| {
+                    PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                })
+        });
+        let requests = match &db {
+            Some(db) => db
+                .iter()
+                .filter_map(|item| item.get_value::<String>())
+                .filter_map(|json| serde_json::from_str::<ApprovalRequest>(&json).ok())
+                .map(|request| (request.action.to_string(), request))
+                .collect(),
+            None => BTreeMap::default(),
+        };
+
+        ApprovalBook { db, requests }
+    }
+
+    pub fn list(&self) -> Vec<ApprovalRequest> {
+        self.requests.values().cloned().collect()
+    }
+
+    /// Records the request of the first principal and returns nothing, or returns the open
+    /// request when another principal confirms it. The request stays open until `remove`, so a
+    /// confirmed action which then fails can be confirmed again. A new request is dated `now`.
+    pub fn request(
+        &mut self,
+        action: AdminAction,
+        principal: &str,
+        now: DateTime<Utc>,
+    ) -> PaymentEngineResult<Option<ApprovalRequest>> {
+        let key = action.to_string();
+
+        match self.requests.get(&key) {
+            Some(request) if request.requested_by == principal => {
+                Err(PaymentEngineError::SamePrincipalConfirmation)
+            }
+            Some(request) => Ok(Some(request.clone())),
+            None => {
+                let request = ApprovalRequest {
+                    action,
+                    requested_by: principal.to_string(),
+                    requested_at: now,
+                };
+
+                if let Some(db) = self.db.as_mut() {
+                    db.set(&key, &serde_json::to_string(&request)?)?;
+                }
+                self.requests.insert(key, request);
+
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn remove(&mut self, action: &AdminAction) -> PaymentEngineResult<()> {
+        let key = action.to_string();
+
+        if self.requests.remove(&key).is_some() {
+            if let Some(db) = self.db.as_mut() {
+                db.rem(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,663 @@
+use crate::config::ServiceConfig;
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{Account, DisputeRecord, Transaction};
+use crate::payment_service::PaymentService;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+
+/// Records the write-behind cache holds before it waits for the writer and drops them.
+const CACHED_RECORDS: usize = 10_000;
+
+/// Datastore reached over the network, e.g. Postgres or Redis, whose calls are awaited instead
+/// of blocking a thread for every round trip. Implementations share their connections between
+/// calls, so every operation takes `&self`.
+#[async_trait]
+pub trait AsyncDatastoreOperations: Send + Sync {
+    async fn retrieve_transaction(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>>;
+    async fn save_transaction(&self, transaction: Transaction) -> PaymentEngineResult<()>;
+    async fn retrieve_client_transactions(
+        &self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>>;
+    async fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>>;
+    async fn save_account(&self, account: Account) -> PaymentEngineResult<()>;
+    async fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>>;
+    async fn set_transaction_disputed(
+        &self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()>;
+    async fn save_pending_transaction(&self, transaction: Transaction) -> PaymentEngineResult<()>;
+    async fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>>;
+    async fn remove_pending_transaction(&self, transaction_id: u32) -> PaymentEngineResult<()>;
+    async fn save_dispute_record(&self, record: DisputeRecord) -> PaymentEngineResult<()>;
+    async fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>>;
+
+    /// Up to `count` transactions in transaction id order, starting after transaction `after`.
+    async fn retrieve_transactions_after(
+        &self,
+        _after: Option<u32>,
+        _count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        Err(PaymentEngineError::TransactionListingUnsupported)
+    }
+
+    /// Makes everything saved so far durable.
+    async fn flush(&self) -> PaymentEngineResult<()> {
+        Ok(())
+    }
+}
+
+/// Async view of a local datastore, e.g. to run an `AsyncPaymentService` on the pickle or
+/// SQLite files.
+pub struct AsyncAdapter<D> {
+    datastore: Mutex<D>,
+}
+
+/// Payment service for async programs, e.g. servers: the engine runs on a thread of its own
+/// and the futures of `process` and `accounts` complete once it answers, so the runtime is
+/// never blocked by the engine or its datastore.
+pub struct AsyncPaymentService {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+enum Request {
+    Process(
+        Box<Transaction>,
+        oneshot::Sender<PaymentEngineResult<Account>>,
+    ),
+    Accounts(oneshot::Sender<PaymentEngineResult<Vec<Account>>>),
+    Transactions(
+        Option<u32>,
+        usize,
+        oneshot::Sender<PaymentEngineResult<Vec<Transaction>>>,
+    ),
+    Flush(oneshot::Sender<PaymentEngineResult<()>>),
+}
+
+enum Write {
+    Transaction(Transaction),
+    Account(Account),
+    Disputed(u32, bool),
+    Pending(Transaction),
+    RemovePending(u32),
+    DisputeRecord(DisputeRecord),
+    Settle(oneshot::Sender<()>),
+    Flush(oneshot::Sender<PaymentEngineResult<()>>),
+}
+
+/// Synchronous datastore of the engine thread in front of an async one. Saves update a cache
+/// and are handed to a writer task in order, so the engine goes on with the next transaction
+/// instead of waiting for the round trip. Reads of single records are answered from the cache;
+/// misses and reads over many records wait for the writer first, so they see every save. Once
+/// the cache holds `CACHED_RECORDS`, the writer is waited for and the saved records dropped.
+struct WriteBehindDatastore {
+    runtime: Handle,
+    datastore: Arc<dyn AsyncDatastoreOperations>,
+    writes: mpsc::UnboundedSender<Write>,
+    transactions: HashMap<u32, Transaction>,
+    accounts: HashMap<u16, Account>,
+    dispute_chains: HashMap<u32, Vec<DisputeRecord>>,
+}
+
+impl<D: DatastoreOperations + Send> AsyncAdapter<D> {
+    pub fn new(datastore: D) -> Self {
+        AsyncAdapter {
+            datastore: Mutex::new(datastore),
+        }
+    }
+
+    fn with<T>(&self, operation: impl FnOnce(&mut D) -> T) -> T {
+        operation(
+            &mut self
+                .datastore
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+}
+
+#[async_trait]
+impl<D: DatastoreOperations + Send> AsyncDatastoreOperations for AsyncAdapter<D> {
+    async fn retrieve_transaction(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        self.with(|datastore| datastore.retrieve_transaction(transaction_id))
+    }
+
+    async fn save_transaction(&self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.with(|datastore| datastore.save_transaction(transaction))
+    }
+
+    async fn retrieve_client_transactions(
+        &self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.with(|datastore| datastore.retrieve_client_transactions(client_id))
+    }
+
+    async fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        self.with(|datastore| datastore.retrieve_account(client_id))
+    }
+
+    async fn save_account(&self, account: Account) -> PaymentEngineResult<()> {
+        self.with(|datastore| datastore.save_account(account))
+    }
+
+    async fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        self.with(|datastore| datastore.retrieve_all_accounts())
+    }
+
+    async fn set_transaction_disputed(
+        &self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        self.with(|datastore| datastore.set_transaction_disputed(transaction_id, disputed))
+    }
+
+    async fn save_pending_transaction(&self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.with(|datastore| datastore.save_pending_transaction(transaction))
+    }
+
+    async fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        self.with(|datastore| datastore.retrieve_pending_transactions())
+    }
+
+    async fn remove_pending_transaction(&self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.with(|datastore| datastore.remove_pending_transaction(transaction_id))
+    }
+
+    async fn save_dispute_record(&self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        self.with(|datastore| datastore.save_dispute_record(record))
+    }
+
+    async fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        self.with(|datastore| datastore.retrieve_dispute_chain(transaction_id))
+    }
+
+    async fn retrieve_transactions_after(
+        &self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.with(|datastore| datastore.retrieve_transactions_after(after, count))
+    }
+
+    async fn flush(&self) -> PaymentEngineResult<()> {
+        self.with(|datastore| datastore.flush())
+    }
+}
+
+impl AsyncPaymentService {
+    /// Starts the engine thread, with its datastore writes running on the current runtime.
+    /// Panics when called outside of a Tokio runtime.
+    pub fn start(datastore: Arc<dyn AsyncDatastoreOperations>, config: ServiceConfig) -> Self {
+        let runtime = Handle::current();
+        let (requests, mut received) = mpsc::unbounded_channel();
+
+        std::thread::spawn(move || {
+            let datastore = WriteBehindDatastore::start(runtime, datastore);
+            let mut service = PaymentService::new(Box::new(datastore), config);
+
+            // Answers go to callers which may have given up waiting, so failed sends are fine.
+            while let Some(request) = received.blocking_recv() {
+                match request {
+                    Request::Process(transaction, answer) => {
+                        let _ = answer.send(service.process(&transaction));
+                    }
+                    Request::Accounts(answer) => {
+                        let _ = answer.send(service.accounts());
+                    }
+                    Request::Transactions(after, count, answer) => {
+                        let _ = answer.send(service.transactions_after(after, count));
+                    }
+                    Request::Flush(answer) => {
+                        let _ = answer.send(service.flush());
+                    }
+                }
+            }
+        });
+
+        AsyncPaymentService { requests }
+    }
+
+    /// Applies a single transaction, like `PaymentService::process`.
+    pub async fn process(&self, transaction: Transaction) -> PaymentEngineResult<Account> {
+        self.request(|answer| Request::Process(Box::new(transaction), answer))
+            .await
+    }
+
+    pub async fn accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        self.request(Request::Accounts).await
+    }
+
+    /// Latest version of at most `count` transactions after transaction `after`, in
+    /// transaction id order, between the transactions the engine applies.
+    pub async fn transactions_after(
+        &self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.request(|answer| Request::Transactions(after, count, answer))
+            .await
+    }
+
+    /// Waits until every change applied so far has been saved by the async datastore.
+    pub async fn flush(&self) -> PaymentEngineResult<()> {
+        self.request(Request::Flush).await
+    }
+
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<PaymentEngineResult<T>>) -> Request,
+    ) -> PaymentEngineResult<T> {
+        let (answer, answered) = oneshot::channel();
+
+        self.requests
+            .send(request(answer))
+            .map_err(|_| PaymentEngineError::AsyncServiceStopped)?;
+        answered
+            .await
+            .map_err(|_| PaymentEngineError::AsyncServiceStopped)?
+    }
+}
+
+impl WriteBehindDatastore {
+    fn start(runtime: Handle, datastore: Arc<dyn AsyncDatastoreOperations>) -> Self {
+        let (writes, mut pending) = mpsc::unbounded_channel();
+        let writer = datastore.clone();
+
+        runtime.spawn(async move {
+            // The first failed write is reported by the next flush, later ones are logged.
+            let mut failure = None;
+
+            while let Some(write) = pending.recv().await {
+                let result = match write {
+                    Write::Transaction(transaction) => writer.save_transaction(transaction).await,
+                    Write::Account(account) => writer.save_account(account).await,
+                    Write::Disputed(transaction_id, disputed) => {
+                        writer
+                            .set_transaction_disputed(transaction_id, disputed)
+                            .await
+                    }
+                    Write::Pending(transaction) => {
+                        writer.save_pending_transaction(transaction).await
+                    }
+                    Write::RemovePending(transaction_id) => {
+                        writer.remove_pending_transaction(transaction_id).await
+                    }
+                    Write::DisputeRecord(record) => writer.save_dispute_record(record).await,
+                    Write::Settle(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                    Write::Flush(done) => {
+                        let result = match failure.take() {
+                            Some(e) => Err(e),
+                            None => writer.flush().await,
+                        };
+                        let _ = done.send(result);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = result {
+                    error!("Async datastore write failed: {}", e);
+                    failure.get_or_insert(e);
+                }
+            }
+        });
+
+        WriteBehindDatastore {
+            runtime,
+            datastore,
+            writes,
+            transactions: HashMap::default(),
+            accounts: HashMap::default(),
+            dispute_chains: HashMap::default(),
+        }
+    }
+
+    fn write(&self, write: Write) -> PaymentEngineResult<()> {
+        self.writes
+            .send(write)
+            .map_err(|_| PaymentEngineError::AsyncServiceStopped)
+    }
+
+    /// Waits until the writer has saved everything handed to it.
+    fn drain(&self) -> PaymentEngineResult<()> {
+        let (done, drained) = oneshot::channel();
+
+        self.write(Write::Flush(done))?;
+        self.runtime
+            .block_on(drained)
+            .map_err(|_| PaymentEngineError::AsyncServiceStopped)?
+    }
+
+    /// Waits until the writer has handed everything so far to the async datastore, without
+    /// making it durable, so a read of the async datastore sees every save.
+    fn settle(&self) -> PaymentEngineResult<()> {
+        let (done, settled) = oneshot::channel();
+
+        self.write(Write::Settle(done))?;
+        self.runtime
+            .block_on(settled)
+            .map_err(|_| PaymentEngineError::AsyncServiceStopped)
+    }
+
+    /// Drops the cached records once there are `CACHED_RECORDS` of them, after the writer has
+    /// saved them.
+    fn evict_saved(&mut self) -> PaymentEngineResult<()> {
+        let cached = self.transactions.len() + self.accounts.len() + self.dispute_chains.len();
+
+        if cached >= CACHED_RECORDS {
+            self.settle()?;
+            self.transactions.clear();
+            self.accounts.clear();
+            self.dispute_chains.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl DatastoreOperations for WriteBehindDatastore {
+    fn retrieve_transaction(
+        &mut self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        if let Some(transaction) = self.transactions.get(&transaction_id) {
+            return Ok(Some(transaction.clone()));
+        }
+
+        self.settle()?;
+
+        let transaction = self
+            .runtime
+            .block_on(self.datastore.retrieve_transaction(transaction_id))?;
+
+        if let Some(transaction) = &transaction {
+            self.transactions
+                .insert(transaction_id, transaction.clone());
+        }
+
+        Ok(transaction)
+    }
+
+    fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.evict_saved()?;
+        self.transactions
+            .insert(transaction.transaction_id, transaction.clone());
+        self.write(Write::Transaction(transaction))
+    }
+
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.drain()?;
+        self.runtime
+            .block_on(self.datastore.retrieve_client_transactions(client_id))
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        if let Some(account) = self.accounts.get(&client_id) {
+            return Ok(Some(account.clone()));
+        }
+
+        self.settle()?;
+        self.runtime
+            .block_on(self.datastore.retrieve_account(client_id))
+    }
+
+    fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+        self.evict_saved()?;
+        self.accounts.insert(account.client_id, account.clone());
+        self.write(Write::Account(account))
+    }
+
+    fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        self.drain()?;
+        self.runtime
+            .block_on(self.datastore.retrieve_all_accounts())
+    }
+
+    fn set_transaction_disputed(
+        &mut self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        let transaction = self
+            .retrieve_transaction(transaction_id)?
+            .ok_or(PaymentEngineError::DisputedValueChange)?;
+
+        self.evict_saved()?;
+        self.transactions.insert(
+            transaction_id,
+            Transaction {
+                disputed,
+                ..transaction
+            },
+        );
+        self.write(Write::Disputed(transaction_id, disputed))
+    }
+
+    fn remove_transaction_from_cache(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.transactions.remove(&transaction_id);
+
+        Ok(())
+    }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.write(Write::Pending(transaction))
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        self.drain()?;
+        self.runtime
+            .block_on(self.datastore.retrieve_pending_transactions())
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.write(Write::RemovePending(transaction_id))
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        let chain = self.retrieve_dispute_chain(record.transaction_id)?;
+
+        self.evict_saved()?;
+        self.dispute_chains.insert(
+            record.transaction_id,
+            [chain, vec![record.clone()]].concat(),
+        );
+        self.write(Write::DisputeRecord(record))
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        if let Some(chain) = self.dispute_chains.get(&transaction_id) {
+            return Ok(chain.clone());
+        }
+
+        self.settle()?;
+        self.runtime
+            .block_on(self.datastore.retrieve_dispute_chain(transaction_id))
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.settle()?;
+        self.runtime
+            .block_on(self.datastore.retrieve_transactions_after(after, count))
+    }
+
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        self.drain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_service::{AsyncAdapter, AsyncDatastoreOperations, AsyncPaymentService};
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::error::PaymentEngineResult;
+    use crate::model::{Account, DisputeRecord, Transaction, TransactionType};
+    use async_trait::async_trait;
+    use rust_decimal::Decimal;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Async datastore whose dispute flag changes take a while, so reads race the writer.
+    struct SlowDisputes(AsyncAdapter<InMemoryDatastore>);
+
+    #[async_trait]
+    impl AsyncDatastoreOperations for SlowDisputes {
+        async fn retrieve_transaction(
+            &self,
+            transaction_id: u32,
+        ) -> PaymentEngineResult<Option<Transaction>> {
+            self.0.retrieve_transaction(transaction_id).await
+        }
+
+        async fn save_transaction(&self, transaction: Transaction) -> PaymentEngineResult<()> {
+            self.0.save_transaction(transaction).await
+        }
+
+        async fn retrieve_client_transactions(
+            &self,
+            client_id: u16,
+        ) -> PaymentEngineResult<Vec<Transaction>> {
+            self.0.retrieve_client_transactions(client_id).await
+        }
+
+        async fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+            self.0.retrieve_account(client_id).await
+        }
+
+        async fn save_account(&self, account: Account) -> PaymentEngineResult<()> {
+            self.0.save_account(account).await
+        }
+
+        async fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+            self.0.retrieve_all_accounts().await
+        }
+
+        async fn set_transaction_disputed(
+            &self,
+            transaction_id: u32,
+            disputed: bool,
+        ) -> PaymentEngineResult<()> {
+            std::thread::sleep(Duration::from_millis(50));
+            self.0
+                .set_transaction_disputed(transaction_id, disputed)
+                .await
+        }
+
+        async fn save_pending_transaction(
+            &self,
+            transaction: Transaction,
+        ) -> PaymentEngineResult<()> {
+            self.0.save_pending_transaction(transaction).await
+        }
+
+        async fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+            self.0.retrieve_pending_transactions().await
+        }
+
+        async fn remove_pending_transaction(&self, transaction_id: u32) -> PaymentEngineResult<()> {
+            self.0.remove_pending_transaction(transaction_id).await
+        }
+
+        async fn save_dispute_record(&self, record: DisputeRecord) -> PaymentEngineResult<()> {
+            self.0.save_dispute_record(record).await
+        }
+
+        async fn retrieve_dispute_chain(
+            &self,
+            transaction_id: u32,
+        ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+            self.0.retrieve_dispute_chain(transaction_id).await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn should_process_transactions_and_write_them_behind() {
+        let datastore = Arc::new(AsyncAdapter::new(InMemoryDatastore::default()));
+        let service = AsyncPaymentService::start(datastore.clone(), ServiceConfig::default());
+        let transaction = |r#type, amount| Transaction::new(r#type, 1, 7, amount);
+
+        service
+            .process(transaction(
+                TransactionType::Deposit,
+                Some(Decimal::from(30)),
+            ))
+            .await
+            .unwrap();
+
+        let account = service
+            .process(transaction(TransactionType::Dispute, None))
+            .await
+            .unwrap();
+
+        assert_eq!(account.held, Decimal::from(30));
+
+        service.flush().await.unwrap();
+
+        assert_eq!(datastore.retrieve_account(1).await.unwrap(), Some(account));
+        assert!(
+            datastore
+                .retrieve_transaction(7)
+                .await
+                .unwrap()
+                .unwrap()
+                .disputed
+        );
+        assert_eq!(datastore.retrieve_dispute_chain(7).await.unwrap().len(), 1);
+        assert_eq!(service.accounts().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn should_read_own_writes_after_evicting_a_resolved_transaction() {
+        let datastore = Arc::new(SlowDisputes(
+            AsyncAdapter::new(InMemoryDatastore::default()),
+        ));
+        let service = AsyncPaymentService::start(datastore, ServiceConfig::default());
+        let transaction = |r#type, amount| Transaction::new(r#type, 1, 7, amount);
+
+        for (r#type, amount) in [
+            (TransactionType::Deposit, Some(Decimal::from(30))),
+            (TransactionType::Dispute, None),
+            (TransactionType::Resolve, None),
+        ] {
+            service.process(transaction(r#type, amount)).await.unwrap();
+        }
+
+        let account = service
+            .process(transaction(TransactionType::Dispute, None))
+            .await
+            .unwrap();
+
+        assert_eq!(account.held, Decimal::from(30));
+    }
+}
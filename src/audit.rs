@@ -0,0 +1,108 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+pub const AUDIT_LOG_PATH: &str = "pe_audit.log";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    TransactionParked,
+    PendingApproved,
+    PendingRejected,
+    ConfigReloaded,
+    DisputeRecorded,
+    DisputeLimitReached,
+    /// Transaction applied to a locked account because strict locking is off for the client.
+    LockedAccountActivity,
+    /// First principal asked for an administrative action under dual control.
+    AdminActionRequested,
+    /// Second principal confirmed an administrative action requested by another one.
+    AdminActionConfirmed,
+    /// Funds moved between currency balances of an account at the rate in the details.
+    CurrencyConverted,
+    /// Amount of a stored transaction replaced, with the old and new amount in the details.
+    TransactionCorrected,
+    /// Client placed under legal hold, with the reason in the details.
+    LegalHoldPlaced,
+    LegalHoldReleased,
+    /// Personal fields of the client's transactions erased, with the counts in the details.
+    ClientErased,
+    /// Erasure request refused because the client is under legal hold.
+    ErasureBlocked,
+    /// Dispute step of a locked account kept until the account is unlocked.
+    LockedAccountQueued,
+    /// Locked account unlocked, with the number of queued dispute steps in the details.
+    AccountUnlocked,
+    /// Served transaction refused for exceeding a run limit, which pauses the server until an
+    /// operator confirms it may go on. The limit is in the details.
+    RunPaused,
+    /// Operator confirmed that a paused server may go on, with the limit in the details.
+    RunResumed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub action: AuditAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    /// Operator who performed the action, for administrative actions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub principal: Option<String>,
+}
+
+/// Append-only log of operator relevant events, one JSON object per line.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, client_id: u16, transaction_id: u32) -> Self {
+        AuditEvent {
+            timestamp: Utc::now(),
+            action,
+            client_id: Some(client_id),
+            transaction_id: Some(transaction_id),
+            details: None,
+            principal: None,
+        }
+    }
+
+    /// Event which is not about a single transaction, described in free text.
+    pub fn with_details(action: AuditAction, details: String) -> Self {
+        AuditEvent {
+            timestamp: Utc::now(),
+            action,
+            client_id: None,
+            transaction_id: None,
+            details: Some(details),
+            principal: None,
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog { path }
+    }
+
+    pub fn record(&self, event: AuditEvent) -> PaymentEngineResult<()> {
+        let json = serde_json::to_string(&event)?;
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", json))
+            .map_err(|source| PaymentEngineError::AuditLog { source })
+    }
+}
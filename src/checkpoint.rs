@@ -0,0 +1,133 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::fork::{self, STATE_PREFIX};
+use crate::manifest::RunCounts;
+use csv::Position;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+
+/// Directory holding the latest checkpoint, next to the state files it snapshots.
+pub const CHECKPOINT_PATH: &str = "pe_checkpoint";
+const STAGED_CHECKPOINT_PATH: &str = "pe_checkpoint.tmp";
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// How often a run saves a checkpoint, and the directory of the state files it snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointPolicy {
+    pub every_rows: NonZeroU64,
+    pub directory: PathBuf,
+}
+
+/// Position in an input file up to which every row has been processed. It is saved together
+/// with a snapshot of the state files as of that row, so a run resumed from it applies no row
+/// twice however far the datastore got before the failure.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub input: String,
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+    pub counts: RunCounts,
+}
+
+impl Checkpoint {
+    pub fn new(input: &str, position: &Position, counts: RunCounts) -> Self {
+        Checkpoint {
+            input: input.to_string(),
+            byte: position.byte(),
+            line: position.line(),
+            record: position.record(),
+            counts,
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        let mut position = Position::new();
+
+        position
+            .set_byte(self.byte)
+            .set_line(self.line)
+            .set_record(self.record);
+
+        position
+    }
+
+    /// Copies the state files of `directory` and the checkpoint into a staging directory, which
+    /// replaces the previous checkpoint once complete. The datastore has to be flushed first.
+    pub fn save(&self, directory: &Path) -> PaymentEngineResult<()> {
+        let staged = directory.join(STAGED_CHECKPOINT_PATH);
+        let save = || -> std::io::Result<()> {
+            if staged.exists() {
+                fs::remove_dir_all(&staged)?;
+            }
+
+            fs::create_dir(&staged)?;
+            copy_state(directory, &staged)?;
+            // Written last, so a staging directory with the file in it is complete.
+            fs::write(staged.join(CHECKPOINT_FILE), serde_json::to_vec(self)?)?;
+
+            let checkpoint = directory.join(CHECKPOINT_PATH);
+
+            if checkpoint.exists() {
+                fs::remove_dir_all(&checkpoint)?;
+            }
+
+            fs::rename(&staged, checkpoint)
+        };
+
+        save().map_err(|source| PaymentEngineError::Checkpoint { source })
+    }
+
+    /// Puts the state files of the latest checkpoint back into `directory` and returns the
+    /// checkpoint.
+    pub fn restore(directory: &Path) -> PaymentEngineResult<Self> {
+        let saved = [CHECKPOINT_PATH, STAGED_CHECKPOINT_PATH]
+            .iter()
+            .map(|name| directory.join(name))
+            .find(|saved| saved.join(CHECKPOINT_FILE).exists())
+            .ok_or(PaymentEngineError::CheckpointNotFound)?;
+        let checkpoint: Checkpoint = serde_json::from_slice(
+            &fs::read(saved.join(CHECKPOINT_FILE))
+                .map_err(|source| PaymentEngineError::Checkpoint { source })?,
+        )?;
+
+        copy_state(&saved, directory)
+            .map_err(|source| PaymentEngineError::Checkpoint { source })?;
+        info!(
+            "Restored state of {} as of line {}",
+            checkpoint.input, checkpoint.line
+        );
+
+        Ok(checkpoint)
+    }
+
+    /// Removes the checkpoint of a run which completed.
+    pub fn clear(directory: &Path) -> PaymentEngineResult<()> {
+        for name in [CHECKPOINT_PATH, STAGED_CHECKPOINT_PATH] {
+            let saved = directory.join(name);
+
+            if saved.exists() {
+                fs::remove_dir_all(saved)
+                    .map_err(|source| PaymentEngineError::Checkpoint { source })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies the `pe_*` state files of `from` into `to`, leaving out the checkpoints.
+fn copy_state(from: &Path, to: &Path) -> std::io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if name.starts_with(STATE_PREFIX) && !name.starts_with(CHECKPOINT_PATH) {
+            fork::copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+
+/// Source of the current time for everything the engine dates or schedules itself: dispute
+/// steps of transactions without a timestamp, timers such as dispute deadlines and reservation
+/// expiry, legal holds, approvals and audit entries. Replacing it makes that behaviour testable
+/// and replays repeatable.
+pub trait Clock: Send {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Wall clock time, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// Clock standing still until it is moved. Clones share the time, so a test can keep one and
+/// move the time of the service holding the other.
+#[derive(Debug, Clone)]
+pub struct FixedClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+/// Clock starting at `start` and moving on by `step` every time it is read, for replays which
+/// need distinct but repeatable times.
+#[derive(Debug)]
+pub struct SteppingClock {
+    next: Mutex<DateTime<Utc>>,
+    step: Duration,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        FixedClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().expect("Clock lock is never poisoned") = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("Clock lock is never poisoned") += duration;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().expect("Clock lock is never poisoned")
+    }
+}
+
+impl SteppingClock {
+    pub fn new(start: DateTime<Utc>, step: Duration) -> Self {
+        SteppingClock {
+            next: Mutex::new(start),
+            step,
+        }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now(&self) -> DateTime<Utc> {
+        let mut next = self.next.lock().expect("Clock lock is never poisoned");
+        let now = *next;
+
+        *next += self.step;
+
+        now
+    }
+}
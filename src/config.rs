@@ -0,0 +1,388 @@
+use crate::anomaly::AnomalyConfig;
+use crate::checkpoint::CheckpointPolicy;
+use crate::delivery::Delivery;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::flags::FeatureFlags;
+use crate::fraud::FraudCheck;
+use crate::ids::IdConfig;
+use crate::limits::RunLimits;
+use crate::locked::LockedDisputePolicy;
+use crate::model::Currency;
+use crate::rates::RateTable;
+use crate::report::{CsvStyle, ReportFormat};
+use crate::risk::ReasonCodes;
+use crate::rounding::RoundingConfig;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub detect_sequence_gaps: bool,
+    pub limits: RunLimits,
+    pub approval_threshold: Option<Decimal>,
+    pub fraud_check: Option<FraudCheck>,
+    /// Days after which a dispute still open is resolved automatically.
+    pub dispute_deadline_days: Option<u32>,
+    /// Transactions of one client which may be under dispute at the same time.
+    pub max_open_disputes: Option<u32>,
+    /// Administrative actions take effect only once a second principal confirms them.
+    pub dual_control: bool,
+    /// Currency of the balances of accounts. Setting it enables transactions in other
+    /// currencies, kept in separate balances and reported as one row per client and currency.
+    pub base_currency: Option<Currency>,
+    pub flags: FeatureFlags,
+    /// Disputes, resolves and chargebacks of locked accounts: allowed, queued or rejected.
+    pub locked_disputes: LockedDisputePolicy,
+    pub ids: IdConfig,
+    pub rounding: RoundingConfig,
+    /// Quoting and line endings of the CSV account report.
+    pub csv: CsvStyle,
+    pub reason_codes: ReasonCodes,
+    pub deliveries: Vec<Delivery>,
+    /// Flags accounts whose balance changed unusually in the run, none without it.
+    pub anomaly: Option<AnomalyConfig>,
+    #[serde(skip)]
+    pub audit_log_path: Option<PathBuf>,
+    /// Checkpoints saved while processing a file, from `--checkpoint-every`.
+    #[serde(skip)]
+    pub checkpoints: Option<CheckpointPolicy>,
+    /// Write-ahead log every transaction is appended to before it is applied.
+    #[serde(skip)]
+    pub wal_path: Option<PathBuf>,
+    /// Rows each stage of the pipeline may hand on ahead of the next, from `--pipeline-depth`.
+    /// Without it, rows are read, processed and written on a single thread.
+    #[serde(skip)]
+    pub pipeline_depth: Option<NonZeroUsize>,
+    #[serde(skip)]
+    pub reservations_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub legal_holds_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub locked_queue_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub ids_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub timers_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub approvals_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub rounding_drift_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub risk_report_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub balance_history_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub balance_anomalies_path: Option<PathBuf>,
+    /// File the amount histograms of the run are written to, none without it.
+    #[serde(skip)]
+    pub analytics_path: Option<PathBuf>,
+    /// File the rejected rows of the run are written to, none without it.
+    #[serde(skip)]
+    pub rejects_path: Option<PathBuf>,
+    /// File the funds held for open disputes are written to at the end of a run, none without it.
+    #[serde(skip)]
+    pub dispute_liability_path: Option<PathBuf>,
+    /// File the currency exposure revalued at `eod_rates` is written to, none without it.
+    #[serde(skip)]
+    pub revaluation_path: Option<PathBuf>,
+    /// File the summary of the run is written to, logged without it.
+    #[serde(skip)]
+    pub stats_path: Option<PathBuf>,
+    /// Aborts the run on the first row which cannot be read or is rejected, instead of
+    /// skipping it.
+    #[serde(skip)]
+    pub strict: bool,
+    /// File the account report is written to, stdout without it.
+    #[serde(skip)]
+    pub report_path: Option<PathBuf>,
+    /// Tenant the run belongs to, selecting the deliveries of the account report.
+    #[serde(skip)]
+    pub tenant: Option<String>,
+    #[serde(skip)]
+    pub report_mode: ReportMode,
+    #[serde(skip)]
+    pub report_hash: bool,
+    #[serde(skip)]
+    pub report_format: ReportFormat,
+    /// Exchange rates of conversions, from the file given with `--rates`.
+    #[serde(skip)]
+    pub rates: RateTable,
+    /// End-of-day exchange rates balances are revalued at, from the file given with
+    /// `--eod-rates`.
+    #[serde(skip)]
+    pub eod_rates: RateTable,
+}
+
+/// Accounts written to the report at the end of a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportMode {
+    #[default]
+    All,
+    /// Only accounts whose balances or status changed during the run.
+    Changed,
+}
+
+impl ReportMode {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "all" => Some(ReportMode::All),
+            "changed" => Some(ReportMode::Changed),
+            _ => None,
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Reads and validates a TOML configuration file, so a broken file is never applied.
+    pub fn load(path: &Path) -> PaymentEngineResult<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| PaymentEngineError::ConfigRead { source })?;
+        let config: ServiceConfig = toml::from_str(&text)?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> PaymentEngineResult<()> {
+        if matches!(self.approval_threshold, Some(threshold) if threshold.is_sign_negative()) {
+            return Err(PaymentEngineError::InvalidConfig {
+                field: "approval_threshold",
+            });
+        }
+
+        if matches!(self.limits.max_total_deposits, Some(max) if max.is_sign_negative()) {
+            return Err(PaymentEngineError::InvalidConfig {
+                field: "limits.max_total_deposits",
+            });
+        }
+
+        if matches!(&self.fraud_check, Some(check) if !check.is_valid()) {
+            return Err(PaymentEngineError::InvalidConfig {
+                field: "fraud_check",
+            });
+        }
+
+        if !self.flags.is_valid() {
+            return Err(PaymentEngineError::InvalidConfig { field: "flags" });
+        }
+
+        if !self.ids.is_valid() {
+            return Err(PaymentEngineError::InvalidConfig { field: "ids" });
+        }
+
+        if !self.rounding.is_valid() {
+            return Err(PaymentEngineError::InvalidConfig { field: "rounding" });
+        }
+
+        if !self.deliveries.iter().all(Delivery::is_valid) {
+            return Err(PaymentEngineError::InvalidConfig {
+                field: "deliveries",
+            });
+        }
+
+        if matches!(&self.anomaly, Some(anomaly) if !anomaly.is_valid()) {
+            return Err(PaymentEngineError::InvalidConfig { field: "anomaly" });
+        }
+
+        if !self.reason_codes.is_valid() {
+            return Err(PaymentEngineError::InvalidConfig {
+                field: "reason_codes",
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Settings without the files which keep state between runs: the audit log, the write-ahead
+    /// log, checkpoints and the stores of reservations, holds, queues, ids, timers and approvals,
+    /// which are kept in memory instead. Side reports are left as they are.
+    pub fn without_state_files(&self) -> ServiceConfig {
+        ServiceConfig {
+            audit_log_path: None,
+            checkpoints: None,
+            wal_path: None,
+            reservations_path: None,
+            legal_holds_path: None,
+            locked_queue_path: None,
+            ids_path: None,
+            timers_path: None,
+            approvals_path: None,
+            balance_history_path: None,
+            ..self.clone()
+        }
+    }
+
+    /// Settings of the configuration file `file` applied to the running settings, keeping
+    /// everything set for the run rather than read from the file, such as its output and
+    /// state files, `--strict` and the report options.
+    pub fn reloaded(&self, file: ServiceConfig) -> ServiceConfig {
+        ServiceConfig {
+            detect_sequence_gaps: file.detect_sequence_gaps,
+            limits: file.limits,
+            approval_threshold: file.approval_threshold,
+            fraud_check: file.fraud_check,
+            dispute_deadline_days: file.dispute_deadline_days,
+            max_open_disputes: file.max_open_disputes,
+            dual_control: file.dual_control,
+            base_currency: file.base_currency,
+            flags: file.flags,
+            locked_disputes: file.locked_disputes,
+            ids: file.ids,
+            rounding: file.rounding,
+            csv: file.csv,
+            reason_codes: file.reason_codes,
+            deliveries: file.deliveries,
+            anomaly: file.anomaly,
+            ..self.clone()
+        }
+    }
+
+    /// Describes every setting which differs in `other`, as `name: old -> new`.
+    pub fn changes(&self, other: &ServiceConfig) -> Vec<String> {
+        let mut changes = vec![];
+
+        describe_change(
+            &mut changes,
+            "detect_sequence_gaps",
+            &self.detect_sequence_gaps,
+            &other.detect_sequence_gaps,
+        );
+        describe_change(
+            &mut changes,
+            "limits.max_rows",
+            &self.limits.max_rows,
+            &other.limits.max_rows,
+        );
+        describe_change(
+            &mut changes,
+            "limits.max_clients",
+            &self.limits.max_clients,
+            &other.limits.max_clients,
+        );
+        describe_change(
+            &mut changes,
+            "limits.max_total_deposits",
+            &self.limits.max_total_deposits,
+            &other.limits.max_total_deposits,
+        );
+        describe_change(
+            &mut changes,
+            "approval_threshold",
+            &self.approval_threshold,
+            &other.approval_threshold,
+        );
+        describe_change(
+            &mut changes,
+            "fraud_check",
+            &self.fraud_check,
+            &other.fraud_check,
+        );
+        describe_change(
+            &mut changes,
+            "dispute_deadline_days",
+            &self.dispute_deadline_days,
+            &other.dispute_deadline_days,
+        );
+        describe_change(
+            &mut changes,
+            "max_open_disputes",
+            &self.max_open_disputes,
+            &other.max_open_disputes,
+        );
+        describe_change(
+            &mut changes,
+            "dual_control",
+            &self.dual_control,
+            &other.dual_control,
+        );
+        describe_change(
+            &mut changes,
+            "base_currency",
+            &self.base_currency,
+            &other.base_currency,
+        );
+        describe_change(&mut changes, "flags", &self.flags, &other.flags);
+        describe_change(
+            &mut changes,
+            "locked_disputes",
+            &self.locked_disputes,
+            &other.locked_disputes,
+        );
+        describe_change(&mut changes, "rounding", &self.rounding, &other.rounding);
+        describe_change(&mut changes, "csv", &self.csv, &other.csv);
+        describe_change(
+            &mut changes,
+            "reason_codes",
+            &self.reason_codes,
+            &other.reason_codes,
+        );
+        describe_change(
+            &mut changes,
+            "deliveries",
+            &self.deliveries,
+            &other.deliveries,
+        );
+        describe_change(&mut changes, "anomaly", &self.anomaly, &other.anomaly);
+
+        changes
+    }
+}
+
+fn describe_change<T: Debug + PartialEq>(changes: &mut Vec<String>, name: &str, old: &T, new: &T) {
+    if old != new {
+        changes.push(format!("{}: {:?} -> {:?}", name, old, new));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use rust_decimal::Decimal;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_load_and_describe_config_changes() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "approval_threshold = \"1000.50\"\n[limits]\nmax_rows = 10"
+        )
+        .unwrap();
+
+        let config = ServiceConfig::load(file.path()).unwrap();
+
+        assert_eq!(config.approval_threshold, Some(Decimal::new(100050, 2)));
+        assert_eq!(config.limits.max_rows, Some(10));
+        assert_eq!(
+            ServiceConfig::default().changes(&config),
+            vec![
+                "limits.max_rows: None -> Some(10)",
+                "approval_threshold: None -> Some(1000.50)"
+            ]
+        );
+    }
+
+    #[test]
+    pub fn should_reject_invalid_config() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "approval_threshold = \"-1\"").unwrap();
+
+        assert!(ServiceConfig::load(file.path()).is_err());
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "max_rows = 10").unwrap();
+
+        assert!(ServiceConfig::load(file.path()).is_err());
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "[flags.strict_locking]\npercentage = 101").unwrap();
+
+        assert!(ServiceConfig::load(file.path()).is_err());
+    }
+}
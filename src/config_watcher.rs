@@ -0,0 +1,58 @@
+use crate::config::ServiceConfig;
+use crate::error::PaymentEngineResult;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+/// Watches a configuration file and sends every valid new version of it. Files which fail to
+/// parse or validate are logged and skipped, so the running configuration is only ever replaced
+/// as a whole by a valid one.
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    pub fn start(path: &Path, updates: Sender<ServiceConfig>) -> PaymentEngineResult<Self> {
+        let config_path = path.to_path_buf();
+        let file_name = path.file_name().map(|name| name.to_os_string());
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("Configuration watch failed, {}", e);
+                        return;
+                    }
+                };
+
+                // Editors often replace the file instead of writing to it, so the parent
+                // directory is watched and unrelated files are filtered out here.
+                let touches_config = event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name().map(|name| name.to_os_string()) == file_name);
+
+                if touches_config
+                    && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                {
+                    match ServiceConfig::load(&config_path) {
+                        Ok(config) => {
+                            let _ = updates.send(config);
+                        }
+                        Err(e) => warn!("Configuration reload rejected, {}", e),
+                    }
+                }
+            })?;
+
+        watcher.watch(&watched_directory(path), RecursiveMode::NonRecursive)?;
+
+        Ok(ConfigWatcher { _watcher: watcher })
+    }
+}
+
+fn watched_directory(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
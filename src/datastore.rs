@@ -1,11 +1,19 @@
 use crate::error::{PaymentEngineError, PaymentEngineResult};
-use crate::model::{Account, Transaction};
+use crate::migrate::MigrationSource;
+use crate::model::{Account, DisputeRecord, Transaction};
+use crate::rebuild;
 use lru::LruCache;
 use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::path::Path;
 use std::time::Duration;
 
-const TRANSACTION_DB_PATH: &str = "pe_transaction.db";
+pub const TRANSACTION_DB_PATH: &str = "pe_transaction.db";
+const PENDING_DB_PATH: &str = "pe_pending.db";
+const DISPUTED_DB_PATH: &str = "pe_disputed.db";
+pub const DISPUTE_CHAINS_DB_PATH: &str = "pe_dispute_chains.db";
+pub const ACCOUNTS_DB_PATH: &str = "pe_accounts.db";
 const FLUSH_INTERVAL_MICROSECONDS: u64 = 500;
 const CACHE_SIZE: usize = 50_000;
 
@@ -15,37 +23,318 @@ pub trait DatastoreOperations {
         transaction_id: u32,
     ) -> PaymentEngineResult<Option<Transaction>>;
     fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()>;
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>>;
     fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>>;
     fn save_account(&mut self, account: Account) -> PaymentEngineResult<()>;
     fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>>;
+
+    /// Up to `count` accounts in client id order, starting after client `after`, so a listing
+    /// is paged through without loading every account.
+    fn retrieve_accounts_after(
+        &self,
+        after: Option<u16>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        let mut accounts = self.retrieve_all_accounts()?;
+
+        accounts.retain(|account| after.is_none_or(|after| account.client_id > after));
+        accounts.sort_by_key(|account| account.client_id);
+        accounts.truncate(count);
+
+        Ok(accounts)
+    }
+
+    /// Up to `count` transactions in transaction id order, starting after transaction `after`,
+    /// in their latest version.
+    fn retrieve_transactions_after(
+        &mut self,
+        _after: Option<u32>,
+        _count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        Err(PaymentEngineError::TransactionListingUnsupported)
+    }
+
     fn set_transaction_disputed(
         &mut self,
         transaction_id: u32,
         disputed: bool,
     ) -> PaymentEngineResult<()>;
     fn remove_transaction_from_cache(&mut self, transaction_id: u32) -> PaymentEngineResult<()>;
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()>;
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>>;
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()>;
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()>;
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>>;
+
+    /// Number of the client's transactions which are currently under dispute.
+    fn count_open_disputes(&mut self, client_id: u16) -> PaymentEngineResult<usize> {
+        Ok(self
+            .retrieve_client_transactions(client_id)?
+            .iter()
+            .filter(|t| t.disputed)
+            .count())
+    }
+
+    /// Writes changes buffered in memory to disk, so the files hold everything saved so far.
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        Ok(())
+    }
+
+    /// Transaction reads answered by the cache and by the files, for datastores with a cache.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
 }
 
+/// Transaction reads since the datastore was opened, by whether they were answered from memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of the reads answered from memory, none before the first read.
+    pub fn hit_rate(&self) -> Option<f64> {
+        match self.hits + self.misses {
+            0 => None,
+            reads => Some(self.hits as f64 / reads as f64),
+        }
+    }
+}
+
+/// Accounts indexed directly by client id. Client ids are `u16`, so all slots are allocated up
+/// front and lookups need neither hashing nor probing.
+#[derive(Debug)]
+pub struct AccountTable {
+    slots: Vec<Option<Account>>,
+}
+
+/// Transactions under dispute, kept in memory and persisted on every change, so resolves and
+/// chargebacks in a later run find their transaction without touching the transaction store.
+pub struct DisputedIndex {
+    db: PickleDb,
+    transactions: HashMap<u32, Transaction>,
+}
+
+/// Datastore keeping everything in memory for the lifetime of the service, without writing any
+/// files. Used for one-shot runs, and by the shadow service and account rebuilds, which start
+/// from empty state.
+#[derive(Debug, Default)]
+pub struct InMemoryDatastore {
+    transactions: BTreeMap<u32, Transaction>,
+    accounts: AccountTable,
+    pending_transactions: HashMap<u32, Transaction>,
+    dispute_chains: HashMap<u32, Vec<DisputeRecord>>,
+}
+
+/// Datastore keeping transactions and account balances in `pickledb` files which are flushed
+/// periodically, so a run which stops midway can be resumed from them.
 pub struct PickleDatastore {
     transaction_db: PickleDb,
-    accounts: HashMap<u16, Account>,
+    accounts_db: PickleDb,
+    pending_db: PickleDb,
+    disputed_index: DisputedIndex,
+    dispute_chains_db: PickleDb,
+    accounts: AccountTable,
     disputed_transactions_cache: LruCache<u32, Transaction>,
+    cache_stats: CacheStats,
+}
+
+impl AccountTable {
+    pub fn new() -> Self {
+        AccountTable {
+            slots: vec![None; usize::from(u16::MAX) + 1],
+        }
+    }
+
+    pub fn get(&self, client_id: u16) -> Option<&Account> {
+        self.slots[usize::from(client_id)].as_ref()
+    }
+
+    pub fn insert(&mut self, account: Account) {
+        let slot = usize::from(account.client_id);
+
+        self.slots[slot] = Some(account);
+    }
+
+    /// Accounts in ascending client id order.
+    pub fn values(&self) -> impl Iterator<Item = &Account> {
+        self.slots.iter().flatten()
+    }
+
+    /// Accounts in ascending client id order, starting after client `after`.
+    pub fn after(&self, after: Option<u16>) -> impl Iterator<Item = &Account> {
+        let start = after.map_or(0, |after| usize::from(after) + 1);
+
+        self.slots[start..].iter().flatten()
+    }
+}
+
+impl Default for AccountTable {
+    fn default() -> Self {
+        AccountTable::new()
+    }
+}
+
+impl DisputedIndex {
+    /// Index kept in the file at `path`, continuing with its content when `resume` is set.
+    pub fn open(path: &Path, resume: bool) -> Self {
+        let db = load_or_create(path, resume);
+        let transactions = db
+            .iter()
+            .filter_map(|item| item.get_value::<String>())
+            .filter_map(|json| serde_json::from_str::<Transaction>(&json).ok())
+            .map(|transaction| (transaction.transaction_id, transaction))
+            .collect();
+
+        DisputedIndex { db, transactions }
+    }
+
+    pub fn get(&self, transaction_id: u32) -> Option<&Transaction> {
+        self.transactions.get(&transaction_id)
+    }
+
+    pub fn count_client(&self, client_id: u16) -> usize {
+        self.transactions
+            .values()
+            .filter(|t| t.client_id == client_id)
+            .count()
+    }
+
+    pub fn insert(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.db.set(
+            &transaction.transaction_id.to_string(),
+            &serde_json::to_string(&transaction)?,
+        )?;
+        self.transactions
+            .insert(transaction.transaction_id, transaction);
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        if self.transactions.remove(&transaction_id).is_some() {
+            self.db.rem(&transaction_id.to_string())?;
+        }
+
+        Ok(())
+    }
 }
 
 impl PickleDatastore {
+    /// Datastore starting with no transactions and accounts. The files of an earlier run are
+    /// replaced.
     pub fn new() -> Self {
-        let transaction_db = PickleDb::new(
-            TRANSACTION_DB_PATH,
-            PickleDbDumpPolicy::PeriodicDump(Duration::from_micros(FLUSH_INTERVAL_MICROSECONDS)),
-            SerializationMethod::Bin,
-        );
+        PickleDatastore::open(Path::new(""), false)
+    }
+
+    /// Datastore continuing with the transactions and account balances of an earlier run, as
+    /// of its last flush.
+    pub fn resume() -> Self {
+        PickleDatastore::open(Path::new(""), true)
+    }
+
+    /// Datastore with its files in `directory`, continuing with their content when `resume`
+    /// is set.
+    pub fn open(directory: &Path, resume: bool) -> Self {
+        let policy =
+            || PickleDbDumpPolicy::PeriodicDump(Duration::from_micros(FLUSH_INTERVAL_MICROSECONDS));
+        let open_db = |name| {
+            let path = directory.join(name);
+            let loaded = if resume {
+                PickleDb::load(&path, policy(), SerializationMethod::Bin).ok()
+            } else {
+                None
+            };
+
+            loaded.unwrap_or_else(|| PickleDb::new(path, policy(), SerializationMethod::Bin))
+        };
+        let accounts_db = open_db(ACCOUNTS_DB_PATH);
+        let mut accounts = AccountTable::new();
+
+        for account in accounts_db
+            .iter()
+            .filter_map(|item| item.get_value::<String>())
+            .filter_map(|json| serde_json::from_str::<Account>(&json).ok())
+        {
+            accounts.insert(account);
+        }
 
         PickleDatastore {
-            transaction_db,
-            accounts: HashMap::default(),
+            transaction_db: open_db(TRANSACTION_DB_PATH),
+            accounts_db,
+            pending_db: load_or_create(directory.join(PENDING_DB_PATH), resume),
+            disputed_index: DisputedIndex::open(&directory.join(DISPUTED_DB_PATH), resume),
+            dispute_chains_db: load_or_create(directory.join(DISPUTE_CHAINS_DB_PATH), resume),
+            accounts,
             disputed_transactions_cache: LruCache::new(CACHE_SIZE),
+            cache_stats: CacheStats::default(),
+        }
+    }
+}
+
+impl Default for PickleDatastore {
+    fn default() -> Self {
+        PickleDatastore::new()
+    }
+}
+
+/// Store in the file at `path`, loaded when `resume` is set and it exists, otherwise replaced by
+/// an empty one.
+fn load_or_create<P: AsRef<Path>>(path: P, resume: bool) -> PickleDb {
+    let loaded = if resume {
+        PickleDb::load(
+            &path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Bin,
+        )
+        .ok()
+    } else {
+        None
+    };
+
+    loaded.unwrap_or_else(|| {
+        PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+    })
+}
+
+/// Every transaction and dispute step kept by the `pickledb` store, in replay order.
+pub fn read_stored_history(
+    transaction_db: &Path,
+    dispute_chains_db: &Path,
+) -> PaymentEngineResult<Vec<Transaction>> {
+    let load = |path| {
+        PickleDb::load(
+            path,
+            PickleDbDumpPolicy::NeverDump,
+            SerializationMethod::Bin,
+        )
+    };
+    let mut transactions = vec![];
+    let mut dispute_records = vec![];
+
+    for entry in load(transaction_db)?.iter() {
+        if let Some(json) = entry.get_value::<String>() {
+            transactions.push(serde_json::from_str(&json)?);
         }
     }
+
+    if dispute_chains_db.exists() {
+        for entry in load(dispute_chains_db)?.iter() {
+            if let Some(json) = entry.get_value::<String>() {
+                dispute_records.extend(serde_json::from_str::<Vec<DisputeRecord>>(&json)?);
+            }
+        }
+    }
+
+    Ok(rebuild::replay_order(transactions, dispute_records))
 }
 
 impl DatastoreOperations for PickleDatastore {
@@ -53,37 +342,80 @@ impl DatastoreOperations for PickleDatastore {
         &mut self,
         transaction_id: u32,
     ) -> PaymentEngineResult<Option<Transaction>> {
+        if let Some(transaction) = self.disputed_index.get(transaction_id) {
+            self.cache_stats.hits += 1;
+            return Ok(Some(transaction.clone()));
+        }
+
         match self.disputed_transactions_cache.get(&transaction_id) {
-            Some(transaction) => Ok(Option::from(transaction.clone())),
-            None => match self
-                .transaction_db
-                .get::<String>(&*transaction_id.to_string())
-            {
-                Some(json) => {
-                    let transaction: Transaction = serde_json::from_str(&*json)?;
-
-                    Ok(Option::from(transaction))
+            Some(transaction) => {
+                self.cache_stats.hits += 1;
+                Ok(Option::from(transaction.clone()))
+            }
+            None => {
+                self.cache_stats.misses += 1;
+
+                match self
+                    .transaction_db
+                    .get::<String>(&transaction_id.to_string())
+                {
+                    Some(json) => {
+                        let transaction: Transaction = serde_json::from_str(&json)?;
+
+                        Ok(Option::from(transaction))
+                    }
+                    None => Ok(None),
                 }
-                None => Ok(None),
-            },
+            }
         }
     }
 
     fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
         let json = serde_json::to_string(&transaction)?;
 
+        // A cached copy would hide the change, e.g. the refunded amount of a deposit.
+        if let Some(cached) = self
+            .disputed_transactions_cache
+            .peek_mut(&transaction.transaction_id)
+        {
+            *cached = transaction.clone();
+        }
+
         self.transaction_db
-            .set(&*transaction.transaction_id.to_string(), &json)?;
+            .set(&transaction.transaction_id.to_string(), &json)?;
 
         Ok(())
     }
 
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transactions = Vec::new();
+
+        for entry in self.transaction_db.iter() {
+            if let Some(json) = entry.get_value::<String>() {
+                let transaction: Transaction = serde_json::from_str(&json)?;
+
+                if transaction.client_id == client_id {
+                    transactions.push(transaction);
+                }
+            }
+        }
+
+        Ok(transactions)
+    }
+
     fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
-        Ok(self.accounts.get(&client_id).cloned())
+        Ok(self.accounts.get(client_id).cloned())
     }
 
     fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
-        self.accounts.insert(account.client_id, account);
+        self.accounts_db.set(
+            &account.client_id.to_string(),
+            &serde_json::to_string(&account)?,
+        )?;
+        self.accounts.insert(account);
 
         Ok(())
     }
@@ -92,6 +424,39 @@ impl DatastoreOperations for PickleDatastore {
         Ok(self.accounts.values().cloned().collect())
     }
 
+    fn retrieve_accounts_after(
+        &self,
+        after: Option<u16>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        Ok(self.accounts.after(after).take(count).cloned().collect())
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transaction_ids: Vec<u32> = self
+            .transaction_db
+            .get_all()
+            .iter()
+            .filter_map(|key| key.parse().ok())
+            .filter(|transaction_id| after.is_none_or(|after| *transaction_id > after))
+            .collect();
+
+        transaction_ids.sort_unstable();
+        transaction_ids.truncate(count);
+
+        let mut transactions = Vec::with_capacity(transaction_ids.len());
+
+        for transaction_id in transaction_ids {
+            transactions.extend(self.retrieve_transaction(transaction_id)?);
+        }
+
+        Ok(transactions)
+    }
+
     fn set_transaction_disputed(
         &mut self,
         transaction_id: u32,
@@ -101,6 +466,12 @@ impl DatastoreOperations for PickleDatastore {
             Some(mut transaction) => {
                 transaction.disputed = disputed;
 
+                if disputed {
+                    self.disputed_index.insert(transaction.clone())?;
+                } else {
+                    self.disputed_index.remove(transaction_id)?;
+                }
+
                 self.disputed_transactions_cache
                     .put(transaction_id, transaction.clone());
                 self.save_transaction(transaction)?;
@@ -116,4 +487,380 @@ impl DatastoreOperations for PickleDatastore {
 
         Ok(())
     }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        let json = serde_json::to_string(&transaction)?;
+
+        self.pending_db
+            .set(&transaction.transaction_id.to_string(), &json)?;
+
+        Ok(())
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transactions = Vec::new();
+
+        for entry in self.pending_db.iter() {
+            if let Some(json) = entry.get_value::<String>() {
+                transactions.push(serde_json::from_str(&json)?);
+            }
+        }
+
+        Ok(transactions)
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.pending_db.rem(&transaction_id.to_string())?;
+
+        Ok(())
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        let mut chain = self.retrieve_dispute_chain(record.transaction_id)?;
+        let key = record.transaction_id.to_string();
+
+        chain.push(record);
+        self.dispute_chains_db
+            .set(&key, &serde_json::to_string(&chain)?)?;
+
+        Ok(())
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        match self
+            .dispute_chains_db
+            .get::<String>(&transaction_id.to_string())
+        {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn count_open_disputes(&mut self, client_id: u16) -> PaymentEngineResult<usize> {
+        Ok(self.disputed_index.count_client(client_id))
+    }
+
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        self.transaction_db.dump()?;
+        self.accounts_db.dump()?;
+
+        Ok(())
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.cache_stats)
+    }
+}
+
+impl DatastoreOperations for InMemoryDatastore {
+    fn retrieve_transaction(
+        &mut self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        Ok(self.transactions.get(&transaction_id).cloned())
+    }
+
+    fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.transactions
+            .insert(transaction.transaction_id, transaction);
+
+        Ok(())
+    }
+
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        Ok(self
+            .transactions
+            .values()
+            .filter(|t| t.client_id == client_id)
+            .cloned()
+            .collect())
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        Ok(self.accounts.get(client_id).cloned())
+    }
+
+    fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+        self.accounts.insert(account);
+
+        Ok(())
+    }
+
+    fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        Ok(self.accounts.values().cloned().collect())
+    }
+
+    fn retrieve_accounts_after(
+        &self,
+        after: Option<u16>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        Ok(self.accounts.after(after).take(count).cloned().collect())
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+
+        Ok(self
+            .transactions
+            .range((start, Bound::Unbounded))
+            .map(|(_, transaction)| transaction.clone())
+            .take(count)
+            .collect())
+    }
+
+    fn set_transaction_disputed(
+        &mut self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        match self.transactions.get_mut(&transaction_id) {
+            Some(transaction) => {
+                transaction.disputed = disputed;
+
+                Ok(())
+            }
+            None => Err(PaymentEngineError::DisputedValueChange),
+        }
+    }
+
+    fn remove_transaction_from_cache(&mut self, _transaction_id: u32) -> PaymentEngineResult<()> {
+        Ok(())
+    }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.pending_transactions
+            .insert(transaction.transaction_id, transaction);
+
+        Ok(())
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        Ok(self.pending_transactions.values().cloned().collect())
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.pending_transactions.remove(&transaction_id);
+
+        Ok(())
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        self.dispute_chains
+            .entry(record.transaction_id)
+            .or_default()
+            .push(record);
+
+        Ok(())
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        Ok(self
+            .dispute_chains
+            .get(&transaction_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+impl MigrationSource for PickleDatastore {
+    fn all_transactions(&mut self) -> PaymentEngineResult<Vec<Transaction>> {
+        self.transaction_db
+            .iter()
+            .filter_map(|entry| entry.get_value::<String>())
+            .map(|json| Ok(serde_json::from_str(&json)?))
+            .collect()
+    }
+
+    fn all_dispute_records(&mut self) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        let mut records = vec![];
+
+        for json in self
+            .dispute_chains_db
+            .iter()
+            .filter_map(|entry| entry.get_value::<String>())
+        {
+            records.extend(serde_json::from_str::<Vec<DisputeRecord>>(&json)?);
+        }
+
+        Ok(records)
+    }
+}
+
+impl MigrationSource for InMemoryDatastore {
+    fn all_transactions(&mut self) -> PaymentEngineResult<Vec<Transaction>> {
+        Ok(self.transactions.values().cloned().collect())
+    }
+
+    fn all_dispute_records(&mut self) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        Ok(self.dispute_chains.values().flatten().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datastore::{
+        AccountTable, DatastoreOperations, DisputedIndex, InMemoryDatastore, PickleDatastore,
+    };
+    use crate::error::PaymentEngineError;
+    use crate::model::{Account, DisputeRecord, Documents, Transaction, TransactionType};
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_keep_accounts_by_client_id_in_order() {
+        let mut table = AccountTable::new();
+
+        for client_id in [u16::MAX, 7, 0] {
+            table.insert(Account::new(client_id));
+        }
+        table.insert(Account {
+            locked: true,
+            ..Account::new(7)
+        });
+
+        assert!(table.get(7).unwrap().locked);
+        assert!(table.get(8).is_none());
+        assert_eq!(
+            table.values().map(|a| a.client_id).collect::<Vec<u16>>(),
+            vec![0, 7, u16::MAX]
+        );
+    }
+
+    #[test]
+    pub fn should_resume_accounts_and_transactions_of_earlier_run() {
+        let directory = TempDir::new().unwrap();
+        let deposit = Transaction::new(TransactionType::Deposit, 3, 9, Some(Decimal::from(5)));
+        let account = Account {
+            available: Decimal::from(5),
+            total: Decimal::from(5),
+            ..Account::new(3)
+        };
+
+        {
+            let mut datastore = PickleDatastore::open(directory.path(), false);
+
+            datastore.save_transaction(deposit.clone()).unwrap();
+            datastore.save_account(account.clone()).unwrap();
+        }
+
+        let mut resumed = PickleDatastore::open(directory.path(), true);
+
+        assert_eq!(resumed.retrieve_account(3).unwrap(), Some(account));
+        assert_eq!(resumed.retrieve_transaction(9).unwrap(), Some(deposit));
+        drop(resumed);
+
+        let fresh = PickleDatastore::open(directory.path(), false);
+
+        assert_eq!(fresh.retrieve_all_accounts().unwrap(), vec![]);
+    }
+
+    #[test]
+    pub fn should_start_fresh_run_without_disputes_of_earlier_run() {
+        let directory = TempDir::new().unwrap();
+        let deposit = Transaction::new(TransactionType::Deposit, 3, 9, Some(Decimal::from(5)));
+
+        {
+            let mut datastore = PickleDatastore::open(directory.path(), false);
+
+            datastore.save_transaction(deposit.clone()).unwrap();
+            datastore.set_transaction_disputed(9, true).unwrap();
+            datastore.save_pending_transaction(deposit.clone()).unwrap();
+            datastore
+                .save_dispute_record(DisputeRecord {
+                    transaction_id: 9,
+                    sequence: 1,
+                    r#type: TransactionType::Dispute,
+                    client_id: 3,
+                    amount: Decimal::from(5),
+                    closes: None,
+                    recorded_at: Utc::now(),
+                    reason_code: None,
+                    documents: Documents::default(),
+                })
+                .unwrap();
+        }
+
+        let mut resumed = PickleDatastore::open(directory.path(), true);
+
+        assert_eq!(resumed.count_open_disputes(3).unwrap(), 1);
+        assert_eq!(resumed.retrieve_dispute_chain(9).unwrap().len(), 1);
+        drop(resumed);
+
+        let mut fresh = PickleDatastore::open(directory.path(), false);
+
+        assert_eq!(fresh.retrieve_transaction(9).unwrap(), None);
+        assert_eq!(fresh.count_open_disputes(3).unwrap(), 0);
+        assert!(fresh.retrieve_pending_transactions().unwrap().is_empty());
+        assert!(fresh.retrieve_dispute_chain(9).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn should_reload_disputed_index() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("disputed.db");
+        let transaction = |transaction_id| Transaction {
+            disputed: true,
+            ..Transaction::new(
+                TransactionType::Deposit,
+                1,
+                transaction_id,
+                Some(Decimal::from(10)),
+            )
+        };
+        let mut index = DisputedIndex::open(&path, false);
+
+        index.insert(transaction(1)).unwrap();
+        index.insert(transaction(2)).unwrap();
+        index.remove(1).unwrap();
+
+        let reloaded = DisputedIndex::open(&path, true);
+
+        assert!(reloaded.get(1).is_none());
+        assert_eq!(reloaded.get(2), Some(&transaction(2)));
+    }
+
+    #[test]
+    pub fn should_keep_state_in_memory() {
+        let mut datastore = InMemoryDatastore::default();
+        let transaction = Transaction::new(TransactionType::Deposit, 4, 11, Some(Decimal::from(5)));
+
+        datastore.save_transaction(transaction.clone()).unwrap();
+        datastore
+            .save_pending_transaction(transaction.clone())
+            .unwrap();
+        datastore.set_transaction_disputed(11, true).unwrap();
+        datastore.remove_pending_transaction(11).unwrap();
+
+        assert!(
+            datastore
+                .retrieve_transaction(11)
+                .unwrap()
+                .unwrap()
+                .disputed
+        );
+        assert_eq!(datastore.retrieve_client_transactions(4).unwrap().len(), 1);
+        assert!(datastore
+            .retrieve_pending_transactions()
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            datastore.set_transaction_disputed(12, true),
+            Err(PaymentEngineError::DisputedValueChange)
+        ));
+    }
 }
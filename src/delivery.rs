@@ -0,0 +1,174 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::Account;
+use crate::report::CsvStyle;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+const FILE_URI_PREFIX: &str = "file://";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryFormat {
+    Csv,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Where the account report of a tenant's runs is delivered, one `[[deliveries]]` entry of the
+/// configuration. The destination is a directory, given as a path or `file://` URI.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Delivery {
+    pub tenant: String,
+    pub destination: String,
+    pub format: DeliveryFormat,
+}
+
+impl DeliveryFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            DeliveryFormat::Csv => "csv",
+            DeliveryFormat::Json => "jsonl",
+        }
+    }
+}
+
+impl Delivery {
+    pub fn is_valid(&self) -> bool {
+        !self.tenant.is_empty()
+            && !self.tenant.contains(std::path::is_separator)
+            && self.directory().is_some()
+    }
+
+    fn directory(&self) -> Option<&Path> {
+        match self.destination.split_once("://") {
+            None => Some(Path::new(&self.destination)),
+            Some(_) => self
+                .destination
+                .strip_prefix(FILE_URI_PREFIX)
+                .map(Path::new),
+        }
+    }
+
+    /// Writes the accounts to `accounts-<tenant>-<run time>` in the destination directory, as
+    /// CSV in `csv` style or as JSON lines. The file appears complete or not at all, so pickup
+    /// jobs never read a partial report.
+    pub fn deliver(
+        &self,
+        accounts: &[Account],
+        run_at: DateTime<Utc>,
+        csv: CsvStyle,
+    ) -> PaymentEngineResult<PathBuf> {
+        let directory = self.directory().ok_or(PaymentEngineError::InvalidConfig {
+            field: "deliveries",
+        })?;
+        let path = directory.join(format!(
+            "accounts-{}-{}.{}",
+            self.tenant,
+            run_at.format("%Y%m%dT%H%M%SZ"),
+            self.format.extension()
+        ));
+        let write_error = |source| PaymentEngineError::DeliveryWrite { source };
+
+        std::fs::create_dir_all(directory).map_err(write_error)?;
+
+        let mut file = NamedTempFile::new_in(directory).map_err(write_error)?;
+
+        match self.format {
+            DeliveryFormat::Csv => {
+                let mut writer = csv.builder().from_writer(file.as_file_mut());
+
+                for account in accounts {
+                    writer.serialize(account)?;
+                }
+
+                writer.flush().map_err(write_error)?;
+            }
+            DeliveryFormat::Json => {
+                let mut writer = BufWriter::new(file.as_file_mut());
+
+                for account in accounts {
+                    serde_json::to_writer(&mut writer, account)?;
+                    writer.write_all(b"\n").map_err(write_error)?;
+                }
+
+                writer.flush().map_err(write_error)?;
+            }
+        }
+
+        file.persist(&path).map_err(|e| write_error(e.error))?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delivery::{Delivery, DeliveryFormat};
+    use crate::model::Account;
+    use crate::report::{CsvQuote, CsvStyle, CsvTerminator};
+    use chrono::{TimeZone, Utc};
+    use rust_decimal::Decimal;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_deliver_report_in_tenant_format() {
+        let directory = TempDir::new().unwrap();
+        let delivery = |destination: String, format| Delivery {
+            tenant: "tenant-a".to_string(),
+            destination,
+            format,
+        };
+        let accounts = vec![Account {
+            available: Decimal::from(5),
+            total: Decimal::from(5),
+            ..Account::new(1)
+        }];
+        let run_at = Utc.with_ymd_and_hms(2024, 3, 1, 6, 0, 0).unwrap();
+
+        let csv = delivery(
+            directory.path().join("out").display().to_string(),
+            DeliveryFormat::Csv,
+        )
+        .deliver(&accounts, run_at, CsvStyle::default())
+        .unwrap();
+        let json = delivery(
+            format!("file://{}", directory.path().display()),
+            DeliveryFormat::Json,
+        )
+        .deliver(&accounts, run_at, CsvStyle::default())
+        .unwrap();
+        let crlf = delivery(
+            directory.path().join("crlf").display().to_string(),
+            DeliveryFormat::Csv,
+        )
+        .deliver(
+            &accounts,
+            run_at,
+            CsvStyle {
+                quote: CsvQuote::Always,
+                terminator: CsvTerminator::Crlf,
+            },
+        )
+        .unwrap();
+
+        assert!(csv.ends_with("out/accounts-tenant-a-20240301T060000Z.csv"));
+        assert_eq!(
+            std::fs::read_to_string(csv).unwrap(),
+            "client,available,held,total,locked\n1,5,0,5,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(json).unwrap(),
+            "{\"client\":1,\"available\":\"5\",\"held\":\"0\",\"total\":\"5\",\"locked\":false}\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(crlf).unwrap(),
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\r\n\
+             \"1\",\"5\",\"0\",\"5\",\"false\"\r\n"
+        );
+        assert!(!delivery("s3://bucket/prefix".to_string(), DeliveryFormat::Csv).is_valid());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config::ServiceConfig;
+use crate::datastore::InMemoryDatastore;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::Account;
+use crate::payment_service::PaymentService;
+use crate::report::Reporter;
+
+const STDIN_PATH: &str = "-";
+
+/// Takes the account report of the verifying run, which is only compared, never written.
+struct DiscardingReporter;
+
+impl Reporter for DiscardingReporter {
+    fn report(&mut self, _accounts: &[Account]) -> PaymentEngineResult<()> {
+        Ok(())
+    }
+}
+
+/// Processes `csv_paths` again on a fresh in-memory datastore with the settings of `service`,
+/// which has just run them, and fails unless both account reports are byte for byte the same.
+/// The second run reads and writes no state or side report files and delivers nothing, so the
+/// first run has to start from empty state as well for the reports to be comparable.
+pub fn verify(service: &PaymentService, csv_paths: &[&str]) -> PaymentEngineResult<()> {
+    if csv_paths.contains(&STDIN_PATH) {
+        return Err(PaymentEngineError::InputNotRepeatable);
+    }
+
+    let mut second = PaymentService::new(
+        Box::new(InMemoryDatastore::default()),
+        fresh_config(service.config()),
+    );
+
+    second.set_reporter(Box::new(DiscardingReporter));
+    second.run_files(csv_paths)?;
+
+    let first = service.render_report()?;
+    let second = second.render_report()?;
+
+    match first_difference(&first, &second) {
+        Some(line) => Err(PaymentEngineError::NondeterministicReport { line }),
+        None => {
+            info!("Report of a second run is identical, {} bytes", first.len());
+            Ok(())
+        }
+    }
+}
+
+/// Settings of `config` without the files and deliveries of a run, keeping everything which
+/// decides how transactions are processed and the report rendered.
+fn fresh_config(config: &ServiceConfig) -> ServiceConfig {
+    ServiceConfig {
+        deliveries: vec![],
+        anomaly: None,
+        rounding_drift_path: None,
+        risk_report_path: None,
+        balance_anomalies_path: None,
+        analytics_path: None,
+        rejects_path: None,
+        dispute_liability_path: None,
+        revaluation_path: None,
+        stats_path: None,
+        report_path: None,
+        ..config.without_state_files()
+    }
+}
+
+/// Line, counting from 1, on which the reports first differ, none when they are the same.
+fn first_difference(first: &[u8], second: &[u8]) -> Option<usize> {
+    if first == second {
+        return None;
+    }
+
+    let mut first_lines = first.split(|byte| *byte == b'\n');
+    let mut second_lines = second.split(|byte| *byte == b'\n');
+    let mut line = 1;
+
+    while first_lines.next() == second_lines.next() {
+        line += 1;
+    }
+
+    Some(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::determinism::{self, first_difference};
+    use crate::error::PaymentEngineError;
+    use crate::payment_service::PaymentService;
+    use std::io::Write;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    pub fn should_pass_for_same_input_and_locate_first_differing_line() {
+        let directory = TempDir::new().unwrap();
+        let mut file = NamedTempFile::new().unwrap();
+
+        write!(
+            file,
+            "type,client,tx,amount\n\
+             deposit,2,1,10.0\n\
+             deposit,1,2,5.0\n\
+             dispute,2,1,\n"
+        )
+        .unwrap();
+
+        let input = file.path().to_str().unwrap();
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig {
+                report_path: Some(directory.path().join("accounts.csv")),
+                ..ServiceConfig::default()
+            },
+        );
+
+        service.run(input).unwrap();
+
+        determinism::verify(&service, &[input]).unwrap();
+        assert!(matches!(
+            determinism::verify(&service, &["-"]),
+            Err(PaymentEngineError::InputNotRepeatable)
+        ));
+        assert_eq!(first_difference(b"a\nb\nc\n", b"a\nb\nd\n"), Some(3));
+        assert_eq!(first_difference(b"a\nb\n", b"a\nb\nc\n"), Some(3));
+        assert_eq!(first_difference(b"a\n", b"a\n"), None);
+    }
+}
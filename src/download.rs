@@ -0,0 +1,273 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use tempfile::TempDir;
+
+const REMOTE_SCHEMES: [&str; 2] = ["https://", "http://"];
+const DIGEST_FRAGMENT: &str = "#sha256=";
+#[cfg(feature = "remote-input")]
+const DOWNLOAD_ATTEMPTS: u64 = 5;
+#[cfg(feature = "remote-input")]
+const RETRY_DELAY_MS: u64 = 200;
+
+/// Input given as an `http(s)://` URL, optionally with the SHA-256 its content must have in a
+/// `#sha256=HEX` fragment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteInput {
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+/// Inputs of a run with every remote input replaced by the path of its downloaded copy. The
+/// copies are removed when this is dropped.
+pub struct LocalInputs {
+    pub paths: Vec<String>,
+    urls: HashMap<String, String>,
+    _directory: Option<TempDir>,
+}
+
+impl RemoteInput {
+    /// Remote input described by `input`, `None` for a local path or pattern.
+    pub fn parse(input: &str) -> Option<Self> {
+        if !REMOTE_SCHEMES
+            .iter()
+            .any(|scheme| input.starts_with(scheme))
+        {
+            return None;
+        }
+
+        let (url, sha256) = match input.split_once(DIGEST_FRAGMENT) {
+            Some((url, sha256)) => (url, Some(sha256.to_ascii_lowercase())),
+            None => (input, None),
+        };
+
+        Some(RemoteInput {
+            url: url.to_string(),
+            sha256,
+        })
+    }
+
+    /// Last segment of the URL path, so the copy keeps the extension of the input.
+    pub fn file_name(&self) -> &str {
+        let path = self.url.split(&['?', '#'][..]).next().unwrap_or_default();
+
+        match path.rsplit('/').next() {
+            Some(name) if !name.is_empty() => name,
+            _ => "input",
+        }
+    }
+
+    /// Downloads the content to `path` and checks it against the expected digest. A dropped
+    /// connection is resumed from the bytes already written.
+    pub fn fetch(&self, path: &Path) -> PaymentEngineResult<()> {
+        self.download(path)?;
+
+        let mut hasher = Sha256::new();
+
+        std::io::copy(&mut File::open(path)?, &mut hasher)?;
+
+        let actual = format!("{:x}", hasher.finalize());
+
+        match &self.sha256 {
+            Some(expected) if *expected != actual => {
+                Err(PaymentEngineError::InputChecksumMismatch {
+                    url: self.url.clone(),
+                    expected: expected.clone(),
+                    actual,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "remote-input")]
+    fn download(&self, path: &Path) -> PaymentEngineResult<()> {
+        let mut attempt = 1;
+
+        loop {
+            match self.download_rest(path) {
+                Ok(()) => return Ok(()),
+                Err(e)
+                    if matches!(*e, ureq::Error::Transport(_)) && attempt < DOWNLOAD_ATTEMPTS =>
+                {
+                    warn!("Download of {} interrupted, resuming: {}", self.url, e);
+                    std::thread::sleep(std::time::Duration::from_millis(RETRY_DELAY_MS * attempt));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(PaymentEngineError::InputDownload {
+                        url: self.url.clone(),
+                        source: e,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Requests the bytes after the ones already in `path` and appends them. A server which
+    /// ignores the range sends the whole content, which replaces the partial file.
+    #[cfg(feature = "remote-input")]
+    fn download_rest(&self, path: &Path) -> Result<(), Box<ureq::Error>> {
+        const PARTIAL_CONTENT: u16 = 206;
+        const RANGE_NOT_SATISFIABLE: u16 = 416;
+
+        let offset = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let request = match offset {
+            0 => ureq::get(&self.url),
+            offset => ureq::get(&self.url).set("Range", &format!("bytes={}-", offset)),
+        };
+        let response = match request.call() {
+            // Everything was written before the connection dropped.
+            Err(ureq::Error::Status(RANGE_NOT_SATISFIABLE, _)) if offset > 0 => return Ok(()),
+            response => response.map_err(Box::new)?,
+        };
+        let mut file = if response.status() == PARTIAL_CONTENT {
+            std::fs::OpenOptions::new().append(true).open(path)
+        } else {
+            File::create(path)
+        }
+        .map_err(|e| Box::new(e.into()))?;
+
+        std::io::copy(&mut response.into_reader(), &mut file)
+            .map(|_| ())
+            .map_err(|e| Box::new(e.into()))
+    }
+
+    #[cfg(not(feature = "remote-input"))]
+    fn download(&self, _path: &Path) -> PaymentEngineResult<()> {
+        Err(PaymentEngineError::RemoteInputNotEnabled {
+            url: self.url.clone(),
+        })
+    }
+}
+
+impl LocalInputs {
+    /// Downloads the remote inputs into a temporary directory. Local paths and patterns are
+    /// kept as they are.
+    pub fn fetch(inputs: &[&str]) -> PaymentEngineResult<Self> {
+        let mut directory: Option<TempDir> = None;
+        let mut paths = Vec::with_capacity(inputs.len());
+        let mut urls = HashMap::new();
+
+        for (index, input) in inputs.iter().enumerate() {
+            let remote = match RemoteInput::parse(input) {
+                Some(remote) => remote,
+                None => {
+                    paths.push(input.to_string());
+                    continue;
+                }
+            };
+            let directory = match &mut directory {
+                Some(directory) => directory,
+                None => directory.insert(TempDir::new()?),
+            };
+            let path = directory
+                .path()
+                .join(format!("{}-{}", index, remote.file_name()))
+                .to_string_lossy()
+                .into_owned();
+
+            info!("Downloading {}", remote.url);
+            remote.fetch(Path::new(&path))?;
+            urls.insert(path.clone(), remote.url);
+            paths.push(path);
+        }
+
+        Ok(LocalInputs {
+            paths,
+            urls,
+            _directory: directory,
+        })
+    }
+
+    /// URL a path was downloaded from, or the path itself for a local input.
+    pub fn original<'a>(&'a self, path: &'a str) -> &'a str {
+        self.urls.get(path).map_or(path, String::as_str)
+    }
+}
+
+#[cfg(all(test, feature = "remote-input"))]
+mod tests {
+    use crate::download::RemoteInput;
+    use crate::error::PaymentEngineError;
+    use sha2::{Digest, Sha256};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tempfile::TempDir;
+
+    const CONTENT: &str = "type,client,tx,amount\ndeposit,1,1,10\n";
+
+    /// Serves the content three times: the first response is cut off after a few bytes, the
+    /// second one answers the range request with the rest and the third one finds nothing left.
+    fn serve_with_drop() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/daily.csv", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for _ in 0..3 {
+                let mut stream = listener.accept().unwrap().0;
+                let mut range = None;
+
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap().to_ascii_lowercase();
+
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("range: bytes=") {
+                        range = Some(value.trim_end_matches('-').parse::<usize>().unwrap());
+                    }
+                }
+
+                match range {
+                    None => write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        CONTENT.len(),
+                        &CONTENT[..10]
+                    ),
+                    Some(offset) if offset == CONTENT.len() => write!(
+                        stream,
+                        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\n\r\n"
+                    ),
+                    Some(offset) => write!(
+                        stream,
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n{}",
+                        CONTENT.len() - offset,
+                        &CONTENT[offset..]
+                    ),
+                }
+                .unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    pub fn should_resume_dropped_download_and_verify_digest() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("daily.csv");
+        let digest = format!("{:x}", Sha256::digest(CONTENT.as_bytes()));
+        let remote =
+            RemoteInput::parse(&format!("{}#sha256={}", serve_with_drop(), digest)).unwrap();
+
+        remote.fetch(&path).unwrap();
+
+        assert_eq!(remote.file_name(), "daily.csv");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CONTENT);
+
+        let tampered = RemoteInput {
+            sha256: Some("0".repeat(64)),
+            ..remote
+        };
+
+        assert!(matches!(
+            tampered.fetch(&path),
+            Err(PaymentEngineError::InputChecksumMismatch { .. })
+        ));
+    }
+}
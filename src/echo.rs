@@ -0,0 +1,207 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{Currency, Transaction, TransactionType};
+use crate::rows::TransactionRows;
+use chrono::{DateTime, Utc};
+use csv::{Writer, WriterBuilder};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+/// Format of the transactions written back in echo mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoFormat {
+    Csv,
+    /// One JSON object per line.
+    Json,
+}
+
+impl EchoFormat {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "csv" => Some(EchoFormat::Csv),
+            "json" => Some(EchoFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Transaction in the canonical input schema: lower case type and the short column names.
+#[derive(Debug, Serialize)]
+pub struct CanonicalTransaction<'a> {
+    r#type: &'static str,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
+    timestamp: Option<DateTime<Utc>>,
+    memo: Option<&'a str>,
+    counterparty: Option<&'a str>,
+    to_client: Option<u16>,
+    currency: Option<&'a Currency>,
+    to_currency: Option<&'a Currency>,
+}
+
+impl<'a> From<&'a Transaction> for CanonicalTransaction<'a> {
+    fn from(transaction: &'a Transaction) -> Self {
+        CanonicalTransaction {
+            r#type: transaction.r#type.name(),
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            amount: transaction.amount,
+            timestamp: transaction.timestamp,
+            memo: transaction.memo.as_deref(),
+            counterparty: transaction.counterparty.as_deref(),
+            to_client: transaction.to_client,
+            currency: transaction.currency.as_ref(),
+            to_currency: transaction.to_currency.as_ref(),
+        }
+    }
+}
+
+enum Output<W: Write> {
+    Csv(Box<Writer<W>>),
+    Json(W),
+}
+
+impl<W: Write> Output<W> {
+    fn write(&mut self, transaction: &CanonicalTransaction) -> PaymentEngineResult<()> {
+        match self {
+            Output::Csv(writer) => writer.serialize(transaction)?,
+            Output::Json(writer) => {
+                serde_json::to_writer(&mut *writer, transaction)?;
+                writeln!(writer).map_err(|source| PaymentEngineError::ExportWrite { source })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        match self {
+            Output::Csv(writer) => writer.flush()?,
+            Output::Json(writer) => writer
+                .flush()
+                .map_err(|source| PaymentEngineError::ExportWrite { source })?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Rows read by an echo run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EchoSummary {
+    pub written: usize,
+    pub rejected: usize,
+}
+
+/// Checks which do not need any account state, i.e. everything the engine rejects a row for
+/// before looking at balances.
+pub fn validate(transaction: &Transaction) -> PaymentEngineResult<()> {
+    match transaction.r#type {
+        TransactionType::Deposit
+        | TransactionType::Withdrawal
+        | TransactionType::Transfer
+        | TransactionType::Convert
+        | TransactionType::Refund
+        | TransactionType::Authorize
+        | TransactionType::Correction
+            if transaction.amount.is_none() =>
+        {
+            Err(PaymentEngineError::NoAmount)
+        }
+        TransactionType::Transfer
+            if transaction.to_client.is_none()
+                || transaction.to_client == Some(transaction.client_id) =>
+        {
+            Err(PaymentEngineError::InvalidTransferRecipient)
+        }
+        TransactionType::Convert if transaction.currency == transaction.to_currency => {
+            Err(PaymentEngineError::InvalidConversion)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Parses and validates the input with the rules of a normal run and writes every accepted
+/// transaction back in canonical form, without applying anything. Rejected rows are logged with
+/// their row number.
+pub fn echo_file<W: Write>(
+    csv_path: &Path,
+    format: EchoFormat,
+    writer: W,
+) -> PaymentEngineResult<EchoSummary> {
+    let mut rows = TransactionRows::from_path(csv_path)?;
+    let mut summary = EchoSummary::default();
+    let mut output = match format {
+        EchoFormat::Csv => Output::Csv(Box::new(WriterBuilder::new().from_writer(writer))),
+        EchoFormat::Json => Output::Json(writer),
+    };
+    let mut row = 1;
+
+    while let Some(entry) = rows.next_transaction() {
+        row += 1;
+
+        let transaction = match entry
+            .map_err(PaymentEngineError::from)
+            .and_then(|transaction| {
+                validate(&transaction)?;
+                Ok(transaction)
+            }) {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                warn!("Row {} rejected: {}", row, e);
+                summary.rejected += 1;
+                continue;
+            }
+        };
+
+        output.write(&CanonicalTransaction::from(&transaction))?;
+        summary.written += 1;
+    }
+
+    output.flush()?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::echo::{echo_file, EchoFormat, EchoSummary};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_echo_valid_rows_in_canonical_form() {
+        let file = NamedTempFile::new().unwrap();
+
+        std::fs::write(
+            file.path(),
+            "type, client_id, transaction_id, amount\nDEPOSIT, 1, 1, 1.50\nwithdrawal, 1, 2,\n\
+             bogus, 1, 3, 1\ndispute, 1, 1,\n",
+        )
+        .unwrap();
+
+        let mut csv = vec![];
+        let summary = echo_file(file.path(), EchoFormat::Csv, &mut csv).unwrap();
+        let mut json = vec![];
+
+        echo_file(file.path(), EchoFormat::Json, &mut json).unwrap();
+
+        assert_eq!(
+            summary,
+            EchoSummary {
+                written: 2,
+                rejected: 2
+            }
+        );
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "type,client,tx,amount,timestamp,memo,counterparty,to_client,currency,to_currency\n\
+             deposit,1,1,1.50,,,,,,\ndispute,1,1,,,,,,,\n"
+        );
+        assert_eq!(
+            String::from_utf8(json).unwrap().lines().next().unwrap(),
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"1.50","timestamp":null,"memo":null,"counterparty":null,"to_client":null,"currency":null,"to_currency":null}"#
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use crate::error::PaymentEngineResult;
+use crate::model::Transaction;
+use chrono::{DateTime, Utc};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const LEGAL_HOLDS_DB_PATH: &str = "pe_legal_holds.db";
+/// Value the personal fields of an erased client's transactions are replaced with, so they
+/// read as erased rather than as never given.
+pub const ERASED: &str = "[erased]";
+
+/// Client whose data has to be kept, e.g. for litigation or a regulator, which blocks erasure
+/// requests until the hold is released.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegalHold {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub reason: String,
+    pub principal: String,
+    pub placed_at: DateTime<Utc>,
+}
+
+/// Legal holds by client, optionally persisted so they survive between runs.
+pub struct LegalHolds {
+    db: Option<PickleDb>,
+    holds: HashMap<u16, LegalHold>,
+}
+
+/// Records changed by erasing the personal data of a client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ErasureSummary {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub transactions: usize,
+    pub pending: usize,
+}
+
+impl LegalHolds {
+    pub fn open(path: Option<&Path>) -> Self {
+        let db = path.map(|path| {
+            PickleDb::load(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                .unwrap_or_else(|_| {
+                    PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                })
+        });
+        let holds = match &db {
+            Some(db) => db
+                .iter()
+                .filter_map(|item| item.get_value::<String>())
+                .filter_map(|json| serde_json::from_str::<LegalHold>(&json).ok())
+                .map(|hold| (hold.client_id, hold))
+                .collect(),
+            None => HashMap::default(),
+        };
+
+        LegalHolds { db, holds }
+    }
+
+    pub fn get(&self, client_id: u16) -> Option<&LegalHold> {
+        self.holds.get(&client_id)
+    }
+
+    pub fn list(&self) -> Vec<LegalHold> {
+        let mut holds: Vec<LegalHold> = self.holds.values().cloned().collect();
+
+        holds.sort_by_key(|hold| hold.client_id);
+
+        holds
+    }
+
+    /// Places the hold, replacing an earlier one of the client.
+    pub fn place(&mut self, hold: LegalHold) -> PaymentEngineResult<()> {
+        if let Some(db) = self.db.as_mut() {
+            db.set(&hold.client_id.to_string(), &serde_json::to_string(&hold)?)?;
+        }
+        self.holds.insert(hold.client_id, hold);
+
+        Ok(())
+    }
+
+    pub fn release(&mut self, client_id: u16) -> PaymentEngineResult<Option<LegalHold>> {
+        if let Some(db) = self.db.as_mut() {
+            db.rem(&client_id.to_string())?;
+        }
+
+        Ok(self.holds.remove(&client_id))
+    }
+}
+
+/// Copy of the transaction with its memo and counterparty replaced by `ERASED`, or `None` when
+/// it has no personal data left. Amounts, ids and dispute state are kept as they are, so
+/// balances and the ledger stay consistent.
+pub fn erase(transaction: &Transaction) -> Option<Transaction> {
+    let erase_field = |field: &Option<String>| match field.as_deref() {
+        Some(value) if value != ERASED => Some(ERASED.to_string()),
+        _ => field.clone(),
+    };
+    let erased = Transaction {
+        memo: erase_field(&transaction.memo),
+        counterparty: erase_field(&transaction.counterparty),
+        ..transaction.clone()
+    };
+
+    if erased == *transaction {
+        None
+    } else {
+        Some(erased)
+    }
+}
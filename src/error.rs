@@ -1,3 +1,5 @@
+#![allow(non_local_definitions)]
+
 #[derive(Debug, Display, Error, From)]
 #[display(fmt = "PaymentEngine error: {}")]
 pub enum PaymentEngineError {
@@ -7,20 +9,312 @@ pub enum PaymentEngineError {
     CsvExport { source: std::io::Error },
     #[display(fmt = "No amount for transaction which requires it")]
     NoAmount,
+    #[display(fmt = "Transfer needs a to_client other than the client")]
+    InvalidTransferRecipient,
+    #[display(fmt = "Transaction has a currency but no base_currency is configured")]
+    CurrencyNotEnabled,
+    #[display(fmt = "Conversion needs a to_currency other than its currency")]
+    InvalidConversion,
+    #[display(fmt = "No exchange rate from {} to {}", from, to)]
+    #[from(ignore)]
+    ExchangeRateNotFound { from: String, to: String },
+    #[display(
+        fmt = "Exchange rate from {} to {} must be positive between two currencies",
+        from,
+        to
+    )]
+    #[from(ignore)]
+    InvalidExchangeRate { from: String, to: String },
     #[display(fmt = "There are not enough funds on the account")]
     InsufficientAccountFunds,
     #[display(fmt = "Disputed transaction does not exist")]
     DisputedTransactionNotFound,
     #[display(
-        fmt = "Invalid disputed transaction, dispute can only be done for withdrawal and deposit"
+        fmt = "Client {} cannot dispute a transaction of client {}",
+        client_id,
+        transaction_client_id
+    )]
+    #[from(ignore)]
+    DisputeClientMismatch {
+        client_id: u16,
+        transaction_client_id: u16,
+    },
+    #[display(
+        fmt = "Invalid disputed transaction, dispute can only be done for withdrawal, deposit and transfer"
     )]
     InvalidDisputedTransactionType,
+    #[display(fmt = "Only deposits which are not disputed or charged back can be refunded")]
+    RefundNotAllowed,
+    #[display(fmt = "Refund exceeds the {} left of the deposit", refundable)]
+    #[from(ignore)]
+    OverRefund { refundable: rust_decimal::Decimal },
+    #[display(
+        fmt = "Only deposits and withdrawals which were not disputed or refunded can be corrected"
+    )]
+    CorrectionNotAllowed,
+    #[display(fmt = "Transaction is not an open authorization")]
+    AuthorizationNotOpen,
+    #[display(fmt = "Capture exceeds the authorized {}", authorized)]
+    #[from(ignore)]
+    OverCapture { authorized: rust_decimal::Decimal },
     #[display(fmt = "Transaction is already disputed")]
     TransactionAlreadyDisputed,
     #[display(fmt = "Transaction not found, cannot change disputed status")]
     DisputedValueChange,
     #[display(fmt = "Transaction is not disputed")]
     TransactionNotDisputed,
+    #[display(fmt = "Only the latest chargeback of a deposit can be represented, once")]
+    RepresentmentNotAllowed,
+    #[display(fmt = "Account is locked")]
+    AccountLocked,
+    #[display(fmt = "Account is locked, its dispute steps are rejected")]
+    DisputeOnLockedAccount,
+    #[display(fmt = "Amount is too large, the balance would overflow")]
+    AmountOverflow,
+    #[display(fmt = "Merged input files must have the same header")]
+    MergeHeaderMismatch,
+    #[display(fmt = "Input file has no column to sort by")]
+    MissingSortColumn,
+    #[display(fmt = "Input file contains a value which cannot be sorted by")]
+    InvalidSortValue,
+    #[display(fmt = "Timestamp is not a valid RFC 3339 date-time")]
+    InvalidTimestamp,
+    #[display(fmt = "Merged input file is not sorted by timestamp")]
+    UnsortedMergeInput,
+    #[display(fmt = "Reason code and document references must be non-empty, without spaces")]
+    InvalidDisputeEvidence,
+    #[display(fmt = "Reason code {} is not in the configured taxonomy", code)]
+    #[from(ignore)]
+    UnknownReasonCode { code: String },
+    #[display(fmt = "Input file has no client column")]
+    MissingClientColumn,
+    #[display(fmt = "Input file has no column for {} in the partner profile", column)]
+    #[from(ignore)]
+    MissingProfileColumn { column: String },
+    #[display(
+        fmt = "Input file has column {} which the partner profile does not map",
+        column
+    )]
+    #[from(ignore)]
+    UnexpectedProfileColumn { column: String },
+    #[display(fmt = "Input file has column {} more than once", column)]
+    #[from(ignore)]
+    DuplicateProfileColumn { column: String },
+    #[display(fmt = "Input {} is not a file path or file:// URI", uri)]
+    #[from(ignore)]
+    UnsupportedInputUri { uri: String },
+    #[display(fmt = "Number of shards must be at least 1")]
+    InvalidShardCount,
+    #[display(fmt = "Error writing exported transactions: {}", source)]
+    #[from(ignore)]
+    ExportWrite { source: std::io::Error },
+    #[display(fmt = "This command needs the event store, pass --event-store")]
+    EventStoreRequired,
+    #[display(fmt = "The datastore cannot list its transactions")]
+    TransactionListingUnsupported,
+    #[display(fmt = "Client {} appears in more than one shard", client_id)]
+    #[from(ignore)]
+    ClientInMultipleShards { client_id: u16 },
+    #[display(
+        fmt = "Strict run aborted at row {} of the run, read from {}: {}",
+        row,
+        input,
+        reason
+    )]
+    #[from(ignore)]
+    StrictRowRejected {
+        input: String,
+        row: u64,
+        reason: String,
+    },
+    #[display(fmt = "Run aborted, maximum number of rows exceeded")]
+    RowLimitExceeded,
+    #[display(fmt = "Run aborted, maximum number of distinct clients exceeded")]
+    ClientLimitExceeded,
+    #[display(fmt = "Run aborted, maximum total deposit value exceeded")]
+    DepositLimitExceeded,
+    #[display(fmt = "Batch not applied, {} transactions failed", failed)]
+    #[from(ignore)]
+    BatchRejected { failed: usize },
+    #[display(fmt = "A staged batch has to be applied or discarded first")]
+    StagedBatchPending,
+    #[display(fmt = "Transaction is not waiting for approval")]
+    PendingTransactionNotFound,
+    #[display(fmt = "Reservation does not exist")]
+    ReservationNotFound,
+    #[display(
+        fmt = "Client {} is under legal hold, data cannot be erased",
+        client_id
+    )]
+    #[from(ignore)]
+    ClientUnderLegalHold { client_id: u16 },
+    #[display(fmt = "Client is not under legal hold")]
+    LegalHoldNotFound,
+    #[display(fmt = "Client has too many open disputes")]
+    OpenDisputeLimitExceeded,
+    #[display(fmt = "Reservation has expired")]
+    ReservationExpired,
+    #[display(fmt = "Reserved amount must be positive")]
+    InvalidReservationAmount,
+    #[display(fmt = "Cannot write audit log")]
+    #[from(ignore)]
+    AuditLog { source: std::io::Error },
+    #[display(fmt = "Cannot read/write event log")]
+    #[from(ignore)]
+    EventLog { source: std::io::Error },
+    #[display(fmt = "Cannot read/write write-ahead log")]
+    #[from(ignore)]
+    WriteAheadLog { source: std::io::Error },
+    #[display(fmt = "Multiple input files require --merge-by-timestamp or --sort-by in this mode")]
+    UnmergedInputFiles,
+    #[display(fmt = "Input pattern {} is invalid or matches no files", pattern)]
+    #[from(ignore)]
+    InvalidInputPattern { pattern: String },
+    #[display(fmt = "Continuation token {} is invalid for this listing", token)]
+    #[from(ignore)]
+    InvalidContinuationToken { token: String },
+    #[cfg(feature = "search")]
+    #[display(fmt = "Cannot read/write search index")]
+    SearchIndex { source: tantivy::TantivyError },
+    #[cfg(feature = "sqlite")]
+    #[display(fmt = "Cannot read/save data with SQLite")]
+    Sqlite { source: rusqlite::Error },
+    #[cfg(feature = "sled")]
+    #[display(fmt = "Cannot read/save data with sled")]
+    Sled { source: sled::Error },
+    #[display(fmt = "Cannot read balance history")]
+    #[from(ignore)]
+    BalanceHistory { source: std::io::Error },
+    #[display(
+        fmt = "Balances of {} clients changed unusually, see the anomaly report",
+        clients
+    )]
+    #[from(ignore)]
+    BalanceAnomalies { clients: usize },
+    #[display(fmt = "Cannot read/write migration progress")]
+    #[from(ignore)]
+    MigrationProgress { source: std::io::Error },
+    #[display(fmt = "{} migrated records differ from the source", mismatches)]
+    #[from(ignore)]
+    MigrationMismatch { mismatches: usize },
+    #[display(fmt = "Source and target of the migration are the same backend")]
+    SameMigrationBackend,
+    #[cfg(feature = "async")]
+    #[display(fmt = "Engine thread of the async service has stopped")]
+    AsyncServiceStopped,
+    #[cfg(feature = "http")]
+    #[display(fmt = "HTTP server cannot listen or read requests")]
+    #[from(ignore)]
+    HttpServer { source: std::io::Error },
+    #[cfg(feature = "grpc")]
+    #[display(fmt = "gRPC server failed")]
+    Grpc { source: tonic::transport::Error },
+    #[cfg(feature = "grpc")]
+    #[display(fmt = "gRPC server cannot start its runtime or listen")]
+    #[from(ignore)]
+    GrpcListen { source: std::io::Error },
+    #[cfg(feature = "kafka")]
+    #[display(fmt = "Kafka consumer failed")]
+    Kafka { source: rdkafka::error::KafkaError },
+    #[cfg(feature = "tui")]
+    #[display(fmt = "Cannot show the run dashboard on the terminal")]
+    #[from(ignore)]
+    Tui { source: std::io::Error },
+    #[cfg(feature = "grpc")]
+    #[display(fmt = "Field {} of the gRPC message is invalid", field)]
+    #[from(ignore)]
+    InvalidGrpcTransaction { field: &'static str },
+    #[cfg(feature = "fraud-check")]
+    #[display(fmt = "Fraud check endpoint failed or answered without a decision")]
+    FraudCheck { source: Box<ureq::Error> },
+    #[cfg(feature = "remote-input")]
+    #[display(fmt = "Cannot download input {}", url)]
+    #[from(ignore)]
+    InputDownload {
+        url: String,
+        source: Box<ureq::Error>,
+    },
+    #[display(
+        fmt = "Remote input {} needs a build with the remote-input feature",
+        url
+    )]
+    #[from(ignore)]
+    RemoteInputNotEnabled { url: String },
+    #[display(
+        fmt = "Input {} has SHA-256 {} instead of the expected {}",
+        url,
+        actual,
+        expected
+    )]
+    #[from(ignore)]
+    InputChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    #[display(fmt = "Withdrawal denied by the fraud check")]
+    FraudCheckDenied,
+    #[display(fmt = "Dual control needs a second, different principal to confirm the action")]
+    SamePrincipalConfirmation,
+    #[display(fmt = "Cannot read configuration file")]
+    #[from(ignore)]
+    ConfigRead { source: std::io::Error },
+    #[display(fmt = "Cannot parse configuration file")]
+    ConfigParse { source: toml::de::Error },
+    #[display(fmt = "Invalid configuration value for {}", field)]
+    #[from(ignore)]
+    InvalidConfig { field: &'static str },
+    #[display(fmt = "Cannot watch configuration file")]
+    ConfigWatch { source: notify::Error },
+    #[display(fmt = "Cannot render PDF")]
+    Pdf { source: printpdf::Error },
+    #[display(fmt = "Cannot deliver account report")]
+    #[from(ignore)]
+    DeliveryWrite { source: std::io::Error },
+    #[display(fmt = "Cannot write statement file")]
+    #[from(ignore)]
+    StatementWrite { source: std::io::Error },
+    #[display(fmt = "Transaction {} of the client does not exist", transaction_id)]
+    #[from(ignore)]
+    TransactionNotFound { transaction_id: u32 },
+    #[display(fmt = "Job {} does not exist", job_id)]
+    #[from(ignore)]
+    JobNotFound { job_id: u64 },
+    #[display(fmt = "Cannot write evidence bundle")]
+    EvidenceBundle { source: zip::result::ZipError },
+    #[display(fmt = "Cannot read input archive {}", path)]
+    #[from(ignore)]
+    InputArchive {
+        path: String,
+        source: zip::result::ZipError,
+    },
+    #[display(fmt = "Cannot save/restore checkpoint")]
+    #[from(ignore)]
+    Checkpoint { source: std::io::Error },
+    #[display(fmt = "No checkpoint to resume from")]
+    CheckpointNotFound,
+    #[display(fmt = "Checkpoint belongs to input {}", input)]
+    #[from(ignore)]
+    CheckpointInputMismatch { input: String },
+    #[display(fmt = "Cannot copy persistent state from {}", path)]
+    #[from(ignore)]
+    StateFork {
+        path: String,
+        source: std::io::Error,
+    },
+    #[display(fmt = "{} accounts differ from their recomputed state", clients)]
+    #[from(ignore)]
+    AccountsMismatch { clients: usize },
+    #[display(
+        fmt = "Report of a second run differs from the first from line {}",
+        line
+    )]
+    #[from(ignore)]
+    NondeterministicReport { line: usize },
+    #[display(fmt = "Standard input cannot be read a second time")]
+    InputNotRepeatable,
+    #[display(fmt = "All transaction ids reserved for this node are used")]
+    IdRangeExhausted,
     #[display(fmt = "Cannot serialize/deserialize JSON")]
     Json { source: serde_json::Error },
     #[display(fmt = "Cannot read/save data with pickle_db")]
@@ -28,3 +322,171 @@ pub enum PaymentEngineError {
 }
 
 pub type PaymentEngineResult<T> = Result<T, PaymentEngineError>;
+
+/// Broad class of an error, for callers deciding whether to retry, fix the input or give up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Reading or writing storage failed; the same call may succeed later.
+    Retryable,
+    /// The transaction or request was refused by a business rule.
+    Rejected,
+    /// The input data is malformed or incomplete.
+    DataQuality,
+    /// Configuration, usage or stored state is wrong; retrying the call cannot succeed.
+    Permanent,
+}
+
+impl PaymentEngineError {
+    pub fn kind(&self) -> ErrorKind {
+        use PaymentEngineError::*;
+
+        match self {
+            CsvImport { source } if source.is_io_error() => ErrorKind::Retryable,
+            CsvExport { .. }
+            | ExportWrite { .. }
+            | AuditLog { .. }
+            | EventLog { .. }
+            | WriteAheadLog { .. }
+            | MigrationProgress { .. }
+            | Checkpoint { .. }
+            | ConfigRead { .. }
+            | ConfigWatch { .. }
+            | StatementWrite { .. }
+            | DeliveryWrite { .. }
+            | EvidenceBundle { .. }
+            | BalanceHistory { .. }
+            | PickleDb { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "search")]
+            SearchIndex { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "sqlite")]
+            Sqlite { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "sled")]
+            Sled { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "http")]
+            HttpServer { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "grpc")]
+            Grpc { .. } | GrpcListen { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "kafka")]
+            Kafka { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "grpc")]
+            InvalidGrpcTransaction { .. } => ErrorKind::DataQuality,
+            #[cfg(feature = "fraud-check")]
+            FraudCheck { .. } => ErrorKind::Retryable,
+            #[cfg(feature = "remote-input")]
+            InputDownload { .. } => ErrorKind::Retryable,
+            InsufficientAccountFunds
+            | DisputedTransactionNotFound
+            | DisputeClientMismatch { .. }
+            | InvalidDisputedTransactionType
+            | RefundNotAllowed
+            | OverRefund { .. }
+            | AuthorizationNotOpen
+            | CorrectionNotAllowed
+            | OverCapture { .. }
+            | TransactionAlreadyDisputed
+            | DisputedValueChange
+            | TransactionNotDisputed
+            | AccountLocked
+            | DisputeOnLockedAccount
+            | RepresentmentNotAllowed
+            | OpenDisputeLimitExceeded
+            | FraudCheckDenied
+            | ExchangeRateNotFound { .. }
+            | SamePrincipalConfirmation
+            | PendingTransactionNotFound
+            | BatchRejected { .. }
+            | ReservationNotFound
+            | ClientUnderLegalHold { .. }
+            | LegalHoldNotFound
+            | ReservationExpired
+            | InvalidReservationAmount => ErrorKind::Rejected,
+            CsvImport { .. }
+            | NoAmount
+            | InvalidTransferRecipient
+            | CurrencyNotEnabled
+            | InvalidConversion
+            | AmountOverflow
+            | MergeHeaderMismatch
+            | MissingSortColumn
+            | InvalidSortValue
+            | InvalidTimestamp
+            | UnsortedMergeInput
+            | MissingClientColumn
+            | InvalidDisputeEvidence
+            | UnknownReasonCode { .. }
+            | MissingProfileColumn { .. }
+            | UnexpectedProfileColumn { .. }
+            | DuplicateProfileColumn { .. }
+            | InputArchive { .. }
+            | InputChecksumMismatch { .. }
+            | InvalidContinuationToken { .. }
+            | BalanceAnomalies { .. } => ErrorKind::DataQuality,
+            InvalidShardCount
+            | UnsupportedInputUri { .. }
+            | EventStoreRequired
+            | TransactionListingUnsupported
+            | ClientInMultipleShards { .. }
+            | StrictRowRejected { .. }
+            | RowLimitExceeded
+            | ClientLimitExceeded
+            | DepositLimitExceeded
+            | UnmergedInputFiles
+            | InvalidInputPattern { .. }
+            | StagedBatchPending
+            | TransactionNotFound { .. }
+            | JobNotFound { .. }
+            | AccountsMismatch { .. }
+            | NondeterministicReport { .. }
+            | InputNotRepeatable
+            | IdRangeExhausted
+            | ConfigParse { .. }
+            | InvalidConfig { .. }
+            | InvalidExchangeRate { .. }
+            | Pdf { .. }
+            | RemoteInputNotEnabled { .. }
+            | StateFork { .. }
+            | MigrationMismatch { .. }
+            | SameMigrationBackend
+            | CheckpointNotFound
+            | CheckpointInputMismatch { .. }
+            | Json { .. } => ErrorKind::Permanent,
+            #[cfg(feature = "async")]
+            AsyncServiceStopped => ErrorKind::Permanent,
+            #[cfg(feature = "tui")]
+            Tui { .. } => ErrorKind::Permanent,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Retryable
+    }
+
+    /// Whether the caller caused the error, with a rejected request or malformed input.
+    pub fn is_client_error(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Rejected | ErrorKind::DataQuality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{ErrorKind, PaymentEngineError};
+    use std::error::Error;
+
+    #[test]
+    pub fn should_classify_errors_and_chain_sources() {
+        let io_error = std::io::Error::other("disk full");
+        let error = PaymentEngineError::EventLog { source: io_error };
+
+        assert!(error.is_retryable());
+        assert!(!error.is_client_error());
+        assert_eq!(error.source().unwrap().to_string(), "disk full");
+
+        assert!(PaymentEngineError::InsufficientAccountFunds.is_client_error());
+        assert_eq!(PaymentEngineError::NoAmount.kind(), ErrorKind::DataQuality);
+        assert_eq!(
+            PaymentEngineError::RowLimitExceeded.kind(),
+            ErrorKind::Permanent
+        );
+        assert!(PaymentEngineError::RowLimitExceeded.source().is_none());
+    }
+}
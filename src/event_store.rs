@@ -0,0 +1,743 @@
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{self, Account, Balances, Currency, DisputeRecord, Transaction};
+use crate::projection::{
+    AccountsProjection, AccountsView, Projection, ProjectionHandle, ProjectionRunner,
+};
+use crate::rebuild;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SNAPSHOT_INTERVAL: usize = 100;
+
+/// Immutable change of a single account. Transactions of the client written while handling a
+/// transaction are attached to the balance change they caused, so both are stored with a single
+/// append.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountEvent {
+    pub client_id: u16,
+    pub sequence: u64,
+    pub transactions: Vec<Transaction>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    #[serde(default)]
+    pub recorded_at: Option<DateTime<Utc>>,
+    /// Changes of the balances in currencies other than the base currency.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub balances: BTreeMap<Currency, Balances>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum LogEntry {
+    Account(AccountEvent),
+    Parked(Transaction),
+    Unparked(u32),
+    Dispute(DisputeRecord),
+    /// Transactions flushed without a change of their client's account to attach them to.
+    Transactions(Vec<Transaction>),
+}
+
+#[derive(Debug, Default)]
+struct AccountStream {
+    snapshot: Account,
+    events: Vec<AccountEvent>,
+    sequence: u64,
+}
+
+/// Event sourced datastore which appends every change to a log file. Accounts are a fold over
+/// their events, starting from an in-memory snapshot taken every `SNAPSHOT_INTERVAL` events,
+/// and transactions are read back from the log through an offset index. Events are also
+/// published to asynchronous projections, which serve the account report.
+pub struct EventSourcedDatastore {
+    writer: File,
+    reader: BufReader<File>,
+    log_length: u64,
+    streams: HashMap<u16, AccountStream>,
+    transaction_offsets: HashMap<u32, u64>,
+    client_transactions: HashMap<u16, Vec<u32>>,
+    staged_transactions: Vec<Transaction>,
+    parked_transactions: HashMap<u32, Transaction>,
+    dispute_chains: HashMap<u32, Vec<DisputeRecord>>,
+    projections: ProjectionRunner,
+    accounts_view: AccountsView,
+}
+
+impl AccountEvent {
+    pub fn apply(&self, account: &mut Account) -> PaymentEngineResult<()> {
+        account.adjust(self.available, self.held, self.total)?;
+        for (currency, change) in &self.balances {
+            account.adjust_in(Some(currency), change.available, change.held, change.total)?;
+
+            if let Some(balances) = account.balances.get_mut(currency) {
+                balances.cost = change.cost;
+            }
+        }
+        account.locked = self.locked;
+
+        Ok(())
+    }
+
+    /// Time the event was recorded. Events appended before the time was stored fall back to
+    /// the latest timestamp of their transactions.
+    fn time(&self) -> Option<DateTime<Utc>> {
+        self.recorded_at.or_else(|| {
+            self.transactions
+                .iter()
+                .filter_map(|transaction| transaction.timestamp)
+                .max()
+        })
+    }
+}
+
+impl AccountStream {
+    fn new(client_id: u16) -> Self {
+        AccountStream {
+            snapshot: Account::new(client_id),
+            ..AccountStream::default()
+        }
+    }
+
+    fn current(&self) -> PaymentEngineResult<Account> {
+        let mut account = self.snapshot.clone();
+
+        for event in &self.events {
+            event.apply(&mut account)?;
+        }
+
+        Ok(account)
+    }
+
+    fn push(&mut self, event: AccountEvent) -> PaymentEngineResult<()> {
+        self.sequence = event.sequence;
+        self.events.push(event);
+
+        if self.events.len() >= SNAPSHOT_INTERVAL {
+            self.snapshot = self.current()?;
+            self.events.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl EventSourcedDatastore {
+    pub fn open(
+        path: &Path,
+        mut projections: Vec<Box<dyn Projection>>,
+    ) -> PaymentEngineResult<Self> {
+        let accounts_projection = AccountsProjection::default();
+        let accounts_view = accounts_projection.view();
+
+        projections.push(Box::new(accounts_projection));
+
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| PaymentEngineError::EventLog { source })?;
+        let reader = File::open(path)
+            .map(BufReader::new)
+            .map_err(|source| PaymentEngineError::EventLog { source })?;
+        let mut datastore = EventSourcedDatastore {
+            writer,
+            reader,
+            log_length: 0,
+            streams: HashMap::default(),
+            transaction_offsets: HashMap::default(),
+            client_transactions: HashMap::default(),
+            staged_transactions: vec![],
+            parked_transactions: HashMap::default(),
+            dispute_chains: HashMap::default(),
+            projections: ProjectionRunner::start(projections),
+            accounts_view,
+        };
+
+        datastore.load()?;
+
+        Ok(datastore)
+    }
+
+    pub fn projection_handle(&self) -> ProjectionHandle {
+        self.projections.handle()
+    }
+
+    fn load(&mut self) -> PaymentEngineResult<()> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+
+            let offset = self.log_length;
+            let read = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|source| PaymentEngineError::EventLog { source })?;
+
+            if read == 0 {
+                return Ok(());
+            }
+
+            self.log_length += read as u64;
+            self.index_entry(serde_json::from_str(&line)?, offset)?;
+        }
+    }
+
+    fn index_entry(&mut self, entry: LogEntry, offset: u64) -> PaymentEngineResult<()> {
+        match entry {
+            LogEntry::Account(event) => {
+                self.index_transactions(&event.transactions, offset);
+                self.projections.publish(event.clone());
+                self.streams
+                    .entry(event.client_id)
+                    .or_insert_with(|| AccountStream::new(event.client_id))
+                    .push(event)?;
+            }
+            LogEntry::Parked(transaction) => {
+                self.parked_transactions
+                    .insert(transaction.transaction_id, transaction);
+            }
+            LogEntry::Unparked(transaction_id) => {
+                self.parked_transactions.remove(&transaction_id);
+            }
+            LogEntry::Dispute(record) => {
+                self.dispute_chains
+                    .entry(record.transaction_id)
+                    .or_default()
+                    .push(record);
+            }
+            LogEntry::Transactions(transactions) => self.index_transactions(&transactions, offset),
+        }
+
+        Ok(())
+    }
+
+    fn index_transactions(&mut self, transactions: &[Transaction], offset: u64) {
+        for transaction in transactions {
+            let previous = self
+                .transaction_offsets
+                .insert(transaction.transaction_id, offset);
+
+            if previous.is_none() {
+                self.client_transactions
+                    .entry(transaction.client_id)
+                    .or_default()
+                    .push(transaction.transaction_id);
+            }
+        }
+    }
+
+    fn append(&mut self, entry: LogEntry) -> PaymentEngineResult<()> {
+        let mut json = serde_json::to_string(&entry)?;
+        let offset = self.log_length;
+
+        json.push('\n');
+        self.writer
+            .write_all(json.as_bytes())
+            .map_err(|source| PaymentEngineError::EventLog { source })?;
+        self.log_length += json.len() as u64;
+        self.index_entry(entry, offset)
+    }
+
+    fn read_entry(&mut self, offset: u64) -> PaymentEngineResult<LogEntry> {
+        let mut line = String::new();
+
+        self.reader
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.reader.read_line(&mut line))
+            .map_err(|source| PaymentEngineError::EventLog { source })?;
+
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Ids of all transactions in the log, in ascending order.
+    pub fn transaction_ids(&self) -> Vec<u32> {
+        let mut transaction_ids: Vec<u32> = self.transaction_offsets.keys().copied().collect();
+
+        transaction_ids.sort_unstable();
+
+        transaction_ids
+    }
+
+    fn current_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        self.streams
+            .get(&client_id)
+            .map(AccountStream::current)
+            .transpose()
+    }
+}
+
+/// Rebuilds accounts on top of `snapshot` from the events of the log recorded in `[from, to)`,
+/// leaving out everything before and after the window. Events without any time are skipped.
+pub fn replay_window(
+    path: &Path,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    snapshot: Vec<Account>,
+) -> PaymentEngineResult<Vec<Account>> {
+    let reader = File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| PaymentEngineError::EventLog { source })?;
+    let mut accounts: BTreeMap<u16, Account> = snapshot
+        .into_iter()
+        .map(|account| (account.client_id, account))
+        .collect();
+    let mut undated = 0;
+
+    for line in reader.lines() {
+        let line = line.map_err(|source| PaymentEngineError::EventLog { source })?;
+
+        if let LogEntry::Account(event) = serde_json::from_str(&line)? {
+            match event.time() {
+                Some(time) if from <= time && time < to => event.apply(
+                    accounts
+                        .entry(event.client_id)
+                        .or_insert_with(|| Account::new(event.client_id)),
+                )?,
+                Some(_) => {}
+                None => undated += 1,
+            }
+        }
+    }
+
+    if undated > 0 {
+        warn!("Skipped {} events without a recorded time", undated);
+    }
+
+    Ok(accounts.into_values().collect())
+}
+
+/// Changes from `current` to `account` of the balances in other currencies than the base one.
+/// The cost of a change is the cost after it, since credits are booked at rates a replay does
+/// not know.
+fn currency_changes(
+    current: &Account,
+    account: &Account,
+) -> PaymentEngineResult<BTreeMap<Currency, Balances>> {
+    let mut changes = BTreeMap::new();
+
+    for currency in current.balances.keys().chain(account.balances.keys()) {
+        let before = current.balances_in(Some(currency));
+        let after = account.balances_in(Some(currency));
+
+        if before != after {
+            let change = Balances {
+                available: model::checked_sub(after.available, before.available)?,
+                held: model::checked_sub(after.held, before.held)?,
+                total: model::checked_sub(after.total, before.total)?,
+                cost: after.cost,
+            };
+
+            changes.insert(currency.clone(), change);
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Account events of the log which carry the transaction, i.e. the balance changes of the
+/// transaction itself and of its disputes, resolves and chargebacks, in log order.
+pub fn transaction_postings(
+    path: &Path,
+    transaction_id: u32,
+) -> PaymentEngineResult<Vec<AccountEvent>> {
+    let reader = File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| PaymentEngineError::EventLog { source })?;
+    let mut postings = vec![];
+
+    for line in reader.lines() {
+        let line = line.map_err(|source| PaymentEngineError::EventLog { source })?;
+
+        if let LogEntry::Account(event) = serde_json::from_str(&line)? {
+            if event
+                .transactions
+                .iter()
+                .any(|transaction| transaction.transaction_id == transaction_id)
+            {
+                postings.push(event);
+            }
+        }
+    }
+
+    Ok(postings)
+}
+
+/// Every transaction and dispute step recorded in the log, in replay order.
+pub fn stored_history(path: &Path) -> PaymentEngineResult<Vec<Transaction>> {
+    let reader = File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| PaymentEngineError::EventLog { source })?;
+    let mut transactions = vec![];
+    let mut dispute_records = vec![];
+
+    for line in reader.lines() {
+        let line = line.map_err(|source| PaymentEngineError::EventLog { source })?;
+
+        match serde_json::from_str(&line)? {
+            LogEntry::Account(event) => transactions.extend(event.transactions),
+            LogEntry::Transactions(flushed) => transactions.extend(flushed),
+            LogEntry::Dispute(record) => dispute_records.push(record),
+            LogEntry::Parked(_) | LogEntry::Unparked(_) => {}
+        }
+    }
+
+    Ok(rebuild::replay_order(transactions, dispute_records))
+}
+
+impl DatastoreOperations for EventSourcedDatastore {
+    fn retrieve_transaction(
+        &mut self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        if let Some(transaction) = self
+            .staged_transactions
+            .iter()
+            .find(|t| t.transaction_id == transaction_id)
+        {
+            return Ok(Some(transaction.clone()));
+        }
+
+        let offset = match self.transaction_offsets.get(&transaction_id) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+
+        let transactions = match self.read_entry(offset)? {
+            LogEntry::Account(event) => event.transactions,
+            LogEntry::Transactions(transactions) => transactions,
+            _ => return Ok(None),
+        };
+
+        Ok(transactions
+            .into_iter()
+            .find(|t| t.transaction_id == transaction_id))
+    }
+
+    fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.staged_transactions
+            .retain(|t| t.transaction_id != transaction.transaction_id);
+        self.staged_transactions.push(transaction);
+
+        Ok(())
+    }
+
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transaction_ids = self
+            .client_transactions
+            .get(&client_id)
+            .cloned()
+            .unwrap_or_default();
+
+        transaction_ids.extend(
+            self.staged_transactions
+                .iter()
+                .filter(|t| {
+                    t.client_id == client_id && !transaction_ids.contains(&t.transaction_id)
+                })
+                .map(|t| t.transaction_id)
+                .collect::<Vec<u32>>(),
+        );
+
+        let mut transactions = Vec::with_capacity(transaction_ids.len());
+
+        for transaction_id in transaction_ids {
+            if let Some(transaction) = self.retrieve_transaction(transaction_id)? {
+                transactions.push(transaction);
+            }
+        }
+
+        Ok(transactions)
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        self.current_account(client_id)
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transaction_ids: Vec<u32> = self
+            .transaction_offsets
+            .keys()
+            .copied()
+            .chain(self.staged_transactions.iter().map(|t| t.transaction_id))
+            .filter(|transaction_id| after.is_none_or(|after| *transaction_id > after))
+            .collect();
+
+        transaction_ids.sort_unstable();
+        transaction_ids.dedup();
+        transaction_ids.truncate(count);
+
+        let mut transactions = Vec::with_capacity(transaction_ids.len());
+
+        for transaction_id in transaction_ids {
+            transactions.extend(self.retrieve_transaction(transaction_id)?);
+        }
+
+        Ok(transactions)
+    }
+
+    fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+        let stream = self.streams.get(&account.client_id);
+        let current = self
+            .current_account(account.client_id)?
+            .unwrap_or_else(|| Account::new(account.client_id));
+        let (transactions, staged) = std::mem::take(&mut self.staged_transactions)
+            .into_iter()
+            .partition(|t| t.client_id == account.client_id);
+
+        self.staged_transactions = staged;
+
+        let event = AccountEvent {
+            client_id: account.client_id,
+            sequence: stream.map(|s| s.sequence).unwrap_or_default() + 1,
+            transactions,
+            available: account.available - current.available,
+            held: account.held - current.held,
+            total: account.total - current.total,
+            locked: account.locked,
+            recorded_at: Some(Utc::now()),
+            balances: currency_changes(&current, &account)?,
+        };
+
+        self.append(LogEntry::Account(event))
+    }
+
+    fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        self.projections.handle().sync();
+
+        Ok(self
+            .accounts_view
+            .snapshot()
+            .accounts
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    fn set_transaction_disputed(
+        &mut self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        match self.retrieve_transaction(transaction_id)? {
+            Some(mut transaction) => {
+                transaction.disputed = disputed;
+                self.save_transaction(transaction)
+            }
+            None => Err(PaymentEngineError::DisputedValueChange),
+        }
+    }
+
+    fn remove_transaction_from_cache(&mut self, _transaction_id: u32) -> PaymentEngineResult<()> {
+        Ok(())
+    }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.append(LogEntry::Parked(transaction))
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        Ok(self.parked_transactions.values().cloned().collect())
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.append(LogEntry::Unparked(transaction_id))
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        self.append(LogEntry::Dispute(record))
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        Ok(self
+            .dispute_chains
+            .get(&transaction_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Appends the transactions no account change of their client has taken along.
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        if self.staged_transactions.is_empty() {
+            return Ok(());
+        }
+
+        let transactions = std::mem::take(&mut self.staged_transactions);
+
+        self.append(LogEntry::Transactions(transactions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::DatastoreOperations;
+    use crate::event_store::{
+        replay_window, stored_history, AccountEvent, EventSourcedDatastore, LogEntry,
+    };
+    use crate::model::{Account, Transaction, TransactionType};
+    use crate::payment_service::PaymentService;
+    use chrono::{TimeZone, Utc};
+    use rust_decimal::Decimal;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_rebuild_accounts_and_transactions_from_log() {
+        let log = NamedTempFile::new().unwrap();
+        let mut datastore = EventSourcedDatastore::open(log.path(), vec![]).unwrap();
+        let transaction =
+            Transaction::new(TransactionType::Deposit, 1, 7, Some(Decimal::from(100)));
+
+        for transaction_id in 0..150 {
+            datastore
+                .save_transaction(Transaction {
+                    transaction_id: 1000 + transaction_id,
+                    ..transaction.clone()
+                })
+                .unwrap();
+            datastore
+                .save_account(Account {
+                    available: Decimal::from(transaction_id + 1),
+                    total: Decimal::from(transaction_id + 1),
+                    ..Account::new(1)
+                })
+                .unwrap();
+        }
+        datastore.save_transaction(transaction.clone()).unwrap();
+        datastore.set_transaction_disputed(7, true).unwrap();
+        datastore
+            .save_account(Account {
+                available: Decimal::from(50),
+                held: Decimal::from(100),
+                total: Decimal::from(150),
+                ..Account::new(1)
+            })
+            .unwrap();
+
+        let mut reopened = EventSourcedDatastore::open(log.path(), vec![]).unwrap();
+        let account = reopened.retrieve_account(1).unwrap().unwrap();
+
+        assert_eq!(account.available, Decimal::from(50));
+        assert_eq!(account.held, Decimal::from(100));
+        assert_eq!(account.total, Decimal::from(150));
+        assert_eq!(reopened.retrieve_all_accounts().unwrap(), vec![account]);
+        assert!(reopened.retrieve_transaction(7).unwrap().unwrap().disputed);
+        assert_eq!(
+            reopened.retrieve_transaction(1042).unwrap().unwrap().amount,
+            Some(Decimal::from(100))
+        );
+    }
+
+    #[test]
+    pub fn should_recompute_stored_accounts_from_history() {
+        let log = NamedTempFile::new().unwrap();
+        let transaction = |r#type, transaction_id, amount: Option<i64>| {
+            Transaction::new(r#type, 1, transaction_id, amount.map(Decimal::from))
+        };
+        let mut service = PaymentService::new(
+            Box::new(EventSourcedDatastore::open(log.path(), vec![]).unwrap()),
+            ServiceConfig::default(),
+        );
+
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, Some(10)),
+            transaction(TransactionType::Deposit, 2, Some(5)),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Withdrawal, 3, Some(4)),
+            transaction(TransactionType::Chargeback, 1, None),
+        ] {
+            service.process_batch(vec![transaction]).unwrap();
+        }
+
+        let history = stored_history(log.path()).unwrap();
+        let stored = EventSourcedDatastore::open(log.path(), vec![])
+            .unwrap()
+            .retrieve_all_accounts()
+            .unwrap();
+
+        assert_eq!(history.len(), 5);
+        assert_eq!(
+            PaymentService::rebuild_accounts(&history, &ServiceConfig::default()).unwrap(),
+            stored
+        );
+        assert!(stored[0].locked);
+    }
+
+    #[test]
+    pub fn should_replay_only_events_inside_window() {
+        let mut log = NamedTempFile::new().unwrap();
+        let day = |day: u32| Utc.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap();
+
+        for (client_id, amount, recorded_at) in [(1, 10, 1), (1, 20, 5), (2, 30, 6), (1, 40, 9)] {
+            let entry = LogEntry::Account(AccountEvent {
+                client_id,
+                sequence: 1,
+                transactions: vec![],
+                available: Decimal::from(amount),
+                held: Decimal::ZERO,
+                total: Decimal::from(amount),
+                locked: false,
+                recorded_at: Some(day(recorded_at)),
+                balances: Default::default(),
+            });
+
+            writeln!(log, "{}", serde_json::to_string(&entry).unwrap()).unwrap();
+        }
+
+        let snapshot = vec![Account {
+            available: Decimal::from(5),
+            total: Decimal::from(5),
+            ..Account::new(1)
+        }];
+        let accounts = replay_window(log.path(), day(2), day(8), snapshot).unwrap();
+
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|account| (account.client_id, account.total))
+                .collect::<Vec<_>>(),
+            vec![(1, Decimal::from(25)), (2, Decimal::from(30))]
+        );
+    }
+
+    #[test]
+    pub fn should_attach_transactions_to_events_of_their_client_only() {
+        let log = NamedTempFile::new().unwrap();
+        let mut datastore = EventSourcedDatastore::open(log.path(), vec![]).unwrap();
+
+        datastore
+            .save_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::from(10)),
+            ))
+            .unwrap();
+        datastore.save_account(Account::new(2)).unwrap();
+        datastore.flush().unwrap();
+
+        let history = stored_history(log.path()).unwrap();
+        let mut reopened = EventSourcedDatastore::open(log.path(), vec![]).unwrap();
+
+        assert_eq!(history.len(), 1);
+        assert_eq!(reopened.retrieve_client_transactions(1).unwrap().len(), 1);
+        assert!(reopened.retrieve_client_transactions(2).unwrap().is_empty());
+    }
+}
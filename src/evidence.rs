@@ -0,0 +1,249 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::event_store;
+use crate::model::{Account, DisputeRecord, Transaction};
+use crate::shard::{CLIENT_COLUMNS, TRANSACTION_ID_COLUMNS};
+use csv::{ReaderBuilder, Trim, WriterBuilder};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Seek, Write};
+use std::path::Path;
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// Single file of an evidence bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvidenceFile {
+    pub name: &'static str,
+    pub content: Vec<u8>,
+}
+
+/// Everything known about one transaction of a client, gathered from the datastore, the input
+/// files, the event log and the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evidence {
+    pub account: Account,
+    pub transaction: Transaction,
+    pub dispute_chain: Vec<DisputeRecord>,
+    pub files: Vec<EvidenceFile>,
+}
+
+impl Evidence {
+    pub fn new(
+        account: Account,
+        transaction: Transaction,
+        dispute_chain: Vec<DisputeRecord>,
+    ) -> PaymentEngineResult<Self> {
+        let mut evidence = Evidence {
+            account,
+            transaction,
+            dispute_chain,
+            files: vec![],
+        };
+
+        evidence.add_file(
+            "transaction.json",
+            serde_json::to_vec_pretty(&evidence.transaction)?,
+        );
+        evidence.add_file("dispute_chain.csv", to_csv(&evidence.dispute_chain)?);
+        evidence.add_file("balances.csv", to_csv(&[evidence.account.clone()])?);
+
+        Ok(evidence)
+    }
+
+    /// Adds the rows of the input files which carry the transaction, below the header of the
+    /// first file.
+    pub fn add_input_rows(&mut self, paths: &[&Path]) -> PaymentEngineResult<()> {
+        let mut content = vec![];
+        let mut writer = WriterBuilder::new().from_writer(&mut content);
+        let mut header_written = false;
+
+        for path in paths {
+            let mut reader = ReaderBuilder::new()
+                .has_headers(true)
+                .trim(Trim::All)
+                .from_path(path)?;
+            let headers = reader.headers()?.clone();
+            let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h));
+            let (client_index, transaction_index) =
+                match (column(&CLIENT_COLUMNS), column(&TRANSACTION_ID_COLUMNS)) {
+                    (Some(client_index), Some(transaction_index)) => {
+                        (client_index, transaction_index)
+                    }
+                    _ => return Err(PaymentEngineError::MissingClientColumn),
+                };
+
+            if !header_written {
+                writer.write_record(&headers)?;
+                header_written = true;
+            }
+
+            for record in reader.records() {
+                let record = record?;
+                let matches = |index: usize, value: String| record.get(index) == Some(&value);
+
+                if matches(
+                    transaction_index,
+                    self.transaction.transaction_id.to_string(),
+                ) && matches(client_index, self.transaction.client_id.to_string())
+                {
+                    writer.write_record(&record)?;
+                }
+            }
+        }
+
+        writer.flush()?;
+        drop(writer);
+        self.add_file("input_rows.csv", content);
+
+        Ok(())
+    }
+
+    /// Adds the account events of the event log which carry the transaction, as JSON lines.
+    pub fn add_ledger_postings(&mut self, event_log: &Path) -> PaymentEngineResult<()> {
+        let mut content = vec![];
+
+        for posting in
+            event_store::transaction_postings(event_log, self.transaction.transaction_id)?
+        {
+            serde_json::to_writer(&mut content, &posting)?;
+            content.push(b'\n');
+        }
+
+        self.add_file("ledger_postings.jsonl", content);
+
+        Ok(())
+    }
+
+    /// Adds the audit log entries about the transaction. A missing audit log adds an empty file.
+    pub fn add_audit_entries(&mut self, audit_log: &Path) -> PaymentEngineResult<()> {
+        let mut content = vec![];
+        let file = match File::open(audit_log) {
+            Ok(file) => Some(file),
+            Err(error) if error.kind() == ErrorKind::NotFound => None,
+            Err(source) => return Err(PaymentEngineError::AuditLog { source }),
+        };
+
+        for line in file
+            .into_iter()
+            .flat_map(|file| BufReader::new(file).lines())
+        {
+            let line = line.map_err(|source| PaymentEngineError::AuditLog { source })?;
+            let entry: serde_json::Value = serde_json::from_str(&line)?;
+
+            if entry["transaction_id"] == self.transaction.transaction_id {
+                content.extend_from_slice(line.as_bytes());
+                content.push(b'\n');
+            }
+        }
+
+        self.add_file("audit_entries.jsonl", content);
+
+        Ok(())
+    }
+
+    fn add_file(&mut self, name: &'static str, content: Vec<u8>) {
+        self.files.push(EvidenceFile { name, content });
+    }
+
+    /// Writes the files into a zip archive together with a `SHA256SUMS` file, in the format of
+    /// `sha256sum`, and returns the SHA-256 of that file, which identifies the whole bundle.
+    pub fn write_bundle<W: Write + Seek>(&self, writer: W) -> PaymentEngineResult<String> {
+        let mut zip = ZipWriter::new(writer);
+        let options = FileOptions::default();
+        let mut checksums = String::new();
+
+        for file in &self.files {
+            checksums.push_str(&format!(
+                "{:x}  {}\n",
+                Sha256::digest(&file.content),
+                file.name
+            ));
+            zip.start_file(file.name, options)?;
+            zip.write_all(&file.content).map_err(ZipError::from)?;
+        }
+
+        zip.start_file(CHECKSUMS_FILE, options)?;
+        zip.write_all(checksums.as_bytes())
+            .map_err(ZipError::from)?;
+        zip.finish()?;
+
+        Ok(format!("{:x}", Sha256::digest(checksums.as_bytes())))
+    }
+}
+
+fn to_csv<T: serde::Serialize>(rows: &[T]) -> PaymentEngineResult<Vec<u8>> {
+    let mut content = vec![];
+    let mut writer = WriterBuilder::new().from_writer(&mut content);
+
+    for row in rows {
+        writer.serialize(row)?;
+    }
+
+    writer.flush()?;
+    drop(writer);
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evidence::Evidence;
+    use crate::model::{Account, Transaction, TransactionType};
+    use rust_decimal::Decimal;
+    use sha2::{Digest, Sha256};
+    use std::io::{Cursor, Read};
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_bundle_evidence_with_checksums() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("input.csv");
+        let audit_log = directory.path().join("missing_audit.log");
+        let transaction = Transaction {
+            disputed: true,
+            ..Transaction::new(TransactionType::Deposit, 1, 7, Some(Decimal::from(10)))
+        };
+
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,7,10\ndeposit,2,7,5\ndispute,1,7,\n",
+        )
+        .unwrap();
+
+        let mut evidence = Evidence::new(Account::new(1), transaction, vec![]).unwrap();
+
+        evidence.add_input_rows(&[&input]).unwrap();
+        evidence.add_audit_entries(&audit_log).unwrap();
+
+        let mut bundle = Cursor::new(vec![]);
+        let digest = evidence.write_bundle(&mut bundle).unwrap();
+        let mut archive = zip::ZipArchive::new(bundle).unwrap();
+        let read = |archive: &mut zip::ZipArchive<Cursor<Vec<u8>>>, name: &str| {
+            let mut content = String::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+        let input_rows = read(&mut archive, "input_rows.csv");
+        let checksums = read(&mut archive, "SHA256SUMS");
+
+        assert_eq!(
+            input_rows,
+            "type,client,tx,amount\ndeposit,1,7,10\ndispute,1,7,\n"
+        );
+        assert!(checksums.contains(&format!(
+            "{:x}  input_rows.csv",
+            Sha256::digest(input_rows.as_bytes())
+        )));
+        assert_eq!(
+            digest,
+            format!("{:x}", Sha256::digest(checksums.as_bytes()))
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::event_store::EventSourcedDatastore;
+use crate::model::Transaction;
+use crate::page;
+use crate::sink;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Transactions written between two checkpoints of an export to a file.
+pub const EXPORT_CHUNK_SIZE: usize = 10_000;
+const PROGRESS_EXTENSION: &str = "progress";
+
+/// Transactions to export. Unset fields match everything; a time bound only matches
+/// transactions carrying a timestamp.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransactionFilter {
+    pub client_id: Option<u16>,
+    pub disputed: Option<bool>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Checkpoint of an export to a file, kept next to it until the export finishes. Everything up
+/// to `bytes` holds the transactions up to `last_transaction_id` and is synced to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportProgress {
+    pub last_transaction_id: Option<u32>,
+    pub exported: usize,
+    pub bytes: u64,
+}
+
+impl TransactionFilter {
+    /// Filter of the transactions from the start of day `from` to the end of day `to`, in UTC.
+    pub fn days(from: Option<NaiveDate>, to: Option<NaiveDate>) -> Self {
+        let start_of_day = |date: NaiveDate| {
+            Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).expect("Midnight is a valid time"))
+        };
+
+        TransactionFilter {
+            from: from.map(start_of_day),
+            to: to.map(|to| start_of_day(to + Duration::days(1))),
+            ..TransactionFilter::default()
+        }
+    }
+
+    pub fn matches(&self, transaction: &Transaction) -> bool {
+        self.client_id.is_none_or(|c| c == transaction.client_id)
+            && self.disputed.is_none_or(|d| d == transaction.disputed)
+            && self
+                .from
+                .is_none_or(|from| transaction.timestamp.is_some_and(|t| t >= from))
+            && self
+                .to
+                .is_none_or(|to| transaction.timestamp.is_some_and(|t| t < to))
+    }
+}
+
+/// Latest version of every matching transaction in transaction id order, read a batch at a
+/// time by `read`, which answers the transactions after the id it is given. Exports, pages and
+/// streams of transactions all select them this way.
+pub fn matching_transactions<'a>(
+    filter: &'a TransactionFilter,
+    read: impl FnMut(Option<u32>, usize) -> PaymentEngineResult<Vec<Transaction>> + 'a,
+    after: Option<u32>,
+) -> impl Iterator<Item = PaymentEngineResult<Transaction>> + 'a {
+    page::read_in_batches(after, read, |transaction| transaction.transaction_id).filter_map(
+        move |item| match item {
+            Ok((_, transaction)) if filter.matches(&transaction) => Some(Ok(transaction)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        },
+    )
+}
+
+/// Writes the latest version of every matching transaction as one JSON object per line, in
+/// transaction id order. Transactions are read from the datastore a batch at a time and written
+/// straight to `writer`, so a slow consumer blocks the export instead of growing a buffer.
+pub fn export_transactions<W: Write>(
+    datastore: &mut dyn DatastoreOperations,
+    filter: &TransactionFilter,
+    mut writer: W,
+) -> PaymentEngineResult<usize> {
+    let mut exported = 0;
+    let read = |after, count| datastore.retrieve_transactions_after(after, count);
+
+    for transaction in matching_transactions(filter, read, None) {
+        serde_json::to_writer(&mut writer, &transaction?)?;
+        writer
+            .write_all(b"\n")
+            .map_err(|source| PaymentEngineError::ExportWrite { source })?;
+        exported += 1;
+    }
+
+    writer
+        .flush()
+        .map_err(|source| PaymentEngineError::ExportWrite { source })?;
+
+    Ok(exported)
+}
+
+/// Exports to the file at `path` in chunks of `chunk_size` transactions, recording a checkpoint
+/// after each one. An export which was interrupted continues after its last checkpoint, dropping
+/// whatever was written after it, instead of starting over.
+pub fn export_to_file(
+    datastore: &mut EventSourcedDatastore,
+    filter: &TransactionFilter,
+    path: &Path,
+    chunk_size: usize,
+) -> PaymentEngineResult<usize> {
+    let export_error = |source| PaymentEngineError::ExportWrite { source };
+    let progress_path = progress_path(path);
+    let mut progress = ExportProgress::load(path)?.unwrap_or_default();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .map_err(export_error)?;
+
+    file.set_len(progress.bytes).map_err(export_error)?;
+    file.seek(SeekFrom::End(0)).map_err(export_error)?;
+
+    if progress.exported > 0 {
+        info!(
+            "Resuming export to {} after {} transactions",
+            path.display(),
+            progress.exported
+        );
+    }
+
+    let mut writer = BufWriter::new(file);
+    let mut in_chunk = 0;
+
+    for transaction_id in datastore.transaction_ids() {
+        if progress
+            .last_transaction_id
+            .is_some_and(|last| transaction_id <= last)
+        {
+            continue;
+        }
+
+        match datastore.retrieve_transaction(transaction_id)? {
+            Some(transaction) if filter.matches(&transaction) => {
+                serde_json::to_writer(&mut writer, &transaction)?;
+                writer.write_all(b"\n").map_err(export_error)?;
+                progress.exported += 1;
+                in_chunk += 1;
+            }
+            _ => {}
+        }
+        progress.last_transaction_id = Some(transaction_id);
+
+        if in_chunk >= chunk_size.max(1) {
+            progress.bytes = checkpoint(&mut writer)?;
+            progress.save(&progress_path)?;
+            in_chunk = 0;
+        }
+    }
+
+    checkpoint(&mut writer)?;
+
+    if progress_path.exists() {
+        std::fs::remove_file(&progress_path).map_err(export_error)?;
+    }
+
+    Ok(progress.exported)
+}
+
+impl ExportProgress {
+    /// Checkpoint of an unfinished export to `path`, if there is one.
+    pub fn load(path: &Path) -> PaymentEngineResult<Option<Self>> {
+        match File::open(progress_path(path)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(PaymentEngineError::ExportWrite { source }),
+        }
+    }
+
+    fn save(&self, progress_path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(progress_path, |file| {
+            serde_json::to_writer(file, self)?;
+
+            Ok(())
+        })
+    }
+}
+
+fn progress_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+
+    name.push(".");
+    name.push(PROGRESS_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Syncs everything written so far and returns the length of the file.
+fn checkpoint(writer: &mut BufWriter<File>) -> PaymentEngineResult<u64> {
+    let export_error = |source| PaymentEngineError::ExportWrite { source };
+
+    writer.flush().map_err(export_error)?;
+    writer.get_ref().sync_data().map_err(export_error)?;
+    writer
+        .get_ref()
+        .metadata()
+        .map(|m| m.len())
+        .map_err(export_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datastore::DatastoreOperations;
+    use crate::event_store::EventSourcedDatastore;
+    use crate::export::{export_to_file, export_transactions, ExportProgress, TransactionFilter};
+    use crate::model::{Account, Transaction, TransactionType};
+    use rust_decimal::Decimal;
+    use std::io::Write;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    pub fn should_export_latest_version_of_matching_transactions() {
+        let log = NamedTempFile::new().unwrap();
+        let mut datastore = EventSourcedDatastore::open(log.path(), vec![]).unwrap();
+
+        for (client_id, transaction_id) in [(1, 3), (2, 1), (1, 2)] {
+            datastore
+                .save_transaction(Transaction::new(
+                    TransactionType::Deposit,
+                    client_id,
+                    transaction_id,
+                    Some(Decimal::from(10)),
+                ))
+                .unwrap();
+            datastore.save_account(Account::new(client_id)).unwrap();
+        }
+        datastore.set_transaction_disputed(3, true).unwrap();
+        datastore.save_account(Account::new(1)).unwrap();
+
+        let mut output = vec![];
+        let filter = TransactionFilter {
+            client_id: Some(1),
+            ..TransactionFilter::default()
+        };
+        let exported = export_transactions(&mut datastore, &filter, &mut output).unwrap();
+        let lines: Vec<Transaction> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(exported, 2);
+        assert_eq!(
+            lines
+                .iter()
+                .map(|t| (t.transaction_id, t.disputed))
+                .collect::<Vec<_>>(),
+            vec![(2, false), (3, true)]
+        );
+    }
+
+    #[test]
+    pub fn should_resume_file_export_after_last_checkpoint() {
+        let directory = TempDir::new().unwrap();
+        let log_path = directory.path().join("events.log");
+        let path = directory.path().join("export.jsonl");
+        let mut datastore = EventSourcedDatastore::open(&log_path, vec![]).unwrap();
+
+        for transaction_id in 1..=5 {
+            datastore
+                .save_transaction(Transaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    transaction_id,
+                    Some(Decimal::from(10)),
+                ))
+                .unwrap();
+            datastore.save_account(Account::new(1)).unwrap();
+        }
+
+        let filter = TransactionFilter::default();
+        let mut expected = vec![];
+
+        export_transactions(&mut datastore, &filter, &mut expected).unwrap();
+
+        // An export interrupted after its second transaction, with part of the third written.
+        let first_two: usize = String::from_utf8(expected.clone())
+            .unwrap()
+            .lines()
+            .take(2)
+            .map(|line| line.len() + 1)
+            .sum();
+        let progress = ExportProgress {
+            last_transaction_id: Some(2),
+            exported: 2,
+            bytes: first_two as u64,
+        };
+        let mut file = std::fs::File::create(&path).unwrap();
+
+        file.write_all(&expected[..first_two + 10]).unwrap();
+        std::fs::write(
+            directory.path().join("export.jsonl.progress"),
+            serde_json::to_string(&progress).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(ExportProgress::load(&path).unwrap(), Some(progress));
+        assert_eq!(
+            export_to_file(&mut datastore, &filter, &path, 2).unwrap(),
+            5
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        assert_eq!(ExportProgress::load(&path).unwrap(), None);
+    }
+}
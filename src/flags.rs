@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+/// Behaviour which can be rolled out gradually.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Transactions on locked accounts are rejected instead of applied.
+    StrictLocking,
+    /// Only deposits can be disputed, disputes of withdrawals are rejected.
+    DepositOnlyDisputes,
+}
+
+/// Clients a feature is enabled for. A client is included when the feature is enabled for
+/// everyone, when its id is in one of the inclusive ranges or when it falls into the rollout
+/// percentage. Percentage buckets are derived from the client id, so a client stays in or out
+/// of a rollout across runs and only gains the feature as the percentage grows.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rollout {
+    pub enabled: bool,
+    pub percentage: u8,
+    pub client_ranges: Vec<(u16, u16)>,
+}
+
+/// Rollouts by feature, read from the `[flags]` table of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureFlags {
+    pub strict_locking: Option<Rollout>,
+    pub deposit_only_disputes: Option<Rollout>,
+}
+
+impl Rollout {
+    fn includes(&self, client_id: u16) -> bool {
+        self.enabled
+            || self
+                .client_ranges
+                .iter()
+                .any(|(from, to)| (*from..=*to).contains(&client_id))
+            || client_bucket(client_id) < u32::from(self.percentage)
+    }
+}
+
+impl FeatureFlags {
+    pub fn is_enabled(&self, feature: Feature, client_id: u16) -> bool {
+        self.rollout(feature)
+            .map(|rollout| rollout.includes(client_id))
+            .unwrap_or(false)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let rollouts = [&self.strict_locking, &self.deposit_only_disputes];
+
+        rollouts
+            .iter()
+            .filter_map(|rollout| rollout.as_ref())
+            .all(|rollout| {
+                rollout.percentage <= 100
+                    && rollout.client_ranges.iter().all(|(from, to)| from <= to)
+            })
+    }
+
+    fn rollout(&self, feature: Feature) -> Option<&Rollout> {
+        match feature {
+            Feature::StrictLocking => self.strict_locking.as_ref(),
+            Feature::DepositOnlyDisputes => self.deposit_only_disputes.as_ref(),
+        }
+    }
+}
+
+/// Spreads consecutive client ids over the buckets 0..100 with multiplicative hashing.
+fn client_bucket(client_id: u16) -> u32 {
+    u32::from(client_id).wrapping_mul(2_654_435_761) % 100
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flags::{Feature, FeatureFlags, Rollout};
+
+    #[test]
+    pub fn should_enable_features_per_client() {
+        let flags = FeatureFlags {
+            strict_locking: Some(Rollout {
+                percentage: 5,
+                client_ranges: vec![(10, 20)],
+                ..Rollout::default()
+            }),
+            ..FeatureFlags::default()
+        };
+
+        assert!(flags.is_enabled(Feature::StrictLocking, 10));
+        assert!(flags.is_enabled(Feature::StrictLocking, 20));
+        assert!(!flags.is_enabled(Feature::DepositOnlyDisputes, 10));
+
+        let enabled = (0..=u16::MAX)
+            .filter(|client_id| !(10..=20).contains(client_id))
+            .filter(|client_id| flags.is_enabled(Feature::StrictLocking, *client_id))
+            .count();
+
+        assert!(
+            (3000..=3600).contains(&enabled),
+            "{} clients enabled",
+            enabled
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Prefix of the files and directories the engine keeps its persistent state in.
+pub(crate) const STATE_PREFIX: &str = "pe_";
+
+/// Scratch copy of persistent state, for rehearsing a run against real data. Everything the
+/// run writes goes to the copy, which is removed when this is dropped.
+pub struct ForkedState {
+    directory: TempDir,
+}
+
+impl ForkedState {
+    /// Copies the `pe_*` files and directories of `snapshot`, e.g. the working directory of
+    /// production runs, into a new scratch directory.
+    pub fn fork(snapshot: &Path) -> PaymentEngineResult<Self> {
+        let directory = TempDir::new()?;
+        let entries = fs::read_dir(snapshot).map_err(|source| fork_error(snapshot, source))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|source| fork_error(snapshot, source))?;
+
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(STATE_PREFIX)
+            {
+                copy_recursively(&entry.path(), &directory.path().join(entry.file_name()))?;
+            }
+        }
+
+        info!(
+            "Forked state of {} into {}",
+            snapshot.display(),
+            directory.path().display()
+        );
+
+        Ok(ForkedState { directory })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.directory.path()
+    }
+
+    /// Copies state kept outside the snapshot, like an event store log, into the scratch
+    /// directory and returns the path of the copy. A missing `path` is left to be created there,
+    /// one the snapshot already contained is not copied again.
+    pub fn include(&self, path: &Path) -> PaymentEngineResult<PathBuf> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| fork_error(path, std::io::ErrorKind::InvalidInput.into()))?;
+        let copy = self.directory.path().join(name);
+
+        if path.exists() && !copy.exists() {
+            copy_recursively(path, &copy)?;
+        }
+
+        Ok(copy)
+    }
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> PaymentEngineResult<()> {
+    copy_tree(from, to).map_err(|source| fork_error(from, source))
+}
+
+pub(crate) fn copy_tree(from: &Path, to: &Path) -> std::io::Result<()> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+
+        copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+fn fork_error(path: &Path, source: std::io::Error) -> PaymentEngineError {
+    PaymentEngineError::StateFork {
+        path: path.to_string_lossy().into_owned(),
+        source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fork::ForkedState;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_copy_state_files_and_leave_snapshot_untouched() {
+        let snapshot = TempDir::new().unwrap();
+        let index = snapshot.path().join("pe_search_index");
+
+        std::fs::write(snapshot.path().join("pe_transaction.db"), "transactions").unwrap();
+        std::fs::write(snapshot.path().join("input.csv"), "type,client,tx,amount").unwrap();
+        std::fs::create_dir(&index).unwrap();
+        std::fs::write(index.join("segment"), "terms").unwrap();
+
+        let fork = ForkedState::fork(snapshot.path()).unwrap();
+        let copy = fork.path().join("pe_transaction.db");
+
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "transactions");
+        assert!(fork.path().join("pe_search_index/segment").exists());
+        assert!(!fork.path().join("input.csv").exists());
+
+        std::fs::write(&copy, "rehearsed").unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(snapshot.path().join("pe_transaction.db")).unwrap(),
+            "transactions"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+#[cfg(feature = "fraud-check")]
+use crate::error::PaymentEngineError;
+use crate::error::PaymentEngineResult;
+use crate::model::{Transaction, TransactionType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// External fraud scoring asked about withdrawals above `threshold` before they are applied.
+/// Calls need the `fraud-check` feature; configs with a check are rejected without it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FraudCheck {
+    /// Endpoint receiving the transaction as a JSON POST body and answering with
+    /// `{"decision": "allow" | "deny" | "hold"}`.
+    pub url: String,
+    pub threshold: Decimal,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+}
+
+/// Decision taken when the endpoint fails, times out or answers with anything but a decision.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailurePolicy {
+    /// The withdrawal is applied.
+    Open,
+    /// The withdrawal is rejected.
+    #[default]
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// Parked like transactions above the approval threshold, until approved or rejected.
+    Hold,
+}
+
+#[cfg(feature = "fraud-check")]
+#[derive(Deserialize)]
+struct CheckResponse {
+    decision: Decision,
+}
+
+fn default_timeout_ms() -> u64 {
+    2_000
+}
+
+impl FraudCheck {
+    pub fn is_valid(&self) -> bool {
+        cfg!(feature = "fraud-check")
+            && !self.url.is_empty()
+            && !self.threshold.is_sign_negative()
+            && self.timeout_ms > 0
+    }
+
+    pub fn applies_to(&self, transaction: &Transaction) -> bool {
+        transaction.r#type == TransactionType::Withdrawal
+            && matches!(transaction.amount, Some(amount) if amount > self.threshold)
+    }
+
+    pub fn decide(&self, transaction: &Transaction) -> Decision {
+        match self.call(transaction) {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!(
+                    "Fraud check of transaction {} failed, failing {:?}: {}",
+                    transaction.transaction_id, self.on_failure, e
+                );
+
+                match self.on_failure {
+                    FailurePolicy::Open => Decision::Allow,
+                    FailurePolicy::Closed => Decision::Deny,
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "fraud-check")]
+    fn call(&self, transaction: &Transaction) -> PaymentEngineResult<Decision> {
+        let fraud_check_error = |e: ureq::Error| PaymentEngineError::FraudCheck {
+            source: Box::new(e),
+        };
+        let response: CheckResponse = ureq::post(&self.url)
+            .timeout(std::time::Duration::from_millis(self.timeout_ms))
+            .send_json(transaction)
+            .map_err(fraud_check_error)?
+            .into_json()
+            .map_err(|e| fraud_check_error(e.into()))?;
+
+        Ok(response.decision)
+    }
+
+    #[cfg(not(feature = "fraud-check"))]
+    fn call(&self, _transaction: &Transaction) -> PaymentEngineResult<Decision> {
+        unreachable!("Configs with a fraud check are rejected without the fraud-check feature")
+    }
+}
+
+#[cfg(all(test, feature = "fraud-check"))]
+mod tests {
+    use crate::fraud::{Decision, FailurePolicy, FraudCheck};
+    use crate::model::{Transaction, TransactionType};
+    use rust_decimal::Decimal;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves one HTTP response per body, in order, and returns the endpoint URL.
+    fn serve(bodies: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/check", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for body in bodies {
+                let mut stream = listener.accept().unwrap().0;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+
+                reader.read_exact(&mut vec![0; length]).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    pub fn should_follow_endpoint_decision_and_failure_policy() {
+        let withdrawal = |amount| {
+            Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                1,
+                Some(Decimal::from(amount)),
+            )
+        };
+        let check = FraudCheck {
+            url: serve(vec![
+                r#"{"decision":"allow"}"#,
+                r#"{"decision":"hold"}"#,
+                r#"{"decision":"deny"}"#,
+                r#"{"score":0.9}"#,
+            ]),
+            threshold: Decimal::from(100),
+            timeout_ms: 2_000,
+            on_failure: FailurePolicy::Open,
+        };
+
+        assert!(!check.applies_to(&withdrawal(100)));
+        assert!(check.applies_to(&withdrawal(101)));
+        assert_eq!(check.decide(&withdrawal(101)), Decision::Allow);
+        assert_eq!(check.decide(&withdrawal(101)), Decision::Hold);
+        assert_eq!(check.decide(&withdrawal(101)), Decision::Deny);
+        assert_eq!(check.decide(&withdrawal(101)), Decision::Allow);
+
+        let unreachable = FraudCheck {
+            url: "http://127.0.0.1:1/check".to_string(),
+            on_failure: FailurePolicy::Closed,
+            ..check
+        };
+
+        assert_eq!(unreachable.decide(&withdrawal(101)), Decision::Deny);
+    }
+}
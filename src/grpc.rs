@@ -0,0 +1,485 @@
+use crate::async_service::AsyncPaymentService;
+use crate::error::{ErrorKind, PaymentEngineError, PaymentEngineResult};
+use crate::export::{self, TransactionFilter};
+use crate::model::{self, Account, Currency, Transaction, TransactionType};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::StreamExt;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+/// Messages and service traits generated from `proto/payment_engine.proto`.
+pub mod proto {
+    tonic::include_proto!("payment_engine");
+}
+
+use proto::ingest_event::Event;
+use proto::payment_engine_server::{PaymentEngine, PaymentEngineServer};
+use proto::{Ack, IngestEvent};
+
+/// Events buffered for a slow client before the ingestion of its stream waits.
+const EVENT_BUFFER: usize = 64;
+
+/// gRPC service applying streamed transactions with an `AsyncPaymentService`, so transactions
+/// of every client stream go through the same engine thread and datastore.
+pub struct GrpcService {
+    engine: Arc<AsyncPaymentService>,
+    base_currency: Option<Currency>,
+}
+
+impl GrpcService {
+    /// With `base_currency`, the accounts are streamed as one message per currency, like the
+    /// rows of the account report.
+    pub fn new(engine: AsyncPaymentService, base_currency: Option<Currency>) -> Self {
+        GrpcService {
+            engine: Arc::new(engine),
+            base_currency,
+        }
+    }
+
+    /// Serves the service on `listener` until the server fails.
+    pub async fn serve(self, listener: TcpListener) -> PaymentEngineResult<()> {
+        tonic::transport::Server::builder()
+            .add_service(PaymentEngineServer::new(self))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl PaymentEngine for GrpcService {
+    type IngestStream = ReceiverStream<Result<IngestEvent, Status>>;
+    type ExportTransactionsStream = ReceiverStream<Result<proto::Transaction, Status>>;
+
+    async fn ingest(
+        &self,
+        request: Request<Streaming<proto::Transaction>>,
+    ) -> Result<Response<Self::IngestStream>, Status> {
+        let mut transactions = request.into_inner();
+        let (events, received) = mpsc::channel(EVENT_BUFFER);
+        let engine = self.engine.clone();
+        let base_currency = self.base_currency.clone();
+
+        tokio::spawn(async move {
+            while let Some(message) = transactions.next().await {
+                let ack = match message {
+                    Ok(message) => {
+                        let transaction_id = message.tx;
+                        let result = match Transaction::try_from(message) {
+                            Ok(transaction) => engine.process(transaction).await.map(|_| ()),
+                            Err(e) => Err(e),
+                        };
+
+                        ack(transaction_id, result)
+                    }
+                    Err(status) => {
+                        let _ = events.send(Err(status)).await;
+                        return;
+                    }
+                };
+
+                // The client went away, which leaves nothing to answer.
+                if events.send(Ok(event(Event::Ack(ack)))).await.is_err() {
+                    return;
+                }
+            }
+
+            let mut accounts = match engine.flush().await.and(engine.accounts().await) {
+                Ok(accounts) => accounts,
+                Err(e) => {
+                    let _ = events.send(Err(status(&e))).await;
+                    return;
+                }
+            };
+
+            accounts.sort_by_key(|account| account.client_id);
+
+            let rows = match &base_currency {
+                Some(base) => accounts
+                    .iter()
+                    .flat_map(|account| account.by_currency(base))
+                    .collect(),
+                None => accounts,
+            };
+
+            for account in rows {
+                let account = Event::Account(proto::Account::from(account));
+
+                if events.send(Ok(event(account))).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(received)))
+    }
+
+    async fn export_transactions(
+        &self,
+        request: Request<proto::ExportFilter>,
+    ) -> Result<Response<Self::ExportTransactionsStream>, Status> {
+        let filter = TransactionFilter::try_from(request.into_inner()).map_err(|e| status(&e))?;
+        let (transactions, received) = mpsc::channel(EVENT_BUFFER);
+        let engine = self.engine.clone();
+        let runtime = Handle::current();
+
+        // Transactions are selected like the export command does, a batch at a time through
+        // the engine thread, so a long export does not hold up the transactions being applied.
+        tokio::task::spawn_blocking(move || {
+            let read = |after, count| runtime.block_on(engine.transactions_after(after, count));
+
+            for transaction in export::matching_transactions(&filter, read, None) {
+                let message = transaction
+                    .map(proto::Transaction::from)
+                    .map_err(|e| status(&e));
+                let failed = message.is_err();
+
+                // The client went away, which leaves nothing to stream.
+                if transactions.blocking_send(message).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(received)))
+    }
+}
+
+fn event(event: Event) -> IngestEvent {
+    IngestEvent { event: Some(event) }
+}
+
+fn ack(transaction_id: u32, result: PaymentEngineResult<()>) -> Ack {
+    match result {
+        Ok(()) => Ack {
+            tx: transaction_id,
+            applied: true,
+            error: String::new(),
+            kind: String::new(),
+        },
+        Err(e) => Ack {
+            tx: transaction_id,
+            applied: false,
+            error: e.to_string(),
+            kind: format!("{:?}", e.kind()),
+        },
+    }
+}
+
+fn status(error: &PaymentEngineError) -> Status {
+    let code = match error.kind() {
+        ErrorKind::Retryable => Code::Unavailable,
+        ErrorKind::Rejected => Code::FailedPrecondition,
+        ErrorKind::DataQuality => Code::InvalidArgument,
+        ErrorKind::Permanent => Code::Internal,
+    };
+
+    Status::new(code, error.to_string())
+}
+
+impl TryFrom<proto::Transaction> for Transaction {
+    type Error = PaymentEngineError;
+
+    fn try_from(message: proto::Transaction) -> PaymentEngineResult<Self> {
+        let invalid = |field| PaymentEngineError::InvalidGrpcTransaction { field };
+        let amount = match message.amount.as_str() {
+            "" => None,
+            amount => Some(model::parse_amount(amount).ok_or_else(|| invalid("amount"))?),
+        };
+        let client = |client: u32, field| u16::try_from(client).map_err(|_| invalid(field));
+        let currency = |currency: Option<String>, field| {
+            currency
+                .map(|code| Currency::try_from(code).map_err(|_| invalid(field)))
+                .transpose()
+        };
+
+        Ok(Transaction {
+            to_client: message
+                .to_client
+                .map(|to_client| client(to_client, "to_client"))
+                .transpose()?,
+            currency: currency(message.currency, "currency")?,
+            to_currency: currency(message.to_currency, "to_currency")?,
+            timestamp: message
+                .timestamp
+                .map(|timestamp| timestamp.parse().map_err(|_| invalid("timestamp")))
+                .transpose()?,
+            memo: message.memo,
+            counterparty: message.counterparty,
+            reason_code: message.reason_code,
+            ..Transaction::new(
+                TransactionType::from_name(&message.r#type).ok_or_else(|| invalid("type"))?,
+                client(message.client, "client")?,
+                message.tx,
+                amount.filter(|amount| !amount.is_zero()),
+            )
+        })
+    }
+}
+
+impl TryFrom<proto::ExportFilter> for TransactionFilter {
+    type Error = PaymentEngineError;
+
+    fn try_from(message: proto::ExportFilter) -> PaymentEngineResult<Self> {
+        let invalid = |field| PaymentEngineError::InvalidGrpcTransaction { field };
+        let time = |time: Option<String>, field| {
+            time.map(|time| time.parse().map_err(|_| invalid(field)))
+                .transpose()
+        };
+
+        Ok(TransactionFilter {
+            client_id: message
+                .client
+                .map(|client| u16::try_from(client).map_err(|_| invalid("client")))
+                .transpose()?,
+            disputed: message.disputed,
+            from: time(message.from, "from")?,
+            to: time(message.to, "to")?,
+        })
+    }
+}
+
+impl From<Transaction> for proto::Transaction {
+    fn from(transaction: Transaction) -> Self {
+        proto::Transaction {
+            r#type: transaction.r#type.name().to_string(),
+            client: transaction.client_id.into(),
+            tx: transaction.transaction_id,
+            amount: transaction
+                .amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+            to_client: transaction.to_client.map(u32::from),
+            currency: transaction.currency.map(|currency| currency.to_string()),
+            to_currency: transaction.to_currency.map(|currency| currency.to_string()),
+            timestamp: transaction
+                .timestamp
+                .map(|timestamp| timestamp.to_rfc3339()),
+            memo: transaction.memo,
+            counterparty: transaction.counterparty,
+            reason_code: transaction.reason_code,
+            disputed: transaction.disputed,
+        }
+    }
+}
+
+impl From<Account> for proto::Account {
+    fn from(account: Account) -> Self {
+        proto::Account {
+            client: account.client_id.into(),
+            available: account.available.to_string(),
+            held: account.held.to_string(),
+            total: account.total.to_string(),
+            locked: account.locked,
+            currency: account.currency.map(|currency| currency.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_service::{AsyncAdapter, AsyncPaymentService};
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::grpc::proto::ingest_event::Event;
+    use crate::grpc::proto::payment_engine_client::PaymentEngineClient;
+    use crate::grpc::proto::{ExportFilter, Transaction};
+    use crate::grpc::GrpcService;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+    use tonic::transport::Channel;
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn should_acknowledge_streamed_transactions_and_stream_accounts() {
+        let engine = AsyncPaymentService::start(
+            Arc::new(AsyncAdapter::new(InMemoryDatastore::default())),
+            ServiceConfig::default(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(GrpcService::new(engine, None).serve(listener));
+
+        let channel = Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = PaymentEngineClient::new(channel);
+        let transaction = |r#type: &str, tx, amount: &str| Transaction {
+            r#type: r#type.to_string(),
+            client: 4,
+            tx,
+            amount: amount.to_string(),
+            ..Transaction::default()
+        };
+        let transactions = tokio_stream::iter(vec![
+            transaction("deposit", 1, "10.0"),
+            transaction("withdrawal", 2, "25.0"),
+            transaction("deposit", 3, "ten"),
+        ]);
+        let events: Vec<Event> = client
+            .ingest(transactions)
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|event| event.unwrap().event.unwrap())
+            .collect()
+            .await;
+
+        let acks: Vec<(u32, bool, String)> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Ack(ack) => Some((ack.tx, ack.applied, ack.kind.clone())),
+                Event::Account(_) => None,
+            })
+            .collect();
+
+        assert_eq!(
+            acks,
+            vec![
+                (1, true, String::new()),
+                (2, false, "Rejected".to_string()),
+                (3, false, "DataQuality".to_string())
+            ]
+        );
+
+        match events.last().unwrap() {
+            Event::Account(account) => {
+                assert_eq!(account.client, 4);
+                assert_eq!(account.available, "10.0");
+            }
+            Event::Ack(_) => panic!("Accounts follow the acknowledgements"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn should_stream_one_account_message_per_currency_with_base_currency() {
+        let base = "EUR".parse().unwrap();
+        let engine = AsyncPaymentService::start(
+            Arc::new(AsyncAdapter::new(InMemoryDatastore::default())),
+            ServiceConfig {
+                base_currency: Some(base),
+                ..ServiceConfig::default()
+            },
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(GrpcService::new(engine, Some("EUR".parse().unwrap())).serve(listener));
+
+        let channel = Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = PaymentEngineClient::new(channel);
+        let transaction = |tx, amount: &str, currency: Option<&str>| Transaction {
+            r#type: "deposit".to_string(),
+            client: 4,
+            tx,
+            amount: amount.to_string(),
+            currency: currency.map(str::to_string),
+            ..Transaction::default()
+        };
+        let transactions = tokio_stream::iter(vec![
+            transaction(1, "10.0", None),
+            transaction(2, "5.0", Some("USD")),
+        ]);
+        let accounts: Vec<(Option<String>, String)> = client
+            .ingest(transactions)
+            .await
+            .unwrap()
+            .into_inner()
+            .filter_map(|event| match event.unwrap().event.unwrap() {
+                Event::Account(account) => Some((account.currency, account.available)),
+                Event::Ack(_) => None,
+            })
+            .collect()
+            .await;
+
+        assert_eq!(
+            accounts,
+            vec![
+                (Some("EUR".to_string()), "10.0".to_string()),
+                (Some("USD".to_string()), "5.0".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    pub async fn should_stream_matching_transactions_in_transaction_id_order() {
+        let engine = AsyncPaymentService::start(
+            Arc::new(AsyncAdapter::new(InMemoryDatastore::default())),
+            ServiceConfig::default(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(GrpcService::new(engine, None).serve(listener));
+
+        let channel = Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = PaymentEngineClient::new(channel);
+        let transaction = |r#type: &str, client, tx, amount: &str| Transaction {
+            r#type: r#type.to_string(),
+            client,
+            tx,
+            amount: amount.to_string(),
+            ..Transaction::default()
+        };
+        let transactions = tokio_stream::iter(vec![
+            transaction("deposit", 1, 3, "10.0"),
+            transaction("deposit", 2, 1, "5.0"),
+            transaction("deposit", 1, 2, "2.5"),
+            transaction("dispute", 1, 3, ""),
+        ]);
+
+        client
+            .ingest(transactions)
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Vec<_>>()
+            .await;
+
+        let exported: Vec<(u32, String, bool)> = client
+            .export_transactions(ExportFilter {
+                client: Some(1),
+                ..ExportFilter::default()
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|transaction| {
+                let transaction = transaction.unwrap();
+
+                (transaction.tx, transaction.amount, transaction.disputed)
+            })
+            .collect()
+            .await;
+
+        assert_eq!(
+            exported,
+            vec![(2, "2.5".to_string(), false), (3, "10.0".to_string(), true)]
+        );
+
+        let invalid = client
+            .export_transactions(ExportFilter {
+                from: Some("yesterday".to_string()),
+                ..ExportFilter::default()
+            })
+            .await;
+
+        assert_eq!(invalid.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,811 @@
+use crate::error::{ErrorKind, PaymentEngineError, PaymentEngineResult};
+use crate::export::TransactionFilter;
+use crate::limits::RunLimitTracker;
+use crate::model::{DisputeEvidence, Transaction};
+use crate::page::{ContinuationToken, PageResource};
+use crate::payment_service::PaymentService;
+use crate::scheduler::{self, JobPoll};
+use serde::Deserialize;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest body a request may send, far above any single transaction or dispute.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+const JSON: &str = "application/json";
+const JSON_LINES: &str = "application/x-ndjson";
+
+/// Status, content type and body of an answer.
+type Answer = (u16, &'static str, Vec<u8>);
+
+/// REST API running a payment service as a long-lived process:
+///
+/// * `POST /transactions` applies the JSON transaction of the body, as written by `export`, and
+///   answers with the account of its client. Transactions are checked like the rows of a
+///   batch: those above the approval threshold are parked, and they count against the run
+///   limits of the configuration. The first one over a limit is not applied and pauses the
+///   server: transactions are answered with 423 until an operator confirms with
+///   `POST /admin/resume`, which starts counting anew.
+/// * `POST /disputes` disputes a transaction on behalf of its client with the evidence of the
+///   body, e.g. `{"client":1,"tx":7,"reason_code":"fraud","documents":["s3://case/1.pdf"]}`,
+///   and answers with the recorded dispute step.
+/// * `GET /disputes/{transaction_id}` answers with the dispute chain of the transaction, with
+///   the evidence of each step.
+/// * `GET /accounts/{client_id}` answers with the stored account.
+/// * `GET /pending` and `GET /pending/requests` answer with the parked transactions and the
+///   administrative actions waiting for a second principal. `POST /pending/{tx}/approve` and
+///   `POST /pending/{tx}/reject` act on behalf of the principal of the body, e.g.
+///   `{"principal":"alice"}`, and answer with 202 while dual control waits for another one.
+/// * `GET /jobs/{id}` answers with a job of the scheduler, as `jobs status` prints it.
+/// * `POST /admin/resume` lets a server paused by a run limit go on, on behalf of the principal
+///   of the body.
+/// * `POST /admin/config/reload` reads the configuration file of the server again and applies
+///   it when it is valid, recording what changed in the audit log, and answers with the
+///   changes as `{"changes":["name: old -> new",...]}`.
+/// * `GET /accounts?after=TOKEN&page_bytes=N` answers with a page of accounts, see `page`.
+/// * `GET /transactions` answers with the transactions as JSON lines, like `export`, filtered
+///   by `client`, `disputed=true|false` and the days `from` and `to`, e.g. `2024-01-31`. With
+///   `after=TOKEN` or `page_bytes=N`, it answers with a page of them instead, see `page`.
+///
+/// Requests are handled one at a time on the thread calling `run`, in the order they arrive,
+/// through the same handlers and datastore as batch runs. Every applied transaction is flushed
+/// before it is answered. Errors are answered with `{"error":...,"kind":...}` and a status
+/// following their kind.
+pub struct ApiServer {
+    server: Server,
+    service: Box<PaymentService>,
+    page_bytes: usize,
+    config_path: Option<PathBuf>,
+    limit_tracker: RunLimitTracker,
+    /// Run limit which paused the server, until an operator confirms it may go on.
+    paused: Option<String>,
+}
+
+impl ApiServer {
+    /// Listens on `address`, e.g. `127.0.0.1:8080`. Account pages are at most `page_bytes` long;
+    /// a request can ask for shorter ones.
+    pub fn bind(
+        address: &str,
+        service: Box<PaymentService>,
+        page_bytes: usize,
+    ) -> PaymentEngineResult<Self> {
+        let server = Server::http(address).map_err(|e| PaymentEngineError::HttpServer {
+            source: std::io::Error::other(e),
+        })?;
+
+        let limit_tracker = RunLimitTracker::new(service.config().limits.clone());
+
+        Ok(ApiServer {
+            server,
+            service,
+            page_bytes,
+            config_path: None,
+            limit_tracker,
+            paused: None,
+        })
+    }
+
+    /// Configuration file `POST /admin/config/reload` reads again, none without one.
+    pub fn with_config_file(mut self, config_path: PathBuf) -> Self {
+        self.config_path = Some(config_path);
+        self
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answers requests until the listener fails.
+    pub fn run(mut self) -> PaymentEngineResult<()> {
+        loop {
+            let request = self
+                .server
+                .recv()
+                .map_err(|source| PaymentEngineError::HttpServer { source })?;
+
+            self.handle(request);
+        }
+    }
+
+    fn handle(&mut self, mut request: Request) {
+        let url = request.url().to_string();
+        let (status, content_type, body) = match self.route(&mut request) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("{} {} failed: {}", request.method(), url, e);
+                error_response(&e)
+            }
+        };
+        let content_type =
+            Header::from_bytes("Content-Type", content_type).expect("Content-Type header is valid");
+        let response = Response::from_data(body)
+            .with_status_code(status)
+            .with_header(content_type);
+
+        if let Err(e) = request.respond(response) {
+            warn!("Cannot answer {}: {}", url, e);
+        }
+    }
+
+    fn route(&mut self, request: &mut Request) -> PaymentEngineResult<Answer> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+        match (request.method(), path) {
+            (Method::Post, "/transactions") => {
+                let transaction: Transaction = match serde_json::from_slice(&body(request)?) {
+                    Ok(transaction) => transaction,
+                    Err(e) => return Ok(message(400, &format!("Invalid transaction: {}", e))),
+                };
+
+                if let Some(reason) = &self.paused {
+                    return Ok(message(423, &paused_message(reason)));
+                }
+
+                let account = match self
+                    .service
+                    .process_served(&transaction, &mut self.limit_tracker)
+                {
+                    Ok(account) => account,
+                    Err(
+                        e @ PaymentEngineError::RowLimitExceeded
+                        | e @ PaymentEngineError::ClientLimitExceeded
+                        | e @ PaymentEngineError::DepositLimitExceeded,
+                    ) => {
+                        let reason = e.to_string();
+
+                        warn!("Transactions paused, {}", reason);
+                        self.service.record_run_paused(&transaction, &reason)?;
+                        self.service.flush()?;
+
+                        let answer = message(423, &paused_message(&reason));
+
+                        self.paused = Some(reason);
+
+                        return Ok(answer);
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                self.service.flush()?;
+
+                Ok((200, JSON, serde_json::to_vec(&account)?))
+            }
+            (Method::Get, "/accounts") => {
+                let after = ContinuationToken::after(
+                    query_value(query, "after").as_deref(),
+                    PageResource::Accounts,
+                )?;
+                let page_bytes = match self.page_bytes(query) {
+                    Ok(page_bytes) => page_bytes,
+                    Err(error) => return Ok(message(400, error)),
+                };
+                let mut body = vec![];
+
+                self.service
+                    .write_accounts_page(&mut body, after, page_bytes)?;
+
+                Ok((200, JSON, body))
+            }
+            (Method::Get, "/transactions")
+                if query_value(query, "after").is_none()
+                    && query_value(query, "page_bytes").is_none() =>
+            {
+                let filter = match transaction_filter(query) {
+                    Ok(filter) => filter,
+                    Err(error) => return Ok(message(400, error)),
+                };
+                let mut body = vec![];
+
+                self.service.export_transactions(&filter, &mut body)?;
+
+                Ok((200, JSON_LINES, body))
+            }
+            (Method::Get, "/transactions") => {
+                let after = ContinuationToken::after(
+                    query_value(query, "after").as_deref(),
+                    PageResource::Transactions,
+                )?;
+                let (page_bytes, filter) = match self
+                    .page_bytes(query)
+                    .and_then(|page_bytes| Ok((page_bytes, transaction_filter(query)?)))
+                {
+                    Ok(parsed) => parsed,
+                    Err(error) => return Ok(message(400, error)),
+                };
+                let mut body = vec![];
+
+                self.service
+                    .write_transactions_page(&mut body, &filter, after, page_bytes)?;
+
+                Ok((200, JSON, body))
+            }
+            (Method::Get, _) if path.starts_with("/accounts/") => {
+                match path["/accounts/".len()..].parse::<u16>() {
+                    Ok(client_id) => match self.service.account(client_id)? {
+                        Some(account) => Ok((200, JSON, serde_json::to_vec(&account)?)),
+                        None => Ok(message(404, &format!("No account of client {}", client_id))),
+                    },
+                    Err(_) => Ok(message(404, "Client ids are numbers from 0 to 65535")),
+                }
+            }
+            (Method::Post, "/disputes") => {
+                let dispute: DisputeRequest = match serde_json::from_slice(&body(request)?) {
+                    Ok(dispute) => dispute,
+                    Err(e) => return Ok(message(400, &format!("Invalid dispute: {}", e))),
+                };
+                let evidence = DisputeEvidence {
+                    reason_code: dispute.reason_code,
+                    documents: dispute.documents,
+                };
+                let record = self
+                    .service
+                    .open_dispute(dispute.client, dispute.tx, evidence)?;
+
+                self.service.flush()?;
+
+                Ok((200, JSON, serde_json::to_vec(&record)?))
+            }
+            (Method::Get, _) if path.starts_with("/disputes/") => {
+                match path["/disputes/".len()..].parse::<u32>() {
+                    Ok(transaction_id) => {
+                        let chain = self.service.dispute_chain(transaction_id)?;
+
+                        Ok((200, JSON, serde_json::to_vec(&chain)?))
+                    }
+                    Err(_) => Ok(message(404, "Transaction ids are numbers")),
+                }
+            }
+            (Method::Get, "/pending") => {
+                let pending = self.service.pending_transactions()?;
+
+                Ok((200, JSON, serde_json::to_vec(&pending)?))
+            }
+            (Method::Get, "/pending/requests") => {
+                let requests = self.service.approval_requests();
+
+                Ok((200, JSON, serde_json::to_vec(&requests)?))
+            }
+            (Method::Post, _) if path.starts_with("/pending/") => {
+                let (transaction_id, decision) = match path["/pending/".len()..]
+                    .split_once('/')
+                    .and_then(|(tx, decision)| Some((tx.parse::<u32>().ok()?, decision)))
+                {
+                    Some(parsed) => parsed,
+                    None => return Ok(message(404, "Not found")),
+                };
+                let principal = match principal(request)? {
+                    Ok(principal) => principal,
+                    Err(answer) => return Ok(answer),
+                };
+                let answer = match decision {
+                    "approve" => match self.service.approve_pending(transaction_id, &principal)? {
+                        Some(account) => (200, JSON, serde_json::to_vec(&account)?),
+                        None => message(202, "Waiting for a second principal to approve"),
+                    },
+                    "reject" => match self.service.reject_pending(transaction_id, &principal)? {
+                        true => (200, JSON, br#"{"rejected":true}"#.to_vec()),
+                        false => message(202, "Waiting for a second principal to reject"),
+                    },
+                    _ => return Ok(message(404, "Not found")),
+                };
+
+                self.service.flush()?;
+
+                Ok(answer)
+            }
+            (Method::Get, _) if path.starts_with("/jobs/") => {
+                let job_id = match path["/jobs/".len()..].parse::<u64>() {
+                    Ok(job_id) => job_id,
+                    Err(_) => return Ok(message(404, "Job ids are numbers")),
+                };
+
+                match JobPoll::load(Path::new(scheduler::JOBS_DB_PATH), job_id) {
+                    Ok(poll) => Ok((200, JSON, serde_json::to_vec(&poll)?)),
+                    Err(e @ PaymentEngineError::JobNotFound { .. }) => {
+                        Ok(message(404, &e.to_string()))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::Post, "/admin/resume") => {
+                let principal = match principal(request)? {
+                    Ok(principal) => principal,
+                    Err(answer) => return Ok(answer),
+                };
+                let reason = match self.paused.take() {
+                    Some(reason) => reason,
+                    None => return Ok(message(409, "The server is not paused")),
+                };
+
+                info!("Transactions resumed by {} after {}", principal, reason);
+                self.limit_tracker = RunLimitTracker::new(self.service.config().limits.clone());
+                self.service.record_run_resumed(&principal, &reason)?;
+                self.service.flush()?;
+
+                Ok((
+                    200,
+                    JSON,
+                    serde_json::json!({ "resumed_after": reason })
+                        .to_string()
+                        .into_bytes(),
+                ))
+            }
+            (Method::Post, "/admin/config/reload") => {
+                let config_path = match &self.config_path {
+                    Some(config_path) => config_path,
+                    None => {
+                        return Ok(message(409, "The server runs without a configuration file"))
+                    }
+                };
+
+                match self.service.reload_config_file(config_path) {
+                    Ok(changes) => {
+                        self.limit_tracker
+                            .set_limits(self.service.config().limits.clone());
+
+                        Ok((
+                            200,
+                            JSON,
+                            serde_json::json!({ "changes": changes })
+                                .to_string()
+                                .into_bytes(),
+                        ))
+                    }
+                    Err(
+                        e @ PaymentEngineError::ConfigParse { .. }
+                        | e @ PaymentEngineError::InvalidConfig { .. },
+                    ) => Ok(message(422, &format!("Configuration rejected, {}", e))),
+                    Err(e) => Err(e),
+                }
+            }
+            (_, "/transactions")
+            | (_, "/accounts")
+            | (_, "/disputes")
+            | (_, "/pending")
+            | (_, "/admin/resume")
+            | (_, "/admin/config/reload") => Ok(message(405, "Method not allowed")),
+            _ => Ok(message(404, "Not found")),
+        }
+    }
+
+    /// Byte budget of a page, the server's unless the request asks for less.
+    fn page_bytes(&self, query: &str) -> Result<usize, &'static str> {
+        match query_value(query, "page_bytes") {
+            Some(page_bytes) => match page_bytes.parse::<usize>() {
+                Ok(page_bytes) => Ok(page_bytes.min(self.page_bytes)),
+                Err(_) => Err("page_bytes must be a number of bytes"),
+            },
+            None => Ok(self.page_bytes),
+        }
+    }
+}
+
+/// Body of `POST /disputes`.
+#[derive(Debug, Deserialize)]
+struct DisputeRequest {
+    client: u16,
+    tx: u32,
+    #[serde(default)]
+    reason_code: Option<String>,
+    #[serde(default)]
+    documents: Vec<String>,
+}
+
+/// Body of the administrative routes acting on behalf of an operator.
+#[derive(Debug, Deserialize)]
+struct PrincipalRequest {
+    principal: String,
+}
+
+/// Operator named in the body, or the answer to a body which names none.
+fn principal(request: &mut Request) -> PaymentEngineResult<Result<String, Answer>> {
+    match serde_json::from_slice::<PrincipalRequest>(&body(request)?) {
+        Ok(body) if !body.principal.trim().is_empty() => Ok(Ok(body.principal)),
+        _ => Ok(Err(message(
+            400,
+            r#"The body has to name the operator, e.g. {"principal":"alice"}"#,
+        ))),
+    }
+}
+
+fn paused_message(reason: &str) -> String {
+    format!(
+        "Transactions are paused after {}, until an operator confirms with POST /admin/resume",
+        reason
+    )
+}
+
+fn body(request: &mut Request) -> PaymentEngineResult<Vec<u8>> {
+    let mut body = vec![];
+
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES)
+        .read_to_end(&mut body)
+        .map_err(|source| PaymentEngineError::HttpServer { source })?;
+
+    Ok(body)
+}
+
+/// Filter of a transaction listing, from the same parameters as the `export` command.
+fn transaction_filter(query: &str) -> Result<TransactionFilter, &'static str> {
+    Ok(TransactionFilter {
+        client_id: parsed_value(query, "client", "client must be a client id")?,
+        disputed: parsed_value(query, "disputed", "disputed must be true or false")?,
+        ..TransactionFilter::days(
+            parsed_value(query, "from", "from must be a day, e.g. 2024-01-31")?,
+            parsed_value(query, "to", "to must be a day, e.g. 2024-01-31")?,
+        )
+    })
+}
+
+fn parsed_value<T: FromStr>(
+    query: &str,
+    name: &str,
+    error: &'static str,
+) -> Result<Option<T>, &'static str> {
+    query_value(query, name)
+        .map(|value| value.parse().map_err(|_| error))
+        .transpose()
+}
+
+fn message(status: u16, error: &str) -> Answer {
+    (
+        status,
+        JSON,
+        serde_json::json!({ "error": error })
+            .to_string()
+            .into_bytes(),
+    )
+}
+
+fn error_response(error: &PaymentEngineError) -> Answer {
+    let status = match error.kind() {
+        ErrorKind::DataQuality => 400,
+        ErrorKind::Rejected => 422,
+        ErrorKind::Retryable => 503,
+        ErrorKind::Permanent => 500,
+    };
+    let body = serde_json::json!({
+        "error": error.to_string(),
+        "kind": format!("{:?}", error.kind()),
+    });
+
+    (status, JSON, body.to_string().into_bytes())
+}
+
+/// Value of `name` in the query string, with percent escapes decoded.
+fn query_value(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::http::ApiServer;
+    use crate::limits::RunLimits;
+    use crate::payment_service::PaymentService;
+    use rust_decimal::Decimal;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use tempfile::TempDir;
+
+    fn call(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+
+        let mut response = String::new();
+
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+        (head[9..12].parse().unwrap(), body.to_string())
+    }
+
+    #[test]
+    pub fn should_apply_transactions_and_serve_accounts_over_http() {
+        let (address_sender, address_receiver) = std::sync::mpsc::channel();
+
+        // The service is not Send, so the server is created on the thread running it.
+        std::thread::spawn(move || {
+            let service = PaymentService::new(
+                Box::new(InMemoryDatastore::default()),
+                ServiceConfig::default(),
+            );
+            let server = ApiServer::bind("127.0.0.1:0", service, 1024).unwrap();
+
+            address_sender.send(server.local_addr().unwrap()).unwrap();
+            server.run()
+        });
+
+        let address = address_receiver.recv().unwrap();
+
+        let deposit = r#"{"type":"deposit","client":7,"tx":1,"amount":"10.5"}"#;
+        let (status, account) = call(address, "POST", "/transactions", deposit);
+
+        assert_eq!(status, 200);
+        assert!(account.contains(r#""available":"10.5""#));
+
+        let withdrawal = r#"{"type":"withdrawal","client":7,"tx":2,"amount":"20"}"#;
+        let (status, error) = call(address, "POST", "/transactions", withdrawal);
+
+        assert_eq!(status, 422);
+        assert!(error.contains(r#""kind":"Rejected""#));
+
+        assert_eq!(call(address, "GET", "/accounts/7", "").0, 200);
+        assert_eq!(call(address, "GET", "/accounts/8", "").0, 404);
+        assert_eq!(call(address, "POST", "/transactions", "{").0, 400);
+
+        let (status, page) = call(address, "GET", "/accounts?after=accounts%3A6", "");
+        let page: serde_json::Value = serde_json::from_str(&page).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(page["items"][0]["client"], 7);
+        assert!(page["next"].is_null());
+
+        let (status, page) = call(
+            address,
+            "GET",
+            "/transactions?client=7&disputed=false&page_bytes=512",
+            "",
+        );
+        let page: serde_json::Value = serde_json::from_str(&page).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["items"][0]["transaction_id"], 1);
+
+        let (status, lines) = call(address, "GET", "/transactions?client=7", "");
+        let transactions: Vec<serde_json::Value> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(status, 200);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0]["transaction_id"], 1);
+        assert_eq!(call(address, "GET", "/transactions?client=8", "").1, "");
+        assert_eq!(
+            call(address, "GET", "/transactions?from=yesterday", "").0,
+            400
+        );
+    }
+
+    #[test]
+    pub fn should_open_disputes_with_evidence_over_http() {
+        let (address_sender, address_receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let service = PaymentService::new(
+                Box::new(InMemoryDatastore::default()),
+                ServiceConfig::default(),
+            );
+            let server = ApiServer::bind("127.0.0.1:0", service, 1024).unwrap();
+
+            address_sender.send(server.local_addr().unwrap()).unwrap();
+            server.run()
+        });
+
+        let address = address_receiver.recv().unwrap();
+        let deposit = r#"{"type":"deposit","client":3,"tx":9,"amount":"40"}"#;
+
+        assert_eq!(call(address, "POST", "/transactions", deposit).0, 200);
+
+        let dispute =
+            r#"{"client":3,"tx":9,"reason_code":"fraud","documents":["s3://case/9.pdf"]}"#;
+        let (status, record) = call(address, "POST", "/disputes", dispute);
+        let record: serde_json::Value = serde_json::from_str(&record).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(record["reason_code"], "fraud");
+
+        let (status, account) = call(address, "GET", "/accounts/3", "");
+
+        assert_eq!(status, 200);
+        assert!(account.contains(r#""held":"40""#));
+
+        let (status, chain) = call(address, "GET", "/disputes/9", "");
+        let chain: serde_json::Value = serde_json::from_str(&chain).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(chain[0]["documents"], record["documents"]);
+
+        let spaced = r#"{"client":3,"tx":9,"reason_code":"two words"}"#;
+
+        assert_eq!(call(address, "POST", "/disputes", spaced).0, 400);
+        assert_eq!(call(address, "POST", "/disputes", "{}").0, 400);
+    }
+
+    #[test]
+    pub fn should_reload_the_configuration_file_on_request() {
+        let directory = TempDir::new().unwrap();
+        let config_path = directory.path().join("config.toml");
+        let audit_path = directory.path().join("audit.log");
+        let (address_sender, address_receiver) = std::sync::mpsc::channel();
+
+        std::fs::write(&config_path, "approval_threshold = \"100\"\n").unwrap();
+
+        let server_config_path = config_path.clone();
+        let server_audit_path = audit_path.clone();
+
+        std::thread::spawn(move || {
+            let service = PaymentService::new(
+                Box::new(InMemoryDatastore::default()),
+                ServiceConfig {
+                    audit_log_path: Some(server_audit_path),
+                    ..ServiceConfig::load(&server_config_path).unwrap()
+                },
+            );
+            let server = ApiServer::bind("127.0.0.1:0", service, 1024)
+                .unwrap()
+                .with_config_file(server_config_path);
+
+            address_sender.send(server.local_addr().unwrap()).unwrap();
+            server.run()
+        });
+
+        let address = address_receiver.recv().unwrap();
+
+        std::fs::write(&config_path, "approval_threshold = \"-1\"\n").unwrap();
+
+        assert_eq!(call(address, "POST", "/admin/config/reload", "").0, 422);
+
+        std::fs::write(&config_path, "approval_threshold = \"50\"\n").unwrap();
+
+        let (status, changes) = call(address, "POST", "/admin/config/reload", "");
+        let changes: serde_json::Value = serde_json::from_str(&changes).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(
+            changes["changes"][0],
+            "approval_threshold: Some(100) -> Some(50)"
+        );
+        assert!(std::fs::read_to_string(&audit_path)
+            .unwrap()
+            .contains("config_reloaded"));
+    }
+
+    #[test]
+    pub fn should_pause_at_run_limits_and_approve_parked_transactions_with_two_principals() {
+        let directory = TempDir::new().unwrap();
+        let audit_path = directory.path().join("audit.log");
+        let server_audit_path = audit_path.clone();
+        let (address_sender, address_receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let service = PaymentService::new(
+                Box::new(InMemoryDatastore::default()),
+                ServiceConfig {
+                    limits: RunLimits {
+                        max_rows: Some(2),
+                        ..RunLimits::default()
+                    },
+                    approval_threshold: Some(Decimal::from(100)),
+                    dual_control: true,
+                    audit_log_path: Some(server_audit_path),
+                    ..ServiceConfig::default()
+                },
+            );
+            let server = ApiServer::bind("127.0.0.1:0", service, 1024).unwrap();
+
+            address_sender.send(server.local_addr().unwrap()).unwrap();
+            server.run()
+        });
+
+        let address = address_receiver.recv().unwrap();
+        let deposit = |tx, amount| {
+            format!(
+                r#"{{"type":"deposit","client":5,"tx":{},"amount":"{}"}}"#,
+                tx, amount
+            )
+        };
+
+        assert_eq!(
+            call(address, "POST", "/transactions", &deposit(1, 10)).0,
+            200
+        );
+        assert_eq!(
+            call(address, "POST", "/transactions", &deposit(2, 500)).0,
+            200
+        );
+        assert_eq!(
+            call(address, "POST", "/transactions", &deposit(3, 1)).0,
+            423
+        );
+        assert_eq!(
+            call(address, "POST", "/transactions", &deposit(4, 1)).0,
+            423
+        );
+        assert_eq!(call(address, "POST", "/admin/resume", "").0, 400);
+        assert_eq!(
+            call(address, "POST", "/admin/resume", r#"{"principal":"alice"}"#).0,
+            200
+        );
+        assert_eq!(
+            call(address, "POST", "/admin/resume", r#"{"principal":"alice"}"#).0,
+            409
+        );
+        assert_eq!(
+            call(address, "POST", "/transactions", &deposit(3, 1)).0,
+            200
+        );
+
+        let (status, pending) = call(address, "GET", "/pending", "");
+        let pending: serde_json::Value = serde_json::from_str(&pending).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(pending[0]["transaction_id"], 2);
+
+        let alice = r#"{"principal":"alice"}"#;
+
+        assert_eq!(call(address, "POST", "/pending/2/approve", alice).0, 202);
+        assert_eq!(
+            call(address, "GET", "/pending/requests", "")
+                .1
+                .matches("alice")
+                .count(),
+            1
+        );
+        assert_eq!(call(address, "POST", "/pending/2/approve", alice).0, 422);
+
+        let (status, account) = call(
+            address,
+            "POST",
+            "/pending/2/approve",
+            r#"{"principal":"bob"}"#,
+        );
+
+        assert_eq!(status, 200);
+        assert!(account.contains(r#""available":"511""#));
+
+        let audit = std::fs::read_to_string(&audit_path).unwrap();
+
+        assert!(audit.contains("run_paused"));
+        assert!(audit.contains("run_resumed"));
+    }
+}
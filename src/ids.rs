@@ -0,0 +1,156 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+pub const IDS_DB_PATH: &str = "pe_ids.db";
+
+/// Source of the ids of transactions the engine creates itself, e.g. withdrawals committing a
+/// reservation. Ids must never collide with partner supplied ones.
+pub trait IdGenerator: Send {
+    fn next_id(&mut self) -> PaymentEngineResult<u32>;
+}
+
+/// Range of transaction ids reserved for internal transactions, the `[ids]` table of the
+/// configuration. Deployments sharing a datastore each take one of `nodes` equal slices of the
+/// range, picked by `node`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdConfig {
+    pub start: u32,
+    pub end: u32,
+    pub nodes: u32,
+    pub node: u32,
+}
+
+/// Hands out the ids of a range in ascending order. The next id is persisted when a database
+/// is given, so ids are not reused by later runs.
+pub struct RangeIdGenerator {
+    next: u64,
+    end: u64,
+    key: String,
+    db: Option<PickleDb>,
+}
+
+impl Default for IdConfig {
+    fn default() -> Self {
+        IdConfig {
+            start: 0xF000_0000,
+            end: u32::MAX,
+            nodes: 1,
+            node: 0,
+        }
+    }
+}
+
+impl IdConfig {
+    pub fn is_valid(&self) -> bool {
+        self.start <= self.end
+            && self.node < self.nodes
+            && u64::from(self.nodes) <= u64::from(self.end - self.start) + 1
+    }
+
+    /// Whether the id belongs to the reserved range, of any node.
+    pub fn contains(&self, transaction_id: u32) -> bool {
+        (self.start..=self.end).contains(&transaction_id)
+    }
+
+    /// First and last id of this node's slice.
+    fn slice(&self) -> (u64, u64) {
+        let size = (u64::from(self.end) - u64::from(self.start) + 1) / u64::from(self.nodes);
+        let first = u64::from(self.start) + size * u64::from(self.node);
+
+        (first, first + size - 1)
+    }
+
+    pub fn generator(&self, path: Option<&Path>) -> RangeIdGenerator {
+        RangeIdGenerator::open(self.slice(), path)
+    }
+}
+
+impl RangeIdGenerator {
+    fn open((first, last): (u64, u64), path: Option<&Path>) -> Self {
+        let key = format!("next:{}-{}", first, last);
+        let db = path.map(|path| {
+            PickleDb::load(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                .unwrap_or_else(|_| {
+                    PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                })
+        });
+        let next = db
+            .as_ref()
+            .and_then(|db| db.get::<u64>(&key))
+            .unwrap_or(first);
+
+        RangeIdGenerator {
+            next,
+            end: last,
+            key,
+            db,
+        }
+    }
+}
+
+impl IdGenerator for RangeIdGenerator {
+    fn next_id(&mut self) -> PaymentEngineResult<u32> {
+        if self.next > self.end {
+            return Err(PaymentEngineError::IdRangeExhausted);
+        }
+
+        let id = self.next as u32;
+
+        self.next += 1;
+        if let Some(db) = self.db.as_mut() {
+            db.set(&self.key, &self.next)?;
+        }
+
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::{IdConfig, IdGenerator};
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_generate_ids_from_node_slice_across_runs() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("ids.db");
+        let config = |node| IdConfig {
+            start: 100,
+            end: 199,
+            nodes: 2,
+            node,
+        };
+
+        let mut first_run = config(1).generator(Some(&path));
+        let ids: Vec<u32> = (0..3).map(|_| first_run.next_id().unwrap()).collect();
+        let mut second_run = config(1).generator(Some(&path));
+        let mut other_node = config(0).generator(None);
+
+        assert_eq!(ids, vec![150, 151, 152]);
+        assert_eq!(second_run.next_id().unwrap(), 153);
+        assert_eq!(other_node.next_id().unwrap(), 100);
+        assert!(config(0).contains(199));
+        assert!(!config(0).contains(200));
+    }
+
+    #[test]
+    pub fn should_accept_one_id_per_node() {
+        let config = |start, end, nodes| IdConfig {
+            start,
+            end,
+            nodes,
+            node: 0,
+        };
+        let mut single = config(7, 7, 1).generator(None);
+
+        assert!(config(7, 7, 1).is_valid());
+        assert!(config(10, 12, 3).is_valid());
+        assert!(!config(10, 12, 4).is_valid());
+        assert!(config(0, u32::MAX, u32::MAX).is_valid());
+        assert_eq!(single.next_id().unwrap(), 7);
+        assert!(single.next_id().is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{self, Account};
+use rust_decimal::Decimal;
+
+/// Summary of what a staged batch would change if it was applied.
+#[derive(Debug, Clone, Default, PartialEq, Display)]
+#[display(
+    fmt = "Staged batch changes {} accounts ({} newly locked), available {}, held {}, total {}",
+    accounts_changed,
+    accounts_locked,
+    available_delta,
+    held_delta,
+    total_delta
+)]
+pub struct BatchImpact {
+    pub accounts_changed: usize,
+    pub accounts_locked: usize,
+    pub available_delta: Decimal,
+    pub held_delta: Decimal,
+    pub total_delta: Decimal,
+}
+
+impl BatchImpact {
+    pub fn from_changes(changes: &[(Account, Account)]) -> PaymentEngineResult<Self> {
+        let mut impact = BatchImpact::default();
+
+        for (before, after) in changes {
+            if before == after {
+                continue;
+            }
+
+            impact.accounts_changed += 1;
+            if after.locked && !before.locked {
+                impact.accounts_locked += 1;
+            }
+            impact.available_delta = model::checked_add(
+                impact.available_delta,
+                model::checked_sub(after.available, before.available)?,
+            )?;
+            impact.held_delta = model::checked_add(
+                impact.held_delta,
+                model::checked_sub(after.held, before.held)?,
+            )?;
+            impact.total_delta = model::checked_add(
+                impact.total_delta,
+                model::checked_sub(after.total, before.total)?,
+            )?;
+        }
+
+        Ok(impact)
+    }
+}
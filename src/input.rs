@@ -0,0 +1,112 @@
+use crate::model::Transaction;
+use csv::Position;
+use std::fmt::Display;
+
+pub use crate::rows::TransactionRows;
+
+/// Transactions a run processes one after the other, e.g. the rows of a CSV file. A transaction
+/// which cannot be read is an error item, counted as rejected and logged, and the run goes on
+/// with the next one. Parsing stays with the source, so new input formats only implement this
+/// trait and `PaymentService::run_input` processes them like CSV files.
+pub trait InputSource<E: Display>: Iterator<Item = Result<Transaction, E>> {
+    /// Name the transactions are attributed to in the logs and the counts per source.
+    fn source(&self) -> &str;
+
+    /// Position after the last transaction read, which a checkpoint resumes from. Sources
+    /// which cannot be resumed have none and are not checkpointed.
+    fn position(&self) -> Option<&Position> {
+        None
+    }
+
+    /// The last row read, as it is in the source, for the report of rejected rows. Sources
+    /// without rows of their own have none.
+    fn raw_row(&self) -> Option<RawRow> {
+        None
+    }
+}
+
+/// Row of a source before it was deserialized, with the line it starts on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawRow {
+    pub line: u64,
+    pub content: String,
+}
+
+/// Transactions of any iterator, e.g. ones built in memory or received over a socket,
+/// attributed to `source`.
+pub struct IterInput<I> {
+    source: String,
+    transactions: I,
+}
+
+impl<I> IterInput<I> {
+    pub fn new(source: &str, transactions: I) -> Self {
+        IterInput {
+            source: source.to_string(),
+            transactions,
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for IterInput<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.transactions.next()
+    }
+}
+
+impl<I, E> InputSource<E> for IterInput<I>
+where
+    I: Iterator<Item = Result<Transaction, E>>,
+    E: Display,
+{
+    fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::error::PaymentEngineError;
+    use crate::input::IterInput;
+    use crate::model::{Transaction, TransactionType};
+    use crate::payment_service::PaymentService;
+    use rust_decimal::Decimal;
+
+    #[test]
+    pub fn should_run_transactions_of_any_iterator() {
+        let deposit = Transaction::new(TransactionType::Deposit, 4, 1, Some(Decimal::from(20)));
+        let withdrawal = Transaction {
+            r#type: TransactionType::Withdrawal,
+            transaction_id: 2,
+            amount: Some(Decimal::from(5)),
+            ..deposit.clone()
+        };
+        let transactions = vec![
+            Ok(deposit),
+            Err(PaymentEngineError::NoAmount),
+            Ok(withdrawal),
+        ];
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig::default(),
+        );
+
+        service
+            .run_input(IterInput::new("memory", transactions.into_iter()))
+            .unwrap();
+
+        let counts = service.source_counts();
+
+        assert_eq!(counts[0].source, "memory");
+        assert_eq!(counts[0].counts.rows, 3);
+        assert_eq!(counts[0].counts.rejected, 1);
+        assert_eq!(
+            service.account(4).unwrap().unwrap().available,
+            Decimal::from(15)
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::{ErrorKind, PaymentEngineResult};
+use crate::manifest::RunCounts;
+use crate::model::Transaction;
+use crate::payment_service::PaymentService;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::Message;
+use std::time::Duration;
+
+/// Messages applied before the datastore is flushed and their offsets are committed.
+const BATCH_SIZE: u64 = 500;
+/// Wait for the next message before an incomplete batch is committed.
+const POLL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Topic `--kafka` consumes and how to reach it. `properties` are further librdkafka consumer
+/// settings, e.g. `security.protocol`, and override the defaults set here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaOptions {
+    pub brokers: String,
+    pub topic: String,
+    pub group: String,
+    pub properties: Vec<(String, String)>,
+}
+
+/// Consumer feeding the transactions of a topic to a payment service. Each message holds one
+/// JSON transaction, as accepted by `POST /transactions`. Offsets are committed by batch, only
+/// after the transactions of the batch are applied and the datastore is flushed, so a consumer
+/// which stops midway leaves the rest of its batch to be consumed again. Delivery is therefore
+/// at least once: transactions applied right before a crash are applied again after it.
+pub struct KafkaInput {
+    consumer: BaseConsumer,
+}
+
+impl KafkaInput {
+    /// Joins the consumer group and subscribes to the topic. A group without committed offsets
+    /// starts at the earliest message, so nothing already on the topic is skipped.
+    pub fn connect(options: &KafkaOptions) -> PaymentEngineResult<Self> {
+        let mut config = ClientConfig::new();
+
+        config
+            .set("bootstrap.servers", &options.brokers)
+            .set("group.id", &options.group)
+            .set("enable.auto.commit", "false")
+            .set("enable.auto.offset.store", "false")
+            .set("auto.offset.reset", "earliest");
+
+        for (key, value) in &options.properties {
+            config.set(key, value);
+        }
+
+        let consumer: BaseConsumer = config.create()?;
+
+        consumer.subscribe(&[&options.topic])?;
+
+        Ok(KafkaInput { consumer })
+    }
+
+    /// Consumes the topic until consuming or storing fails.
+    pub fn run(&mut self, service: &mut PaymentService) -> PaymentEngineResult<()> {
+        let mut counts = RunCounts::default();
+
+        loop {
+            let batch = self.process_batch(service)?;
+
+            if batch.rows > 0 {
+                counts.rows += batch.rows;
+                counts.applied += batch.applied;
+                counts.rejected += batch.rejected;
+                info!(
+                    "{} messages, {} applied, {} rejected",
+                    counts.rows, counts.applied, counts.rejected
+                );
+            }
+        }
+    }
+
+    /// Applies the messages which arrive until the batch is full or none arrives for a while,
+    /// then flushes the datastore and commits their offsets. Messages which cannot be read and
+    /// rejected transactions are logged and committed with the rest. Storage failures end the
+    /// batch without committing it.
+    pub fn process_batch(
+        &mut self,
+        service: &mut PaymentService,
+    ) -> PaymentEngineResult<RunCounts> {
+        let mut counts = RunCounts::default();
+
+        while counts.rows < BATCH_SIZE {
+            let message = match self.consumer.poll(POLL_TIMEOUT) {
+                Some(message) => message?,
+                None => break,
+            };
+
+            counts.rows += 1;
+
+            match serde_json::from_slice::<Transaction>(message.payload().unwrap_or_default()) {
+                Ok(transaction) => match service.process(&transaction) {
+                    Ok(_) => counts.applied += 1,
+                    Err(e) if matches!(e.kind(), ErrorKind::Rejected | ErrorKind::DataQuality) => {
+                        counts.rejected += 1;
+                        warn!("{} | {:?}", e, transaction);
+                    }
+                    Err(e) => return Err(e),
+                },
+                Err(e) => {
+                    counts.rejected += 1;
+                    warn!(
+                        "Invalid message at offset {} of partition {}, cannot deserialize it to \
+                         transaction Error: {}",
+                        message.offset(),
+                        message.partition(),
+                        e
+                    );
+                }
+            }
+
+            self.consumer.store_offset_from_message(&message)?;
+        }
+
+        if counts.rows > 0 {
+            service.flush()?;
+            self.consumer.commit_consumer_state(CommitMode::Sync)?;
+        }
+
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::kafka::{KafkaInput, KafkaOptions};
+    use crate::payment_service::PaymentService;
+    use rdkafka::consumer::Consumer;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+    use rdkafka::{ClientConfig, Offset};
+    use rust_decimal::Decimal;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    pub fn should_apply_messages_and_commit_their_offsets() {
+        let cluster = MockCluster::new(1).unwrap();
+        let topic = "transactions";
+
+        cluster.create_topic(topic, 1, 1).unwrap();
+
+        let producer: BaseProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()
+            .unwrap();
+
+        for payload in [
+            r#"{"type":"deposit","client":3,"tx":1,"amount":"10.0"}"#,
+            "not json",
+            r#"{"type":"withdrawal","client":3,"tx":2,"amount":"4.0"}"#,
+        ] {
+            producer
+                .send(BaseRecord::<(), str>::to(topic).payload(payload))
+                .unwrap();
+        }
+
+        producer.flush(Duration::from_secs(5)).unwrap();
+
+        let mut input = KafkaInput::connect(&KafkaOptions {
+            brokers: cluster.bootstrap_servers(),
+            topic: topic.to_string(),
+            group: "engine".to_string(),
+            properties: vec![],
+        })
+        .unwrap();
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig::default(),
+        );
+        let started = Instant::now();
+        let mut rows = 0;
+
+        while rows < 3 && started.elapsed() < Duration::from_secs(30) {
+            rows += input.process_batch(&mut service).unwrap().rows;
+        }
+
+        assert_eq!(rows, 3);
+        assert_eq!(
+            service.account(3).unwrap().unwrap().available,
+            Decimal::new(60, 1)
+        );
+
+        let committed = input.consumer.committed(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(
+            committed.find_partition(topic, 0).unwrap().offset(),
+            Offset::Offset(3)
+        );
+    }
+}
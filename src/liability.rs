@@ -0,0 +1,162 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{self, Currency};
+use crate::sink;
+use chrono::Duration;
+use csv::WriterBuilder;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Upper bounds in days of the age buckets of open disputes, with their names. Disputes older
+/// than the last bound fall into `OLDEST_BUCKET`.
+const AGE_BUCKETS: [(i64, &str); 4] = [(7, "0-7"), (30, "8-30"), (60, "31-60"), (90, "61-90")];
+const OLDEST_BUCKET: &str = "over-90";
+
+/// Client, currency, reason code and index of the age bucket of a row.
+type LiabilityKey = (u16, Option<Currency>, String, usize);
+
+/// Funds held for the open disputes of one client in one currency, age bucket and reason code.
+/// `currency` is empty for the base currency, `reason_code` for disputes without one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LiabilityRow {
+    pub client: u16,
+    pub currency: Option<Currency>,
+    pub reason_code: String,
+    pub age_days: &'static str,
+    pub disputes: u64,
+    pub held: Decimal,
+}
+
+/// Funds held for open disputes at the end of a run, which finance provisions chargebacks
+/// against. Funds of a disputed transfer are held on the recipient, so they count for it.
+#[derive(Debug, Default)]
+pub struct DisputeLiability {
+    rows: BTreeMap<LiabilityKey, (u64, Decimal)>,
+}
+
+impl DisputeLiability {
+    /// Adds an open dispute of `age`, counted from when it was opened.
+    pub fn record(
+        &mut self,
+        client_id: u16,
+        currency: Option<Currency>,
+        reason_code: Option<&str>,
+        age: Duration,
+        held: Decimal,
+    ) -> PaymentEngineResult<()> {
+        let bucket = AGE_BUCKETS
+            .iter()
+            .position(|(days, _)| age <= Duration::days(*days))
+            .unwrap_or(AGE_BUCKETS.len());
+        let key = (
+            client_id,
+            currency,
+            reason_code.unwrap_or_default().to_string(),
+            bucket,
+        );
+        let (disputes, total) = self.rows.entry(key).or_default();
+
+        *disputes += 1;
+        *total = model::checked_add(*total, held)?;
+
+        Ok(())
+    }
+
+    /// Rows ordered by client, currency, reason code and age, youngest first.
+    pub fn rows(&self) -> Vec<LiabilityRow> {
+        self.rows
+            .iter()
+            .map(
+                |((client, currency, reason_code, bucket), (disputes, held))| LiabilityRow {
+                    client: *client,
+                    currency: currency.clone(),
+                    reason_code: reason_code.clone(),
+                    age_days: AGE_BUCKETS
+                        .get(*bucket)
+                        .map_or(OLDEST_BUCKET, |(_, name)| name),
+                    disputes: *disputes,
+                    held: *held,
+                },
+            )
+            .collect()
+    }
+
+    /// Writes the rows as CSV, with a header even when no dispute is open.
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |sink| {
+            let mut writer = WriterBuilder::new().has_headers(false).from_writer(sink);
+
+            writer.write_record([
+                "client",
+                "currency",
+                "reason_code",
+                "age_days",
+                "disputes",
+                "held",
+            ])?;
+
+            for row in self.rows() {
+                writer.serialize(row)?;
+            }
+
+            writer.flush()?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::FixedClock;
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::payment_service::PaymentService;
+    use chrono::{TimeZone, Utc};
+    use std::io::Write;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    pub fn should_report_held_funds_of_open_disputes_by_age_and_reason_code() {
+        let directory = TempDir::new().unwrap();
+        let liability = directory.path().join("liability.csv");
+        let mut file = NamedTempFile::new().unwrap();
+
+        write!(
+            file,
+            "type,client,tx,amount,timestamp,reason_code\n\
+             deposit,1,1,10.0,,\n\
+             deposit,1,2,5.0,,\n\
+             deposit,1,3,2.0,,\n\
+             deposit,2,4,20.0,,\n\
+             dispute,1,1,,2026-10-10T00:00:00Z,fraud\n\
+             dispute,1,2,,2026-10-12T00:00:00Z,fraud\n\
+             dispute,1,3,,2026-07-01T00:00:00Z,duplicate\n\
+             dispute,2,4,,2026-10-10T00:00:00Z,fraud\n\
+             resolve,2,4,,,\n"
+        )
+        .unwrap();
+
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig {
+                dispute_liability_path: Some(liability.clone()),
+                report_path: Some(directory.path().join("accounts.csv")),
+                ..ServiceConfig::default()
+            },
+        );
+
+        service.set_clock(Box::new(FixedClock::new(
+            Utc.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap(),
+        )));
+        service.run(file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&liability).unwrap(),
+            "client,currency,reason_code,age_days,disputes,held\n\
+             1,,duplicate,over-90,1,2.0\n\
+             1,,fraud,0-7,2,15.0\n"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Payments engine applying deposits, withdrawals, disputes, resolves and chargebacks to client
+//! accounts. The types re-exported at the crate root are the API for embedding the engine:
+//! create a `PaymentService` on a datastore, feed it transactions and read the accounts back.
+//! The modules behind the command line tool are public as well, but may change with it.
+//!
+//! ```
+//! use payment_engine::{EventSourcedDatastore, PaymentService, ServiceConfig};
+//! use payment_engine::{Transaction, TransactionType};
+//! use rust_decimal::Decimal;
+//!
+//! let log = tempfile::NamedTempFile::new().unwrap();
+//! let datastore = EventSourcedDatastore::open(log.path(), vec![]).unwrap();
+//! let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+//! let deposit = Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::from(10)));
+//!
+//! service.process(&deposit).unwrap();
+//!
+//! assert_eq!(service.accounts().unwrap()[0].available, Decimal::from(10));
+//! ```
+
+pub mod analytics;
+pub mod anomaly;
+pub mod approvals;
+#[cfg(feature = "async")]
+pub mod async_service;
+pub mod audit;
+pub mod checkpoint;
+pub mod clock;
+pub mod config;
+pub mod config_watcher;
+pub mod datastore;
+pub mod delivery;
+pub mod determinism;
+pub mod download;
+pub mod echo;
+pub mod erasure;
+pub mod error;
+pub mod event_store;
+pub mod evidence;
+pub mod export;
+mod flags;
+pub mod fork;
+pub mod fraud;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod ids;
+mod impact;
+pub mod input;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod liability;
+pub mod limits;
+pub mod locked;
+pub mod manifest;
+pub mod merge;
+pub mod migrate;
+pub mod model;
+pub mod monitor;
+pub mod page;
+pub mod payment_service;
+mod pipeline;
+pub mod profile;
+pub mod profiling;
+pub mod projection;
+pub mod quality;
+pub mod rates;
+pub mod rebuild;
+pub mod rejects;
+pub mod report;
+pub mod reservation;
+pub mod revaluation;
+pub mod risk;
+pub mod rounding;
+mod rows;
+pub mod scheduler;
+#[cfg(feature = "search")]
+pub mod search;
+mod sequence;
+pub mod shadow;
+pub mod shard;
+pub mod sink;
+#[cfg(feature = "sled")]
+pub mod sled_store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod statement;
+pub mod summary;
+pub mod timers;
+#[cfg(feature = "tui")]
+pub mod tui;
+mod unit_of_work;
+pub mod wal;
+pub mod warning;
+
+pub use crate::config::ServiceConfig;
+pub use crate::datastore::{DatastoreOperations, InMemoryDatastore, PickleDatastore};
+pub use crate::error::{ErrorKind, PaymentEngineError, PaymentEngineResult};
+pub use crate::event_store::EventSourcedDatastore;
+pub use crate::model::{Account, Transaction, TransactionType};
+pub use crate::payment_service::PaymentService;
+pub use crate::warning::Warning;
+
+#[macro_use]
+extern crate derive_more;
+#[macro_use]
+extern crate log;
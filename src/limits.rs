@@ -0,0 +1,75 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{Transaction, TransactionType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Caps which protect persistent state from obviously wrong input files. Exceeding any of them
+/// aborts the run before the offending row is applied.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunLimits {
+    pub max_rows: Option<u64>,
+    pub max_clients: Option<usize>,
+    pub max_total_deposits: Option<Decimal>,
+}
+
+#[derive(Debug, Default)]
+pub struct RunLimitTracker {
+    limits: RunLimits,
+    rows: u64,
+    clients: HashSet<u16>,
+    total_deposits: Decimal,
+}
+
+impl RunLimitTracker {
+    pub fn new(limits: RunLimits) -> Self {
+        RunLimitTracker {
+            limits,
+            ..RunLimitTracker::default()
+        }
+    }
+
+    /// Replaces the limits, keeping what has been counted so far in the run.
+    pub fn set_limits(&mut self, limits: RunLimits) {
+        self.limits = limits;
+    }
+
+    pub fn check_row(&mut self) -> PaymentEngineResult<()> {
+        self.rows += 1;
+
+        match self.limits.max_rows {
+            Some(max_rows) if self.rows > max_rows => Err(PaymentEngineError::RowLimitExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts the transaction, whose amount in the base currency is `amount_in_base`, so
+    /// deposits in other currencies add up with the same weight as they have in base terms.
+    pub fn check_transaction(
+        &mut self,
+        transaction: &Transaction,
+        amount_in_base: Option<Decimal>,
+    ) -> PaymentEngineResult<()> {
+        if let Some(max_clients) = self.limits.max_clients {
+            if !self.clients.contains(&transaction.client_id) && self.clients.len() >= max_clients {
+                return Err(PaymentEngineError::ClientLimitExceeded);
+            }
+        }
+        self.clients.insert(transaction.client_id);
+
+        if let (TransactionType::Deposit, Some(amount)) = (&transaction.r#type, amount_in_base) {
+            let total_deposits = self.total_deposits.checked_add(amount);
+
+            if let Some(max_total_deposits) = self.limits.max_total_deposits {
+                match total_deposits {
+                    Some(total_deposits) if total_deposits <= max_total_deposits => {}
+                    _ => return Err(PaymentEngineError::DepositLimitExceeded),
+                }
+            }
+            self.total_deposits = total_deposits.unwrap_or(Decimal::MAX);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{Transaction, TransactionType};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const LOCKED_QUEUE_DB_PATH: &str = "pe_locked_queue.db";
+
+/// What a dispute step does to an account which is already locked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockedAction {
+    /// Applied like on any other account, with an audit entry.
+    #[default]
+    Allow,
+    /// Kept until an operator unlocks the account, then applied in arrival order.
+    Queue,
+    /// Rejected with `DisputeOnLockedAccount`.
+    Reject,
+}
+
+/// Handling of disputes, resolves and chargebacks of locked accounts, the `[locked_disputes]`
+/// table of the configuration, e.g. `resolve = "queue"`. Strict locking still rejects steps
+/// which are allowed here.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockedDisputePolicy {
+    pub dispute: LockedAction,
+    pub resolve: LockedAction,
+    pub chargeback: LockedAction,
+}
+
+/// Dispute steps of locked accounts waiting for the account to be unlocked, by client and in
+/// arrival order, optionally persisted so they survive between runs.
+pub struct LockedQueue {
+    db: Option<PickleDb>,
+    queued: BTreeMap<u16, Vec<Transaction>>,
+}
+
+impl LockedDisputePolicy {
+    /// Action for the transaction type; transactions other than dispute steps are allowed.
+    pub fn action(&self, r#type: &TransactionType) -> LockedAction {
+        match r#type {
+            TransactionType::Dispute => self.dispute,
+            TransactionType::Resolve => self.resolve,
+            TransactionType::Chargeback => self.chargeback,
+            _ => LockedAction::Allow,
+        }
+    }
+}
+
+impl LockedQueue {
+    pub fn open(path: Option<&Path>) -> Self {
+        let db = path.map(|path| {
+            PickleDb::load(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                .unwrap_or_else(|_| {
+                    PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                })
+        });
+        let queued = match &db {
+            Some(db) => db
+                .get_all()
+                .into_iter()
+                .filter_map(|client_id| {
+                    let steps = db.get::<String>(&client_id)?;
+
+                    Some((
+                        client_id.parse().ok()?,
+                        serde_json::from_str::<Vec<Transaction>>(&steps).ok()?,
+                    ))
+                })
+                .collect(),
+            None => BTreeMap::default(),
+        };
+
+        LockedQueue { db, queued }
+    }
+
+    pub fn list(&self) -> Vec<Transaction> {
+        self.queued.values().flatten().cloned().collect()
+    }
+
+    pub fn push(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        let client_id = transaction.client_id;
+        let steps = self.queued.entry(client_id).or_default();
+
+        steps.push(transaction);
+
+        if let Some(db) = self.db.as_mut() {
+            db.set(&client_id.to_string(), &serde_json::to_string(steps)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes and returns the steps queued for the client, oldest first.
+    pub fn take(&mut self, client_id: u16) -> PaymentEngineResult<Vec<Transaction>> {
+        if let Some(db) = self.db.as_mut() {
+            db.rem(&client_id.to_string())?;
+        }
+
+        Ok(self.queued.remove(&client_id).unwrap_or_default())
+    }
+}
@@ -1,50 +1,2472 @@
-mod datastore;
-mod error;
-mod model;
-mod payment_service;
-
-use crate::datastore::PickleDatastore;
-
-use crate::payment_service::PaymentService;
-use clap::{App, Arg};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use csv::WriterBuilder;
+#[cfg(feature = "grpc")]
+use payment_engine::async_service::{AsyncAdapter, AsyncDatastoreOperations, AsyncPaymentService};
+use payment_engine::checkpoint::{Checkpoint, CheckpointPolicy};
+use payment_engine::clock::FixedClock;
+use payment_engine::config::{ReportMode, ServiceConfig};
+use payment_engine::config_watcher::ConfigWatcher;
+use payment_engine::datastore::{DatastoreOperations, InMemoryDatastore, PickleDatastore};
+use payment_engine::download::LocalInputs;
+use payment_engine::echo::EchoFormat;
+use payment_engine::error::{PaymentEngineError, PaymentEngineResult};
+use payment_engine::event_store::EventSourcedDatastore;
+use payment_engine::evidence::Evidence;
+use payment_engine::export::TransactionFilter;
+use payment_engine::fork::ForkedState;
+#[cfg(feature = "grpc")]
+use payment_engine::grpc::GrpcService;
+#[cfg(feature = "http")]
+use payment_engine::http::ApiServer;
+#[cfg(feature = "kafka")]
+use payment_engine::kafka::{KafkaInput, KafkaOptions};
+use payment_engine::limits::RunLimits;
+use payment_engine::manifest::RunManifest;
+use payment_engine::merge::SortKey;
+use payment_engine::migrate::{self, MigrationSource};
+use payment_engine::model::DisputeEvidence;
+#[cfg(feature = "tui")]
+use payment_engine::monitor::RunMonitor;
+use payment_engine::page::{self, ContinuationToken, PageResource};
+use payment_engine::payment_service::PaymentService;
+use payment_engine::profile::PartnerProfile;
+#[cfg(feature = "profiling")]
+use payment_engine::profiling;
+use payment_engine::projection::{AggregatesProjection, Projection};
+use payment_engine::quality::QualityReport;
+use payment_engine::rates::RateTable;
+use payment_engine::report::ReportFormat;
+use payment_engine::scheduler::{Job, JobKind, JobPoll, JobQueue, RetryPolicy};
+#[cfg(feature = "search")]
+use payment_engine::search;
+#[cfg(feature = "sled")]
+use payment_engine::sled_store::{self, SledDatastore};
+#[cfg(feature = "sqlite")]
+use payment_engine::sqlite::{self, SqliteDatastore};
+use payment_engine::statement::StatementTemplate;
+#[cfg(feature = "tui")]
+use payment_engine::tui::Dashboard;
+use payment_engine::{
+    anomaly, approvals, audit, datastore, determinism, echo, erasure, event_store, export, ids,
+    locked, manifest, merge, profile, rebuild, reservation, risk, rounding, scheduler, shadow,
+    shard, statement, timers, wal,
+};
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::channel;
+#[cfg(feature = "grpc")]
+use std::sync::Arc;
+use std::sync::PoisonError;
 
-#[macro_use]
-extern crate derive_more;
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate clap;
 
 const CSV_INPUT_FILE: &str = "CSV_INPUT_FILE";
+const DETECT_GAPS: &str = "detect-gaps";
+const MERGE_BY_TIMESTAMP: &str = "merge-by-timestamp";
+const SORT_BY: &str = "sort-by";
+const MAX_ROWS: &str = "max-rows";
+const MAX_CLIENTS: &str = "max-clients";
+const MAX_TOTAL_DEPOSITS: &str = "max-total-deposits";
+const TWO_PHASE: &str = "two-phase";
+const APPROVE: &str = "approve";
+const ATOMIC: &str = "atomic";
+const VERIFY_DETERMINISM: &str = "verify-determinism";
+const STRICT: &str = "strict";
+const DRY_RUN: &str = "dry-run";
+const APPROVAL_THRESHOLD: &str = "approval-threshold";
+const PENDING: &str = "pending";
+const PENDING_LIST: &str = "list";
+const PENDING_APPROVE: &str = "approve";
+const PENDING_REJECT: &str = "reject";
+const PENDING_REQUESTS: &str = "requests";
+const PRINCIPAL: &str = "principal";
+const TRANSACTION_ID: &str = "TRANSACTION_ID";
+const EVENT_STORE: &str = "event-store";
+const DATASTORE: &str = "datastore";
+const MEMORY: &str = "memory";
+const PICKLE: &str = "pickle";
+#[cfg(feature = "sqlite")]
+const SQLITE: &str = "sqlite";
+#[cfg(feature = "sled")]
+const SLED: &str = "sled";
+const CONFIG: &str = "config";
+const SHADOW_CONFIG: &str = "shadow-config";
+const RESERVATION: &str = "reservation";
+const RESERVATION_LIST: &str = "list";
+const RESERVATION_CREATE: &str = "create";
+const RESERVATION_COMMIT: &str = "commit";
+const RESERVATION_CANCEL: &str = "cancel";
+const LEGAL_HOLD: &str = "legal-hold";
+const LEGAL_HOLD_LIST: &str = "list";
+const LEGAL_HOLD_PLACE: &str = "place";
+const LEGAL_HOLD_RELEASE: &str = "release";
+const ERASE_CLIENT: &str = "erase-client";
+const UNLOCK: &str = "unlock";
+const TIMERS: &str = "timers";
+const TIMERS_LIST: &str = "list";
+const TIMERS_RUN: &str = "run";
+const CLIENT: &str = "client";
+const AMOUNT: &str = "amount";
+const TTL: &str = "ttl";
+const TOKEN: &str = "TOKEN";
+const TX: &str = "tx";
+const STATEMENT: &str = "statement";
+const PDF: &str = "pdf";
+const TEMPLATE: &str = "template";
+const SPLIT: &str = "split";
+const SHARDS: &str = "shards";
+const OUTPUT_DIR: &str = "output-dir";
+const MERGE: &str = "merge";
+const INPUTS: &str = "inputs";
+const REPLAY: &str = "replay";
+const FROM: &str = "from";
+const TO: &str = "to";
+const SNAPSHOT: &str = "snapshot";
+const EXPORT: &str = "export";
+const REPORT: &str = "report";
+const REPORT_HASH: &str = "report-hash";
+const TENANT: &str = "tenant";
+const ANALYTICS: &str = "analytics";
+const REJECTS: &str = "rejects";
+const STATS: &str = "stats";
+const DISPUTE_LIABILITY: &str = "dispute-liability";
+const RATES: &str = "rates";
+const REVALUATION: &str = "revaluation";
+const EOD_RATES: &str = "eod-rates";
+const OUTPUT_FORMAT: &str = "output-format";
+const DISPUTED: &str = "disputed";
+const DISPUTE_CHAIN: &str = "dispute-chain";
+const OPEN_DISPUTE: &str = "open-dispute";
+const REASON: &str = "reason";
+const DOCUMENT: &str = "document";
+const EXPORT_EVIDENCE: &str = "export-evidence";
+const OUTPUT: &str = "output";
+const ECHO: &str = "echo";
+const NORMALIZE: &str = "normalize";
+const QUALITY: &str = "quality";
+const RESUME: &str = "resume";
+const FORK_STATE: &str = "fork-state";
+const CHECKPOINT_EVERY: &str = "checkpoint-every";
+const PIPELINE_DEPTH: &str = "pipeline-depth";
+const PAGE_BYTES: &str = "page-bytes";
+const AFTER: &str = "after";
+const RESUME_FROM_CHECKPOINT: &str = "resume-from-checkpoint";
+const WAL: &str = "wal";
+const CLOCK: &str = "clock";
+const PROFILE: &str = "profile";
+const CSV_OUTPUT_FILE: &str = "CSV_OUTPUT_FILE";
+const REBUILD_ACCOUNTS: &str = "rebuild-accounts";
+const VERIFY: &str = "verify";
+const MIGRATE_BACKEND: &str = "migrate-backend";
+/// Datastores keeping their content between runs, which a migration can copy from and to.
+const MIGRATION_BACKENDS: &[&str] = &[
+    PICKLE,
+    #[cfg(feature = "sqlite")]
+    SQLITE,
+    #[cfg(feature = "sled")]
+    SLED,
+];
+const JOBS: &str = "jobs";
+const JOBS_SUBMIT: &str = "submit";
+const JOBS_LIST: &str = "list";
+const JOBS_RUN: &str = "run";
+const JOBS_EXPORT: &str = "export";
+const JOBS_STATUS: &str = "status";
+const JOB_ID: &str = "JOB_ID";
+const INPUT: &str = "INPUT";
+const MAX_ATTEMPTS: &str = "max-attempts";
+const MAX_PARALLEL: &str = "max-parallel";
+const RETRY_DELAY: &str = "retry-delay";
+const POLL: &str = "poll";
+const ONCE: &str = "once";
+const DEFAULT_POLL_SECONDS: u64 = 10;
+#[cfg(feature = "search")]
+const SEARCH_INDEX: &str = "search-index";
+#[cfg(feature = "search")]
+const SEARCH_TEXT: &str = "search-text";
+#[cfg(feature = "search")]
+const QUERY: &str = "QUERY";
+#[cfg(feature = "search")]
+const LIMIT: &str = "limit";
+#[cfg(feature = "http")]
+const SERVE: &str = "serve";
+#[cfg(any(feature = "http", feature = "grpc"))]
+const LISTEN: &str = "listen";
+#[cfg(feature = "grpc")]
+const SERVE_GRPC: &str = "serve-grpc";
+#[cfg(feature = "kafka")]
+const KAFKA: &str = "kafka";
+#[cfg(feature = "kafka")]
+const KAFKA_BROKERS: &str = "kafka-brokers";
+#[cfg(feature = "kafka")]
+const KAFKA_GROUP: &str = "kafka-group";
+#[cfg(feature = "kafka")]
+const KAFKA_PROPERTY: &str = "kafka-property";
+#[cfg(feature = "tui")]
+const TUI: &str = "tui";
+#[cfg(feature = "profiling")]
+const PROFILING: &str = "profiling";
+#[cfg(feature = "profiling")]
+const PROFILING_FOLDED: &str = "profiling-folded";
 
 fn main() {
-    let arg_matches = App::new(crate_name!())
+    let transaction_id_arg = Arg::with_name(TRANSACTION_ID)
+        .help("Id of the transaction waiting for approval")
+        .required(true)
+        .index(1);
+    let base_currency_config_arg = Arg::with_name(CONFIG)
+        .long(CONFIG)
+        .takes_value(true)
+        .help("TOML file with the base_currency the transactions were processed with");
+    let principal_arg = Arg::with_name(PRINCIPAL)
+        .long(PRINCIPAL)
+        .takes_value(true)
+        .env("USER")
+        .help("Operator performing the action, checked by dual control");
+    let token_arg = Arg::with_name(TOKEN)
+        .help("Token returned when the reservation was created")
+        .required(true)
+        .index(1);
+    let input_arg = Arg::with_name(CSV_INPUT_FILE)
+        .help(
+            "Paths or glob patterns of the CSV input files, - for standard input, or http(s) \
+             URLs with an optional #sha256= digest",
+        )
+        .required(true)
+        .multiple(true)
+        .index(1);
+    #[cfg(feature = "kafka")]
+    let input_arg = input_arg.required_unless(KAFKA);
+    let app = App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(input_arg)
         .arg(
-            Arg::with_name(CSV_INPUT_FILE)
-                .help("Path for the CSV input file")
-                .required(true)
-                .index(1),
+            Arg::with_name(DETECT_GAPS)
+                .long(DETECT_GAPS)
+                .help("Report gaps and out of order transaction ids per client"),
         )
-        .get_matches();
-    let csv_path = arg_matches
-        .value_of(CSV_INPUT_FILE)
-        .expect("CSV input file path is expected for app to run");
+        .arg(
+            Arg::with_name(MERGE_BY_TIMESTAMP)
+                .long(MERGE_BY_TIMESTAMP)
+                .help("Merge input files sorted by timestamp into chronological order"),
+        )
+        .arg(
+            Arg::with_name(SORT_BY)
+                .long(SORT_BY)
+                .takes_value(true)
+                .possible_values(&["tx", "timestamp"])
+                .conflicts_with(MERGE_BY_TIMESTAMP)
+                .help("Sort unsorted input files before processing, using temporary spill files"),
+        )
+        .arg(
+            Arg::with_name(MAX_ROWS)
+                .long(MAX_ROWS)
+                .takes_value(true)
+                .help("Abort the run when the input has more rows"),
+        )
+        .arg(
+            Arg::with_name(MAX_CLIENTS)
+                .long(MAX_CLIENTS)
+                .takes_value(true)
+                .help("Abort the run when the input touches more distinct clients"),
+        )
+        .arg(
+            Arg::with_name(MAX_TOTAL_DEPOSITS)
+                .long(MAX_TOTAL_DEPOSITS)
+                .takes_value(true)
+                .help("Abort the run when the deposited amount exceeds this value"),
+        )
+        .arg(
+            Arg::with_name(TWO_PHASE)
+                .long(TWO_PHASE)
+                .help("Print the impact of the batch and apply it only after confirmation"),
+        )
+        .arg(
+            Arg::with_name(APPROVE)
+                .long(APPROVE)
+                .requires(TWO_PHASE)
+                .help("Apply a two-phase batch without interactive confirmation"),
+        )
+        .arg(
+            Arg::with_name(ATOMIC)
+                .long(ATOMIC)
+                .conflicts_with(TWO_PHASE)
+                .help("Apply the input file only when every transaction in it succeeds"),
+        )
+        .arg(
+            Arg::with_name(DRY_RUN)
+                .long(DRY_RUN)
+                .conflicts_with_all(&[TWO_PHASE, ATOMIC, OUTPUT])
+                .help("Validate every row against the current state and report problems without applying or reporting anything"),
+        )
+        .arg(
+            Arg::with_name(STRICT)
+                .long(STRICT)
+                .help("Abort the run on the first row which cannot be read or is rejected"),
+        )
+        .arg(
+            Arg::with_name(VERIFY_DETERMINISM)
+                .long(VERIFY_DETERMINISM)
+                .conflicts_with_all(&[RESUME, RESUME_FROM_CHECKPOINT, FORK_STATE])
+                .help("Process the input a second time on fresh in-memory state and fail unless both reports are identical"),
+        )
+        .arg(
+            Arg::with_name(APPROVAL_THRESHOLD)
+                .long(APPROVAL_THRESHOLD)
+                .takes_value(true)
+                .help("Park deposits and withdrawals above this amount until they are approved"),
+        )
+        .arg(
+            Arg::with_name(CONFIG)
+                .long(CONFIG)
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    DETECT_GAPS,
+                    MAX_ROWS,
+                    MAX_CLIENTS,
+                    MAX_TOTAL_DEPOSITS,
+                    APPROVAL_THRESHOLD,
+                ])
+                .help("Read settings from this TOML file and reload them whenever it changes"),
+        )
+        .arg(
+            Arg::with_name(REPORT)
+                .long(REPORT)
+                .takes_value(true)
+                .possible_values(&["all", "changed"])
+                .help("Report all accounts or only those changed by this run [default: all]"),
+        )
+        .arg(
+            Arg::with_name(ECHO)
+                .long(ECHO)
+                .takes_value(true)
+                .possible_values(&["csv", "json"])
+                .help("Only parse and validate the input and write it back in canonical form"),
+        )
+        .arg(
+            Arg::with_name(REPORT_HASH)
+                .long(REPORT_HASH)
+                .help("Add a hash of balances and status to every account of the report"),
+        )
+        .arg(
+            Arg::with_name(OUTPUT)
+                .long(OUTPUT)
+                .takes_value(true)
+                .help("Write the account report to this file instead of stdout, replacing it atomically"),
+        )
+        .arg(
+            Arg::with_name(OUTPUT_FORMAT)
+                .long(OUTPUT_FORMAT)
+                .takes_value(true)
+                .possible_values(&["csv", "json", "table"])
+                .default_value("csv")
+                .help("Format of the account report, json writing one object per line"),
+        )
+        .arg(
+            Arg::with_name(ANALYTICS)
+                .long(ANALYTICS)
+                .takes_value(true)
+                .help("Write amount histograms per transaction type and client decile to this .json or .csv file"),
+        )
+        .arg(
+            Arg::with_name(REJECTS)
+                .long(REJECTS)
+                .takes_value(true)
+                .help("Write the rejected rows with their line, content and error to this CSV file"),
+        )
+        .arg(
+            Arg::with_name(DISPUTE_LIABILITY)
+                .long(DISPUTE_LIABILITY)
+                .takes_value(true)
+                .help("Write the funds held for open disputes per client, age and reason code to this CSV file"),
+        )
+        .arg(
+            Arg::with_name(STATS)
+                .long(STATS)
+                .takes_value(true)
+                .help("Write the summary of the run, counts by outcome and transaction type, to this JSON file"),
+        )
+        .arg(
+            Arg::with_name(TENANT)
+                .long(TENANT)
+                .takes_value(true)
+                .help("Deliver the account report to the destinations configured for this tenant"),
+        )
+        .arg(
+            Arg::with_name(RATES)
+                .long(RATES)
+                .takes_value(true)
+                .help("CSV file with from,to,rate columns, the exchange rates of convert rows"),
+        )
+        .arg(
+            Arg::with_name(REVALUATION)
+                .long(REVALUATION)
+                .takes_value(true)
+                .requires_all(&[RATES, EOD_RATES])
+                .help("Write the exposure of balances in other currencies and their unrealized FX P&L to this CSV file"),
+        )
+        .arg(
+            Arg::with_name(EOD_RATES)
+                .long(EOD_RATES)
+                .takes_value(true)
+                .requires(REVALUATION)
+                .help("CSV file with from,to,rate columns, the end-of-day rates balances are revalued at"),
+        )
+        .arg(
+            Arg::with_name(SHADOW_CONFIG)
+                .long(SHADOW_CONFIG)
+                .takes_value(true)
+                .help("Evaluate the flags of this TOML file next to production and report differences"),
+        )
+        .arg(
+            Arg::with_name(EVENT_STORE)
+                .long(EVENT_STORE)
+                .takes_value(true)
+                .global(true)
+                .help("Store accounts and transactions as events in this log file"),
+        )
+        .arg(
+            Arg::with_name(RESUME)
+                .long(RESUME)
+                .help(
+                    "Continue with the transactions and balances the pickle datastore kept from \
+                     an interrupted run",
+                ),
+        )
+        .arg(
+            Arg::with_name(CHECKPOINT_EVERY)
+                .long(CHECKPOINT_EVERY)
+                .takes_value(true)
+                .value_name("ROWS")
+                .conflicts_with(EVENT_STORE)
+                .help(
+                    "Save the input position with a snapshot of the state files after every \
+                     ROWS rows",
+                ),
+        )
+        .arg(
+            Arg::with_name(PIPELINE_DEPTH)
+                .long(PIPELINE_DEPTH)
+                .takes_value(true)
+                .value_name("ROWS")
+                .help(
+                    "Read and parse rows on threads of their own, at most ROWS rows ahead of \
+                     processing, and write the report on another",
+                ),
+        )
+        .arg(
+            Arg::with_name(RESUME_FROM_CHECKPOINT)
+                .long(RESUME_FROM_CHECKPOINT)
+                .conflicts_with_all(&[RESUME, FORK_STATE, WAL, EVENT_STORE, ATOMIC, TWO_PHASE])
+                .help(
+                    "Restore the state files of the last checkpoint and continue the input \
+                     after its rows",
+                ),
+        )
+        .arg(
+            Arg::with_name(FORK_STATE)
+                .long(FORK_STATE)
+                .takes_value(true)
+                .value_name("SNAPSHOT")
+                .conflicts_with(RESUME)
+                .help(
+                    "Rehearse the run on a scratch copy of the state files in this directory, \
+                     without deliveries and without changing the originals",
+                ),
+        )
+        .arg(
+            Arg::with_name(WAL)
+                .long(WAL)
+                .takes_value(true)
+                .help(
+                    "Append every transaction to this write-ahead log before applying it, \
+                     emptied first unless resuming",
+                ),
+        )
+        .arg(
+            Arg::with_name(DATASTORE)
+                .long(DATASTORE)
+                .takes_value(true)
+                .global(true)
+                .possible_values(&[
+                    MEMORY,
+                    PICKLE,
+                    #[cfg(feature = "sqlite")]
+                    SQLITE,
+                    #[cfg(feature = "sled")]
+                    SLED,
+                ])
+                .default_value(PICKLE)
+                .help("Where accounts and transactions are kept without --event-store"),
+        )
+        .subcommand(
+            SubCommand::with_name(PENDING)
+                .about("Manage transactions waiting for approval")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .arg(
+                    Arg::with_name(CONFIG)
+                        .long(CONFIG)
+                        .takes_value(true)
+                        .help("TOML file enabling dual_control"),
+                )
+                .subcommand(SubCommand::with_name(PENDING_LIST).about("List waiting transactions"))
+                .subcommand(
+                    SubCommand::with_name(PENDING_APPROVE)
+                        .about("Apply a waiting transaction")
+                        .arg(transaction_id_arg.clone())
+                        .arg(principal_arg.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name(PENDING_REJECT)
+                        .about("Discard a waiting transaction")
+                        .arg(transaction_id_arg)
+                        .arg(principal_arg.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name(PENDING_REQUESTS)
+                        .about("List approvals and rejections waiting for a second principal"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(RESERVATION)
+                .about("Manage funds reserved for external authorization flows")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name(RESERVATION_LIST).about("List open reservations"),
+                )
+                .subcommand(
+                    SubCommand::with_name(RESERVATION_CREATE)
+                        .about("Reserve available funds and print the reservation token")
+                        .arg(
+                            Arg::with_name(CLIENT)
+                                .long(CLIENT)
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name(AMOUNT)
+                                .long(AMOUNT)
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name(TTL)
+                                .long(TTL)
+                                .takes_value(true)
+                                .help("Seconds until the reservation expires [default: 900]"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(RESERVATION_COMMIT)
+                        .about("Withdraw the reserved funds")
+                        .arg(token_arg.clone())
+                        .arg(
+                            Arg::with_name(TX)
+                                .long(TX)
+                                .takes_value(true)
+                                .help("Id of the resulting withdrawal, generated when not given"),
+                        )
+                        .arg(
+                            Arg::with_name(CONFIG)
+                                .long(CONFIG)
+                                .takes_value(true)
+                                .help("TOML file with the [ids] range of generated ids"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(RESERVATION_CANCEL)
+                        .about("Release the reserved funds")
+                        .arg(token_arg),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(LEGAL_HOLD)
+                .about("Manage legal holds, which block erasure of a client's data")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name(LEGAL_HOLD_LIST).about("List legal holds"))
+                .subcommand(
+                    SubCommand::with_name(LEGAL_HOLD_PLACE)
+                        .about("Place a client under legal hold")
+                        .arg(
+                            Arg::with_name(CLIENT)
+                                .long(CLIENT)
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name(REASON)
+                                .long(REASON)
+                                .takes_value(true)
+                                .required(true)
+                                .help("Why the client's data has to be kept, e.g. a case number"),
+                        )
+                        .arg(principal_arg.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name(LEGAL_HOLD_RELEASE)
+                        .about("Release the legal hold of a client")
+                        .arg(
+                            Arg::with_name(CLIENT)
+                                .long(CLIENT)
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(principal_arg.clone()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(ERASE_CLIENT)
+                .about(
+                    "Erase the memo and counterparty of a client's transactions, keeping balances \
+                     and the ledger, unless the client is under legal hold",
+                )
+                .arg(
+                    Arg::with_name(CLIENT)
+                        .long(CLIENT)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(principal_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name(UNLOCK)
+                .about(
+                    "Unlock a client's account and apply the dispute steps queued while it was \
+                     locked",
+                )
+                .arg(
+                    Arg::with_name(CLIENT)
+                        .long(CLIENT)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(principal_arg)
+                .arg(
+                    Arg::with_name(CONFIG)
+                        .long(CONFIG)
+                        .takes_value(true)
+                        .help("TOML file with the policies the queued steps are applied with"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(TIMERS)
+                .about("Manage reservation expiries and dispute deadlines")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name(TIMERS_LIST).about("List scheduled timers as JSON lines"),
+                )
+                .subcommand(SubCommand::with_name(TIMERS_RUN).about("Execute the timers which are due")),
+        )
+        .subcommand(
+            SubCommand::with_name(STATEMENT)
+                .about("Render balances and transaction history of a client")
+                .arg(
+                    Arg::with_name(CLIENT)
+                        .long(CLIENT)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(PDF)
+                        .long(PDF)
+                        .takes_value(true)
+                        .required(true)
+                        .help("Path of the PDF file to write"),
+                )
+                .arg(
+                    Arg::with_name(TEMPLATE)
+                        .long(TEMPLATE)
+                        .takes_value(true)
+                        .help("TOML file with the statement title, company, address and footer"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SPLIT)
+                .about("Partition a CSV file into shard files by client")
+                .arg(
+                    Arg::with_name(CSV_INPUT_FILE)
+                        .help("Path for the CSV input file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name(SHARDS)
+                        .long(SHARDS)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(OUTPUT_DIR)
+                        .long(OUTPUT_DIR)
+                        .takes_value(true)
+                        .help("Directory of the shard files, defaults to the directory of the input"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(MERGE)
+                .about("Combine account reports or event stores of shards into one report")
+                .arg(
+                    Arg::with_name(INPUTS)
+                        .long(INPUTS)
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true)
+                        .help("Account report CSV files or event store logs, one per shard"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(REPLAY)
+                .about(
+                    "Rebuild accounts from the events of the event store in a date range, or \
+                     from a write-ahead log",
+                )
+                .arg(
+                    Arg::with_name(FROM)
+                        .long(FROM)
+                        .takes_value(true)
+                        .required_unless(WAL)
+                        .help("First day of the range, e.g. 2024-01-01"),
+                )
+                .arg(
+                    Arg::with_name(TO)
+                        .long(TO)
+                        .takes_value(true)
+                        .required_unless(WAL)
+                        .help("Last day of the range, included"),
+                )
+                .arg(
+                    Arg::with_name(WAL)
+                        .long(WAL)
+                        .takes_value(true)
+                        .conflicts_with_all(&[FROM, TO, SNAPSHOT])
+                        .help(
+                            "Write-ahead log of a crashed run, replayed into a new datastore \
+                             that --resume continues from",
+                        ),
+                )
+                .arg(
+                    Arg::with_name(CONFIG)
+                        .long(CONFIG)
+                        .takes_value(true)
+                        .requires(WAL)
+                        .help("TOML file the logged run was processed with"),
+                )
+                .arg(
+                    Arg::with_name(CLOCK)
+                        .long(CLOCK)
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .requires(WAL)
+                        .help(
+                            "Time the replay runs at, e.g. 2024-03-01T12:00:00Z, so dispute steps \
+                             and timers are dated the same on every replay [default: now]",
+                        ),
+                )
+                .arg(
+                    Arg::with_name(SNAPSHOT)
+                        .long(SNAPSHOT)
+                        .takes_value(true)
+                        .help("Account report to start from, accounts start empty without it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(EXPORT)
+                .about("Stream transactions of the event store as JSON lines")
+                .args(&export_filter_args())
+                .arg(
+                    Arg::with_name(PAGE_BYTES)
+                        .long(PAGE_BYTES)
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .help(
+                            "Write one page of at most BYTES bytes as a JSON object, with the \
+                             token continuing the export in `next`",
+                        ),
+                )
+                .arg(
+                    Arg::with_name(AFTER)
+                        .long(AFTER)
+                        .takes_value(true)
+                        .value_name("TOKEN")
+                        .help("Continuation token of the page to write"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(QUALITY)
+                .about("Print per-column data-quality metrics of input files as JSON")
+                .arg(
+                    Arg::with_name(CSV_INPUT_FILE)
+                        .help("Paths or glob patterns of the CSV input files")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(NORMALIZE)
+                .about("Convert a partner file to the canonical input schema")
+                .arg(
+                    Arg::with_name(PROFILE)
+                        .long(PROFILE)
+                        .takes_value(true)
+                        .required(true)
+                        .help("Partner profile, profiles/<name>.toml or a path to a TOML file"),
+                )
+                .arg(
+                    Arg::with_name(CSV_INPUT_FILE)
+                        .help("Path for the partner input file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name(CSV_OUTPUT_FILE)
+                        .help("Path of the canonical CSV file to write")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(EXPORT_EVIDENCE)
+                .about("Bundle everything known about a transaction into a zip file with checksums")
+                .arg(
+                    Arg::with_name(CLIENT)
+                        .long(CLIENT)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(TX)
+                        .long(TX)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(INPUTS)
+                        .long(INPUTS)
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Input files to copy the rows of the transaction from"),
+                )
+                .arg(
+                    Arg::with_name(OUTPUT)
+                        .long(OUTPUT)
+                        .takes_value(true)
+                        .help("Path of the zip file, defaults to evidence-<client>-<tx>.zip"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(OPEN_DISPUTE)
+                .about("Dispute a transaction on behalf of the client and attach evidence")
+                .arg(
+                    Arg::with_name(CLIENT)
+                        .long(CLIENT)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(TX)
+                        .long(TX)
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(REASON)
+                        .long(REASON)
+                        .takes_value(true)
+                        .help("Reason code of the dispute"),
+                )
+                .arg(
+                    Arg::with_name(DOCUMENT)
+                        .long(DOCUMENT)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("URI or opaque reference of a supporting document, repeatable"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(DISPUTE_CHAIN)
+                .about("Print the disputes, resolves and chargebacks of a transaction")
+                .arg(
+                    Arg::with_name(TRANSACTION_ID)
+                        .help("Id of the disputed transaction")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(REBUILD_ACCOUNTS)
+                .about("Regenerate accounts from the transactions kept in pe_transaction.db")
+                .arg(
+                    Arg::with_name(SNAPSHOT)
+                        .long(SNAPSHOT)
+                        .takes_value(true)
+                        .help("Surviving account report to verify the rebuilt accounts against"),
+                )
+                .arg(base_currency_config_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name(VERIFY)
+                .about(
+                    "Recompute accounts from the transactions of the event store and list the \
+                     clients whose stored balances differ",
+                )
+                .arg(base_currency_config_arg),
+        )
+        .subcommand(
+            SubCommand::with_name(MIGRATE_BACKEND)
+                .about(
+                    "Copy transactions, accounts and dispute chains to another datastore, verify \
+                     the copy and print its summary as JSON",
+                )
+                .arg(
+                    Arg::with_name(FROM)
+                        .long(FROM)
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(MIGRATION_BACKENDS)
+                        .help("Datastore to copy from"),
+                )
+                .arg(
+                    Arg::with_name(TO)
+                        .long(TO)
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(MIGRATION_BACKENDS)
+                        .help("Datastore to copy to; an interrupted migration resumes when rerun"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(JOBS)
+                .about("Queue batch runs and run them with the embedded scheduler")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name(JOBS_SUBMIT)
+                        .about("Queue a batch run of a file, with --event-store as its tenant")
+                        .arg(
+                            Arg::with_name(INPUT)
+                                .help("Input CSV file path or file:// URI")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name(OUTPUT)
+                                .long(OUTPUT)
+                                .takes_value(true)
+                                .help("Account report file [default: job-<id>.accounts.csv]"),
+                        )
+                        .arg(
+                            Arg::with_name(MAX_ATTEMPTS)
+                                .long(MAX_ATTEMPTS)
+                                .takes_value(true)
+                                .help("Runs before a job failing with retryable errors gives up [default: 3]"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(JOBS_EXPORT)
+                        .about(
+                            "Queue an export of the event store's transactions as JSON lines, \
+                             written in chunks which survive restarts",
+                        )
+                        .args(&export_filter_args())
+                        .arg(
+                            Arg::with_name(OUTPUT)
+                                .long(OUTPUT)
+                                .takes_value(true)
+                                .help("Export file [default: job-<id>.export.jsonl]"),
+                        )
+                        .arg(
+                            Arg::with_name(MAX_ATTEMPTS)
+                                .long(MAX_ATTEMPTS)
+                                .takes_value(true)
+                                .help("Runs before a job failing with retryable errors gives up [default: 3]"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name(JOBS_LIST).about("List jobs and their status"))
+                .subcommand(
+                    SubCommand::with_name(JOBS_STATUS)
+                        .about("Print a job and the progress of its export as JSON")
+                        .arg(Arg::with_name(JOB_ID).required(true).index(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name(JOBS_RUN)
+                        .about("Run queued jobs as they become due")
+                        .arg(
+                            Arg::with_name(MAX_PARALLEL)
+                                .long(MAX_PARALLEL)
+                                .takes_value(true)
+                                .help("Tenants whose jobs run at the same time [default: 1]"),
+                        )
+                        .arg(
+                            Arg::with_name(RETRY_DELAY)
+                                .long(RETRY_DELAY)
+                                .takes_value(true)
+                                .help("Seconds before the first retry, growing with every attempt [default: 60]"),
+                        )
+                        .arg(
+                            Arg::with_name(POLL)
+                                .long(POLL)
+                                .takes_value(true)
+                                .help("Seconds between checks of the queue [default: 10]"),
+                        )
+                        .arg(
+                            Arg::with_name(ONCE)
+                                .long(ONCE)
+                                .help("Run the jobs which are due now and exit"),
+                        ),
+                ),
+        );
+    #[cfg(feature = "search")]
+    let app = app
+        .arg(
+            Arg::with_name(SEARCH_INDEX)
+                .long(SEARCH_INDEX)
+                .takes_value(true)
+                .global(true)
+                .default_value(search::SEARCH_INDEX_PATH)
+                .help("Directory of the memo and counterparty index kept with the event store"),
+        )
+        .subcommand(
+            SubCommand::with_name(SEARCH_TEXT)
+                .about("Find transactions by words in their memo or counterparty")
+                .arg(
+                    Arg::with_name(QUERY)
+                        .help("Words which must all appear in the memo or counterparty")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name(LIMIT)
+                        .long(LIMIT)
+                        .takes_value(true)
+                        .default_value("20")
+                        .help("Maximum number of transactions to return"),
+                ),
+        );
+    #[cfg(feature = "http")]
+    let app = app.subcommand(
+        SubCommand::with_name(SERVE)
+            .about("Run the engine as a long-lived REST API service")
+            .arg(
+                Arg::with_name(LISTEN)
+                    .long(LISTEN)
+                    .takes_value(true)
+                    .default_value("127.0.0.1:8080")
+                    .help("Address and port to listen on"),
+            )
+            .arg(
+                Arg::with_name(PAGE_BYTES)
+                    .long(PAGE_BYTES)
+                    .takes_value(true)
+                    .value_name("BYTES")
+                    .help("Longest page of GET /accounts [default: 1 MiB]"),
+            )
+            .arg(
+                Arg::with_name(CONFIG)
+                    .long(CONFIG)
+                    .takes_value(true)
+                    .help("TOML file with the policies transactions are processed with"),
+            ),
+    );
+    #[cfg(feature = "grpc")]
+    let app = app.subcommand(
+        SubCommand::with_name(SERVE_GRPC)
+            .about(
+                "Run the engine as a gRPC service ingesting streamed transactions, see \
+                 proto/payment_engine.proto",
+            )
+            .arg(
+                Arg::with_name(LISTEN)
+                    .long(LISTEN)
+                    .takes_value(true)
+                    .default_value("127.0.0.1:50051")
+                    .help("Address and port to listen on"),
+            )
+            .arg(
+                Arg::with_name(CONFIG)
+                    .long(CONFIG)
+                    .takes_value(true)
+                    .help("TOML file with the policies transactions are processed with"),
+            ),
+    );
+    #[cfg(feature = "kafka")]
+    let app = app
+        .arg(
+            Arg::with_name(KAFKA)
+                .long(KAFKA)
+                .takes_value(true)
+                .value_name("TOPIC")
+                .conflicts_with(CSV_INPUT_FILE)
+                .help("Consume JSON transactions from the Kafka topic instead of input files"),
+        )
+        .arg(
+            Arg::with_name(KAFKA_BROKERS)
+                .long(KAFKA_BROKERS)
+                .takes_value(true)
+                .default_value("localhost:9092")
+                .help("Comma separated host:port list of Kafka brokers"),
+        )
+        .arg(
+            Arg::with_name(KAFKA_GROUP)
+                .long(KAFKA_GROUP)
+                .takes_value(true)
+                .default_value("payment_engine")
+                .help("Consumer group committing the offsets of the applied transactions"),
+        )
+        .arg(
+            Arg::with_name(KAFKA_PROPERTY)
+                .long(KAFKA_PROPERTY)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .validator(|property| match property.split_once('=') {
+                    Some(_) => Ok(()),
+                    None => Err("Kafka properties are written as KEY=VALUE".to_string()),
+                })
+                .help("Further librdkafka consumer setting, repeatable"),
+        );
+    #[cfg(feature = "tui")]
+    let app = app.arg(Arg::with_name(TUI).long(TUI).help(
+        "Show live throughput, rejects, busy clients and datastore figures on the \
+                 terminal instead of the log while the run goes on",
+    ));
+    #[cfg(feature = "profiling")]
+    let app = app
+        .arg(
+            Arg::with_name(PROFILING)
+                .long(PROFILING)
+                .help("Time handlers and datastore calls and print the hotspots after the run"),
+        )
+        .arg(
+            Arg::with_name(PROFILING_FOLDED)
+                .long(PROFILING_FOLDED)
+                .takes_value(true)
+                .requires(PROFILING)
+                .help("Write the timed call stacks in folded format for flamegraph tools"),
+        );
+    let arg_matches = app.get_matches();
 
     env_logger::init();
 
-    info!("Starting transaction processing");
-
-    let datastore = PickleDatastore::new();
-    let mut service = PaymentService::new(Box::new(datastore));
+    let result = match arg_matches.subcommand() {
+        (PENDING, Some(pending_matches)) => run_pending_command(pending_matches),
+        (RESERVATION, Some(reservation_matches)) => run_reservation_command(reservation_matches),
+        (LEGAL_HOLD, Some(legal_hold_matches)) => run_legal_hold_command(legal_hold_matches),
+        (ERASE_CLIENT, Some(erase_matches)) => run_erase_client(erase_matches),
+        (UNLOCK, Some(unlock_matches)) => run_unlock(unlock_matches),
+        (TIMERS, Some(timers_matches)) => run_timers_command(timers_matches),
+        (STATEMENT, Some(statement_matches)) => run_statement(statement_matches),
+        (SPLIT, Some(split_matches)) => run_split(split_matches),
+        (MERGE, Some(merge_matches)) => run_merge(merge_matches),
+        (REPLAY, Some(replay_matches)) => run_replay(replay_matches),
+        (EXPORT, Some(export_matches)) => run_export(export_matches),
+        (NORMALIZE, Some(normalize_matches)) => run_normalize(normalize_matches),
+        (QUALITY, Some(quality_matches)) => run_quality(quality_matches),
+        (EXPORT_EVIDENCE, Some(evidence_matches)) => run_export_evidence(evidence_matches),
+        (OPEN_DISPUTE, Some(dispute_matches)) => run_open_dispute(dispute_matches),
+        (DISPUTE_CHAIN, Some(chain_matches)) => run_dispute_chain(chain_matches),
+        (REBUILD_ACCOUNTS, Some(rebuild_matches)) => run_rebuild_accounts(rebuild_matches),
+        (VERIFY, Some(verify_matches)) => run_verify(verify_matches),
+        (MIGRATE_BACKEND, Some(migrate_matches)) => run_migrate_backend(migrate_matches),
+        (JOBS, Some(jobs_matches)) => run_jobs_command(jobs_matches),
+        #[cfg(feature = "search")]
+        (SEARCH_TEXT, Some(search_matches)) => run_search(search_matches),
+        #[cfg(feature = "http")]
+        (SERVE, Some(serve_matches)) => run_serve(serve_matches),
+        #[cfg(feature = "grpc")]
+        (SERVE_GRPC, Some(serve_matches)) => run_serve_grpc(serve_matches),
+        #[cfg(feature = "kafka")]
+        _ if arg_matches.is_present(KAFKA) => run_kafka(&arg_matches),
+        _ => run_batch(&arg_matches),
+    };
 
-    match service.run(&*csv_path) {
+    match result {
         Ok(_) => {
             info!("Processed all transactions");
         }
         Err(e) => {
             error!("Fatal {}", e);
+
+            let mut source = std::error::Error::source(&e);
+
+            while let Some(cause) = source {
+                error!("Caused by: {}", cause);
+                source = cause.source();
+            }
+
+            if e.is_retryable() {
+                error!("The error is {:?}, the run can be retried", e.kind());
+            }
+
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_batch(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let inputs = LocalInputs::fetch(
+        &arg_matches
+            .values_of(CSV_INPUT_FILE)
+            .expect("CSV input file path is expected for app to run")
+            .collect::<Vec<_>>(),
+    )?;
+    let csv_paths =
+        merge::expand_paths(&inputs.paths.iter().map(String::as_str).collect::<Vec<_>>())?;
+    let csv_paths: Vec<&str> = csv_paths.iter().map(String::as_str).collect();
+
+    let sort_key = arg_matches.value_of(SORT_BY).and_then(SortKey::from_arg);
+    let prepared_input = if let Some(sort_key) = sort_key {
+        Some(merge::sort_by(&csv_paths, sort_key))
+    } else if arg_matches.is_present(MERGE_BY_TIMESTAMP) {
+        Some(merge::merge_by_timestamp(&csv_paths))
+    } else {
+        None
+    };
+    let prepared_input = prepared_input.transpose()?;
+    // Without merging, several files are processed one after the other. Only the plain run
+    // supports that.
+    let csv_path = match &prepared_input {
+        Some(prepared_input) => Some(
+            prepared_input
+                .path()
+                .to_str()
+                .expect("Temporary file path is valid UTF-8"),
+        ),
+        None if csv_paths.len() == 1 => Some(csv_paths[0]),
+        None => None,
+    };
+    let single_input = || csv_path.ok_or(PaymentEngineError::UnmergedInputFiles);
+
+    if let Some(format) = arg_matches.value_of(ECHO).and_then(EchoFormat::from_arg) {
+        let summary =
+            echo::echo_file(Path::new(single_input()?), format, std::io::stdout().lock())?;
+
+        info!(
+            "Echoed {} transactions, rejected {} rows",
+            summary.written, summary.rejected
+        );
+
+        return Ok(());
+    }
+
+    info!("Starting transaction processing");
+
+    let fork = arg_matches
+        .value_of(FORK_STATE)
+        .map(|snapshot| ForkedState::fork(Path::new(snapshot)))
+        .transpose()?;
+    let config_path = arg_matches.value_of(CONFIG).map(Path::new);
+    let config = match config_path {
+        Some(config_path) => with_local_files(ServiceConfig::load(config_path)?),
+        None => with_local_files(ServiceConfig {
+            detect_sequence_gaps: arg_matches.is_present(DETECT_GAPS),
+            limits: RunLimits {
+                max_rows: optional_value(arg_matches, MAX_ROWS),
+                max_clients: optional_value(arg_matches, MAX_CLIENTS),
+                max_total_deposits: optional_value::<Decimal>(arg_matches, MAX_TOTAL_DEPOSITS),
+            },
+            approval_threshold: optional_value::<Decimal>(arg_matches, APPROVAL_THRESHOLD),
+            ..ServiceConfig::default()
+        }),
+    };
+    let config = ServiceConfig {
+        report_mode: arg_matches
+            .value_of(REPORT)
+            .and_then(ReportMode::from_arg)
+            .unwrap_or_default(),
+        report_hash: arg_matches.is_present(REPORT_HASH),
+        strict: arg_matches.is_present(STRICT),
+        tenant: arg_matches.value_of(TENANT).map(str::to_string),
+        report_path: arg_matches.value_of(OUTPUT).map(PathBuf::from),
+        report_format: arg_matches
+            .value_of(OUTPUT_FORMAT)
+            .and_then(ReportFormat::from_arg)
+            .unwrap_or_default(),
+        analytics_path: arg_matches.value_of(ANALYTICS).map(PathBuf::from),
+        rejects_path: arg_matches.value_of(REJECTS).map(PathBuf::from),
+        stats_path: arg_matches.value_of(STATS).map(PathBuf::from),
+        dispute_liability_path: arg_matches.value_of(DISPUTE_LIABILITY).map(PathBuf::from),
+        wal_path: arg_matches.value_of(WAL).map(PathBuf::from),
+        checkpoints: optional_value::<NonZeroU64>(arg_matches, CHECKPOINT_EVERY).map(
+            |every_rows| CheckpointPolicy {
+                every_rows,
+                directory: PathBuf::from("."),
+            },
+        ),
+        pipeline_depth: optional_value::<NonZeroUsize>(arg_matches, PIPELINE_DEPTH),
+        rates: match arg_matches.value_of(RATES) {
+            Some(rates_path) => RateTable::load(Path::new(rates_path))?,
+            None => RateTable::default(),
+        },
+        revaluation_path: arg_matches.value_of(REVALUATION).map(PathBuf::from),
+        eod_rates: match arg_matches.value_of(EOD_RATES) {
+            Some(rates_path) => RateTable::load(Path::new(rates_path))?,
+            None => RateTable::default(),
+        },
+        ..config
+    };
+    // A run on the in-memory datastore leaves no files behind but the outputs asked for, and
+    // a dry run leaves the state files alone, whatever it changes is kept in memory.
+    let config = if keeps_state_in_memory(arg_matches) {
+        without_local_files(config)
+    } else if arg_matches.is_present(DRY_RUN) {
+        config.without_state_files()
+    } else {
+        config
+    };
+    // The state files go back to the checkpoint before any of them is opened.
+    let checkpoint = if arg_matches.is_present(RESUME_FROM_CHECKPOINT) {
+        Some(Checkpoint::restore(Path::new("."))?)
+    } else {
+        None
+    };
+    let mut service = match &fork {
+        Some(fork) => create_forked_service(arg_matches, config, fork)?,
+        None => {
+            if let Some(wal_path) = &config.wal_path {
+                if !arg_matches.is_present(RESUME) {
+                    wal::WriteAheadLog::reset(wal_path)?;
+                }
+            }
+
+            create_service(arg_matches, config)?
+        }
+    };
+
+    if let Some(shadow_config) = arg_matches.value_of(SHADOW_CONFIG) {
+        service.enable_shadow(
+            ServiceConfig::load(Path::new(shadow_config))?,
+            PathBuf::from(shadow::SHADOW_REPORT_PATH),
+        );
+    }
+
+    let _config_watcher = match config_path {
+        Some(config_path) => {
+            let (updates, receiver) = channel();
+
+            service.watch_config(receiver);
+            Some(ConfigWatcher::start(config_path, updates)?)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "profiling")]
+    if arg_matches.is_present(PROFILING) {
+        profiling::enable();
+    }
+
+    #[cfg(feature = "tui")]
+    let dashboard = if arg_matches.is_present(TUI) {
+        let monitor = RunMonitor::default();
+
+        service.set_monitor(monitor.clone());
+        Some(Dashboard::start(monitor)?)
+    } else {
+        None
+    };
+
+    let processed = process_inputs(
+        &mut service,
+        arg_matches,
+        csv_path,
+        &csv_paths,
+        checkpoint.as_ref(),
+    );
+
+    // The terminal is given back before an error of the run is logged.
+    #[cfg(feature = "tui")]
+    let processed = match dashboard {
+        Some(dashboard) => processed.and(dashboard.finish()),
+        None => processed,
+    };
+
+    processed?;
+
+    if arg_matches.is_present(VERIFY_DETERMINISM) {
+        // The second run reads what the first did: the merged or sorted file if there is one.
+        match csv_path {
+            Some(csv_path) => determinism::verify(&service, &[csv_path])?,
+            None => determinism::verify(&service, &csv_paths)?,
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    profiling::finish(arg_matches.value_of(PROFILING_FOLDED).map(Path::new))?;
+
+    // The manifest goes next to the report, so it is only written when the report is a file.
+    if let Some(report_path) = arg_matches.value_of(OUTPUT).map(Path::new) {
+        let mut manifest = RunManifest::new(
+            &csv_paths,
+            service.config(),
+            service.run_counts(),
+            service.outputs(),
+        )?
+        .with_sources(service.source_counts());
+
+        for input in manifest.inputs.iter_mut() {
+            input.path = inputs.original(&input.path).to_string();
+        }
+
+        manifest.write(&report_path.with_file_name(manifest::MANIFEST_NAME))?;
+    }
+
+    Ok(())
+}
+
+fn process_inputs(
+    service: &mut PaymentService,
+    arg_matches: &ArgMatches,
+    csv_path: Option<&str>,
+    csv_paths: &[&str],
+    checkpoint: Option<&Checkpoint>,
+) -> PaymentEngineResult<()> {
+    let single_input = || csv_path.ok_or(PaymentEngineError::UnmergedInputFiles);
+
+    if arg_matches.is_present(TWO_PHASE) {
+        run_two_phase(service, single_input()?, arg_matches.is_present(APPROVE))
+    } else if arg_matches.is_present(ATOMIC) {
+        service.run_atomic(single_input()?)
+    } else if arg_matches.is_present(DRY_RUN) {
+        run_dry(service, single_input()?)
+    } else {
+        match (csv_path, checkpoint) {
+            (Some(csv_path), Some(checkpoint)) => {
+                service.resume_from_checkpoint(csv_path, checkpoint)?;
+                Checkpoint::clear(Path::new("."))
+            }
+            (None, Some(_)) => Err(PaymentEngineError::UnmergedInputFiles),
+            (Some(csv_path), None) => service.run(csv_path),
+            (None, None) => service.run_files(csv_paths),
+        }
+    }
+}
+
+/// Config file given with `--config`, or the default config.
+fn load_config(arg_matches: &ArgMatches) -> PaymentEngineResult<ServiceConfig> {
+    match arg_matches.value_of(CONFIG) {
+        Some(config_path) => ServiceConfig::load(Path::new(config_path)),
+        None => Ok(ServiceConfig::default()),
+    }
+}
+
+fn run_pending_command(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let config = with_local_files(load_config(arg_matches)?);
+    let mut service = create_continuing_service(arg_matches, config)?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+    let principal =
+        |matches: &ArgMatches| matches.value_of(PRINCIPAL).unwrap_or("unknown").to_string();
+
+    match arg_matches.subcommand() {
+        (PENDING_APPROVE, Some(approve_matches)) => {
+            let transaction_id = value_t_or_exit!(approve_matches, TRANSACTION_ID, u32);
+
+            match service.approve_pending(transaction_id, &principal(approve_matches))? {
+                Some(account) => writer.serialize(account)?,
+                None => info!(
+                    "Approval of {} waits for a second principal",
+                    transaction_id
+                ),
+            }
+        }
+        (PENDING_REJECT, Some(reject_matches)) => {
+            let transaction_id = value_t_or_exit!(reject_matches, TRANSACTION_ID, u32);
+
+            if !service.reject_pending(transaction_id, &principal(reject_matches))? {
+                info!(
+                    "Rejection of {} waits for a second principal",
+                    transaction_id
+                );
+            }
+        }
+        (PENDING_REQUESTS, Some(_)) => {
+            for request in service.approval_requests() {
+                println!("{}", serde_json::to_string(&request)?);
+            }
+        }
+        _ => {
+            for transaction in service.pending_transactions()? {
+                writer.serialize(transaction)?;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "search")]
+fn run_search(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let query = arg_matches
+        .value_of(QUERY)
+        .expect("Search query is required");
+    let limit = value_t_or_exit!(arg_matches, LIMIT, usize);
+    let path = arg_matches
+        .value_of(SEARCH_INDEX)
+        .expect("Search index has a default path");
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    for transaction in search::search_text(Path::new(path), query, limit)? {
+        writer.serialize(transaction)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+fn run_serve(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let service =
+        create_continuing_service(arg_matches, with_local_files(load_config(arg_matches)?))?;
+    let address = arg_matches
+        .value_of(LISTEN)
+        .expect("Listen address has a default");
+    let page_bytes = optional_value(arg_matches, PAGE_BYTES).unwrap_or(page::DEFAULT_PAGE_BYTES);
+    let mut server = ApiServer::bind(address, service, page_bytes)?;
+
+    if let Some(config_path) = arg_matches.value_of(CONFIG) {
+        server = server.with_config_file(PathBuf::from(config_path));
+    }
+
+    info!("Serving the REST API on {}", address);
+
+    server.run()
+}
+
+/// Applies the transactions of the Kafka topic to the stored state until consuming or storing
+/// fails. The offsets of a group only match state which is kept, so the pickle files are always
+/// continued.
+#[cfg(feature = "kafka")]
+fn run_kafka(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(load_config(arg_matches)?))?;
+    let options = KafkaOptions {
+        brokers: arg_matches
+            .value_of(KAFKA_BROKERS)
+            .expect("Kafka brokers have a default")
+            .to_string(),
+        topic: arg_matches
+            .value_of(KAFKA)
+            .expect("Kafka topic is given")
+            .to_string(),
+        group: arg_matches
+            .value_of(KAFKA_GROUP)
+            .expect("Kafka group has a default")
+            .to_string(),
+        properties: arg_matches
+            .values_of(KAFKA_PROPERTY)
+            .into_iter()
+            .flatten()
+            .filter_map(|property| property.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+    };
+
+    info!(
+        "Consuming {} from {} as group {}",
+        options.topic, options.brokers, options.group
+    );
+
+    KafkaInput::connect(&options)?.run(&mut service)
+}
+
+#[cfg(feature = "grpc")]
+fn run_serve_grpc(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let config = with_local_files(load_config(arg_matches)?);
+    let datastore = open_async_datastore(arg_matches)?;
+    let address = arg_matches
+        .value_of(LISTEN)
+        .expect("Listen address has a default");
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|source| PaymentEngineError::GrpcListen { source })?;
+
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|source| PaymentEngineError::GrpcListen { source })?;
+        let base_currency = config.base_currency.clone();
+        let engine = AsyncPaymentService::start(datastore, config);
+
+        info!("Serving gRPC on {}", address);
+
+        GrpcService::new(engine, base_currency)
+            .serve(listener)
+            .await
+    })
+}
+
+/// Datastore of `--event-store` or `--datastore` for the async service. The pickle files are
+/// always continued.
+#[cfg(feature = "grpc")]
+fn open_async_datastore(
+    arg_matches: &ArgMatches,
+) -> PaymentEngineResult<Arc<dyn AsyncDatastoreOperations>> {
+    Ok(
+        match (
+            arg_matches.value_of(EVENT_STORE),
+            arg_matches.value_of(DATASTORE),
+        ) {
+            (Some(path), _) => Arc::new(AsyncAdapter::new(open_event_store(
+                arg_matches,
+                Path::new(path),
+                None,
+            )?)),
+            (None, Some(MEMORY)) => Arc::new(AsyncAdapter::new(InMemoryDatastore::default())),
+            #[cfg(feature = "sqlite")]
+            (None, Some(SQLITE)) => Arc::new(AsyncAdapter::new(SqliteDatastore::open(Path::new(
+                sqlite::SQLITE_DB_PATH,
+            ))?)),
+            #[cfg(feature = "sled")]
+            (None, Some(SLED)) => Arc::new(AsyncAdapter::new(SledDatastore::open(Path::new(
+                sled_store::SLED_DB_PATH,
+            ))?)),
+            (None, _) => Arc::new(AsyncAdapter::new(PickleDatastore::resume())),
+        },
+    )
+}
+
+fn run_reservation_command(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let config_path = arg_matches
+        .subcommand_matches(RESERVATION_COMMIT)
+        .and_then(|commit_matches| commit_matches.value_of(CONFIG));
+    let config = match config_path {
+        Some(config_path) => with_local_files(ServiceConfig::load(Path::new(config_path))?),
+        None => with_local_files(ServiceConfig::default()),
+    };
+    let mut service = create_continuing_service(arg_matches, config)?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    match arg_matches.subcommand() {
+        (RESERVATION_CREATE, Some(create_matches)) => {
+            let client_id = value_t_or_exit!(create_matches, CLIENT, u16);
+            let amount = value_t_or_exit!(create_matches, AMOUNT, Decimal);
+            let ttl = optional_value(create_matches, TTL)
+                .unwrap_or(reservation::DEFAULT_RESERVATION_TTL_SECONDS);
+
+            writer.serialize(service.reserve(client_id, amount, Duration::seconds(ttl))?)?;
+        }
+        (RESERVATION_COMMIT, Some(commit_matches)) => {
+            let token = commit_matches.value_of(TOKEN).expect("Token is required");
+            let transaction_id = optional_value(commit_matches, TX);
+
+            writer.serialize(service.commit_reservation(token, transaction_id)?)?;
+        }
+        (RESERVATION_CANCEL, Some(cancel_matches)) => {
+            let token = cancel_matches.value_of(TOKEN).expect("Token is required");
+
+            service.cancel_reservation(token)?;
+        }
+        _ => {
+            for reservation in service.reservations() {
+                writer.serialize(reservation)?;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_legal_hold_command(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(ServiceConfig::default()))?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+    let principal =
+        |matches: &ArgMatches| matches.value_of(PRINCIPAL).unwrap_or("unknown").to_string();
+
+    match arg_matches.subcommand() {
+        (LEGAL_HOLD_PLACE, Some(place_matches)) => {
+            let client_id = value_t_or_exit!(place_matches, CLIENT, u16);
+            let reason = place_matches.value_of(REASON).expect("Reason is required");
+
+            writer.serialize(service.place_legal_hold(
+                client_id,
+                reason,
+                &principal(place_matches),
+            )?)?;
+        }
+        (LEGAL_HOLD_RELEASE, Some(release_matches)) => {
+            let client_id = value_t_or_exit!(release_matches, CLIENT, u16);
+
+            service.release_legal_hold(client_id, &principal(release_matches))?;
+        }
+        _ => {
+            for hold in service.legal_holds() {
+                writer.serialize(hold)?;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_unlock(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let client_id = value_t_or_exit!(arg_matches, CLIENT, u16);
+    let principal = arg_matches.value_of(PRINCIPAL).unwrap_or("unknown");
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(load_config(arg_matches)?))?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    writer.serialize(service.unlock_account(client_id, principal)?)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_erase_client(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let client_id = value_t_or_exit!(arg_matches, CLIENT, u16);
+    let principal = arg_matches.value_of(PRINCIPAL).unwrap_or("unknown");
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(ServiceConfig::default()))?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    writer.serialize(service.erase_client(client_id, principal)?)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_timers_command(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(ServiceConfig::default()))?;
+
+    match arg_matches.subcommand() {
+        (TIMERS_RUN, Some(_)) => {
+            info!("Executed {} timers", service.run_due_timers(Utc::now())?);
+        }
+        _ => {
+            for timer in service.timers() {
+                println!("{}", serde_json::to_string(&timer)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_jobs_command(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let mut queue = JobQueue::open(Path::new(scheduler::JOBS_DB_PATH));
+
+    match arg_matches.subcommand() {
+        (JOBS_SUBMIT, Some(submit_matches)) => {
+            let job = Job::new(
+                submit_matches.value_of(INPUT).expect("Input is required"),
+                submit_matches.value_of(EVENT_STORE),
+                submit_matches.value_of(OUTPUT),
+                optional_value(submit_matches, MAX_ATTEMPTS)
+                    .unwrap_or(scheduler::DEFAULT_MAX_ATTEMPTS),
+            )?;
+
+            println!("{}", queue.submit(job)?);
+        }
+        (JOBS_EXPORT, Some(export_matches)) => {
+            let event_store = export_matches
+                .value_of(EVENT_STORE)
+                .ok_or(PaymentEngineError::EventStoreRequired)?;
+            let job = Job::export(
+                event_store,
+                export_filter(export_matches),
+                export_matches.value_of(OUTPUT),
+                optional_value(export_matches, MAX_ATTEMPTS)
+                    .unwrap_or(scheduler::DEFAULT_MAX_ATTEMPTS),
+            );
+
+            println!("{}", queue.submit(job)?);
+        }
+        (JOBS_STATUS, Some(status_matches)) => {
+            let job_id = value_t_or_exit!(status_matches, JOB_ID, u64);
+            let poll = JobPoll::load(Path::new(scheduler::JOBS_DB_PATH), job_id)?;
+
+            println!("{}", serde_json::to_string(&poll)?);
+        }
+        (JOBS_RUN, Some(run_matches)) => {
+            let max_parallel = optional_value(run_matches, MAX_PARALLEL).unwrap_or(1);
+            let retry = RetryPolicy {
+                delay: Duration::seconds(
+                    optional_value(run_matches, RETRY_DELAY)
+                        .unwrap_or(scheduler::DEFAULT_RETRY_DELAY_SECONDS),
+                ),
+            };
+            let poll = std::time::Duration::from_secs(
+                optional_value(run_matches, POLL).unwrap_or(DEFAULT_POLL_SECONDS),
+            );
+
+            loop {
+                queue.run_due(max_parallel, retry, run_job)?;
+
+                if run_matches.is_present(ONCE) {
+                    break;
+                }
+
+                std::thread::sleep(poll);
+            }
+        }
+        _ => {
+            let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+            for job in queue.list() {
+                writer.serialize(job.summary())?;
+            }
+
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Batch run of a scheduled job, like a run from the command line with default options, or an
+/// export written in chunks.
+fn run_job(job: &Job) -> PaymentEngineResult<()> {
+    if let JobKind::Export { filter } = &job.kind {
+        let log_path = job
+            .event_store
+            .as_deref()
+            .ok_or(PaymentEngineError::EventStoreRequired)?;
+        let mut datastore = EventSourcedDatastore::open(Path::new(log_path), vec![])?;
+
+        info!("Running job {}, exporting {}", job.id, log_path);
+        let exported = export::export_to_file(
+            &mut datastore,
+            filter,
+            Path::new(&job.output),
+            export::EXPORT_CHUNK_SIZE,
+        )?;
+        info!("Job {} exported {} transactions", job.id, exported);
+
+        return Ok(());
+    }
+
+    let config = with_local_files(ServiceConfig {
+        report_path: Some(PathBuf::from(&job.output)),
+        ..ServiceConfig::default()
+    });
+    let datastore: Box<dyn DatastoreOperations> = match &job.event_store {
+        Some(path) => Box::new(EventSourcedDatastore::open(Path::new(path), vec![])?),
+        None => Box::new(PickleDatastore::new()),
+    };
+
+    info!("Running job {} on {}", job.id, job.input);
+    PaymentService::new(datastore, config).run(&job.input)
+}
+
+fn run_statement(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let client_id = value_t_or_exit!(arg_matches, CLIENT, u16);
+    let pdf_path = arg_matches.value_of(PDF).expect("PDF path is required");
+    let template = match arg_matches.value_of(TEMPLATE) {
+        Some(template_path) => StatementTemplate::load(Path::new(template_path))?,
+        None => StatementTemplate::default(),
+    };
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(ServiceConfig::default()))?;
+    let (account, transactions, disputes) = service.client_statement(client_id)?;
+
+    statement::write_statement_pdf(
+        Path::new(pdf_path),
+        &template,
+        &account,
+        &transactions,
+        &disputes,
+    )
+}
+
+fn run_normalize(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let profile_name = arg_matches.value_of(PROFILE).expect("Profile is required");
+    let profile = PartnerProfile::load(&PartnerProfile::path(profile_name))?;
+    let input_path = arg_matches
+        .value_of(CSV_INPUT_FILE)
+        .expect("CSV input file path is required");
+    let output_path = arg_matches
+        .value_of(CSV_OUTPUT_FILE)
+        .expect("CSV output file path is required");
+    let summary = profile::normalize_file(&profile, Path::new(input_path), Path::new(output_path))?;
+
+    info!(
+        "Normalized {} transactions, rejected {} rows",
+        summary.written, summary.rejected
+    );
+
+    Ok(())
+}
+
+fn run_quality(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let csv_paths = merge::expand_paths(
+        &arg_matches
+            .values_of(CSV_INPUT_FILE)
+            .expect("CSV input file path is required")
+            .collect::<Vec<_>>(),
+    )?;
+    let csv_paths: Vec<&str> = csv_paths.iter().map(String::as_str).collect();
+    let report = QualityReport::of_files(&csv_paths)?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+fn run_export_evidence(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let client_id = value_t_or_exit!(arg_matches, CLIENT, u16);
+    let transaction_id = value_t_or_exit!(arg_matches, TX, u32);
+    let output_path = arg_matches
+        .value_of(OUTPUT)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(format!("evidence-{}-{}.zip", client_id, transaction_id)));
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(ServiceConfig::default()))?;
+    let (account, transactions, _) = service.client_statement(client_id)?;
+    let transaction = transactions
+        .into_iter()
+        .find(|transaction| transaction.transaction_id == transaction_id)
+        .ok_or(PaymentEngineError::TransactionNotFound { transaction_id })?;
+    let mut evidence = Evidence::new(account, transaction, service.dispute_chain(transaction_id)?)?;
+
+    if let Some(inputs) = arg_matches.values_of(INPUTS) {
+        evidence.add_input_rows(&inputs.map(Path::new).collect::<Vec<&Path>>())?;
+    }
+    if let Some(log_path) = arg_matches.value_of(EVENT_STORE) {
+        evidence.add_ledger_postings(Path::new(log_path))?;
+    }
+    evidence.add_audit_entries(Path::new(audit::AUDIT_LOG_PATH))?;
+
+    let file = File::create(&output_path).map_err(zip::result::ZipError::from)?;
+    let digest = evidence.write_bundle(file)?;
+
+    println!("{} sha256:{}", output_path.display(), digest);
+
+    Ok(())
+}
+
+fn run_open_dispute(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let client_id = value_t_or_exit!(arg_matches, CLIENT, u16);
+    let transaction_id = value_t_or_exit!(arg_matches, TX, u32);
+    let evidence = DisputeEvidence {
+        reason_code: arg_matches.value_of(REASON).map(str::to_string),
+        documents: arg_matches
+            .values_of(DOCUMENT)
+            .map(|documents| documents.map(str::to_string).collect())
+            .unwrap_or_default(),
+    };
+    let mut service =
+        create_continuing_service(arg_matches, with_local_files(ServiceConfig::default()))?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    writer.serialize(service.open_dispute(client_id, transaction_id, evidence)?)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_dispute_chain(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let transaction_id = value_t_or_exit!(arg_matches, TRANSACTION_ID, u32);
+    let service =
+        create_continuing_service(arg_matches, with_local_files(ServiceConfig::default()))?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    for record in service.dispute_chain(transaction_id)? {
+        writer.serialize(record)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_migrate_backend(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let from = arg_matches
+        .value_of(FROM)
+        .expect("Source backend is required");
+    let to = arg_matches
+        .value_of(TO)
+        .expect("Target backend is required");
+
+    if from == to {
+        return Err(PaymentEngineError::SameMigrationBackend);
+    }
+
+    let summary = migrate::migrate(
+        open_migration_backend(from)?.as_mut(),
+        open_migration_backend(to)?.as_mut(),
+        Path::new(migrate::MIGRATION_PROGRESS_PATH),
+        from,
+        to,
+    )?;
+
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+
+    Ok(())
+}
+
+fn open_migration_backend(backend: &str) -> PaymentEngineResult<Box<dyn MigrationSource>> {
+    Ok(match backend {
+        #[cfg(feature = "sqlite")]
+        SQLITE => Box::new(SqliteDatastore::open(Path::new(sqlite::SQLITE_DB_PATH))?),
+        #[cfg(feature = "sled")]
+        SLED => Box::new(SledDatastore::open(Path::new(sled_store::SLED_DB_PATH))?),
+        _ => Box::new(PickleDatastore::resume()),
+    })
+}
+
+fn run_rebuild_accounts(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let history = datastore::read_stored_history(
+        Path::new(datastore::TRANSACTION_DB_PATH),
+        Path::new(datastore::DISPUTE_CHAINS_DB_PATH),
+    )?;
+    let accounts = PaymentService::rebuild_accounts(&history, &load_config(arg_matches)?)?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    info!(
+        "Rebuilt {} accounts from {} transactions",
+        accounts.len(),
+        history.len()
+    );
+
+    for account in &accounts {
+        writer.serialize(account)?;
+    }
+
+    writer.flush()?;
+
+    if let Some(snapshot_path) = arg_matches.value_of(SNAPSHOT) {
+        let snapshot = shard::read_account_report(Path::new(snapshot_path))?;
+        let mismatches = rebuild::compare_accounts(&accounts, &snapshot);
+
+        for mismatch in &mismatches {
+            warn!("Rebuilt account differs from snapshot: {:?}", mismatch);
+        }
+
+        if !mismatches.is_empty() {
+            return Err(PaymentEngineError::AccountsMismatch {
+                clients: mismatches.len(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn run_verify(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let log_path = Path::new(
+        arg_matches
+            .value_of(EVENT_STORE)
+            .ok_or(PaymentEngineError::EventStoreRequired)?,
+    );
+    let persisted = EventSourcedDatastore::open(log_path, vec![])?.retrieve_all_accounts()?;
+    let recomputed = PaymentService::rebuild_accounts(
+        &event_store::stored_history(log_path)?,
+        &load_config(arg_matches)?,
+    )?;
+    let mismatches = rebuild::compare_accounts(&recomputed, &persisted);
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    for mismatch in &mismatches {
+        writer.serialize(mismatch)?;
+    }
+
+    writer.flush()?;
+    info!(
+        "Verified {} accounts, {} mismatched",
+        persisted.len(),
+        mismatches.len()
+    );
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(PaymentEngineError::AccountsMismatch {
+            clients: mismatches.len(),
+        })
+    }
+}
+
+fn run_split(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let csv_path = Path::new(
+        arg_matches
+            .value_of(CSV_INPUT_FILE)
+            .expect("CSV input file path is required"),
+    );
+    let shards = value_t_or_exit!(arg_matches, SHARDS, usize);
+    let output_dir = match arg_matches.value_of(OUTPUT_DIR) {
+        Some(output_dir) => PathBuf::from(output_dir),
+        None => csv_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+
+    for path in shard::split_by_client(csv_path, shards, &output_dir)? {
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+fn run_merge(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let paths: Vec<&Path> = arg_matches
+        .values_of(INPUTS)
+        .expect("Merge inputs are required")
+        .map(Path::new)
+        .collect();
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    for account in shard::merge_shards(&paths)? {
+        writer.serialize(account)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_replay(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    if let Some(wal_path) = arg_matches.value_of(WAL) {
+        return replay_wal(arg_matches, Path::new(wal_path));
+    }
+
+    let log_path = arg_matches
+        .value_of(EVENT_STORE)
+        .ok_or(PaymentEngineError::EventStoreRequired)?;
+    let start_of_day = |name: &str, days: i64| {
+        start_of_day(value_t_or_exit!(arg_matches, name, NaiveDate) + Duration::days(days))
+    };
+    let snapshot = match arg_matches.value_of(SNAPSHOT) {
+        Some(snapshot_path) => shard::read_account_report(Path::new(snapshot_path))?,
+        None => vec![],
+    };
+    let accounts = event_store::replay_window(
+        Path::new(log_path),
+        start_of_day(FROM, 0),
+        start_of_day(TO, 1),
+        snapshot,
+    )?;
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    for account in accounts {
+        writer.serialize(account)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Applies the transactions of the write-ahead log to a new datastore and prints the accounts.
+fn replay_wal(arg_matches: &ArgMatches, wal_path: &Path) -> PaymentEngineResult<()> {
+    let config = match arg_matches.value_of(CONFIG) {
+        Some(config_path) => ServiceConfig::load(Path::new(config_path))?,
+        None => ServiceConfig::default(),
+    };
+    let transactions = wal::WriteAheadLog::read(wal_path)?;
+    let mut service = create_service(arg_matches, config)?;
+
+    if let Some(now) = optional_value::<DateTime<Utc>>(arg_matches, CLOCK) {
+        service.set_clock(Box::new(FixedClock::new(now)));
+    }
+
+    for transaction in &transactions {
+        if let Err(e) = service.process(transaction) {
+            warn!("{} | {:?}", e, transaction);
+        }
+    }
+
+    info!("Replayed {} transactions", transactions.len());
+
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+
+    for account in service.accounts()? {
+        writer.serialize(account)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn run_export(arg_matches: &ArgMatches) -> PaymentEngineResult<()> {
+    let log_path = arg_matches
+        .value_of(EVENT_STORE)
+        .ok_or(PaymentEngineError::EventStoreRequired)?;
+    let filter = export_filter(arg_matches);
+    let mut datastore = EventSourcedDatastore::open(Path::new(log_path), vec![])?;
+    let stdout = std::io::stdout();
+
+    if arg_matches.is_present(PAGE_BYTES) || arg_matches.is_present(AFTER) {
+        let after =
+            ContinuationToken::after(arg_matches.value_of(AFTER), PageResource::Transactions)?;
+        let budget = optional_value(arg_matches, PAGE_BYTES).unwrap_or(page::DEFAULT_PAGE_BYTES);
+        let summary =
+            page::write_transactions_page(stdout.lock(), &mut datastore, &filter, after, budget)?;
+
+        info!(
+            "Exported {} transactions in {} bytes",
+            summary.items, summary.bytes
+        );
+
+        return Ok(());
+    }
+
+    let exported = export::export_transactions(&mut datastore, &filter, stdout.lock())?;
+
+    info!("Exported {} transactions", exported);
+
+    Ok(())
+}
+
+fn export_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name(CLIENT).long(CLIENT).takes_value(true),
+        Arg::with_name(DISPUTED)
+            .long(DISPUTED)
+            .help("Only export transactions under dispute"),
+        Arg::with_name(FROM)
+            .long(FROM)
+            .takes_value(true)
+            .help("First day of the transaction timestamps, e.g. 2024-01-01"),
+        Arg::with_name(TO)
+            .long(TO)
+            .takes_value(true)
+            .help("Last day of the transaction timestamps, included"),
+    ]
+}
+
+fn export_filter(arg_matches: &ArgMatches) -> TransactionFilter {
+    TransactionFilter {
+        client_id: optional_value(arg_matches, CLIENT),
+        disputed: Some(true).filter(|_| arg_matches.is_present(DISPUTED)),
+        ..TransactionFilter::days(
+            optional_value(arg_matches, FROM),
+            optional_value(arg_matches, TO),
+        )
+    }
+}
+
+fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).expect("Midnight is a valid time"))
+}
+
+/// Adds the paths of the files the service keeps in the working directory.
+fn with_local_files(config: ServiceConfig) -> ServiceConfig {
+    with_files_in(config, Path::new(""))
+}
+
+/// Leaves out the state files and default side reports of `with_local_files`, so only the
+/// outputs given on the command line are written.
+fn without_local_files(config: ServiceConfig) -> ServiceConfig {
+    ServiceConfig {
+        rounding_drift_path: None,
+        risk_report_path: None,
+        balance_anomalies_path: None,
+        ..config.without_state_files()
+    }
+}
+
+fn keeps_state_in_memory(arg_matches: &ArgMatches) -> bool {
+    arg_matches.value_of(EVENT_STORE).is_none() && arg_matches.value_of(DATASTORE) == Some(MEMORY)
+}
+
+fn with_files_in(config: ServiceConfig, directory: &Path) -> ServiceConfig {
+    ServiceConfig {
+        audit_log_path: Some(directory.join(audit::AUDIT_LOG_PATH)),
+        reservations_path: Some(directory.join(reservation::RESERVATIONS_DB_PATH)),
+        legal_holds_path: Some(directory.join(erasure::LEGAL_HOLDS_DB_PATH)),
+        locked_queue_path: Some(directory.join(locked::LOCKED_QUEUE_DB_PATH)),
+        ids_path: Some(directory.join(ids::IDS_DB_PATH)),
+        timers_path: Some(directory.join(timers::TIMERS_DB_PATH)),
+        approvals_path: Some(directory.join(approvals::APPROVALS_DB_PATH)),
+        rounding_drift_path: Some(directory.join(rounding::ROUNDING_DRIFT_PATH)),
+        risk_report_path: Some(directory.join(risk::RISK_REPORT_PATH)),
+        balance_history_path: Some(directory.join(anomaly::BALANCE_HISTORY_PATH)),
+        balance_anomalies_path: Some(directory.join(anomaly::BALANCE_ANOMALIES_PATH)),
+        ..config
+    }
+}
+
+fn create_service(
+    arg_matches: &ArgMatches,
+    config: ServiceConfig,
+) -> PaymentEngineResult<Box<PaymentService>> {
+    let datastore: Box<dyn DatastoreOperations> = match (
+        arg_matches.value_of(EVENT_STORE),
+        arg_matches.value_of(DATASTORE),
+    ) {
+        (Some(path), _) => Box::new(open_event_store(arg_matches, Path::new(path), None)?),
+        (None, Some(MEMORY)) => Box::new(InMemoryDatastore::default()),
+        #[cfg(feature = "sqlite")]
+        (None, Some(SQLITE)) => Box::new(SqliteDatastore::open(Path::new(sqlite::SQLITE_DB_PATH))?),
+        #[cfg(feature = "sled")]
+        (None, Some(SLED)) => Box::new(SledDatastore::open(Path::new(sled_store::SLED_DB_PATH))?),
+        (None, _)
+            if arg_matches.is_present(RESUME) || arg_matches.is_present(RESUME_FROM_CHECKPOINT) =>
+        {
+            Box::new(PickleDatastore::resume())
         }
+        // A dry run from empty state has nothing to read and must not replace the files.
+        (None, _) if arg_matches.is_present(DRY_RUN) => Box::new(InMemoryDatastore::default()),
+        (None, _) => Box::new(PickleDatastore::new()),
+    };
+
+    Ok(PaymentService::new(datastore, config))
+}
+
+/// Service which always continues the files of the pickle datastore, for commands working on
+/// the data stored by earlier runs.
+fn create_continuing_service(
+    arg_matches: &ArgMatches,
+    config: ServiceConfig,
+) -> PaymentEngineResult<Box<PaymentService>> {
+    match (
+        arg_matches.value_of(EVENT_STORE),
+        arg_matches.value_of(DATASTORE),
+    ) {
+        (None, Some(PICKLE)) => Ok(PaymentService::new(
+            Box::new(PickleDatastore::resume()),
+            config,
+        )),
+        _ => create_service(arg_matches, config),
+    }
+}
+
+/// Service continuing from the forked copy of the state, which all its files are written to.
+/// Deliveries are left out, since they would hand the rehearsed report to partners.
+fn create_forked_service(
+    arg_matches: &ArgMatches,
+    config: ServiceConfig,
+    fork: &ForkedState,
+) -> PaymentEngineResult<Box<PaymentService>> {
+    let datastore: Box<dyn DatastoreOperations> = match (
+        arg_matches.value_of(EVENT_STORE),
+        arg_matches.value_of(DATASTORE),
+    ) {
+        (Some(path), _) => Box::new(open_event_store(
+            arg_matches,
+            &fork.include(Path::new(path))?,
+            Some(fork),
+        )?),
+        (None, Some(MEMORY)) => Box::new(InMemoryDatastore::default()),
+        #[cfg(feature = "sqlite")]
+        (None, Some(SQLITE)) => Box::new(SqliteDatastore::open(
+            &fork.path().join(sqlite::SQLITE_DB_PATH),
+        )?),
+        #[cfg(feature = "sled")]
+        (None, Some(SLED)) => Box::new(SledDatastore::open(
+            &fork.path().join(sled_store::SLED_DB_PATH),
+        )?),
+        (None, _) => Box::new(PickleDatastore::open(fork.path(), true)),
+    };
+    let wal_path = match &config.wal_path {
+        Some(wal_path) => Some(fork.include(wal_path)?),
+        None => None,
+    };
+    let checkpoints = config.checkpoints.clone().map(|policy| CheckpointPolicy {
+        directory: fork.path().to_path_buf(),
+        ..policy
+    });
+    let config = ServiceConfig {
+        wal_path,
+        checkpoints,
+        deliveries: vec![],
+        ..with_files_in(config, fork.path())
+    };
+
+    Ok(PaymentService::new(datastore, config))
+}
+
+#[cfg_attr(not(feature = "search"), allow(unused_variables, unused_mut))]
+fn open_event_store(
+    arg_matches: &ArgMatches,
+    path: &Path,
+    fork: Option<&ForkedState>,
+) -> PaymentEngineResult<EventSourcedDatastore> {
+    let aggregates = AggregatesProjection::default();
+    let aggregates_view = aggregates.view();
+    let mut projections: Vec<Box<dyn Projection>> = vec![Box::new(aggregates)];
+
+    #[cfg(feature = "search")]
+    if let Some(search_index) = arg_matches.value_of(SEARCH_INDEX) {
+        let search_index = match fork {
+            Some(fork) => fork.include(Path::new(search_index))?,
+            None => PathBuf::from(search_index),
+        };
+
+        projections.push(Box::new(search::SearchProjection::open(&search_index)?));
+    }
+
+    let datastore = EventSourcedDatastore::open(path, projections)?;
+
+    datastore.projection_handle().sync();
+    info!(
+        "Event store loaded with {}",
+        aggregates_view
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    );
+
+    Ok(datastore)
+}
+
+fn run_two_phase(
+    service: &mut PaymentService,
+    csv_path: &str,
+    approved: bool,
+) -> PaymentEngineResult<()> {
+    let impact = service.stage(csv_path)?;
+
+    eprintln!("{}", impact);
+
+    if approved || confirm("Apply staged batch? [y/N] ") {
+        service.apply_staged()
+    } else {
+        info!("Staged batch discarded");
+        service.discard_staged();
+
+        Ok(())
+    }
+}
+
+/// Prints the impact the file would have and fails when any of its rows would be rejected.
+fn run_dry(service: &mut PaymentService, csv_path: &str) -> PaymentEngineResult<()> {
+    let impact = service.dry_run(csv_path)?;
+
+    eprintln!("{}", impact);
+
+    match service.run_counts().rejected {
+        0 => Ok(()),
+        failed => Err(PaymentEngineError::BatchRejected {
+            failed: failed as usize,
+        }),
+    }
+}
+
+fn confirm(prompt: &str) -> bool {
+    eprint!("{}", prompt);
+
+    let mut answer = String::new();
+
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+fn optional_value<T>(arg_matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if arg_matches.is_present(name) {
+        Some(value_t!(arg_matches, name, T).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
     }
 }
@@ -0,0 +1,175 @@
+use crate::config::ServiceConfig;
+use crate::error::PaymentEngineResult;
+use crate::sink;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+const STDIN_PATH: &str = "-";
+
+/// Rows of the input files of a run by outcome. Rows which cannot be read count as rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunCounts {
+    pub rows: u64,
+    pub applied: u64,
+    pub rejected: u64,
+    pub parked: u64,
+}
+
+/// Rows of one input source by outcome, so rejects can be traced to the member of an archive
+/// or the file they came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceCounts {
+    pub source: String,
+    #[serde(flatten)]
+    pub counts: RunCounts,
+}
+
+/// File and the SHA-256 of its content. Standard input cannot be read again, so it has none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileDigest {
+    pub path: String,
+    pub sha256: Option<String>,
+}
+
+/// What a run read, with which settings, and what it wrote, so consumers can check that the
+/// outputs they pick up belong to the inputs they expect.
+#[derive(Debug, Serialize)]
+pub struct RunManifest {
+    pub engine_version: &'static str,
+    pub created_at: DateTime<Utc>,
+    pub inputs: Vec<FileDigest>,
+    pub config_sha256: String,
+    pub config: ServiceConfig,
+    pub counts: RunCounts,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceCounts>,
+    pub outputs: Vec<FileDigest>,
+}
+
+impl RunCounts {
+    /// Rows counted since `earlier`, a copy of these counts taken before.
+    pub fn since(&self, earlier: RunCounts) -> RunCounts {
+        RunCounts {
+            rows: self.rows - earlier.rows,
+            applied: self.applied - earlier.applied,
+            rejected: self.rejected - earlier.rejected,
+            parked: self.parked - earlier.parked,
+        }
+    }
+}
+
+impl FileDigest {
+    pub fn of(path: &Path) -> PaymentEngineResult<Self> {
+        let sha256 = if path == Path::new(STDIN_PATH) {
+            None
+        } else {
+            let mut hasher = Sha256::new();
+
+            std::io::copy(&mut File::open(path)?, &mut hasher)?;
+
+            Some(format!("{:x}", hasher.finalize()))
+        };
+
+        Ok(FileDigest {
+            path: path.to_string_lossy().into_owned(),
+            sha256,
+        })
+    }
+}
+
+impl RunManifest {
+    pub fn new(
+        inputs: &[&str],
+        config: &ServiceConfig,
+        counts: RunCounts,
+        outputs: &[PathBuf],
+    ) -> PaymentEngineResult<Self> {
+        Ok(RunManifest {
+            engine_version: env!("CARGO_PKG_VERSION"),
+            created_at: Utc::now(),
+            inputs: inputs
+                .iter()
+                .map(|input| FileDigest::of(Path::new(input)))
+                .collect::<PaymentEngineResult<_>>()?,
+            config_sha256: format!("{:x}", Sha256::digest(serde_json::to_vec(config)?)),
+            config: config.clone(),
+            counts,
+            sources: Vec::new(),
+            outputs: outputs
+                .iter()
+                .map(|output| FileDigest::of(output))
+                .collect::<PaymentEngineResult<_>>()?,
+        })
+    }
+
+    /// Manifest with the counts of every input source, in processing order.
+    pub fn with_sources(mut self, sources: &[SourceCounts]) -> Self {
+        self.sources = sources.to_vec();
+        self
+    }
+
+    /// Writes the manifest as pretty-printed JSON, replacing an earlier one atomically.
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |sink| {
+            serde_json::to_writer_pretty(&mut *sink, self)?;
+            writeln!(sink)?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::manifest::{RunCounts, RunManifest};
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_describe_inputs_config_and_outputs() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let output = directory.path().join("accounts.csv");
+        let path = directory.path().join("manifest.json");
+
+        std::fs::write(&input, "abc").unwrap();
+        std::fs::write(&output, "").unwrap();
+
+        let counts = RunCounts {
+            rows: 3,
+            applied: 2,
+            rejected: 1,
+            parked: 0,
+        };
+        let manifest = RunManifest::new(
+            &[input.to_str().unwrap(), "-"],
+            &ServiceConfig::default(),
+            counts,
+            &[output],
+        )
+        .unwrap();
+
+        manifest.write(&path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(
+            json["inputs"][0]["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(json["inputs"][1]["sha256"].is_null());
+        assert_eq!(
+            json["outputs"][0]["sha256"],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(json["counts"]["rejected"], 1);
+        assert_eq!(json["config"]["rounding"]["output_decimals"], 4);
+        assert_eq!(json["config_sha256"].as_str().unwrap().len(), 64);
+    }
+}
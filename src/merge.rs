@@ -0,0 +1,448 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{Provenance, PROVENANCE_COLUMN};
+use chrono::{DateTime, Utc};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+const TIMESTAMP_COLUMN: &str = "timestamp";
+const TRANSACTION_ID_COLUMNS: [&str; 2] = ["tx", "transaction_id"];
+const TYPE_COLUMN: &str = "type";
+const SORT_CHUNK_ROWS: usize = 500_000;
+const GLOB_CHARACTERS: [char; 3] = ['*', '?', '['];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    TransactionId,
+    Timestamp,
+}
+
+/// Ordering key of a single CSV row. Rows referencing a transaction (disputes, resolves and
+/// chargebacks) share the id of the referenced transaction, so they are ordered after it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum RecordKey {
+    TransactionId {
+        transaction_id: u32,
+        reference: bool,
+    },
+    Timestamp(DateTime<Utc>),
+}
+
+struct KeyColumns {
+    sort_key: SortKey,
+    key_index: usize,
+    type_index: Option<usize>,
+}
+
+struct MergeInput {
+    reader: Reader<File>,
+    columns: KeyColumns,
+    last_key: Option<RecordKey>,
+    source: Option<Arc<str>>,
+}
+
+impl SortKey {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "tx" => Some(SortKey::TransactionId),
+            "timestamp" => Some(SortKey::Timestamp),
+            _ => None,
+        }
+    }
+}
+
+impl KeyColumns {
+    fn new(headers: &StringRecord, sort_key: SortKey) -> PaymentEngineResult<Self> {
+        let key_index = match sort_key {
+            SortKey::TransactionId => headers
+                .iter()
+                .position(|h| TRANSACTION_ID_COLUMNS.contains(&h)),
+            SortKey::Timestamp => headers.iter().position(|h| h == TIMESTAMP_COLUMN),
+        };
+
+        match key_index {
+            Some(key_index) => Ok(KeyColumns {
+                sort_key,
+                key_index,
+                type_index: headers.iter().position(|h| h == TYPE_COLUMN),
+            }),
+            None => Err(PaymentEngineError::MissingSortColumn),
+        }
+    }
+
+    fn record_key(&self, record: &StringRecord) -> PaymentEngineResult<RecordKey> {
+        let key_text = record.get(self.key_index).unwrap_or_default();
+
+        match self.sort_key {
+            SortKey::TransactionId => {
+                let transaction_id = match key_text.parse::<u32>() {
+                    Ok(transaction_id) => transaction_id,
+                    Err(_) => return Err(PaymentEngineError::InvalidSortValue),
+                };
+                let reference = match self.type_index.and_then(|index| record.get(index)) {
+                    Some(type_text) => {
+                        !matches!(type_text.to_lowercase().as_str(), "deposit" | "withdrawal")
+                    }
+                    None => false,
+                };
+
+                Ok(RecordKey::TransactionId {
+                    transaction_id,
+                    reference,
+                })
+            }
+            SortKey::Timestamp => Ok(RecordKey::Timestamp(parse_timestamp(key_text)?)),
+        }
+    }
+}
+
+/// Input paths with every glob pattern, e.g. `data/*.csv`, replaced by the files it matches in
+/// alphabetical order. A pattern matching no file is an error, so a missing day is noticed.
+pub fn expand_paths(patterns: &[&str]) -> PaymentEngineResult<Vec<String>> {
+    let mut paths = vec![];
+
+    for pattern in patterns {
+        if !pattern.contains(GLOB_CHARACTERS) {
+            paths.push(pattern.to_string());
+            continue;
+        }
+
+        let invalid = || PaymentEngineError::InvalidInputPattern {
+            pattern: pattern.to_string(),
+        };
+        let matched = glob::glob(pattern)
+            .map_err(|_| invalid())?
+            .filter_map(Result::ok)
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        if matched.is_empty() {
+            return Err(invalid());
+        }
+
+        paths.extend(matched);
+    }
+
+    Ok(paths)
+}
+
+/// K-way merges CSV files which are each sorted by the `timestamp` column into a single
+/// temporary CSV file, so transactions from overlapping files are applied in chronological
+/// order. Only one row per input is held in memory at a time.
+pub fn merge_by_timestamp(csv_paths: &[&str]) -> PaymentEngineResult<NamedTempFile> {
+    let paths: Vec<&Path> = csv_paths.iter().map(Path::new).collect();
+
+    merge_sorted(&paths, SortKey::Timestamp)
+}
+
+/// Sorts CSV files of any size with bounded memory. Rows are sorted in chunks which are spilled
+/// to temporary files and then k-way merged into a single temporary CSV file.
+pub fn sort_by(csv_paths: &[&str], sort_key: SortKey) -> PaymentEngineResult<NamedTempFile> {
+    sort_in_chunks(csv_paths, sort_key, SORT_CHUNK_ROWS)
+}
+
+fn sort_in_chunks(
+    csv_paths: &[&str],
+    sort_key: SortKey,
+    chunk_rows: usize,
+) -> PaymentEngineResult<NamedTempFile> {
+    let mut headers: Option<StringRecord> = None;
+    let mut runs = Vec::new();
+    let mut chunk = Vec::with_capacity(chunk_rows);
+
+    for csv_path in csv_paths {
+        let mut reader = open_reader(Path::new(csv_path))?;
+        let input_headers = check_headers(&mut headers, &mut reader)?;
+        let columns = KeyColumns::new(&input_headers, sort_key)?;
+        let source = provenance_source(Path::new(csv_path), &input_headers);
+        let run_headers = annotated_headers(&input_headers, &source);
+
+        for record in reader.records() {
+            let mut record = record?;
+            let key = columns.record_key(&record)?;
+
+            annotate(&mut record, &source);
+            chunk.push((key, record));
+
+            if chunk.len() == chunk_rows {
+                runs.push(write_run(&run_headers, &mut chunk)?);
+            }
+        }
+    }
+
+    let headers = headers
+        .map(|headers| {
+            let source = csv_paths
+                .first()
+                .and_then(|path| provenance_source(Path::new(path), &headers));
+
+            annotated_headers(&headers, &source)
+        })
+        .unwrap_or_default();
+
+    if !chunk.is_empty() || runs.is_empty() {
+        runs.push(write_run(&headers, &mut chunk)?);
+    }
+
+    let run_paths: Vec<&Path> = runs.iter().map(|run| run.path()).collect();
+
+    merge_sorted(&run_paths, sort_key)
+}
+
+fn write_run(
+    headers: &StringRecord,
+    chunk: &mut Vec<(RecordKey, StringRecord)>,
+) -> PaymentEngineResult<NamedTempFile> {
+    chunk.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let run = NamedTempFile::new()?;
+    let mut writer = WriterBuilder::new().from_writer(run.reopen()?);
+
+    writer.write_record(headers)?;
+
+    for (_, record) in chunk.drain(..) {
+        writer.write_record(&record)?;
+    }
+
+    writer.flush()?;
+
+    Ok(run)
+}
+
+fn merge_sorted(paths: &[&Path], sort_key: SortKey) -> PaymentEngineResult<NamedTempFile> {
+    let mut inputs = Vec::with_capacity(paths.len());
+    let mut headers: Option<StringRecord> = None;
+
+    for path in paths {
+        let mut reader = open_reader(path)?;
+        let input_headers = check_headers(&mut headers, &mut reader)?;
+
+        inputs.push(MergeInput {
+            reader,
+            columns: KeyColumns::new(&input_headers, sort_key)?,
+            last_key: None,
+            source: provenance_source(path, &input_headers),
+        });
+    }
+
+    let merged_file = NamedTempFile::new()?;
+    let mut writer = WriterBuilder::new().from_writer(merged_file.reopen()?);
+
+    if let (Some(headers), Some(input)) = (&headers, inputs.first()) {
+        writer.write_record(&annotated_headers(headers, &input.source))?;
+    }
+
+    let mut heap = BinaryHeap::new();
+    let mut pending_records = Vec::with_capacity(inputs.len());
+
+    for (input_index, input) in inputs.iter_mut().enumerate() {
+        match next_record(input)? {
+            Some((key, record)) => {
+                heap.push(Reverse((key, input_index)));
+                pending_records.push(Some(record));
+            }
+            None => pending_records.push(None),
+        }
+    }
+
+    while let Some(Reverse((_, input_index))) = heap.pop() {
+        if let Some(record) = pending_records[input_index].take() {
+            writer.write_record(&record)?;
+        }
+
+        if let Some((key, record)) = next_record(&mut inputs[input_index])? {
+            heap.push(Reverse((key, input_index)));
+            pending_records[input_index] = Some(record);
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(merged_file)
+}
+
+fn open_reader(path: &Path) -> PaymentEngineResult<Reader<File>> {
+    Ok(ReaderBuilder::new()
+        .has_headers(true)
+        .trim(Trim::All)
+        .from_path(path)?)
+}
+
+fn check_headers(
+    headers: &mut Option<StringRecord>,
+    reader: &mut Reader<File>,
+) -> PaymentEngineResult<StringRecord> {
+    let input_headers = reader.headers()?.clone();
+
+    match headers {
+        Some(headers) if *headers != input_headers => Err(PaymentEngineError::MergeHeaderMismatch),
+        Some(_) => Ok(input_headers),
+        None => {
+            *headers = Some(input_headers.clone());
+            Ok(input_headers)
+        }
+    }
+}
+
+fn next_record(input: &mut MergeInput) -> PaymentEngineResult<Option<(RecordKey, StringRecord)>> {
+    let mut record = StringRecord::new();
+
+    if !input.reader.read_record(&mut record)? {
+        return Ok(None);
+    }
+
+    let key = input.columns.record_key(&record)?;
+
+    if let Some(last_key) = &input.last_key {
+        if key < *last_key {
+            return Err(PaymentEngineError::UnsortedMergeInput);
+        }
+    }
+    input.last_key = Some(key.clone());
+    annotate(&mut record, &input.source);
+
+    Ok(Some((key, record)))
+}
+
+/// Path recorded as the provenance of the rows of an input file, `None` when its rows carry
+/// their provenance already, like the runs of a sort.
+fn provenance_source(path: &Path, headers: &StringRecord) -> Option<Arc<str>> {
+    if headers.iter().any(|h| h == PROVENANCE_COLUMN) {
+        None
+    } else {
+        Some(Arc::from(path.to_string_lossy().as_ref()))
+    }
+}
+
+fn annotated_headers(headers: &StringRecord, source: &Option<Arc<str>>) -> StringRecord {
+    let mut headers = headers.clone();
+
+    if source.is_some() {
+        headers.push_field(PROVENANCE_COLUMN);
+    }
+
+    headers
+}
+
+/// Appends the file and line of a row as its provenance, so it survives merging and sorting.
+fn annotate(record: &mut StringRecord, source: &Option<Arc<str>>) {
+    if let Some(path) = source {
+        let line = record.position().map_or(0, |p| p.line());
+
+        record.push_field(
+            &Provenance::File {
+                path: path.clone(),
+                line,
+            }
+            .to_string(),
+        );
+    }
+}
+
+pub fn parse_timestamp(text: &str) -> PaymentEngineResult<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(text) {
+        Ok(timestamp) => Ok(timestamp.with_timezone(&Utc)),
+        Err(_) => Err(PaymentEngineError::InvalidTimestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merge::{expand_paths, merge_by_timestamp, sort_in_chunks, SortKey};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn csv_file(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn column(file: &NamedTempFile, index: usize) -> Vec<String> {
+        std::fs::read_to_string(file.path())
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(index).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    pub fn should_merge_files_by_timestamp() {
+        let first = csv_file(
+            "type, client, tx, amount, timestamp\n\
+             deposit, 1, 1, 10, 2024-01-01T10:00:00Z\n\
+             withdrawal, 1, 3, 5, 2024-01-01T12:00:00Z\n",
+        );
+        let second = csv_file(
+            "type, client, tx, amount, timestamp\n\
+             deposit, 2, 2, 20, 2024-01-01T11:00:00Z\n\
+             dispute, 1, 1, , 2024-01-01T13:00:00Z\n",
+        );
+
+        let merged = merge_by_timestamp(&[
+            first.path().to_str().unwrap(),
+            second.path().to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(column(&merged, 2), vec!["1", "2", "3", "1"]);
+        assert_eq!(
+            column(&merged, 5)[3],
+            format!("file:{}:3", second.path().display())
+        );
+    }
+
+    #[test]
+    pub fn should_reject_unsorted_input() {
+        let input = csv_file(
+            "type, client, tx, amount, timestamp\n\
+             deposit, 1, 1, 10, 2024-01-02T10:00:00Z\n\
+             deposit, 1, 2, 10, 2024-01-01T10:00:00Z\n",
+        );
+
+        assert!(merge_by_timestamp(&[input.path().to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    pub fn should_sort_by_transaction_id_with_spilled_chunks() {
+        let input = csv_file(
+            "type, client, tx, amount\n\
+             dispute, 1, 4,\n\
+             resolve, 1, 4,\n\
+             deposit, 1, 5, 10\n\
+             deposit, 1, 4, 10\n\
+             deposit, 1, 1, 10\n",
+        );
+
+        let sorted =
+            sort_in_chunks(&[input.path().to_str().unwrap()], SortKey::TransactionId, 2).unwrap();
+
+        assert_eq!(column(&sorted, 2), vec!["1", "4", "4", "4", "5"]);
+        assert_eq!(
+            column(&sorted, 0),
+            vec!["deposit", "deposit", "dispute", "resolve", "deposit"]
+        );
+    }
+
+    #[test]
+    pub fn should_expand_glob_patterns_in_order() {
+        let directory = tempfile::TempDir::new().unwrap();
+
+        for name in ["day2.csv", "day1.csv", "notes.txt"] {
+            std::fs::write(directory.path().join(name), "").unwrap();
+        }
+
+        let pattern = format!("{}/*.csv", directory.path().display());
+        let paths = expand_paths(&["first.csv", &pattern]).unwrap();
+
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[0], "first.csv");
+        assert!(paths[1].ends_with("day1.csv") && paths[2].ends_with("day2.csv"));
+        assert!(expand_paths(&["missing/*.csv"]).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{DisputeRecord, Transaction};
+use crate::sink;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Progress of the running migration, kept in the working directory until it is verified.
+pub const MIGRATION_PROGRESS_PATH: &str = "pe_migration.json";
+/// Records copied between two progress updates.
+const BATCH_SIZE: usize = 1_000;
+/// Mismatches logged one by one by the verification pass, the rest are only counted.
+const MISMATCHES_LOGGED: usize = 10;
+
+/// Datastore whose whole content can be read back, so it can be the source of a migration.
+pub trait MigrationSource: DatastoreOperations {
+    /// Every stored transaction, in any order.
+    fn all_transactions(&mut self) -> PaymentEngineResult<Vec<Transaction>>;
+    /// Every step of every dispute chain, in any order.
+    fn all_dispute_records(&mut self) -> PaymentEngineResult<Vec<DisputeRecord>>;
+}
+
+/// Records copied so far by a migration between two backends. The records are copied in id
+/// order and writes replace records with the same id, so an interrupted migration continues
+/// after the last saved counts and rewrites at most one batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationProgress {
+    pub from: String,
+    pub to: String,
+    pub transactions: usize,
+    pub pending: usize,
+    pub accounts: usize,
+}
+
+/// Content of a datastore checked by the verification pass: record counts and the aggregate
+/// balances over all accounts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MigrationSummary {
+    pub transactions: usize,
+    pub pending: usize,
+    pub accounts: usize,
+    pub dispute_records: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl MigrationProgress {
+    /// Progress saved at `path` by an earlier run of the same migration, or a new one.
+    fn load(path: &Path, from: &str, to: &str) -> PaymentEngineResult<Self> {
+        if path.exists() {
+            let progress: MigrationProgress = serde_json::from_slice(
+                &std::fs::read(path)
+                    .map_err(|source| PaymentEngineError::MigrationProgress { source })?,
+            )?;
+
+            if progress.from == from && progress.to == to {
+                info!("Resuming migration from {} to {}", from, to);
+                return Ok(progress);
+            }
+        }
+
+        Ok(MigrationProgress {
+            from: from.to_string(),
+            to: to.to_string(),
+            ..MigrationProgress::default()
+        })
+    }
+
+    fn save(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |file| {
+            file.write_all(&serde_json::to_vec(self)?)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl MigrationSummary {
+    fn of(datastore: &mut dyn MigrationSource) -> PaymentEngineResult<Self> {
+        let accounts = datastore.retrieve_all_accounts()?;
+
+        Ok(MigrationSummary {
+            transactions: datastore.all_transactions()?.len(),
+            pending: datastore.retrieve_pending_transactions()?.len(),
+            accounts: accounts.len(),
+            dispute_records: datastore.all_dispute_records()?.len(),
+            available: accounts.iter().map(|account| account.available).sum(),
+            held: accounts.iter().map(|account| account.held).sum(),
+            total: accounts.iter().map(|account| account.total).sum(),
+        })
+    }
+}
+
+/// Copies transactions with their disputed flag, pending transactions, accounts and dispute
+/// chains from `source` to `target`, then verifies every record. `from` and `to` name the
+/// backends, so progress saved at `progress_path` is only resumed by the same migration; it is
+/// removed once the target is verified.
+pub fn migrate(
+    source: &mut dyn MigrationSource,
+    target: &mut dyn MigrationSource,
+    progress_path: &Path,
+    from: &str,
+    to: &str,
+) -> PaymentEngineResult<MigrationSummary> {
+    let mut progress = MigrationProgress::load(progress_path, from, to)?;
+    let mut transactions = source.all_transactions()?;
+    let mut pending = source.retrieve_pending_transactions()?;
+    let mut accounts = source.retrieve_all_accounts()?;
+
+    transactions.sort_by_key(|transaction| transaction.transaction_id);
+    pending.sort_by_key(|transaction| transaction.transaction_id);
+    accounts.sort_by_key(|account| account.client_id);
+
+    copy_in_batches(
+        "transactions",
+        &transactions,
+        progress.transactions,
+        target,
+        copy_transaction,
+        |copied| {
+            progress.transactions = copied;
+            progress.save(progress_path)
+        },
+    )?;
+    copy_in_batches(
+        "pending transactions",
+        &pending,
+        progress.pending,
+        target,
+        |target, transaction| target.save_pending_transaction(transaction.clone()),
+        |copied| {
+            progress.pending = copied;
+            progress.save(progress_path)
+        },
+    )?;
+    copy_in_batches(
+        "accounts",
+        &accounts,
+        progress.accounts,
+        target,
+        |target, account| target.save_account(account.clone()),
+        |copied| {
+            progress.accounts = copied;
+            progress.save(progress_path)
+        },
+    )?;
+
+    let chains = dispute_chains(source.all_dispute_records()?);
+
+    for (transaction_id, chain) in &chains {
+        // Dispute steps are appended, so the ones an interrupted run copied are skipped.
+        let copied = target.retrieve_dispute_chain(*transaction_id)?.len();
+
+        for record in chain.iter().skip(copied) {
+            target.save_dispute_record(record.clone())?;
+        }
+    }
+
+    target.flush()?;
+    info!("Copied {} dispute chains, verifying", chains.len());
+
+    let summary = verify(source, target)?;
+
+    if progress_path.exists() {
+        std::fs::remove_file(progress_path)
+            .map_err(|source| PaymentEngineError::MigrationProgress { source })?;
+    }
+
+    Ok(summary)
+}
+
+/// Copies the records after the `copied` ones, saving the progress after every batch once the
+/// target has flushed it.
+fn copy_in_batches<T>(
+    kind: &str,
+    records: &[T],
+    copied: usize,
+    target: &mut dyn MigrationSource,
+    copy: impl Fn(&mut dyn MigrationSource, &T) -> PaymentEngineResult<()>,
+    mut save_progress: impl FnMut(usize) -> PaymentEngineResult<()>,
+) -> PaymentEngineResult<()> {
+    let mut copied = copied.min(records.len());
+
+    for batch in records[copied..].chunks(BATCH_SIZE) {
+        for record in batch {
+            copy(target, record)?;
+        }
+
+        copied += batch.len();
+        target.flush()?;
+        save_progress(copied)?;
+        info!("Copied {}/{} {}", copied, records.len(), kind);
+    }
+
+    Ok(())
+}
+
+fn copy_transaction(
+    target: &mut dyn MigrationSource,
+    transaction: &Transaction,
+) -> PaymentEngineResult<()> {
+    target.save_transaction(transaction.clone())?;
+
+    // The pickle store keeps disputed transactions in an index of their own.
+    if transaction.disputed {
+        target.set_transaction_disputed(transaction.transaction_id, true)?;
+    }
+
+    Ok(())
+}
+
+/// Dispute records by transaction, each chain in sequence order.
+fn dispute_chains(records: Vec<DisputeRecord>) -> BTreeMap<u32, Vec<DisputeRecord>> {
+    let mut chains: BTreeMap<u32, Vec<DisputeRecord>> = BTreeMap::new();
+
+    for record in records {
+        chains
+            .entry(record.transaction_id)
+            .or_default()
+            .push(record);
+    }
+
+    for chain in chains.values_mut() {
+        chain.sort_by_key(|record| record.sequence);
+    }
+
+    chains
+}
+
+/// Compares every record of `source` with the one `target` returns for its id, and the
+/// summaries of both. Returns the summary of the target when nothing differs.
+fn verify(
+    source: &mut dyn MigrationSource,
+    target: &mut dyn MigrationSource,
+) -> PaymentEngineResult<MigrationSummary> {
+    let mut mismatches = 0;
+    let mut mismatch = |record: String| {
+        if mismatches < MISMATCHES_LOGGED {
+            warn!("Migrated {} differs from the source", record);
+        }
+        mismatches += 1;
+    };
+
+    for transaction in source.all_transactions()? {
+        if target.retrieve_transaction(transaction.transaction_id)? != Some(transaction.clone()) {
+            mismatch(format!("transaction {}", transaction.transaction_id));
+        }
+    }
+
+    let mut target_pending = target.retrieve_pending_transactions()?;
+    let mut source_pending = source.retrieve_pending_transactions()?;
+
+    target_pending.sort_by_key(|transaction| transaction.transaction_id);
+    source_pending.sort_by_key(|transaction| transaction.transaction_id);
+
+    if target_pending != source_pending {
+        mismatch("pending transactions".to_string());
+    }
+
+    for account in source.retrieve_all_accounts()? {
+        if target.retrieve_account(account.client_id)?.as_ref() != Some(&account) {
+            mismatch(format!("account {}", account.client_id));
+        }
+    }
+
+    for (transaction_id, chain) in dispute_chains(source.all_dispute_records()?) {
+        if target.retrieve_dispute_chain(transaction_id)? != chain {
+            mismatch(format!("dispute chain of transaction {}", transaction_id));
+        }
+    }
+
+    let summary = MigrationSummary::of(target)?;
+
+    if summary != MigrationSummary::of(source)? {
+        mismatch(format!("summary {:?}", summary));
+    }
+
+    match mismatches {
+        0 => Ok(summary),
+        mismatches => Err(PaymentEngineError::MigrationMismatch { mismatches }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datastore::{DatastoreOperations, InMemoryDatastore, PickleDatastore};
+    use crate::error::PaymentEngineError;
+    use crate::migrate::{self, MigrationProgress};
+    use crate::model::{Account, DisputeRecord, Documents, Transaction, TransactionType};
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_resume_migration_and_verify_the_copy() {
+        let directory = TempDir::new().unwrap();
+        let progress_path = directory.path().join(migrate::MIGRATION_PROGRESS_PATH);
+        let deposit = |transaction_id| Transaction {
+            disputed: transaction_id == 2,
+            ..Transaction::new(
+                TransactionType::Deposit,
+                1,
+                transaction_id,
+                Some(Decimal::from(10)),
+            )
+        };
+        let mut source = InMemoryDatastore::default();
+
+        for transaction_id in 1..=3 {
+            source.save_transaction(deposit(transaction_id)).unwrap();
+        }
+
+        source
+            .save_account(Account {
+                available: Decimal::from(20),
+                held: Decimal::from(10),
+                total: Decimal::from(30),
+                ..Account::new(1)
+            })
+            .unwrap();
+        source
+            .save_dispute_record(DisputeRecord {
+                transaction_id: 2,
+                sequence: 1,
+                r#type: TransactionType::Dispute,
+                client_id: 1,
+                amount: Decimal::from(10),
+                closes: None,
+                recorded_at: Utc::now(),
+                reason_code: None,
+                documents: Documents::default(),
+            })
+            .unwrap();
+
+        // An earlier run copied the first transaction before it was interrupted.
+        let mut target = PickleDatastore::open(directory.path(), false);
+        let progress = |transactions| MigrationProgress {
+            from: "memory".to_string(),
+            to: "pickle".to_string(),
+            transactions,
+            ..MigrationProgress::default()
+        };
+
+        target.save_transaction(deposit(1)).unwrap();
+        progress(1).save(&progress_path).unwrap();
+
+        let summary =
+            migrate::migrate(&mut source, &mut target, &progress_path, "memory", "pickle").unwrap();
+
+        assert_eq!(summary.transactions, 3);
+        assert_eq!(summary.dispute_records, 1);
+        assert_eq!(summary.total, Decimal::from(30));
+        assert_eq!(target.retrieve_transaction(2).unwrap(), Some(deposit(2)));
+        assert!(!progress_path.exists());
+
+        // Progress claiming records which never reached the target fails the verification.
+        std::fs::create_dir(directory.path().join("empty")).unwrap();
+
+        let mut empty = PickleDatastore::open(&directory.path().join("empty"), false);
+
+        progress(3).save(&progress_path).unwrap();
+
+        assert!(matches!(
+            migrate::migrate(&mut source, &mut empty, &progress_path, "memory", "pickle"),
+            Err(PaymentEngineError::MigrationMismatch { .. })
+        ));
+    }
+}
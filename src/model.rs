@@ -1,9 +1,20 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use chrono::{DateTime, Utc};
 use rust_decimal::prelude::*;
 use rust_decimal::Decimal;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+
+pub const PROVENANCE_COLUMN: &str = "provenance";
 
 const DECIMAL_POINT: u32 = 4;
+const FAST_PATH_MAX_DIGITS: usize = 18;
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
 pub struct Transaction {
@@ -15,27 +26,345 @@ pub struct Transaction {
     pub transaction_id: u32,
     #[serde(deserialize_with = "amount_deserializer")]
     pub amount: Option<Decimal>,
+    /// Client credited by a transfer.
+    #[serde(default)]
+    pub to_client: Option<u16>,
+    /// Currency of the amount. Transactions without one, or in the base currency of the config,
+    /// move the base currency balances.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Currency a conversion credits, or the base currency without one.
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
     #[serde(default = "default_disputed")]
     pub disputed: bool,
+    /// Part of a deposit which refunds have given back so far.
+    #[serde(default)]
+    pub refunded: Decimal,
+    /// How an authorization was closed, `None` while its funds are still held.
+    #[serde(default)]
+    pub settled: Option<Settlement>,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub memo: Option<String>,
+    #[serde(default)]
+    pub counterparty: Option<String>,
+    /// Why a dispute or chargeback was raised, from the reason code taxonomy of the config.
+    #[serde(default)]
+    pub reason_code: Option<String>,
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+}
+
+/// Where a transaction came from, stored with it so it can be traced back to its input. Written
+/// as `file:<path>:<line>` or `internal:<subsystem>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Provenance {
+    /// Row of an input file, the line counted from 1 with the header as line 1.
+    File { path: Arc<str>, line: u64 },
+    /// Created by the engine, e.g. the withdrawal committing a reservation.
+    Internal { subsystem: String },
+}
+
+/// Three letter ISO 4217 code such as `EUR`, stored in upper case.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency(String);
+
+/// Balances of an account in one currency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Balances {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// Cost of `total` in the base currency, at the rates its funds were credited at. Unknown
+    /// once funds were credited without a rate, and for balances stored before it was kept.
+    #[serde(default)]
+    pub cost: Option<Decimal>,
 }
 
+/// Balances of a client. `available`, `held` and `total` are in the base currency; balances in
+/// other currencies are kept by currency in `balances`.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq, Default)]
 pub struct Account {
     #[serde(rename = "client")]
     pub client_id: u16,
+    /// Currency of the balances on a row of a multi-currency report, see `by_currency`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub balances: BTreeMap<Currency, Balances>,
+}
+
+/// Step in the dispute history of a transaction. Resolves and chargebacks refer to the
+/// dispute they close by its sequence number, so a transaction disputed more than once keeps
+/// every round apart; representments refer to the chargeback they reverse.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisputeRecord {
+    #[serde(rename = "tx")]
+    pub transaction_id: u32,
+    pub sequence: u32,
+    pub r#type: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub amount: Decimal,
+    pub closes: Option<u32>,
+    pub recorded_at: DateTime<Utc>,
+    #[serde(default)]
+    pub reason_code: Option<String>,
+    #[serde(default)]
+    pub documents: Documents,
+}
+
+/// Reference documents backing a dispute, URIs or opaque references the engine does not
+/// interpret. Written space separated, so a reference cannot contain whitespace.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct Documents(pub Vec<String>);
+
+/// Evidence a client submits when opening a dispute, kept with the dispute step.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DisputeEvidence {
+    pub reason_code: Option<String>,
+    pub documents: Vec<String>,
+}
+
+/// Report row of an account with its fingerprint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FingerprintedAccount {
+    pub client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    pub hash: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Eq, Hash)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
+    /// Debits the client and credits `to_client` by the same amount.
+    Transfer,
     Dispute,
     Resolve,
     Chargeback,
+    /// Second presentment after a chargeback which the merchant won.
+    Representment,
+    /// Moves funds of the client from `currency` to `to_currency` at the rate of the rate table.
+    Convert,
+    /// Gives back part of the deposit with the same id; the refunded total is kept on the deposit.
+    Refund,
+    /// Moves `amount` from available to held until the authorization is captured or released.
+    Authorize,
+    /// Settles the authorization with the same id as a withdrawal of `amount`, all of it when
+    /// empty, and releases the rest.
+    Capture,
+    /// Returns the held funds of the authorization with the same id to available.
+    Release,
+    /// Replaces the amount of the deposit or withdrawal with the same id by `amount`.
+    Correction,
+}
+
+/// Final step of an authorization.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Settlement {
+    Captured,
+    Released,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Provenance::File { path, line } => write!(f, "file:{}:{}", path, line),
+            Provenance::Internal { subsystem } => write!(f, "internal:{}", subsystem),
+        }
+    }
+}
+
+impl From<Provenance> for String {
+    fn from(provenance: Provenance) -> Self {
+        provenance.to_string()
+    }
+}
+
+impl TryFrom<String> for Provenance {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let file = text
+            .strip_prefix("file:")
+            .and_then(|location| location.rsplit_once(':'))
+            .and_then(|(path, line)| Some((path, line.parse().ok()?)));
+
+        match (file, text.strip_prefix("internal:")) {
+            (Some((path, line)), _) => Ok(Provenance::File {
+                path: Arc::from(path),
+                line,
+            }),
+            (None, Some(subsystem)) => Ok(Provenance::Internal {
+                subsystem: subsystem.to_string(),
+            }),
+            (None, None) => Err(format!("'{}' is not a valid provenance", text)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.0
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        if text.len() == 3 && text.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+            Ok(Currency(text.to_ascii_uppercase()))
+        } else {
+            Err(format!("'{}' is not a three letter currency code", text))
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Currency::try_from(text.to_string())
+    }
+}
+
+impl From<Documents> for String {
+    fn from(documents: Documents) -> Self {
+        documents.0.join(" ")
+    }
+}
+
+impl From<String> for Documents {
+    fn from(text: String) -> Self {
+        Documents(text.split_whitespace().map(str::to_string).collect())
+    }
+}
+
+impl DisputeEvidence {
+    pub fn is_valid(&self) -> bool {
+        self.reason_code
+            .iter()
+            .chain(&self.documents)
+            .all(|text| !text.is_empty() && !text.contains(char::is_whitespace))
+    }
+}
+
+impl TransactionType {
+    /// Name of the type in input files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Representment => "representment",
+            TransactionType::Convert => "convert",
+            TransactionType::Refund => "refund",
+            TransactionType::Authorize => "authorize",
+            TransactionType::Capture => "capture",
+            TransactionType::Release => "release",
+            TransactionType::Correction => "correction",
+        }
+    }
+
+    /// Type with the name in any case, `None` for an unknown name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let transaction_type = match name.to_lowercase().as_str() {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "transfer" => TransactionType::Transfer,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::Chargeback,
+            "representment" => TransactionType::Representment,
+            "convert" => TransactionType::Convert,
+            "refund" => TransactionType::Refund,
+            "authorize" => TransactionType::Authorize,
+            "capture" => TransactionType::Capture,
+            "release" => TransactionType::Release,
+            "correction" => TransactionType::Correction,
+            _ => return None,
+        };
+
+        Some(transaction_type)
+    }
+}
+
+impl Transaction {
+    /// Transaction of `type` with none of the optional fields set, not disputed and nothing
+    /// refunded.
+    pub fn new(
+        r#type: TransactionType,
+        client_id: u16,
+        transaction_id: u32,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Transaction {
+            r#type,
+            client_id,
+            transaction_id,
+            amount,
+            to_client: None,
+            currency: None,
+            to_currency: None,
+            disputed: false,
+            refunded: Decimal::ZERO,
+            settled: None,
+            timestamp: None,
+            memo: None,
+            counterparty: None,
+            reason_code: None,
+            provenance: None,
+        }
+    }
+}
+
+impl Balances {
+    /// Cost once `total` becomes the total, at the average rate of the funds. Moves between
+    /// available and held funds keep the cost, and an emptied balance costs nothing.
+    fn cost_at(&self, total: Decimal) -> Option<Decimal> {
+        if total == self.total {
+            self.cost
+        } else if total.is_zero() {
+            Some(Decimal::ZERO)
+        } else {
+            self.cost?.checked_div(self.total)?.checked_mul(total)
+        }
+    }
+
+    /// Cost funds credited at a rate add to, none when the cost of the balance is unknown.
+    fn booked_cost(&self) -> Option<Decimal> {
+        if self.total.is_zero() {
+            Some(Decimal::ZERO)
+        } else {
+            self.cost
+        }
+    }
 }
 
 impl Account {
@@ -46,60 +375,324 @@ impl Account {
         }
     }
 
-    pub fn round_values(&mut self) {
-        self.available = self.available.round_dp(DECIMAL_POINT);
-        self.held = self.held.round_dp(DECIMAL_POINT);
-        self.total = self.total.round_dp(DECIMAL_POINT);
+    /// Adds the changes to the balances. When any balance would overflow, the account is left
+    /// untouched.
+    pub fn adjust(
+        &mut self,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    ) -> PaymentEngineResult<()> {
+        let available = checked_add(self.available, available)?;
+        let held = checked_add(self.held, held)?;
+        let total = checked_add(self.total, total)?;
+
+        self.available = available;
+        self.held = held;
+        self.total = total;
+
+        Ok(())
+    }
+
+    /// Balances in `currency`, or in the base currency without one.
+    pub fn balances_in(&self, currency: Option<&Currency>) -> Balances {
+        match currency {
+            Some(currency) => self.balances.get(currency).copied().unwrap_or_default(),
+            None => Balances {
+                available: self.available,
+                held: self.held,
+                total: self.total,
+                cost: None,
+            },
+        }
+    }
+
+    /// Adds the changes to the balances in `currency`, or in the base currency without one. The
+    /// cost of a changed total follows at the average rate of the balance.
+    pub fn adjust_in(
+        &mut self,
+        currency: Option<&Currency>,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    ) -> PaymentEngineResult<()> {
+        let currency = match currency {
+            Some(currency) => currency,
+            None => return self.adjust(available, held, total),
+        };
+        let balances = self.balances_in(Some(currency));
+        let total = checked_add(balances.total, total)?;
+        let adjusted = Balances {
+            available: checked_add(balances.available, available)?,
+            held: checked_add(balances.held, held)?,
+            total,
+            cost: balances.cost_at(total),
+        };
+
+        self.balances.insert(currency.clone(), adjusted);
+
+        Ok(())
+    }
+
+    /// Credits `amount` to the available and total balances in `currency`, or in the base
+    /// currency without one, booking it at `rate` to the base currency. Without a rate, the
+    /// cost of the balance becomes unknown.
+    pub fn book_in(
+        &mut self,
+        currency: Option<&Currency>,
+        amount: Decimal,
+        rate: Option<Decimal>,
+    ) -> PaymentEngineResult<()> {
+        let currency = match currency {
+            Some(currency) => currency,
+            None => return self.adjust(amount, Decimal::ZERO, amount),
+        };
+        let cost = match (self.balances_in(Some(currency)).booked_cost(), rate) {
+            (Some(cost), Some(rate)) => Some(checked_add(
+                cost,
+                amount
+                    .checked_mul(rate)
+                    .ok_or(PaymentEngineError::AmountOverflow)?,
+            )?),
+            _ => None,
+        };
+
+        self.adjust_in(Some(currency), amount, Decimal::ZERO, amount)?;
+
+        if let Some(balances) = self.balances.get_mut(currency) {
+            balances.cost = cost;
+        }
+
+        Ok(())
+    }
+
+    /// One report row per currency: the base currency balances labelled with `base`, then the
+    /// other currencies in code order. The base currency row is left out when it is empty and
+    /// the client only holds other currencies.
+    pub fn by_currency(&self, base: &Currency) -> Vec<Account> {
+        let row = |currency: &Currency, balances: Balances| Account {
+            client_id: self.client_id,
+            currency: Some(currency.clone()),
+            available: balances.available,
+            held: balances.held,
+            total: balances.total,
+            locked: self.locked,
+            balances: BTreeMap::new(),
+        };
+        let base_balances = self.balances_in(None);
+        let base_row = Some(row(base, base_balances))
+            .filter(|_| self.balances.is_empty() || base_balances != Balances::default());
+
+        base_row
+            .into_iter()
+            .chain(
+                self.balances
+                    .iter()
+                    .map(|(currency, balances)| row(currency, *balances)),
+            )
+            .collect()
+    }
+
+    /// FNV-1a hash of balances and status, as 16 hex digits. Balances are normalized first, so
+    /// `1.5` and `1.5000` hash the same, and the value is stable across runs and builds.
+    pub fn fingerprint(&self) -> String {
+        let mut content = format!(
+            "{}|{}|{}|{}|{}",
+            self.client_id,
+            self.available.normalize(),
+            self.held.normalize(),
+            self.total.normalize(),
+            self.locked
+        );
+
+        if let Some(currency) = &self.currency {
+            content = format!("{}|{}", content, currency);
+        }
+        let hash = content.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        });
+
+        format!("{:016x}", hash)
+    }
+
+    pub fn with_fingerprint(&self) -> FingerprintedAccount {
+        FingerprintedAccount {
+            client: self.client_id,
+            currency: self.currency.clone(),
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            hash: self.fingerprint(),
+        }
     }
 }
 
+pub fn checked_add(left: Decimal, right: Decimal) -> PaymentEngineResult<Decimal> {
+    left.checked_add(right)
+        .ok_or(PaymentEngineError::AmountOverflow)
+}
+
+pub fn checked_sub(left: Decimal, right: Decimal) -> PaymentEngineResult<Decimal> {
+    left.checked_sub(right)
+        .ok_or(PaymentEngineError::AmountOverflow)
+}
+
 fn amount_deserializer<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let amount_text: &str = Deserialize::deserialize(deserializer)?;
-
-    if amount_text.is_empty() {
-        return Ok(None);
-    }
+    // Transactions serialized without an amount hold null rather than an empty field.
+    let amount_text = match Option::<&str>::deserialize(deserializer)? {
+        Some(amount_text) if !amount_text.is_empty() => amount_text,
+        _ => return Ok(None),
+    };
 
-    match Decimal::from_str(amount_text) {
-        Ok(amount) => {
+    match parse_amount(amount_text) {
+        Some(amount) => {
             if amount.is_zero() {
                 Ok(None)
             } else {
-                Ok(Option::from(amount.round_dp(DECIMAL_POINT)))
+                Ok(Option::from(amount))
             }
         }
-        Err(_) => Err(Error::custom(format!(
+        None => Err(Error::custom(format!(
             "value \'{}\' cannot be converted to decimal",
             amount_text
         ))),
     }
 }
 
+/// Amount in the text of an input file, `None` when it is not a decimal number.
+pub fn parse_amount(text: &str) -> Option<Decimal> {
+    parse_plain_amount(text).or_else(|| Decimal::from_str(text).ok())
+}
+
+/// Fast path for plain amounts like `1234.5678`: digits with an optional sign and at most
+/// `DECIMAL_POINT` fractional digits, which fit into an i64 of minor units. The scale of the
+/// result is the number of fractional digits given, as with `Decimal::from_str`. Anything else
+/// (exponents, longer fractions, very large values) returns `None` and goes through
+/// `Decimal::from_str` instead.
+fn parse_plain_amount(text: &str) -> Option<Decimal> {
+    let (negative, digits) = match text.as_bytes() {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        rest => (false, rest),
+    };
+
+    if digits.is_empty() || digits.len() > FAST_PATH_MAX_DIGITS + 1 {
+        return None;
+    }
+
+    let mut minor_units: i64 = 0;
+    let mut scale: Option<u32> = None;
+    let mut digit_count = 0;
+
+    for byte in digits {
+        match byte {
+            b'0'..=b'9' => {
+                minor_units = minor_units * 10 + i64::from(byte - b'0');
+                digit_count += 1;
+                if let Some(scale) = scale.as_mut() {
+                    *scale += 1;
+                }
+            }
+            b'.' if scale.is_none() => scale = Some(0),
+            _ => return None,
+        }
+    }
+
+    let scale = scale.unwrap_or(0);
+
+    if digit_count == 0 || digit_count > FAST_PATH_MAX_DIGITS || scale > DECIMAL_POINT {
+        return None;
+    }
+
+    Some(Decimal::new(
+        if negative { -minor_units } else { minor_units },
+        scale,
+    ))
+}
+
 fn transaction_type_deserializer<'de, D>(deserializer: D) -> Result<TransactionType, D::Error>
 where
     D: Deserializer<'de>,
 {
     let type_text: &str = Deserialize::deserialize(deserializer)?;
-    let transaction_type = match type_text.to_lowercase().as_str() {
-        "deposit" => TransactionType::Deposit,
-        "withdrawal" => TransactionType::Withdrawal,
-        "dispute" => TransactionType::Dispute,
-        "resolve" => TransactionType::Resolve,
-        "chargeback" => TransactionType::Chargeback,
-        _ => {
-            return Err(Error::custom(format!(
-                "value \'{}\' cannot be converted to a valid transaction type",
-                type_text
-            )))
-        }
-    };
 
-    Ok(transaction_type)
+    TransactionType::from_name(type_text).ok_or_else(|| {
+        Error::custom(format!(
+            "value \'{}\' cannot be converted to a valid transaction type",
+            type_text
+        ))
+    })
 }
 
 pub fn default_disputed() -> bool {
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{parse_plain_amount, Account};
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    #[test]
+    pub fn should_fingerprint_account_content() {
+        let account = Account {
+            available: Decimal::new(15, 1),
+            total: Decimal::new(15, 1),
+            ..Account::new(7)
+        };
+        let padded = Account {
+            available: Decimal::new(15000, 4),
+            total: Decimal::new(15000, 4),
+            ..Account::new(7)
+        };
+        let locked = Account {
+            locked: true,
+            ..account.clone()
+        };
+
+        assert_eq!(account.fingerprint(), "f86cd4729e969ab9");
+        assert_eq!(account.fingerprint(), padded.fingerprint());
+        assert_ne!(account.fingerprint(), locked.fingerprint());
+    }
+
+    #[test]
+    pub fn should_parse_plain_amounts_like_decimal() {
+        for text in [
+            "0",
+            "1",
+            "1234.5678",
+            "1.50",
+            "-2.5",
+            "+3",
+            ".5",
+            "7.",
+            "00012.0100",
+            "99999999999999.9999",
+        ] {
+            let parsed = parse_plain_amount(text).unwrap();
+            let expected = Decimal::from_str(text).unwrap();
+
+            assert_eq!(parsed, expected, "{}", text);
+            assert_eq!(parsed.to_string(), expected.to_string(), "{}", text);
+        }
+
+        for text in [
+            "",
+            ".",
+            "-",
+            "1.23456",
+            "1e5",
+            "1,5",
+            "1.2.3",
+            "12345678901234567890",
+            "abc",
+        ] {
+            assert_eq!(parse_plain_amount(text), None, "{}", text);
+        }
+    }
+}
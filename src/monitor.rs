@@ -0,0 +1,186 @@
+use crate::datastore::CacheStats;
+use crate::error::PaymentEngineError;
+use crate::manifest::RunCounts;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Reject reason of rows which cannot be read as a transaction.
+pub const INVALID_ROW: &str = "InvalidRow";
+
+/// Live figures of a run, updated by the service processing it and read by whoever displays
+/// them, e.g. the `--tui` dashboard. Clones share the figures.
+#[derive(Debug, Clone)]
+pub struct RunMonitor {
+    stats: Arc<Mutex<RunStats>>,
+}
+
+/// Figures of a run so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunStats {
+    pub started: Instant,
+    pub counts: RunCounts,
+    /// Rejected rows by the error they were rejected with.
+    pub rejects: BTreeMap<String, u64>,
+    /// Rows by client.
+    pub clients: HashMap<u16, u64>,
+    pub cache: Option<CacheStats>,
+    /// Datastore flushes so far, when the last one ended and how long it took.
+    pub flushes: u64,
+    pub last_flush: Option<Instant>,
+    pub last_flush_duration: Duration,
+    /// Rows applied since the last flush, which the datastore files may not hold yet.
+    pub unflushed_rows: u64,
+    pub finished: bool,
+}
+
+impl Default for RunMonitor {
+    fn default() -> Self {
+        RunMonitor {
+            stats: Arc::new(Mutex::new(RunStats::new(Instant::now()))),
+        }
+    }
+}
+
+impl RunMonitor {
+    /// Copy of the figures so far.
+    pub fn snapshot(&self) -> RunStats {
+        self.stats
+            .lock()
+            .expect("Monitor lock is never poisoned")
+            .clone()
+    }
+
+    /// Marks the run as over, so displays can show the final figures and stop.
+    pub fn finish(&self) {
+        self.update(|stats| stats.finished = true);
+    }
+
+    pub(crate) fn record_row(
+        &self,
+        counts: RunCounts,
+        cache: Option<CacheStats>,
+        client_id: Option<u16>,
+        reject: Option<&str>,
+    ) {
+        self.update(|stats| {
+            if counts.applied > stats.counts.applied {
+                stats.unflushed_rows += counts.applied - stats.counts.applied;
+            }
+
+            stats.counts = counts;
+            stats.cache = cache;
+
+            if let Some(client_id) = client_id {
+                *stats.clients.entry(client_id).or_default() += 1;
+            }
+
+            if let Some(reject) = reject {
+                *stats.rejects.entry(reject.to_string()).or_default() += 1;
+            }
+        });
+    }
+
+    pub(crate) fn record_flush(&self, duration: Duration) {
+        self.update(|stats| {
+            stats.flushes += 1;
+            stats.last_flush = Some(Instant::now());
+            stats.last_flush_duration = duration;
+            stats.unflushed_rows = 0;
+        });
+    }
+
+    fn update(&self, update: impl FnOnce(&mut RunStats)) {
+        update(&mut self.stats.lock().expect("Monitor lock is never poisoned"));
+    }
+}
+
+impl RunStats {
+    fn new(started: Instant) -> Self {
+        RunStats {
+            started,
+            counts: RunCounts::default(),
+            rejects: BTreeMap::new(),
+            clients: HashMap::new(),
+            cache: None,
+            flushes: 0,
+            last_flush: None,
+            last_flush_duration: Duration::ZERO,
+            unflushed_rows: 0,
+            finished: false,
+        }
+    }
+
+    /// Clients with the most rows, busiest first.
+    pub fn top_clients(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut clients: Vec<(u16, u64)> = self
+            .clients
+            .iter()
+            .map(|(client_id, rows)| (*client_id, *rows))
+            .collect();
+
+        clients.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        clients.truncate(count);
+        clients
+    }
+
+    /// Time since the datastore was last flushed, or since the run started.
+    pub fn flush_lag(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_flush.unwrap_or(self.started))
+    }
+}
+
+/// Name of the error variant, e.g. `InsufficientAccountFunds`, so rejects group by cause rather
+/// than by the values in the message.
+pub(crate) fn reject_reason(error: &PaymentEngineError) -> String {
+    format!("{:?}", error)
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::monitor::{self, RunMonitor};
+    use crate::payment_service::PaymentService;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_count_rejects_by_error_and_rows_by_client() {
+        let mut input = NamedTempFile::new().unwrap();
+
+        writeln!(
+            input,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,5.0\n\
+             withdrawal,2,3,50.0\n\
+             deposit,2,4,ten\n\
+             deposit,2,5,1.0"
+        )
+        .unwrap();
+
+        let monitor = RunMonitor::default();
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig::default(),
+        );
+
+        service.set_monitor(monitor.clone());
+        service.run(input.path().to_str().unwrap()).unwrap();
+        monitor.finish();
+
+        let stats = monitor.snapshot();
+
+        assert!(stats.finished);
+        assert_eq!(stats.counts.rows, 5);
+        assert_eq!(stats.counts.applied, 3);
+        assert_eq!(stats.rejects["InsufficientAccountFunds"], 1);
+        assert_eq!(stats.rejects[monitor::INVALID_ROW], 1);
+        assert_eq!(stats.top_clients(1), vec![(2, 3)]);
+    }
+}
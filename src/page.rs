@@ -0,0 +1,318 @@
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::export::{self, TransactionFilter};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Byte budget of a page when the caller sets none.
+pub const DEFAULT_PAGE_BYTES: usize = 1 << 20;
+/// Items read from the datastore at a time while a page is filled.
+const READ_BATCH: usize = 100;
+const PAGE_START: &[u8] = br#"{"items":["#;
+/// Longest possible end of a page: the closing brackets and a quoted token with the longest
+/// resource name and id.
+const PAGE_END_RESERVE: usize = r#"],"next":"transactions:4294967295"}"#.len();
+
+/// What a continuation token pages through, so a token cannot continue another listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageResource {
+    Accounts,
+    Transactions,
+}
+
+/// Where the next page of a listing starts: after the item with id `after`, a client id for
+/// accounts and a transaction id for transactions. Written as `accounts:17`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContinuationToken {
+    pub resource: PageResource,
+    pub after: u32,
+}
+
+/// What a page held, and the token of the next page unless it was the last one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageSummary {
+    pub items: usize,
+    pub bytes: usize,
+    pub next: Option<ContinuationToken>,
+}
+
+impl PageResource {
+    fn name(self) -> &'static str {
+        match self {
+            PageResource::Accounts => "accounts",
+            PageResource::Transactions => "transactions",
+        }
+    }
+}
+
+impl fmt::Display for ContinuationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.resource.name(), self.after)
+    }
+}
+
+impl FromStr for ContinuationToken {
+    type Err = PaymentEngineError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let invalid = || PaymentEngineError::InvalidContinuationToken {
+            token: token.to_string(),
+        };
+        let (resource, after) = token.split_once(':').ok_or_else(invalid)?;
+        let resource = [PageResource::Accounts, PageResource::Transactions]
+            .iter()
+            .copied()
+            .find(|candidate| candidate.name() == resource)
+            .ok_or_else(invalid)?;
+
+        Ok(ContinuationToken {
+            resource,
+            after: after.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl ContinuationToken {
+    /// Id the page of `resource` continues after, none for the first page.
+    pub fn after(token: Option<&str>, resource: PageResource) -> PaymentEngineResult<Option<u32>> {
+        let token = match token {
+            Some(token) => token.parse::<ContinuationToken>()?,
+            None => return Ok(None),
+        };
+
+        if token.resource != resource {
+            return Err(PaymentEngineError::InvalidContinuationToken {
+                token: token.to_string(),
+            });
+        }
+
+        Ok(Some(token.after))
+    }
+}
+
+/// Streams `{"items":[...],"next":"<token>"}` to `writer`, taking items in id order until the
+/// next one would make the page longer than `budget` bytes. Items are serialized one at a time
+/// and written straight away, so neither the listing nor the page is held in memory. A page
+/// has at least one item, even one larger than the budget, so paging always advances. `next`
+/// is `null` on the last page.
+pub fn write_page<W, T, I>(
+    mut writer: W,
+    resource: PageResource,
+    items: I,
+    budget: usize,
+) -> PaymentEngineResult<PageSummary>
+where
+    W: Write,
+    T: Serialize,
+    I: IntoIterator<Item = PaymentEngineResult<(u32, T)>>,
+{
+    let mut summary = PageSummary::default();
+    let mut item_json = vec![];
+    let mut last = None;
+
+    writer.write_all(PAGE_START)?;
+    summary.bytes += PAGE_START.len();
+
+    for item in items {
+        let (id, item) = item?;
+
+        item_json.clear();
+        serde_json::to_writer(&mut item_json, &item)?;
+
+        let separator: &[u8] = if summary.items == 0 { b"" } else { b"," };
+
+        if summary.items > 0
+            && summary.bytes + separator.len() + item_json.len() + PAGE_END_RESERVE > budget
+        {
+            summary.next = last.map(|after| ContinuationToken { resource, after });
+            break;
+        }
+
+        writer.write_all(separator)?;
+        writer.write_all(&item_json)?;
+        summary.bytes += separator.len() + item_json.len();
+        summary.items += 1;
+        last = Some(id);
+    }
+
+    let end = match summary.next {
+        Some(next) => format!(r#"],"next":"{}"}}"#, next),
+        None => r#"],"next":null}"#.to_string(),
+    };
+
+    writer.write_all(end.as_bytes())?;
+    writer.flush()?;
+    summary.bytes += end.len();
+
+    Ok(summary)
+}
+
+/// Items in id order after `after`, read `READ_BATCH` at a time by `read`, which answers the
+/// items after the id it is given, and only as far as they are taken.
+pub(crate) fn read_in_batches<T>(
+    after: Option<u32>,
+    mut read: impl FnMut(Option<u32>, usize) -> PaymentEngineResult<Vec<T>>,
+    id: impl Fn(&T) -> u32,
+) -> impl Iterator<Item = PaymentEngineResult<(u32, T)>> {
+    let mut batch = VecDeque::new();
+    let mut after = after;
+    let mut exhausted = false;
+
+    std::iter::from_fn(move || {
+        if batch.is_empty() && !exhausted {
+            match read(after, READ_BATCH) {
+                Ok(items) => {
+                    exhausted = items.len() < READ_BATCH;
+                    after = items.last().map(&id).or(after);
+                    batch.extend(items);
+                }
+                Err(e) => {
+                    exhausted = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        batch.pop_front().map(|item| Ok((id(&item), item)))
+    })
+}
+
+/// Page of the accounts of `datastore` in client id order, continuing after `after`. Accounts
+/// are read from the client after the token on, and only as many as the page holds.
+pub fn write_accounts_page<W: Write>(
+    writer: W,
+    datastore: &dyn DatastoreOperations,
+    after: Option<u32>,
+    budget: usize,
+) -> PaymentEngineResult<PageSummary> {
+    let items = read_in_batches(
+        after,
+        |after, count| match after.map(u16::try_from).transpose() {
+            Ok(after) => datastore.retrieve_accounts_after(after, count),
+            // No client id is above the largest one.
+            Err(_) => Ok(vec![]),
+        },
+        |account| u32::from(account.client_id),
+    );
+
+    write_page(writer, PageResource::Accounts, items, budget)
+}
+
+/// Page of the latest version of the matching transactions in transaction id order,
+/// continuing after `after`. Transactions are read from the one after the token on, and only
+/// as many as the page holds.
+pub fn write_transactions_page<W: Write>(
+    writer: W,
+    datastore: &mut dyn DatastoreOperations,
+    filter: &TransactionFilter,
+    after: Option<u32>,
+    budget: usize,
+) -> PaymentEngineResult<PageSummary> {
+    let read = |after, count| datastore.retrieve_transactions_after(after, count);
+    let items = export::matching_transactions(filter, read, after)
+        .map(|transaction| transaction.map(|t| (t.transaction_id, t)));
+
+    write_page(writer, PageResource::Transactions, items, budget)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datastore::{DatastoreOperations, InMemoryDatastore};
+    use crate::error::PaymentEngineError;
+    use crate::export::TransactionFilter;
+    use crate::model::{Account, Transaction, TransactionType};
+    use crate::page::{self, ContinuationToken, PageResource};
+    use rust_decimal::Decimal;
+
+    #[test]
+    pub fn should_page_accounts_within_byte_budget() {
+        let mut datastore = InMemoryDatastore::default();
+
+        for client_id in 1..=5 {
+            datastore
+                .save_account(Account {
+                    available: Decimal::from(client_id),
+                    total: Decimal::from(client_id),
+                    ..Account::new(client_id)
+                })
+                .unwrap();
+        }
+
+        let budget = 200;
+        let mut clients = vec![];
+        let mut token: Option<String> = None;
+
+        loop {
+            let after = ContinuationToken::after(token.as_deref(), PageResource::Accounts).unwrap();
+            let mut body = vec![];
+            let summary = page::write_accounts_page(&mut body, &datastore, after, budget).unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(body.len(), summary.bytes);
+            assert!(summary.bytes <= budget);
+            assert!(summary.items >= 1);
+
+            for account in json["items"].as_array().unwrap() {
+                clients.push(account["client"].as_u64().unwrap());
+            }
+
+            token = json["next"].as_str().map(str::to_string);
+
+            if token.is_none() {
+                break;
+            }
+        }
+
+        assert_eq!(clients, vec![1, 2, 3, 4, 5]);
+        assert!(matches!(
+            ContinuationToken::after(Some("accounts:3"), PageResource::Transactions),
+            Err(PaymentEngineError::InvalidContinuationToken { .. })
+        ));
+    }
+
+    #[test]
+    pub fn should_page_matching_transactions_across_datastore_reads() {
+        let mut datastore = InMemoryDatastore::default();
+
+        for transaction_id in 1..=250 {
+            datastore
+                .save_transaction(Transaction::new(
+                    TransactionType::Deposit,
+                    (transaction_id % 2) as u16,
+                    transaction_id,
+                    Some(Decimal::ONE),
+                ))
+                .unwrap();
+        }
+
+        let filter = TransactionFilter {
+            client_id: Some(1),
+            ..TransactionFilter::default()
+        };
+        let mut transaction_ids = vec![];
+        let mut after = None;
+
+        loop {
+            let mut body = vec![];
+            let summary =
+                page::write_transactions_page(&mut body, &mut datastore, &filter, after, 2048)
+                    .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            for transaction in json["items"].as_array().unwrap() {
+                transaction_ids.push(transaction["transaction_id"].as_u64().unwrap());
+            }
+
+            match summary.next {
+                Some(next) => after = Some(next.after),
+                None => break,
+            }
+        }
+
+        assert_eq!(transaction_ids, (1..=250).step_by(2).collect::<Vec<u64>>());
+    }
+}
@@ -1,625 +1,4762 @@
-use crate::datastore::DatastoreOperations;
+use crate::analytics::AmountAnalytics;
+use crate::anomaly::{self, BalanceHistory};
+use crate::approvals::{AdminAction, ApprovalBook, ApprovalRequest};
+use crate::audit::{AuditAction, AuditEvent, AuditLog};
+use crate::checkpoint::Checkpoint;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{ReportMode, ServiceConfig};
+use crate::datastore::{DatastoreOperations, InMemoryDatastore};
+use crate::erasure::{self, ErasureSummary, LegalHold, LegalHolds};
 use crate::error::{PaymentEngineError, PaymentEngineResult};
-use crate::model::{Account, Transaction, TransactionType};
-use csv::{ReaderBuilder, Trim, WriterBuilder};
+use crate::export::{self, TransactionFilter};
+use crate::flags::Feature;
+use crate::fraud::Decision;
+use crate::ids::IdGenerator;
+use crate::impact::BatchImpact;
+use crate::input::InputSource;
+use crate::liability::DisputeLiability;
+use crate::limits::RunLimitTracker;
+use crate::locked::{LockedAction, LockedQueue};
+use crate::manifest::{RunCounts, SourceCounts};
+use crate::model::{
+    self, Account, Currency, DisputeEvidence, DisputeRecord, Documents, Provenance, Settlement,
+    Transaction, TransactionType,
+};
+use crate::monitor::{self, RunMonitor};
+use crate::page::{self, PageSummary};
+use crate::pipeline::{PipelinedRows, ReportStage};
+use crate::profiling;
+use crate::rejects::RejectsReport;
+use crate::report::{self, FileReporter, Reporter};
+use crate::reservation::{Reservation, ReservationBook};
+use crate::revaluation::Revaluation;
+use crate::risk::RiskReport;
+use crate::rounding::RoundingDrift;
+use crate::rows::TransactionRows;
+use crate::sequence::SequenceTracker;
+use crate::shadow::ShadowReport;
+use crate::summary::{Outcome, RunSummary, SummaryStats};
+use crate::timers::{Timer, TimerAction, TimerWheel};
+use crate::unit_of_work::UnitOfWork;
+use crate::wal::WriteAheadLog;
+use crate::warning::Warning;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
 
 pub struct PaymentService {
-    datastore: Box<dyn DatastoreOperations>,
+    datastore: UnitOfWork,
+    config: ServiceConfig,
+    sequence_tracker: Option<SequenceTracker>,
+    audit_log: Option<AuditLog>,
+    wal: Option<WriteAheadLog>,
+    /// Transactions of the open unit of work, written to the write-ahead log on commit.
+    unlogged: Vec<Transaction>,
+    /// Audit events of the open unit of work, recorded once it is committed.
+    unaudited: Vec<AuditEvent>,
+    config_updates: Option<Receiver<ServiceConfig>>,
+    shadow: Option<Shadow>,
+    reservations: ReservationBook,
+    legal_holds: LegalHolds,
+    locked_queue: LockedQueue,
+    timers: TimerWheel,
+    approvals: ApprovalBook,
+    ids: Box<dyn IdGenerator>,
+    clock: Box<dyn Clock>,
+    monitor: Option<RunMonitor>,
+    reporter: Option<Box<dyn Reporter>>,
+    changed_accounts: HashSet<u16>,
+    rounding_drift: RoundingDrift,
+    risk_report: RiskReport,
+    analytics: Option<AmountAnalytics>,
+    rejects: Option<RejectsReport>,
+    warnings: Option<Sender<Warning>>,
+    dispute_evidence: Option<DisputeEvidence>,
+    run_counts: RunCounts,
+    summary: RunSummary,
+    source_counts: Vec<SourceCounts>,
+    outputs: Vec<PathBuf>,
+}
+
+/// Outcome of `process_batch`, with one result per transaction in input order.
+#[derive(Debug)]
+pub struct BatchResult {
+    pub applied: bool,
+    pub outcomes: Vec<PaymentEngineResult<()>>,
+}
+
+/// Service running an alternative policy set next to production, see `enable_shadow`.
+struct Shadow {
+    service: Box<PaymentService>,
+    report: ShadowReport,
 }
 
 impl PaymentService {
-    pub fn new(datastore: Box<dyn DatastoreOperations>) -> Box<Self> {
-        Box::new(PaymentService { datastore })
+    pub fn new(datastore: Box<dyn DatastoreOperations>, config: ServiceConfig) -> Box<Self> {
+        let sequence_tracker = if config.detect_sequence_gaps {
+            Some(SequenceTracker::new())
+        } else {
+            None
+        };
+
+        let audit_log = config.audit_log_path.clone().map(AuditLog::new);
+        let wal = config.wal_path.clone().map(WriteAheadLog::new);
+        let reservations = ReservationBook::open(config.reservations_path.as_deref());
+        let legal_holds = LegalHolds::open(config.legal_holds_path.as_deref());
+        let locked_queue = LockedQueue::open(config.locked_queue_path.as_deref());
+        let analytics = config
+            .analytics_path
+            .as_ref()
+            .map(|_| AmountAnalytics::default());
+        let rejects = config
+            .rejects_path
+            .as_ref()
+            .map(|_| RejectsReport::default());
+        let timers = TimerWheel::open(config.timers_path.as_deref());
+        let approvals = ApprovalBook::open(config.approvals_path.as_deref());
+        let ids = Box::new(config.ids.generator(config.ids_path.as_deref()));
+
+        Box::new(PaymentService {
+            datastore: UnitOfWork::new(profiling::profiled(datastore)),
+            config,
+            sequence_tracker,
+            audit_log,
+            wal,
+            unlogged: vec![],
+            unaudited: vec![],
+            config_updates: None,
+            shadow: None,
+            reservations,
+            legal_holds,
+            locked_queue,
+            timers,
+            approvals,
+            ids,
+            clock: Box::new(SystemClock),
+            monitor: None,
+            reporter: None,
+            changed_accounts: HashSet::default(),
+            rounding_drift: RoundingDrift::default(),
+            risk_report: RiskReport::default(),
+            analytics,
+            rejects,
+            warnings: None,
+            dispute_evidence: None,
+            run_counts: RunCounts::default(),
+            summary: RunSummary::default(),
+            source_counts: Vec::new(),
+            outputs: vec![],
+        })
     }
 
-    pub fn run(&mut self, csv_path: &str) -> PaymentEngineResult<()> {
-        let mut reader = ReaderBuilder::new()
-            .has_headers(true)
-            .trim(Trim::All)
-            .from_path(csv_path)?;
+    /// Evaluates every transaction of later runs with the policies of `config` as well, without
+    /// applying the result. Transactions whose outcome differs and accounts whose balances end
+    /// up different are written to a comparison report at `report_path`.
+    pub fn enable_shadow(&mut self, config: ServiceConfig, report_path: PathBuf) {
+        let config = ServiceConfig {
+            audit_log_path: None,
+            wal_path: None,
+            ..config
+        };
 
-        for entry in reader.deserialize() {
-            let transaction: Transaction = match entry {
-                Ok(transaction) => transaction,
-                Err(e) => {
-                    warn!(
-                        "Invalid data, cannot deserialize row to transaction Error: {}",
-                        e
-                    );
-                    continue;
-                }
-            };
-            let mut account = self.retrieve_account(transaction.client_id)?;
+        self.shadow = Some(Shadow {
+            service: PaymentService::new(Box::new(InMemoryDatastore::default()), config),
+            report: ShadowReport::new(report_path),
+        });
+    }
 
-            match self.process_transaction(&transaction, &mut account) {
-                Ok(_) => {}
-                Err(e) => {
-                    warn!("{} | {:?} {:?}", e, account, transaction)
-                }
-            };
-        }
+    /// Replaces the wall clock the service dates and schedules with, e.g. by a `FixedClock` in
+    /// tests or for a replay.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
 
-        self.write_accounts()?;
+    /// Reports the rows of later runs, their rejects and the datastore flushes to `monitor` as
+    /// they happen.
+    pub fn set_monitor(&mut self, monitor: RunMonitor) {
+        self.monitor = Some(monitor);
+    }
 
-        Ok(())
+    /// Hands the accounts of later runs to `reporter` instead of writing them in the configured
+    /// report format and path.
+    pub fn set_reporter(&mut self, reporter: Box<dyn Reporter>) {
+        self.reporter = Some(reporter);
     }
 
-    fn process_transaction(
+    /// Sends a `Warning` for every row later runs reject to `warnings`, next to the log.
+    /// Warnings are no longer sent once the receiver is dropped.
+    pub fn send_warnings(&mut self, warnings: Sender<Warning>) {
+        self.warnings = Some(warnings);
+    }
+
+    /// Applies a single transaction, for programs embedding the engine. Failures leave the
+    /// account unchanged and are returned instead of logged.
+    pub fn process(&mut self, transaction: &Transaction) -> PaymentEngineResult<Account> {
+        let mut account = self.retrieve_account(transaction.client_id)?;
+
+        self.process_transaction(transaction, &mut account)?;
+
+        Ok(account)
+    }
+
+    /// Applies a transaction served on its own like a row of a batch: it is counted against
+    /// the limits of `limit_tracker` and not applied when it exceeds one, and it is parked
+    /// instead of applied when it needs approval.
+    pub fn process_served(
         &mut self,
         transaction: &Transaction,
-        account: &mut Account,
-    ) -> PaymentEngineResult<()> {
-        match transaction.r#type {
-            TransactionType::Deposit => self.handle_deposit(transaction, account),
-            TransactionType::Withdrawal => self.handle_withdrawal(transaction, account),
-            TransactionType::Dispute => self.handle_dispute(transaction, account),
-            TransactionType::Resolve => self.handle_resolve(transaction, account),
-            TransactionType::Chargeback => self.handle_chargeback(transaction, account),
+        limit_tracker: &mut RunLimitTracker,
+    ) -> PaymentEngineResult<Account> {
+        let amount_in_base = self.amount_for_limits(transaction)?;
+
+        limit_tracker.check_row()?;
+        limit_tracker.check_transaction(transaction, amount_in_base)?;
+
+        if self.requires_approval(transaction, amount_in_base) {
+            self.park_transaction(transaction.clone())?;
+
+            return self.retrieve_account(transaction.client_id);
         }
+
+        self.process(transaction)
     }
 
-    fn handle_deposit(
+    /// Records that the transaction was refused for exceeding the run limit `reason`, which
+    /// holds further transactions until `record_run_resumed`.
+    pub fn record_run_paused(
         &mut self,
         transaction: &Transaction,
-        account: &mut Account,
+        reason: &str,
     ) -> PaymentEngineResult<()> {
-        let amount = match transaction.amount {
-            Some(amount) => amount,
-            None => return Err(PaymentEngineError::NoAmount),
-        };
-        account.available += amount;
-        account.total += amount;
+        self.record_audit_event(AuditEvent {
+            details: Some(reason.to_string()),
+            ..AuditEvent::new(
+                AuditAction::RunPaused,
+                transaction.client_id,
+                transaction.transaction_id,
+            )
+        })
+    }
 
-        self.datastore.save_transaction(transaction.clone())?;
-        self.save_account_to_datastore(account)?;
+    /// Records that `principal` confirmed transactions may go on after the run limit `reason`.
+    pub fn record_run_resumed(&mut self, principal: &str, reason: &str) -> PaymentEngineResult<()> {
+        self.record_audit_event(AuditEvent {
+            principal: Some(principal.to_string()),
+            ..AuditEvent::with_details(AuditAction::RunResumed, reason.to_string())
+        })
+    }
 
-        Ok(())
+    /// Current state of every account.
+    pub fn accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        self.datastore.retrieve_all_accounts()
     }
 
-    fn handle_withdrawal(
-        &mut self,
-        transaction: &Transaction,
-        account: &mut Account,
-    ) -> PaymentEngineResult<()> {
-        let amount = match transaction.amount {
-            Some(amount) => {
-                if amount > account.available {
-                    return Err(PaymentEngineError::InsufficientAccountFunds);
-                } else {
-                    amount
-                }
-            }
-            None => return Err(PaymentEngineError::NoAmount),
-        };
-        account.available -= amount;
-        account.total -= amount;
+    /// Stored account of a client, none before its first transaction.
+    pub fn account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        self.datastore.retrieve_account(client_id)
+    }
 
-        self.datastore.save_transaction(transaction.clone())?;
-        self.save_account_to_datastore(account)?;
+    /// Writes a page of at most `budget` bytes of the accounts after client `after`, see
+    /// `page::write_page`.
+    pub fn write_accounts_page<W: Write>(
+        &self,
+        writer: W,
+        after: Option<u32>,
+        budget: usize,
+    ) -> PaymentEngineResult<PageSummary> {
+        page::write_accounts_page(writer, &self.datastore, after, budget)
+    }
 
-        Ok(())
+    /// Writes a page of at most `budget` bytes of the matching transactions after transaction
+    /// `after`, see `page::write_page`.
+    pub fn write_transactions_page<W: Write>(
+        &mut self,
+        writer: W,
+        filter: &TransactionFilter,
+        after: Option<u32>,
+        budget: usize,
+    ) -> PaymentEngineResult<PageSummary> {
+        page::write_transactions_page(writer, &mut self.datastore, filter, after, budget)
     }
 
-    fn handle_dispute(
+    /// Writes the matching transactions as JSON lines, see `export::export_transactions`.
+    pub fn export_transactions<W: Write>(
         &mut self,
-        transaction: &Transaction,
-        account: &mut Account,
-    ) -> PaymentEngineResult<()> {
-        let referenced_transaction = self.retrieve_transaction(transaction.transaction_id)?;
-        let referenced_transaction_id = referenced_transaction.transaction_id;
+        filter: &TransactionFilter,
+        writer: W,
+    ) -> PaymentEngineResult<usize> {
+        export::export_transactions(&mut self.datastore, filter, writer)
+    }
 
-        if referenced_transaction.disputed {
-            return Err(PaymentEngineError::TransactionAlreadyDisputed);
+    /// Latest version of at most `count` stored transactions after transaction `after`, in
+    /// transaction id order.
+    pub fn transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.datastore.retrieve_transactions_after(after, count)
+    }
+
+    /// Writes every change applied so far through to the datastore.
+    pub fn flush(&mut self) -> PaymentEngineResult<()> {
+        let started = Instant::now();
+
+        self.datastore.flush()?;
+
+        if let Some(monitor) = &self.monitor {
+            monitor.record_flush(started.elapsed());
         }
 
-        let amount = match referenced_transaction.amount {
-            Some(amount) => amount,
-            None => return Err(PaymentEngineError::NoAmount),
-        };
+        Ok(())
+    }
 
-        match referenced_transaction.r#type {
-            TransactionType::Deposit => {
-                account.available -= amount;
-                account.held += amount;
-            }
-            TransactionType::Withdrawal => {
-                account.held += amount;
-                account.total += amount;
+    /// Regenerates account state by applying stored transactions, in order, to empty in-memory
+    /// state with the default policies and the base currency of `config`. Transactions which
+    /// fail are logged and skipped.
+    pub fn rebuild_accounts(
+        history: &[Transaction],
+        config: &ServiceConfig,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig {
+                base_currency: config.base_currency.clone(),
+                ..ServiceConfig::default()
+            },
+        );
+
+        for transaction in history {
+            let mut account = service.retrieve_account(transaction.client_id)?;
+
+            if let Err(e) = service.process_transaction(transaction, &mut account) {
+                warn!("{} | {:?}", e, transaction);
             }
-            _ => return Err(PaymentEngineError::InvalidDisputedTransactionType),
         }
 
-        self.datastore
-            .set_transaction_disputed(referenced_transaction_id, true)?;
-        self.save_account_to_datastore(account)?;
+        service.accounts()
+    }
 
-        Ok(())
+    /// Configurations received here replace the current one between rows of a running batch.
+    pub fn watch_config(&mut self, updates: Receiver<ServiceConfig>) {
+        self.config_updates = Some(updates);
     }
 
-    fn handle_resolve(
-        &mut self,
-        transaction: &Transaction,
-        account: &mut Account,
-    ) -> PaymentEngineResult<()> {
-        let referenced_transaction = self.retrieve_transaction(transaction.transaction_id)?;
-        let referenced_transaction_id = referenced_transaction.transaction_id;
+    pub fn run(&mut self, csv_path: &str) -> PaymentEngineResult<()> {
+        self.run_files(&[csv_path])
+    }
 
-        if !referenced_transaction.disputed {
-            return Err(PaymentEngineError::TransactionNotDisputed);
-        }
+    /// Processes the files one after the other against the same state and writes a single
+    /// account report at the end. Run limits apply to all files together. A `.zip` file is
+    /// processed as its CSV members in name order.
+    pub fn run_files(&mut self, csv_paths: &[&str]) -> PaymentEngineResult<()> {
+        let mut limit_tracker = RunLimitTracker::new(self.config.limits.clone());
 
-        let amount = match referenced_transaction.amount {
-            Some(amount) => amount,
-            None => return Err(PaymentEngineError::NoAmount),
-        };
+        self.run_due_timers(self.clock.now())?;
 
-        match referenced_transaction.r#type {
-            TransactionType::Deposit | TransactionType::Withdrawal => {
-                account.available += amount;
-                account.held -= amount;
-            }
-            _ => return Err(PaymentEngineError::InvalidDisputedTransactionType),
+        for csv_path in csv_paths {
+            info!("Processing {}", csv_path);
+            self.process_input(csv_path, &mut limit_tracker)?;
         }
 
-        self.remove_disputed_state(referenced_transaction_id)?;
-        self.save_account_to_datastore(account)?;
+        self.write_accounts()?;
+        self.clear_checkpoint()
+    }
 
-        Ok(())
+    /// Processes the transactions of any input source, e.g. an `input::IterInput`, and writes
+    /// the account report like a run of files does.
+    pub fn run_input<S, E>(&mut self, input: S) -> PaymentEngineResult<()>
+    where
+        S: InputSource<E>,
+        E: Display,
+    {
+        let mut limit_tracker = RunLimitTracker::new(self.config.limits.clone());
+
+        self.run_due_timers(self.clock.now())?;
+        info!("Processing {}", input.source());
+        self.process_rows(input, &mut limit_tracker)?;
+        self.write_accounts()?;
+        self.clear_checkpoint()
     }
 
-    fn handle_chargeback(
+    /// Continues the run of `csv_path` after the rows the checkpoint covers. The state files
+    /// have to be restored from the checkpoint before the datastore is opened.
+    pub fn resume_from_checkpoint(
         &mut self,
-        transaction: &Transaction,
-        account: &mut Account,
+        csv_path: &str,
+        checkpoint: &Checkpoint,
     ) -> PaymentEngineResult<()> {
-        let referenced_transaction = self.retrieve_transaction(transaction.transaction_id)?;
-        let referenced_transaction_id = referenced_transaction.transaction_id;
-
-        if !referenced_transaction.disputed {
-            return Err(PaymentEngineError::TransactionNotDisputed);
+        if checkpoint.input != csv_path {
+            return Err(PaymentEngineError::CheckpointInputMismatch {
+                input: checkpoint.input.clone(),
+            });
         }
 
-        let amount = match referenced_transaction.amount {
-            Some(amount) => amount,
-            None => return Err(PaymentEngineError::NoAmount),
-        };
+        let mut limit_tracker = RunLimitTracker::new(self.config.limits.clone());
 
-        match referenced_transaction.r#type {
-            TransactionType::Deposit | TransactionType::Withdrawal => {
-                account.held -= amount;
-                account.total -= amount;
-                account.locked = true;
-            }
-            _ => return Err(PaymentEngineError::InvalidDisputedTransactionType),
+        info!("Resuming {} at line {}", csv_path, checkpoint.line);
+        self.run_counts = checkpoint.counts;
+        self.process_file_rows(
+            TransactionRows::from_position(csv_path, checkpoint.position())?,
+            &mut limit_tracker,
+        )?;
+        self.write_accounts()?;
+        self.clear_checkpoint()
+    }
+
+    /// Processes the file without applying it to the datastore and returns the impact it would
+    /// have. The staged changes are kept until `apply_staged` or `discard_staged` is called.
+    pub fn stage(&mut self, csv_path: &str) -> PaymentEngineResult<BatchImpact> {
+        self.datastore.begin();
+
+        if let Err(e) = self.process_file(csv_path) {
+            self.rollback();
+            return Err(e);
         }
 
-        self.remove_disputed_state(referenced_transaction_id)?;
-        self.save_account_to_datastore(account)?;
+        let changes = self.datastore.pending_account_changes()?;
 
-        Ok(())
+        BatchImpact::from_changes(&changes)
     }
 
-    fn remove_disputed_state(&mut self, referenced_transaction_id: u32) -> PaymentEngineResult<()> {
-        self.datastore
-            .set_transaction_disputed(referenced_transaction_id, false)?;
-        self.datastore
-            .remove_transaction_from_cache(referenced_transaction_id)?;
+    /// Processes the file against the current state like a run, so the rows it would reject
+    /// are logged and written to the rejects report, then discards every change. Returns the
+    /// impact the file would have; no account report is written.
+    pub fn dry_run(&mut self, csv_path: &str) -> PaymentEngineResult<BatchImpact> {
+        let impact = self.stage(csv_path)?;
+
+        self.discard_staged();
+        self.write_rejects()?;
+        self.write_stats()?;
+
+        Ok(impact)
+    }
+
+    pub fn apply_staged(&mut self) -> PaymentEngineResult<()> {
+        self.commit()?;
+        self.write_accounts()?;
 
         Ok(())
     }
 
-    fn retrieve_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<Transaction> {
-        match self.datastore.retrieve_transaction(transaction_id)? {
-            Some(referenced_transaction) => Ok(referenced_transaction),
-            None => Err(PaymentEngineError::DisputedTransactionNotFound),
+    /// Applies the file only when every transaction in it succeeds.
+    pub fn run_atomic(&mut self, csv_path: &str) -> PaymentEngineResult<()> {
+        let transactions =
+            TransactionRows::from_path(csv_path)?.collect::<Result<Vec<Transaction>, _>>()?;
+        let result = self.process_batch(transactions.clone())?;
+        let failed = result.outcomes.iter().filter(|o| o.is_err()).count();
+
+        self.run_counts.rows += transactions.len() as u64;
+        self.run_counts.rejected += failed as u64;
+
+        for (transaction, outcome) in transactions.iter().zip(&result.outcomes) {
+            if let Err(e) = outcome {
+                warn!("{} | {:?}", e, transaction);
+            }
         }
-    }
 
-    fn save_account_to_datastore(&mut self, account: &mut Account) -> PaymentEngineResult<()> {
-        account.round_values();
-        self.datastore.save_account(account.clone())?;
+        if !result.applied {
+            return Err(PaymentEngineError::BatchRejected { failed });
+        }
 
-        Ok(())
+        self.run_counts.applied += transactions.len() as u64;
+        self.write_accounts()
     }
 
-    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Account> {
-        match self.datastore.retrieve_account(client_id)? {
-            None => Ok(Account::new(client_id)),
-            Some(account) => Ok(account),
+    /// Processes the transactions in one unit of work, which is committed only when all of them
+    /// succeed. Each outcome is evaluated against the state left by the transactions before it,
+    /// so callers see every failure of a rejected batch, not just the first one.
+    pub fn process_batch(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> PaymentEngineResult<BatchResult> {
+        if self.datastore.is_active() {
+            return Err(PaymentEngineError::StagedBatchPending);
+        }
+
+        let changed_accounts = self.changed_accounts.clone();
+        let mut outcomes = Vec::with_capacity(transactions.len());
+
+        self.datastore.begin();
+
+        for transaction in &transactions {
+            let outcome = self
+                .retrieve_account(transaction.client_id)
+                .and_then(|mut account| self.process_transaction(transaction, &mut account));
+
+            outcomes.push(outcome);
+        }
+
+        let applied = outcomes.iter().all(Result::is_ok);
+
+        if applied {
+            self.commit()?;
+        } else {
+            self.rollback();
+            self.changed_accounts = changed_accounts;
         }
+
+        Ok(BatchResult { applied, outcomes })
     }
 
-    fn write_accounts(&self) -> PaymentEngineResult<()> {
-        let accounts = self.datastore.retrieve_all_accounts()?;
-        let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+    pub fn discard_staged(&mut self) {
+        self.rollback();
+        self.changed_accounts.clear();
+    }
 
-        for account in accounts {
-            writer.serialize(account)?;
+    /// Commits the open unit of work once its transactions are in the write-ahead log.
+    fn commit(&mut self) -> PaymentEngineResult<()> {
+        if let Some(wal) = &self.wal {
+            wal.append(&self.unlogged)?;
         }
 
-        writer.flush()?;
+        self.unlogged.clear();
+        self.datastore.commit()?;
+
+        for event in std::mem::take(&mut self.unaudited) {
+            self.write_audit_event(event)?;
+        }
 
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::datastore::DatastoreOperations;
-    use crate::error::PaymentEngineResult;
-    use crate::model::{Account, Transaction, TransactionType};
-    use crate::payment_service::PaymentService;
-    use rust_decimal::prelude::*;
-    use rust_decimal::Decimal;
-    use std::collections::HashMap;
 
-    struct MockDatastore {
-        accounts: HashMap<u16, Account>,
-        transactions: Vec<Transaction>,
+    fn rollback(&mut self) {
+        self.unlogged.clear();
+        self.unaudited.clear();
+        self.datastore.rollback();
     }
 
-    impl MockDatastore {
-        pub fn new(accounts: HashMap<u16, Account>, transactions: Vec<Transaction>) -> Self {
-            MockDatastore {
-                accounts,
-                transactions,
+    /// Writes the transaction to the write-ahead log before it is applied. Inside a unit of work
+    /// nothing reaches the datastore before the commit, so it is logged then.
+    fn log_ahead(&mut self, transaction: &Transaction) -> PaymentEngineResult<()> {
+        match &self.wal {
+            Some(_) if self.datastore.is_active() => {
+                self.unlogged.push(transaction.clone());
+                Ok(())
             }
+            Some(wal) => wal.append(std::slice::from_ref(transaction)),
+            None => Ok(()),
         }
     }
 
-    impl DatastoreOperations for MockDatastore {
-        fn retrieve_transaction(
-            &mut self,
-            transaction_id: u32,
-        ) -> PaymentEngineResult<Option<Transaction>> {
-            Ok(self
-                .transactions
-                .iter()
-                .find(|t| t.transaction_id == transaction_id)
-                .cloned())
-        }
+    fn process_file(&mut self, csv_path: &str) -> PaymentEngineResult<()> {
+        let mut limit_tracker = RunLimitTracker::new(self.config.limits.clone());
 
-        fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
-            self.transactions.push(transaction);
-            Ok(())
+        self.process_input(csv_path, &mut limit_tracker)
+    }
+
+    fn process_input(
+        &mut self,
+        csv_path: &str,
+        limit_tracker: &mut RunLimitTracker,
+    ) -> PaymentEngineResult<()> {
+        let is_archive = matches!(
+            Path::new(csv_path).extension(),
+            Some(extension) if extension.eq_ignore_ascii_case("zip")
+        );
+
+        if is_archive {
+            self.process_archive(csv_path, limit_tracker)
+        } else {
+            self.process_file_rows(TransactionRows::from_path(csv_path)?, limit_tracker)
         }
+    }
 
-        fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
-            Ok(self.accounts.get(&client_id).cloned())
+    /// Processes the rows of a file, read and deserialized by pipeline stages ahead of the
+    /// processing when a pipeline depth is set. Archive members borrow their archive, so they
+    /// are always read on the processing thread.
+    fn process_file_rows<R: Read + Send + 'static>(
+        &mut self,
+        rows: TransactionRows<R>,
+        limit_tracker: &mut RunLimitTracker,
+    ) -> PaymentEngineResult<()> {
+        match self.config.pipeline_depth {
+            Some(depth) => self.process_rows(PipelinedRows::start(rows, depth), limit_tracker),
+            None => self.process_rows(rows, limit_tracker),
         }
+    }
 
-        fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
-            self.accounts.insert(account.client_id, account);
+    /// Processes the CSV members of the archive in name order, each attributed to
+    /// `archive.zip!member.csv` in provenance and in the counts per source.
+    fn process_archive(
+        &mut self,
+        zip_path: &str,
+        limit_tracker: &mut RunLimitTracker,
+    ) -> PaymentEngineResult<()> {
+        let archive_error = |source| PaymentEngineError::InputArchive {
+            path: zip_path.to_string(),
+            source,
+        };
+        let file = File::open(zip_path).map_err(csv::Error::from)?;
+        let mut archive = zip::ZipArchive::new(file).map_err(archive_error)?;
+        let mut members: Vec<String> = archive
+            .file_names()
+            .filter(|name| name.to_ascii_lowercase().ends_with(".csv"))
+            .map(String::from)
+            .collect();
 
-            Ok(())
-        }
+        members.sort_unstable();
 
-        fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
-            Ok(self.accounts.values().cloned().collect())
+        for member in members {
+            let source = format!("{}!{}", zip_path, member);
+            let entry = archive.by_name(&member).map_err(archive_error)?;
+
+            info!("Processing {}", source);
+            self.process_rows(TransactionRows::from_reader(entry, &source)?, limit_tracker)?;
         }
 
-        fn set_transaction_disputed(
-            &mut self,
-            transaction_id: u32,
-            disputed: bool,
-        ) -> PaymentEngineResult<()> {
-            let transaction = self
-                .transactions
-                .iter_mut()
-                .find(|t| t.transaction_id == transaction_id)
-                .unwrap();
+        Ok(())
+    }
+
+    fn process_rows<S, E>(
+        &mut self,
+        mut rows: S,
+        limit_tracker: &mut RunLimitTracker,
+    ) -> PaymentEngineResult<()>
+    where
+        S: InputSource<E>,
+        E: Display,
+    {
+        let counts_before = self.run_counts;
+
+        loop {
+            if self.run_counts.rows > counts_before.rows {
+                self.save_checkpoint(&rows)?;
+            }
+
+            let entry = match rows.next() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            self.reload_config(limit_tracker)?;
+            limit_tracker.check_row()?;
+            self.run_counts.rows += 1;
+
+            let transaction: Transaction = match entry {
+                Ok(transaction) => transaction,
+                Err(e) => {
+                    self.run_counts.rejected += 1;
+                    self.monitor_row(None, Some(monitor::INVALID_ROW));
+                    self.record_reject(&rows, monitor::INVALID_ROW, e.to_string());
+                    warn!(
+                        "Invalid data in {}, cannot deserialize row to transaction Error: {}",
+                        rows.source(),
+                        e
+                    );
+
+                    if self.config.strict {
+                        return Err(self.strict_abort(&rows, e.to_string()));
+                    }
+                    continue;
+                }
+            };
+            let amount_in_base = self.amount_for_limits(&transaction)?;
+
+            limit_tracker.check_transaction(&transaction, amount_in_base)?;
+            self.track_sequence(&transaction);
+            self.check_internal_id(&transaction);
+
+            let decision = self.check_fraud(&transaction);
+
+            if self.requires_approval(&transaction, amount_in_base) || decision == Decision::Hold {
+                let client_id = transaction.client_id;
+
+                self.summary.record(&transaction.r#type, Outcome::Parked);
+                self.park_transaction(transaction)?;
+                self.run_counts.parked += 1;
+                self.monitor_row(Some(client_id), None);
+                continue;
+            }
+
+            let shadow_result = self.evaluate_shadow(&transaction)?;
+            let mut account = self.retrieve_account(transaction.client_id)?;
+            let queued = self.locked_action(&transaction, &account) == LockedAction::Queue;
+            let result = match decision {
+                Decision::Deny => Err(PaymentEngineError::FraudCheckDenied),
+                _ => self.process_transaction(&transaction, &mut account),
+            };
+
+            if let (Some(shadow), Some(shadow_result)) = (self.shadow.as_mut(), shadow_result) {
+                shadow
+                    .report
+                    .compare_outcomes(&transaction, &result, &shadow_result);
+            }
+
+            if let Err(e) = &result {
+                self.record_reject(&rows, &monitor::reject_reason(e), e.to_string());
+            }
+
+            let outcome = match &result {
+                Err(_) => Outcome::Rejected,
+                Ok(_) if queued => Outcome::Parked,
+                Ok(_) => Outcome::Applied,
+            };
+
+            self.summary.record(&transaction.r#type, outcome);
+
+            let rejection = result.as_ref().err().map(ToString::to_string);
+
+            match result {
+                Err(e) if e.is_client_error() => {
+                    self.run_counts.rejected += 1;
+                    self.monitor_reject(transaction.client_id, &e);
+                    warn!("{} | {:?} {:?}", e, account, transaction)
+                }
+                Err(e) => {
+                    self.run_counts.rejected += 1;
+                    self.monitor_reject(transaction.client_id, &e);
+                    error!("{} | {:?} {:?}", e, account, transaction)
+                }
+                Ok(_) if queued => {
+                    self.run_counts.parked += 1;
+                    self.monitor_row(Some(transaction.client_id), None);
+                }
+                Ok(_) => {
+                    self.run_counts.applied += 1;
+                    self.monitor_row(Some(transaction.client_id), None);
+                }
+            }
+
+            if let (true, Some(reason)) = (self.config.strict, rejection) {
+                return Err(self.strict_abort(&rows, reason));
+            }
+        }
+
+        let counts = self.run_counts.since(counts_before);
+
+        info!(
+            "{}: {} rows, {} applied, {} rejected, {} parked",
+            rows.source(),
+            counts.rows,
+            counts.applied,
+            counts.rejected,
+            counts.parked
+        );
+        self.source_counts.push(SourceCounts {
+            source: rows.source().to_string(),
+            counts,
+        });
+        self.report_sequence_issues();
+        self.report_shadow_differences()?;
+
+        Ok(())
+    }
+
+    fn monitor_row(&self, client_id: Option<u16>, reject: Option<&str>) {
+        if let Some(monitor) = &self.monitor {
+            monitor.record_row(
+                self.run_counts,
+                self.datastore.cache_stats(),
+                client_id,
+                reject,
+            );
+        }
+    }
+
+    fn monitor_reject(&self, client_id: u16, error: &PaymentEngineError) {
+        if self.monitor.is_some() {
+            self.monitor_row(Some(client_id), Some(&monitor::reject_reason(error)));
+        }
+    }
+
+    /// Error ending a strict run at the last row of `rows`. The rows before it stay applied.
+    fn strict_abort<S, E>(&self, rows: &S, reason: String) -> PaymentEngineError
+    where
+        S: InputSource<E>,
+        E: Display,
+    {
+        PaymentEngineError::StrictRowRejected {
+            input: rows.source().to_string(),
+            row: self.run_counts.rows,
+            reason,
+        }
+    }
+
+    /// Adds the last row of `rows` to the rejects report, when one is written, and sends it as
+    /// a warning, when they are sent.
+    fn record_reject<S, E>(&mut self, rows: &S, error: &str, message: String)
+    where
+        S: InputSource<E>,
+        E: Display,
+    {
+        if self.rejects.is_none() && self.warnings.is_none() {
+            return;
+        }
+
+        let raw_row = rows.raw_row();
+
+        if let Some(sender) = &self.warnings {
+            let warning = Warning {
+                source: rows.source().to_string(),
+                row: self.run_counts.rows,
+                code: error.to_string(),
+                message: message.clone(),
+                record: raw_row.clone(),
+            };
+
+            if sender.send(warning).is_err() {
+                self.warnings = None;
+            }
+        }
+
+        if let Some(rejects) = self.rejects.as_mut() {
+            rejects.record(rows.source(), raw_row, error, message);
+        }
+    }
+
+    /// Saves a checkpoint after every `every_rows` rows of the run. Rows processed inside a
+    /// unit of work are not in the datastore yet, so no checkpoint is saved then.
+    fn save_checkpoint<S, E>(&mut self, rows: &S) -> PaymentEngineResult<()>
+    where
+        S: InputSource<E>,
+        E: Display,
+    {
+        let position = match rows.position() {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        let directory = match &self.config.checkpoints {
+            Some(policy)
+                if self.run_counts.rows.is_multiple_of(policy.every_rows.get())
+                    && !self.datastore.is_active() =>
+            {
+                policy.directory.clone()
+            }
+            _ => return Ok(()),
+        };
+
+        self.flush()?;
+        Checkpoint::new(rows.source(), position, self.run_counts).save(&directory)
+    }
+
+    fn clear_checkpoint(&self) -> PaymentEngineResult<()> {
+        match &self.config.checkpoints {
+            Some(policy) => Checkpoint::clear(&policy.directory),
+            None => Ok(()),
+        }
+    }
+
+    /// Processes the transaction with the shadow service, first copying the account and the
+    /// referenced transaction from production when the shadow service has not seen them yet.
+    fn evaluate_shadow(
+        &mut self,
+        transaction: &Transaction,
+    ) -> PaymentEngineResult<Option<PaymentEngineResult<()>>> {
+        let shadow = match self.shadow.as_mut() {
+            Some(shadow) => shadow,
+            None => return Ok(None),
+        };
+        let shadow_datastore = &mut shadow.service.datastore;
+
+        if shadow_datastore
+            .retrieve_account(transaction.client_id)?
+            .is_none()
+        {
+            if let Some(account) = self.datastore.retrieve_account(transaction.client_id)? {
+                shadow_datastore.save_account(account)?;
+            }
+        }
+
+        if !matches!(
+            transaction.r#type,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Convert
+                | TransactionType::Authorize
+        ) && shadow_datastore
+            .retrieve_transaction(transaction.transaction_id)?
+            .is_none()
+        {
+            if let Some(referenced) = self
+                .datastore
+                .retrieve_transaction(transaction.transaction_id)?
+            {
+                shadow_datastore.save_transaction(referenced)?;
+            }
+        }
+
+        let mut account = shadow.service.retrieve_account(transaction.client_id)?;
+
+        Ok(Some(
+            shadow
+                .service
+                .process_transaction(transaction, &mut account),
+        ))
+    }
+
+    fn report_shadow_differences(&mut self) -> PaymentEngineResult<()> {
+        let shadow = match self.shadow.as_mut() {
+            Some(shadow) => shadow,
+            None => return Ok(()),
+        };
+
+        for shadow_account in shadow.service.datastore.retrieve_all_accounts()? {
+            let account = self
+                .datastore
+                .retrieve_account(shadow_account.client_id)?
+                .unwrap_or_else(|| Account::new(shadow_account.client_id));
+
+            shadow.report.compare_accounts(&account, &shadow_account);
+        }
+
+        info!(
+            "Shadow policies differ in {} places",
+            shadow.report.differences().len()
+        );
+        shadow.report.write()?;
+
+        Ok(())
+    }
+
+    pub fn pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transactions = self.datastore.retrieve_pending_transactions()?;
+
+        transactions.sort_by_key(|t| t.transaction_id);
+
+        Ok(transactions)
+    }
+
+    /// Applies the parked transaction on behalf of `principal`. Under dual control nothing is
+    /// applied until a second principal approves it as well.
+    pub fn approve_pending(
+        &mut self,
+        transaction_id: u32,
+        principal: &str,
+    ) -> PaymentEngineResult<Option<Account>> {
+        let transaction = self.retrieve_pending_transaction(transaction_id)?;
+        let action = AdminAction::ApprovePending { transaction_id };
+
+        if !self.confirm_admin_action(&action, &transaction, principal)? {
+            return Ok(None);
+        }
+
+        let mut account = self.retrieve_account(transaction.client_id)?;
+
+        self.process_transaction(&transaction, &mut account)?;
+        self.datastore.remove_pending_transaction(transaction_id)?;
+        self.approvals.remove(&action)?;
+        self.record_admin_audit(AuditAction::PendingApproved, &transaction, principal)?;
+
+        Ok(Some(account))
+    }
+
+    /// Discards the parked transaction on behalf of `principal` and returns whether it was
+    /// discarded, which under dual control needs a second principal.
+    pub fn reject_pending(
+        &mut self,
+        transaction_id: u32,
+        principal: &str,
+    ) -> PaymentEngineResult<bool> {
+        let transaction = self.retrieve_pending_transaction(transaction_id)?;
+        let action = AdminAction::RejectPending { transaction_id };
+
+        if !self.confirm_admin_action(&action, &transaction, principal)? {
+            return Ok(false);
+        }
+
+        self.datastore.remove_pending_transaction(transaction_id)?;
+        self.approvals.remove(&action)?;
+        self.record_admin_audit(AuditAction::PendingRejected, &transaction, principal)?;
+
+        Ok(true)
+    }
+
+    /// Administrative actions waiting for a second principal.
+    pub fn approval_requests(&self) -> Vec<ApprovalRequest> {
+        self.approvals.list()
+    }
+
+    /// Whether the action may take effect now: always without dual control, otherwise only
+    /// when a principal other than the one who requested it confirms.
+    fn confirm_admin_action(
+        &mut self,
+        action: &AdminAction,
+        transaction: &Transaction,
+        principal: &str,
+    ) -> PaymentEngineResult<bool> {
+        if !self.config.dual_control {
+            return Ok(true);
+        }
+
+        let (audit_action, details) =
+            match self
+                .approvals
+                .request(action.clone(), principal, self.clock.now())?
+            {
+                None => (AuditAction::AdminActionRequested, action.to_string()),
+                Some(request) => (
+                    AuditAction::AdminActionConfirmed,
+                    format!("{} requested by {}", action, request.requested_by),
+                ),
+            };
+        let confirmed = audit_action == AuditAction::AdminActionConfirmed;
+
+        self.record_audit_event(AuditEvent {
+            details: Some(details),
+            principal: Some(principal.to_string()),
+            ..AuditEvent::new(
+                audit_action,
+                transaction.client_id,
+                transaction.transaction_id,
+            )
+        })?;
+
+        Ok(confirmed)
+    }
+
+    /// Current balances of the client, its transactions ordered by id and the dispute chains
+    /// of the contested ones.
+    pub fn client_statement(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<(Account, Vec<Transaction>, Vec<DisputeRecord>)> {
+        let account = self.retrieve_account(client_id)?;
+        let mut transactions = self.datastore.retrieve_client_transactions(client_id)?;
+        let mut disputes = vec![];
+
+        transactions.sort_by_key(|t| t.transaction_id);
+
+        for transaction in &transactions {
+            disputes.extend(
+                self.datastore
+                    .retrieve_dispute_chain(transaction.transaction_id)?,
+            );
+        }
+
+        Ok((account, transactions, disputes))
+    }
+
+    pub fn dispute_chain(&self, transaction_id: u32) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        self.datastore.retrieve_dispute_chain(transaction_id)
+    }
+
+    /// Disputes a transaction on behalf of the client, with the usual checks, and keeps the
+    /// evidence with the recorded dispute step, which is returned.
+    pub fn open_dispute(
+        &mut self,
+        client_id: u16,
+        transaction_id: u32,
+        evidence: DisputeEvidence,
+    ) -> PaymentEngineResult<DisputeRecord> {
+        if !evidence.is_valid() {
+            return Err(PaymentEngineError::InvalidDisputeEvidence);
+        }
+
+        let transaction = Transaction {
+            reason_code: evidence.reason_code.clone(),
+            provenance: Some(Provenance::Internal {
+                subsystem: "dispute".to_string(),
+            }),
+            ..Transaction::new(TransactionType::Dispute, client_id, transaction_id, None)
+        };
+        let mut account = self.retrieve_account(client_id)?;
+
+        self.dispute_evidence = Some(evidence);
+        let result = self.process_transaction(&transaction, &mut account);
+        self.dispute_evidence = None;
+        result?;
+
+        self.dispute_chain(transaction_id)?
+            .pop()
+            .ok_or(PaymentEngineError::DisputedTransactionNotFound)
+    }
+
+    pub fn legal_holds(&self) -> Vec<LegalHold> {
+        self.legal_holds.list()
+    }
+
+    /// Places the client under legal hold, which blocks erasure of its data until released.
+    pub fn place_legal_hold(
+        &mut self,
+        client_id: u16,
+        reason: &str,
+        principal: &str,
+    ) -> PaymentEngineResult<LegalHold> {
+        let hold = LegalHold {
+            client_id,
+            reason: reason.to_string(),
+            principal: principal.to_string(),
+            placed_at: self.clock.now(),
+        };
+
+        self.legal_holds.place(hold.clone())?;
+        self.record_client_audit(
+            AuditAction::LegalHoldPlaced,
+            client_id,
+            reason.to_string(),
+            principal,
+        )?;
+
+        Ok(hold)
+    }
+
+    /// Dispute steps waiting for their locked account to be unlocked, by client and in arrival
+    /// order.
+    pub fn locked_queue(&self) -> Vec<Transaction> {
+        self.locked_queue.list()
+    }
+
+    /// Unlocks the account and applies the dispute steps queued while it was locked, oldest
+    /// first. Steps which fail are logged and dropped. A queued chargeback locks the account
+    /// again, so the steps after it are queued once more.
+    pub fn unlock_account(
+        &mut self,
+        client_id: u16,
+        principal: &str,
+    ) -> PaymentEngineResult<Account> {
+        let mut account = self.retrieve_account(client_id)?;
+        let steps = self.locked_queue.take(client_id)?;
+
+        account.locked = false;
+        self.save_account_to_datastore(&mut account)?;
+        self.record_client_audit(
+            AuditAction::AccountUnlocked,
+            client_id,
+            format!("{} queued dispute steps", steps.len()),
+            principal,
+        )?;
+
+        for step in steps {
+            if let Err(e) = self.process(&step) {
+                warn!("{} | {:?}", e, step);
+            }
+        }
+
+        self.flush()?;
+        self.retrieve_account(client_id)
+    }
+
+    pub fn release_legal_hold(
+        &mut self,
+        client_id: u16,
+        principal: &str,
+    ) -> PaymentEngineResult<()> {
+        let hold = self
+            .legal_holds
+            .release(client_id)?
+            .ok_or(PaymentEngineError::LegalHoldNotFound)?;
+
+        self.record_client_audit(
+            AuditAction::LegalHoldReleased,
+            client_id,
+            format!("placed by {} at {}", hold.principal, hold.placed_at),
+            principal,
+        )
+    }
+
+    /// Replaces the memo and counterparty of the client's stored and pending transactions with
+    /// `erasure::ERASED`, keeping amounts, ids and dispute state, so balances and the ledger are
+    /// unchanged. Fields already erased are skipped, so an interrupted erasure can be requested
+    /// again. A client under legal hold is refused, and both outcomes are audited.
+    pub fn erase_client(
+        &mut self,
+        client_id: u16,
+        principal: &str,
+    ) -> PaymentEngineResult<ErasureSummary> {
+        if let Some(hold) = self.legal_holds.get(client_id) {
+            let details = format!("legal hold: {}", hold.reason);
+
+            self.record_client_audit(AuditAction::ErasureBlocked, client_id, details, principal)?;
+
+            return Err(PaymentEngineError::ClientUnderLegalHold { client_id });
+        }
+
+        let mut summary = ErasureSummary {
+            client_id,
+            ..ErasureSummary::default()
+        };
+
+        for transaction in self.datastore.retrieve_client_transactions(client_id)? {
+            if let Some(erased) = erasure::erase(&transaction) {
+                self.datastore.save_transaction(erased)?;
+                summary.transactions += 1;
+            }
+        }
+
+        for transaction in self.datastore.retrieve_pending_transactions()? {
+            if transaction.client_id != client_id {
+                continue;
+            }
+
+            if let Some(erased) = erasure::erase(&transaction) {
+                self.datastore.save_pending_transaction(erased)?;
+                summary.pending += 1;
+            }
+        }
+
+        self.flush()?;
+        self.record_client_audit(
+            AuditAction::ClientErased,
+            client_id,
+            format!(
+                "{} transactions, {} pending transactions",
+                summary.transactions, summary.pending
+            ),
+            principal,
+        )?;
+
+        Ok(summary)
+    }
+
+    pub fn reservations(&self) -> Vec<Reservation> {
+        self.reservations.list()
+    }
+
+    /// Sets funds aside for `ttl`, so they cannot be withdrawn until the reservation is
+    /// committed, cancelled or expires.
+    pub fn reserve(
+        &mut self,
+        client_id: u16,
+        amount: Decimal,
+        ttl: Duration,
+    ) -> PaymentEngineResult<Reservation> {
+        if amount <= Decimal::ZERO {
+            return Err(PaymentEngineError::InvalidReservationAmount);
+        }
+
+        let account = self.retrieve_account(client_id)?;
+
+        if account.locked {
+            return Err(PaymentEngineError::AccountLocked);
+        }
+
+        if amount > self.withdrawable(&account, None)? {
+            return Err(PaymentEngineError::InsufficientAccountFunds);
+        }
+
+        let reservation = Reservation::new(client_id, amount, self.clock.now(), ttl);
+
+        self.reservations.insert(reservation.clone())?;
+        self.timers.schedule(
+            reservation.expires_at,
+            TimerAction::ExpireReservation {
+                token: reservation.token.clone(),
+            },
+        )?;
+
+        Ok(reservation)
+    }
+
+    /// Withdraws the reserved funds as transaction `transaction_id`, or as an internal
+    /// transaction with a generated id when none is given. The reservation is kept when the
+    /// withdrawal fails.
+    pub fn commit_reservation(
+        &mut self,
+        token: &str,
+        transaction_id: Option<u32>,
+    ) -> PaymentEngineResult<Account> {
+        let reservation = self.reservations.take(token, self.clock.now())?;
+        let transaction_id = match transaction_id.map_or_else(|| self.next_internal_id(), Ok) {
+            Ok(transaction_id) => transaction_id,
+            Err(e) => {
+                self.reservations.insert(reservation)?;
+                return Err(e);
+            }
+        };
+        let transaction = Transaction {
+            memo: Some(format!("reservation {}", reservation.token)),
+            provenance: Some(Provenance::Internal {
+                subsystem: "reservation".to_string(),
+            }),
+            ..Transaction::new(
+                TransactionType::Withdrawal,
+                reservation.client_id,
+                transaction_id,
+                Some(reservation.amount),
+            )
+        };
+        let mut account = self.retrieve_account(reservation.client_id)?;
+
+        if let Err(e) = self.process_transaction(&transaction, &mut account) {
+            self.reservations.insert(reservation)?;
+            return Err(e);
+        }
+
+        Ok(account)
+    }
+
+    fn check_internal_id(&self, transaction: &Transaction) {
+        let is_new = matches!(
+            transaction.r#type,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Convert
+                | TransactionType::Authorize
+        );
+
+        if is_new && self.config.ids.contains(transaction.transaction_id) {
+            warn!(
+                "Transaction {} uses an id reserved for internal transactions",
+                transaction.transaction_id
+            );
+        }
+    }
+
+    /// Id for a transaction created by the engine. Ids already taken, e.g. by a partner file
+    /// using the reserved range, are skipped.
+    fn next_internal_id(&mut self) -> PaymentEngineResult<u32> {
+        loop {
+            let transaction_id = self.ids.next_id()?;
+
+            match self.datastore.retrieve_transaction(transaction_id)? {
+                Some(_) => warn!("Internal transaction id {} already taken", transaction_id),
+                None => return Ok(transaction_id),
+            }
+        }
+    }
+
+    pub fn cancel_reservation(&mut self, token: &str) -> PaymentEngineResult<()> {
+        match self.reservations.take(token, self.clock.now()) {
+            Ok(_) | Err(PaymentEngineError::ReservationExpired) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn timers(&self) -> Vec<Timer> {
+        self.timers.list()
+    }
+
+    /// Executes the timers due at `now` through the usual handlers and returns how many ran.
+    /// Timers whose subject has moved on, e.g. a dispute resolved in the meantime, do nothing.
+    pub fn run_due_timers(&mut self, now: DateTime<Utc>) -> PaymentEngineResult<usize> {
+        let due = self.timers.take_due(now)?;
+
+        for timer in &due {
+            if let Err(e) = self.run_timer(&timer.action) {
+                warn!("{} | {:?}", e, timer);
+            }
+        }
+
+        Ok(due.len())
+    }
+
+    fn run_timer(&mut self, action: &TimerAction) -> PaymentEngineResult<()> {
+        match action {
+            TimerAction::ExpireReservation { token } => self.cancel_reservation(token),
+            TimerAction::DisputeDeadline {
+                client_id,
+                transaction_id,
+                sequence,
+            } => {
+                let still_open = matches!(
+                    self.datastore.retrieve_dispute_chain(*transaction_id)?.last(),
+                    Some(record) if record.sequence == *sequence
+                        && record.r#type == TransactionType::Dispute
+                );
+
+                if !still_open {
+                    return Ok(());
+                }
+
+                let transaction = Transaction {
+                    memo: Some("dispute deadline".to_string()),
+                    provenance: Some(Provenance::Internal {
+                        subsystem: "timer".to_string(),
+                    }),
+                    ..Transaction::new(TransactionType::Resolve, *client_id, *transaction_id, None)
+                };
+
+                self.process(&transaction).map(|_| ())
+            }
+        }
+    }
+
+    fn retrieve_pending_transaction(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Transaction> {
+        match self
+            .datastore
+            .retrieve_pending_transactions()?
+            .into_iter()
+            .find(|t| t.transaction_id == transaction_id)
+        {
+            Some(transaction) => Ok(transaction),
+            None => Err(PaymentEngineError::PendingTransactionNotFound),
+        }
+    }
+
+    /// Amount of the transaction in the base currency, which deposit caps and the approval
+    /// threshold are set in. Amounts in other currencies are converted at the rate of the rate
+    /// table, so a rate is needed only while a cap or threshold is configured.
+    fn amount_for_limits(&self, transaction: &Transaction) -> PaymentEngineResult<Option<Decimal>> {
+        let limited = self.config.limits.max_total_deposits.is_some()
+            || self.config.approval_threshold.is_some();
+
+        match (
+            &transaction.currency,
+            &self.config.base_currency,
+            transaction.amount,
+        ) {
+            (Some(currency), Some(base), Some(amount)) if limited && currency != base => amount
+                .checked_mul(self.config.rates.rate(currency, base)?)
+                .map(Some)
+                .ok_or(PaymentEngineError::AmountOverflow),
+            _ => Ok(transaction.amount),
+        }
+    }
+
+    fn requires_approval(
+        &self,
+        transaction: &Transaction,
+        amount_in_base: Option<Decimal>,
+    ) -> bool {
+        match (self.config.approval_threshold, amount_in_base) {
+            (Some(threshold), Some(amount)) => {
+                matches!(
+                    transaction.r#type,
+                    TransactionType::Deposit
+                        | TransactionType::Withdrawal
+                        | TransactionType::Transfer
+                ) && amount > threshold
+            }
+            _ => false,
+        }
+    }
+
+    /// Asks the configured fraud check about the transaction, allowing it without a check or
+    /// when the check does not apply.
+    fn check_fraud(&self, transaction: &Transaction) -> Decision {
+        match &self.config.fraud_check {
+            Some(check) if check.applies_to(transaction) => check.decide(transaction),
+            _ => Decision::Allow,
+        }
+    }
+
+    fn park_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        info!(
+            "Transaction {} exceeds approval threshold, waiting for approval",
+            transaction.transaction_id
+        );
+        self.record_audit(AuditAction::TransactionParked, &transaction)?;
+        self.datastore.save_pending_transaction(transaction)?;
+
+        Ok(())
+    }
+
+    fn reload_config(&mut self, limit_tracker: &mut RunLimitTracker) -> PaymentEngineResult<()> {
+        let config = match self
+            .config_updates
+            .as_ref()
+            .and_then(|updates| updates.try_iter().last())
+        {
+            Some(file) => self.config.reloaded(file),
+            None => return Ok(()),
+        };
+
+        if !self.apply_config(config)?.is_empty() {
+            limit_tracker.set_limits(self.config.limits.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Reads the configuration file at `path` again and applies its settings, as a watched file
+    /// is between rows of a batch, and returns what changed. An invalid file changes nothing.
+    pub fn reload_config_file(&mut self, path: &Path) -> PaymentEngineResult<Vec<String>> {
+        let config = self.config.reloaded(ServiceConfig::load(path)?);
+
+        self.apply_config(config)
+    }
+
+    /// Replaces the configuration and records what changed in the audit log.
+    fn apply_config(&mut self, config: ServiceConfig) -> PaymentEngineResult<Vec<String>> {
+        let changes = self.config.changes(&config);
+
+        if changes.is_empty() {
+            return Ok(changes);
+        }
+
+        info!("Configuration reloaded, {}", changes.join(", "));
+
+        if config.detect_sequence_gaps && self.sequence_tracker.is_none() {
+            self.sequence_tracker = Some(SequenceTracker::new());
+        } else if !config.detect_sequence_gaps {
+            self.report_sequence_issues();
+            self.sequence_tracker = None;
+        }
+
+        self.config = config;
+        self.record_audit_event(AuditEvent::with_details(
+            AuditAction::ConfigReloaded,
+            changes.join(", "),
+        ))?;
+
+        Ok(changes)
+    }
+
+    fn record_audit(
+        &mut self,
+        action: AuditAction,
+        transaction: &Transaction,
+    ) -> PaymentEngineResult<()> {
+        self.record_audit_event(AuditEvent {
+            details: transaction.provenance.as_ref().map(Provenance::to_string),
+            ..AuditEvent::new(action, transaction.client_id, transaction.transaction_id)
+        })
+    }
+
+    fn record_admin_audit(
+        &mut self,
+        action: AuditAction,
+        transaction: &Transaction,
+        principal: &str,
+    ) -> PaymentEngineResult<()> {
+        self.record_audit_event(AuditEvent {
+            details: transaction.provenance.as_ref().map(Provenance::to_string),
+            principal: Some(principal.to_string()),
+            ..AuditEvent::new(action, transaction.client_id, transaction.transaction_id)
+        })
+    }
+
+    fn record_client_audit(
+        &mut self,
+        action: AuditAction,
+        client_id: u16,
+        details: String,
+        principal: &str,
+    ) -> PaymentEngineResult<()> {
+        self.record_audit_event(AuditEvent {
+            client_id: Some(client_id),
+            principal: Some(principal.to_string()),
+            ..AuditEvent::with_details(action, details)
+        })
+    }
+
+    /// Records the event in the audit log. Inside a unit of work it is held back until the
+    /// commit, so changes which are rolled back leave no trace in the log.
+    fn record_audit_event(&mut self, event: AuditEvent) -> PaymentEngineResult<()> {
+        let event = AuditEvent {
+            timestamp: self.clock.now(),
+            ..event
+        };
+
+        if self.datastore.is_active() {
+            self.unaudited.push(event);
+            return Ok(());
+        }
+
+        self.write_audit_event(event)
+    }
+
+    fn write_audit_event(&self, event: AuditEvent) -> PaymentEngineResult<()> {
+        match &self.audit_log {
+            Some(audit_log) => audit_log.record(event),
+            None => Ok(()),
+        }
+    }
+
+    fn report_sequence_issues(&self) {
+        if let Some(tracker) = &self.sequence_tracker {
+            if !tracker.issues().is_empty() {
+                warn!(
+                    "Detected {} transaction sequence issues",
+                    tracker.issues().len()
+                );
+            }
+        }
+    }
+
+    fn track_sequence(&mut self, transaction: &Transaction) {
+        let tracker = match self.sequence_tracker.as_mut() {
+            Some(tracker) => tracker,
+            None => return,
+        };
+
+        if !matches!(
+            transaction.r#type,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Convert
+                | TransactionType::Authorize
+        ) {
+            return;
+        }
+
+        if let Some(issue) = tracker.observe(transaction.client_id, transaction.transaction_id) {
+            warn!("{}", issue);
+        }
+    }
+
+    /// What the `locked_disputes` policy does with the transaction, `Allow` unless it is a
+    /// dispute step of a locked account.
+    fn locked_action(&self, transaction: &Transaction, account: &Account) -> LockedAction {
+        if account.locked {
+            self.config.locked_disputes.action(&transaction.r#type)
+        } else {
+            LockedAction::Allow
+        }
+    }
+
+    fn queue_until_unlock(&mut self, transaction: &Transaction) -> PaymentEngineResult<()> {
+        info!(
+            "{:?} {} of locked account {} waits until the account is unlocked",
+            transaction.r#type, transaction.transaction_id, transaction.client_id
+        );
+        self.record_audit(AuditAction::LockedAccountQueued, transaction)?;
+        self.locked_queue.push(transaction.clone())
+    }
+
+    fn is_enabled(&self, feature: Feature, client_id: u16) -> bool {
+        self.config.flags.is_enabled(feature, client_id)
+    }
+
+    fn process_transaction(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let _span = profiling::span("process_transaction");
+
+        self.log_ahead(transaction)?;
+
+        // A representment reverses the chargeback which locked the account.
+        let on_locked_account =
+            account.locked && transaction.r#type != TransactionType::Representment;
+
+        match self.locked_action(transaction, account) {
+            LockedAction::Allow => {}
+            LockedAction::Queue => return self.queue_until_unlock(transaction),
+            LockedAction::Reject => return Err(PaymentEngineError::DisputeOnLockedAccount),
+        }
+
+        if on_locked_account && self.is_enabled(Feature::StrictLocking, account.client_id) {
+            return Err(PaymentEngineError::AccountLocked);
+        }
+
+        if let Some(code) = &transaction.reason_code {
+            let has_reason = matches!(
+                transaction.r#type,
+                TransactionType::Dispute
+                    | TransactionType::Chargeback
+                    | TransactionType::Representment
+            );
+
+            if has_reason && !self.config.reason_codes.contains(code) {
+                return Err(PaymentEngineError::UnknownReasonCode { code: code.clone() });
+            }
+        }
+
+        let rounded;
+        let mut adjustment = Decimal::ZERO;
+        let transaction = match transaction.amount {
+            Some(amount) if amount.scale() > self.config.rounding.input_decimals => {
+                let rounded_amount = self.config.rounding.round_input(amount);
+
+                adjustment = model::checked_sub(amount, rounded_amount)?;
+                rounded = Transaction {
+                    amount: Some(rounded_amount),
+                    ..transaction.clone()
+                };
+                &rounded
+            }
+            _ => transaction,
+        };
+
+        let handler_span = profiling::span(handler_name(&transaction.r#type));
+
+        match transaction.r#type {
+            TransactionType::Deposit => self.handle_deposit(transaction, account),
+            TransactionType::Withdrawal => self.handle_withdrawal(transaction, account),
+            TransactionType::Transfer => self.handle_transfer(transaction, account),
+            TransactionType::Dispute => self.handle_dispute(transaction, account),
+            TransactionType::Resolve => self.handle_resolve(transaction, account),
+            TransactionType::Chargeback => self.handle_chargeback(transaction, account),
+            TransactionType::Representment => self.handle_representment(transaction, account),
+            TransactionType::Convert => self.handle_convert(transaction, account),
+            TransactionType::Refund => self.handle_refund(transaction, account),
+            TransactionType::Authorize => self.handle_authorize(transaction, account),
+            TransactionType::Capture => {
+                self.handle_settlement(transaction, account, Settlement::Captured)
+            }
+            TransactionType::Release => {
+                self.handle_settlement(transaction, account, Settlement::Released)
+            }
+            TransactionType::Correction => self.handle_correction(transaction, account),
+        }?;
+        drop(handler_span);
+
+        // Without strict locking, activity on locked accounts is applied but left for an
+        // operator to review.
+        if on_locked_account {
+            warn!(
+                "Applied {:?} {} to locked account {}",
+                transaction.r#type, transaction.transaction_id, account.client_id
+            );
+            self.record_audit_event(AuditEvent::new(
+                AuditAction::LockedAccountActivity,
+                account.client_id,
+                transaction.transaction_id,
+            ))?;
+        }
+
+        if !adjustment.is_zero() {
+            self.rounding_drift
+                .record_input(transaction.client_id, adjustment)?;
+        }
+
+        // Dispute steps are recorded with the amount they concern in `record_dispute_step`.
+        let moves_funds = matches!(
+            transaction.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        );
+
+        if let (Some(analytics), Some(amount), true) =
+            (self.analytics.as_mut(), transaction.amount, moves_funds)
+        {
+            analytics.record(transaction.client_id, &transaction.r#type, amount)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_deposit(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let amount = match transaction.amount {
+            Some(amount) => amount,
+            None => return Err(PaymentEngineError::NoAmount),
+        };
+        let currency = self.balance_currency(transaction)?;
+
+        account.book_in(
+            currency.as_ref(),
+            amount,
+            self.booking_rate(currency.as_ref()),
+        )?;
+
+        self.datastore.save_transaction(transaction.clone())?;
+        self.save_account_to_datastore(account)?;
+
+        Ok(())
+    }
+
+    fn handle_withdrawal(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let currency = self.balance_currency(transaction)?;
+        let amount = match transaction.amount {
+            Some(amount) => {
+                if amount > self.withdrawable(account, currency.as_ref())? {
+                    return Err(PaymentEngineError::InsufficientAccountFunds);
+                } else {
+                    amount
+                }
+            }
+            None => return Err(PaymentEngineError::NoAmount),
+        };
+        account.adjust_in(currency.as_ref(), -amount, Decimal::ZERO, -amount)?;
+
+        self.datastore.save_transaction(transaction.clone())?;
+        self.save_account_to_datastore(account)?;
+
+        Ok(())
+    }
+
+    /// Debits the client and credits `to_client` as one transaction, so a dispute of the
+    /// transfer can find both sides through its id. Nothing is saved unless both sides can be
+    /// adjusted.
+    fn handle_transfer(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let amount = match transaction.amount {
+            Some(amount) => amount,
+            None => return Err(PaymentEngineError::NoAmount),
+        };
+        let mut recipient = match transaction.to_client {
+            Some(to_client) if to_client != account.client_id => {
+                self.retrieve_account(to_client)?
+            }
+            _ => return Err(PaymentEngineError::InvalidTransferRecipient),
+        };
+
+        if recipient.locked && self.is_enabled(Feature::StrictLocking, recipient.client_id) {
+            return Err(PaymentEngineError::AccountLocked);
+        }
+
+        let currency = self.balance_currency(transaction)?;
+
+        if amount > self.withdrawable(account, currency.as_ref())? {
+            return Err(PaymentEngineError::InsufficientAccountFunds);
+        }
+
+        account.adjust_in(currency.as_ref(), -amount, Decimal::ZERO, -amount)?;
+        recipient.book_in(
+            currency.as_ref(),
+            amount,
+            self.booking_rate(currency.as_ref()),
+        )?;
+
+        self.datastore.save_transaction(transaction.clone())?;
+        self.save_account_to_datastore(account)?;
+        self.save_account_to_datastore(&mut recipient)?;
+
+        Ok(())
+    }
+
+    /// Moves the amount from the balance in `currency` to the one in `to_currency`, converted at
+    /// the rate of the rate table and rounded like transaction amounts. The applied rate is
+    /// recorded in the audit log.
+    fn handle_convert(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let amount = match transaction.amount {
+            Some(amount) => amount,
+            None => return Err(PaymentEngineError::NoAmount),
+        };
+        let from = self.balance_currency(transaction)?;
+        let to = self.balance_currency_of(transaction.to_currency.as_ref())?;
+
+        if from == to {
+            return Err(PaymentEngineError::InvalidConversion);
+        }
+
+        let base = self
+            .config
+            .base_currency
+            .clone()
+            .ok_or(PaymentEngineError::CurrencyNotEnabled)?;
+        let from_code = from.clone().unwrap_or_else(|| base.clone());
+        let to_code = to.clone().unwrap_or(base);
+        let rate = self.config.rates.rate(&from_code, &to_code)?;
+        let converted = self.config.rounding.round_input(
+            amount
+                .checked_mul(rate)
+                .ok_or(PaymentEngineError::AmountOverflow)?,
+        );
+
+        if amount > self.withdrawable(account, from.as_ref())? {
+            return Err(PaymentEngineError::InsufficientAccountFunds);
+        }
+
+        let mut converted_account = account.clone();
+
+        converted_account.adjust_in(from.as_ref(), -amount, Decimal::ZERO, -amount)?;
+        converted_account.book_in(to.as_ref(), converted, self.booking_rate(to.as_ref()))?;
+        *account = converted_account;
+
+        self.datastore.save_transaction(transaction.clone())?;
+        self.save_account_to_datastore(account)?;
+        self.record_audit_event(AuditEvent {
+            details: Some(format!(
+                "{} {} -> {} {} at {}",
+                amount, from_code, converted, to_code, rate
+            )),
+            ..AuditEvent::new(
+                AuditAction::CurrencyConverted,
+                transaction.client_id,
+                transaction.transaction_id,
+            )
+        })
+    }
+
+    /// Account credited by a disputed transfer. Only the client who sent the transfer can
+    /// dispute it, which `retrieve_referenced_transaction` checks.
+    fn transfer_recipient(&self, transfer: &Transaction) -> PaymentEngineResult<Account> {
+        match transfer.to_client {
+            Some(to_client) => self.retrieve_account(to_client),
+            None => Err(PaymentEngineError::InvalidTransferRecipient),
+        }
+    }
+
+    /// Gives back part or all of the deposit with the transaction id, in the currency of the
+    /// deposit. The refunded total is kept on the deposit, so refunds cannot exceed it and a
+    /// later dispute only holds what is left of it.
+    fn handle_refund(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let amount = match transaction.amount {
+            Some(amount) => amount,
+            None => return Err(PaymentEngineError::NoAmount),
+        };
+        let mut deposit = self.retrieve_referenced_transaction(transaction)?;
+        let charged_back = matches!(
+            self.datastore
+                .retrieve_dispute_chain(deposit.transaction_id)?
+                .last(),
+            Some(record) if record.r#type == TransactionType::Chargeback
+        );
+
+        if deposit.r#type != TransactionType::Deposit || deposit.disputed || charged_back {
+            return Err(PaymentEngineError::RefundNotAllowed);
+        }
+
+        let refundable = model::checked_sub(deposit.amount.unwrap_or_default(), deposit.refunded)?;
+
+        if amount > refundable {
+            return Err(PaymentEngineError::OverRefund { refundable });
+        }
+
+        let currency = self.balance_currency(&deposit)?;
+
+        if amount > self.withdrawable(account, currency.as_ref())? {
+            return Err(PaymentEngineError::InsufficientAccountFunds);
+        }
+
+        account.adjust_in(currency.as_ref(), -amount, Decimal::ZERO, -amount)?;
+        deposit.refunded = model::checked_add(deposit.refunded, amount)?;
+
+        self.datastore.save_transaction(deposit)?;
+        self.save_account_to_datastore(account)
+    }
+
+    /// Holds `amount` of the available funds until the authorization is captured or released.
+    fn handle_authorize(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let amount = match transaction.amount {
+            Some(amount) => amount,
+            None => return Err(PaymentEngineError::NoAmount),
+        };
+        let currency = self.balance_currency(transaction)?;
+
+        if amount > self.withdrawable(account, currency.as_ref())? {
+            return Err(PaymentEngineError::InsufficientAccountFunds);
+        }
+
+        account.adjust_in(currency.as_ref(), -amount, amount, Decimal::ZERO)?;
+
+        self.datastore.save_transaction(transaction.clone())?;
+        self.save_account_to_datastore(account)
+    }
+
+    /// Closes the open authorization with the transaction id. A capture settles its amount of
+    /// the held funds like a withdrawal, all of them when it has no amount, and returns the rest
+    /// to available; a release returns all of them.
+    fn handle_settlement(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+        settlement: Settlement,
+    ) -> PaymentEngineResult<()> {
+        let mut authorization = self.retrieve_referenced_transaction(transaction)?;
+
+        if authorization.r#type != TransactionType::Authorize || authorization.settled.is_some() {
+            return Err(PaymentEngineError::AuthorizationNotOpen);
+        }
+
+        let authorized = authorization.amount.ok_or(PaymentEngineError::NoAmount)?;
+        let captured = match settlement {
+            Settlement::Captured => transaction.amount.unwrap_or(authorized),
+            Settlement::Released => Decimal::ZERO,
+        };
+
+        if captured > authorized {
+            return Err(PaymentEngineError::OverCapture { authorized });
+        }
+
+        let released = model::checked_sub(authorized, captured)?;
+        let currency = self.balance_currency(&authorization)?;
+
+        account.adjust_in(currency.as_ref(), released, -authorized, -captured)?;
+        authorization.settled = Some(settlement);
+
+        self.datastore.save_transaction(authorization)?;
+        self.save_account_to_datastore(account)
+    }
+
+    /// Replaces the amount of a deposit or withdrawal which was never disputed or refunded and
+    /// applies the difference to the account. Correcting to the stored amount changes nothing,
+    /// so a correction can be sent again safely. Both amounts are recorded in the audit log.
+    fn handle_correction(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let amount = match transaction.amount {
+            Some(amount) => amount,
+            None => return Err(PaymentEngineError::NoAmount),
+        };
+        let mut original = self.retrieve_referenced_transaction(transaction)?;
+        let ever_disputed = original.disputed
+            || !self
+                .datastore
+                .retrieve_dispute_chain(original.transaction_id)?
+                .is_empty();
+        let is_deposit = match original.r#type {
+            TransactionType::Deposit => true,
+            TransactionType::Withdrawal => false,
+            _ => return Err(PaymentEngineError::CorrectionNotAllowed),
+        };
+
+        if ever_disputed || !original.refunded.is_zero() {
+            return Err(PaymentEngineError::CorrectionNotAllowed);
+        }
+
+        let previous = original.amount.unwrap_or_default();
+
+        if previous == amount {
+            return Ok(());
+        }
+
+        // A larger deposit credits the difference, a larger withdrawal debits it.
+        let delta = if is_deposit {
+            model::checked_sub(amount, previous)?
+        } else {
+            model::checked_sub(previous, amount)?
+        };
+        let currency = self.balance_currency(&original)?;
+
+        if -delta > self.withdrawable(account, currency.as_ref())? {
+            return Err(PaymentEngineError::InsufficientAccountFunds);
+        }
+
+        account.adjust_in(currency.as_ref(), delta, Decimal::ZERO, delta)?;
+        original.amount = Some(amount);
+
+        self.datastore.save_transaction(original)?;
+        self.save_account_to_datastore(account)?;
+        self.record_audit_event(AuditEvent {
+            details: Some(format!("amount {} -> {}", previous, amount)),
+            ..AuditEvent::new(
+                AuditAction::TransactionCorrected,
+                transaction.client_id,
+                transaction.transaction_id,
+            )
+        })
+    }
+
+    fn handle_dispute(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let referenced_transaction = self.retrieve_referenced_transaction(transaction)?;
+        let referenced_transaction_id = referenced_transaction.transaction_id;
+
+        if referenced_transaction.disputed {
+            return Err(PaymentEngineError::TransactionAlreadyDisputed);
+        }
+
+        self.check_open_disputes(transaction)?;
+
+        let amount = disputable_amount(&referenced_transaction)?;
+
+        let currency = self.balance_currency(&referenced_transaction)?;
+        let currency = currency.as_ref();
+
+        match referenced_transaction.r#type {
+            TransactionType::Deposit => {
+                account.adjust_in(currency, -amount, amount, Decimal::ZERO)?
+            }
+            TransactionType::Withdrawal
+                if !self.is_enabled(Feature::DepositOnlyDisputes, account.client_id) =>
+            {
+                account.adjust_in(currency, Decimal::ZERO, amount, amount)?
+            }
+            // The transferred funds are held on the recipient until the dispute is closed.
+            TransactionType::Transfer => {
+                let mut recipient = self.transfer_recipient(&referenced_transaction)?;
+
+                recipient.adjust_in(currency, -amount, amount, Decimal::ZERO)?;
+                self.save_account_to_datastore(&mut recipient)?;
+            }
+            _ => return Err(PaymentEngineError::InvalidDisputedTransactionType),
+        }
+
+        self.datastore
+            .set_transaction_disputed(referenced_transaction_id, true)?;
+        self.save_account_to_datastore(account)?;
+        self.record_dispute_step(transaction, amount)?;
+        self.schedule_dispute_deadline(transaction)
+    }
+
+    /// Rejects the dispute when the client already has `max_open_disputes` open ones, and
+    /// records the rejection in the audit log for risk to follow up.
+    fn check_open_disputes(&mut self, transaction: &Transaction) -> PaymentEngineResult<()> {
+        let max = match self.config.max_open_disputes {
+            Some(max) => max as usize,
+            None => return Ok(()),
+        };
+        let open = self.datastore.count_open_disputes(transaction.client_id)?;
+
+        if open < max {
+            return Ok(());
+        }
+
+        warn!(
+            "Client {} reached the limit of {} open disputes",
+            transaction.client_id, max
+        );
+        self.record_audit_event(AuditEvent {
+            details: Some(format!("{} disputes open", open)),
+            ..AuditEvent::new(
+                AuditAction::DisputeLimitReached,
+                transaction.client_id,
+                transaction.transaction_id,
+            )
+        })?;
+
+        Err(PaymentEngineError::OpenDisputeLimitExceeded)
+    }
+
+    fn schedule_dispute_deadline(&mut self, transaction: &Transaction) -> PaymentEngineResult<()> {
+        let days = match self.config.dispute_deadline_days {
+            Some(days) => days,
+            None => return Ok(()),
+        };
+        let dispute = self
+            .datastore
+            .retrieve_dispute_chain(transaction.transaction_id)?
+            .pop()
+            .ok_or(PaymentEngineError::DisputedTransactionNotFound)?;
+
+        self.timers.schedule(
+            dispute.recorded_at + Duration::days(days.into()),
+            TimerAction::DisputeDeadline {
+                client_id: dispute.client_id,
+                transaction_id: dispute.transaction_id,
+                sequence: dispute.sequence,
+            },
+        )
+    }
+
+    fn handle_resolve(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let referenced_transaction = self.retrieve_referenced_transaction(transaction)?;
+        let referenced_transaction_id = referenced_transaction.transaction_id;
+
+        if !referenced_transaction.disputed {
+            return Err(PaymentEngineError::TransactionNotDisputed);
+        }
+
+        let amount = disputable_amount(&referenced_transaction)?;
+
+        let currency = self.balance_currency(&referenced_transaction)?;
+        let currency = currency.as_ref();
+
+        match referenced_transaction.r#type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                account.adjust_in(currency, amount, -amount, Decimal::ZERO)?
+            }
+            TransactionType::Transfer => {
+                let mut recipient = self.transfer_recipient(&referenced_transaction)?;
+
+                recipient.adjust_in(currency, amount, -amount, Decimal::ZERO)?;
+                self.save_account_to_datastore(&mut recipient)?;
+            }
+            _ => return Err(PaymentEngineError::InvalidDisputedTransactionType),
+        }
+
+        self.remove_disputed_state(referenced_transaction_id)?;
+        self.save_account_to_datastore(account)?;
+        self.record_dispute_step(transaction, amount)?;
+
+        Ok(())
+    }
+
+    fn handle_chargeback(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let referenced_transaction = self.retrieve_referenced_transaction(transaction)?;
+        let referenced_transaction_id = referenced_transaction.transaction_id;
+
+        if !referenced_transaction.disputed {
+            return Err(PaymentEngineError::TransactionNotDisputed);
+        }
+
+        let amount = disputable_amount(&referenced_transaction)?;
+
+        let currency = self.balance_currency(&referenced_transaction)?;
+        let currency = currency.as_ref();
+
+        match referenced_transaction.r#type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                account.adjust_in(currency, Decimal::ZERO, -amount, -amount)?;
+                account.locked = true;
+            }
+            // The held funds go back to the sender, whose account is locked like on every
+            // chargeback.
+            TransactionType::Transfer => {
+                let mut recipient = self.transfer_recipient(&referenced_transaction)?;
+
+                recipient.adjust_in(currency, Decimal::ZERO, -amount, -amount)?;
+                account.adjust_in(currency, amount, Decimal::ZERO, amount)?;
+                account.locked = true;
+                self.save_account_to_datastore(&mut recipient)?;
+            }
+            _ => return Err(PaymentEngineError::InvalidDisputedTransactionType),
+        }
+
+        self.remove_disputed_state(referenced_transaction_id)?;
+        self.save_account_to_datastore(account)?;
+        self.record_dispute_step(transaction, amount)?;
+
+        Ok(())
+    }
+
+    /// Credits a charged back deposit to the client again after the merchant won the second
+    /// presentment. Only the latest step of the dispute chain can be represented, and only when
+    /// it is a chargeback, so every chargeback is represented at most once.
+    fn handle_representment(
+        &mut self,
+        transaction: &Transaction,
+        account: &mut Account,
+    ) -> PaymentEngineResult<()> {
+        let referenced_transaction = self.retrieve_referenced_transaction(transaction)?;
+        let chain = self
+            .datastore
+            .retrieve_dispute_chain(transaction.transaction_id)?;
+        let follows_chargeback = matches!(
+            chain.last(),
+            Some(record) if record.r#type == TransactionType::Chargeback
+        );
+
+        if !follows_chargeback || referenced_transaction.r#type != TransactionType::Deposit {
+            return Err(PaymentEngineError::RepresentmentNotAllowed);
+        }
+
+        let amount = disputable_amount(&referenced_transaction)?;
+
+        let currency = self.balance_currency(&referenced_transaction)?;
+
+        account.adjust_in(currency.as_ref(), amount, Decimal::ZERO, amount)?;
+        self.save_account_to_datastore(account)?;
+        self.record_dispute_step(transaction, amount)?;
+
+        Ok(())
+    }
+
+    /// Links a dispute to the transaction it contests, a resolve or chargeback to the dispute it
+    /// closes and a representment to the chargeback it reverses, so the whole story of the
+    /// transaction can be read back later.
+    fn record_dispute_step(
+        &mut self,
+        transaction: &Transaction,
+        amount: Decimal,
+    ) -> PaymentEngineResult<()> {
+        let chain = self
+            .datastore
+            .retrieve_dispute_chain(transaction.transaction_id)?;
+        let evidence = match transaction.r#type {
+            TransactionType::Dispute => self.dispute_evidence.take().unwrap_or_default(),
+            _ => DisputeEvidence::default(),
+        };
+        let closed = match transaction.r#type {
+            TransactionType::Dispute => None,
+            TransactionType::Representment => chain.last(),
+            _ => chain
+                .iter()
+                .rev()
+                .find(|record| record.r#type == TransactionType::Dispute),
+        };
+        // A chargeback or representment without a reason code of its own keeps the one of the
+        // step it follows up on.
+        let reason_code = match transaction.r#type {
+            TransactionType::Resolve => None,
+            _ => transaction
+                .reason_code
+                .clone()
+                .or_else(|| closed.and_then(|record| record.reason_code.clone())),
+        };
+        let record = DisputeRecord {
+            transaction_id: transaction.transaction_id,
+            sequence: chain.len() as u32 + 1,
+            r#type: transaction.r#type.clone(),
+            client_id: transaction.client_id,
+            amount,
+            closes: closed.map(|record| record.sequence),
+            recorded_at: transaction.timestamp.unwrap_or_else(|| self.clock.now()),
+            reason_code,
+            documents: Documents(evidence.documents),
+        };
+        let details = match (&record.r#type, record.closes) {
+            (TransactionType::Representment, Some(closes)) => format!(
+                "{:?} #{} reverses chargeback #{}",
+                record.r#type, record.sequence, closes
+            ),
+            (_, Some(closes)) => format!(
+                "{:?} #{} closes dispute #{}",
+                record.r#type, record.sequence, closes
+            ),
+            (_, None) => format!("{:?} #{}", record.r#type, record.sequence),
+        };
+
+        self.risk_report.record(&record)?;
+
+        if let Some(analytics) = self.analytics.as_mut() {
+            analytics.record(record.client_id, &record.r#type, record.amount)?;
+        }
+
+        self.datastore.save_dispute_record(record)?;
+        self.record_audit_event(AuditEvent {
+            details: Some(details),
+            ..AuditEvent::new(
+                AuditAction::DisputeRecorded,
+                transaction.client_id,
+                transaction.transaction_id,
+            )
+        })
+    }
+
+    fn remove_disputed_state(&mut self, referenced_transaction_id: u32) -> PaymentEngineResult<()> {
+        self.datastore
+            .set_transaction_disputed(referenced_transaction_id, false)?;
+        self.datastore
+            .remove_transaction_from_cache(referenced_transaction_id)?;
+
+        Ok(())
+    }
+
+    /// Transaction a dispute step refers to, which has to belong to the client of the step.
+    fn retrieve_referenced_transaction(
+        &mut self,
+        transaction: &Transaction,
+    ) -> PaymentEngineResult<Transaction> {
+        match self
+            .datastore
+            .retrieve_transaction(transaction.transaction_id)?
+        {
+            Some(referenced) if referenced.client_id != transaction.client_id => {
+                Err(PaymentEngineError::DisputeClientMismatch {
+                    client_id: transaction.client_id,
+                    transaction_client_id: referenced.client_id,
+                })
+            }
+            Some(referenced_transaction) => Ok(referenced_transaction),
+            None => Err(PaymentEngineError::DisputedTransactionNotFound),
+        }
+    }
+
+    fn save_account_to_datastore(&mut self, account: &mut Account) -> PaymentEngineResult<()> {
+        if self.datastore.retrieve_account(account.client_id)?.as_ref() != Some(account) {
+            self.changed_accounts.insert(account.client_id);
+        }
+
+        self.datastore.save_account(account.clone())?;
+
+        Ok(())
+    }
+
+    /// Available funds in `currency` which are not set aside by reservations. Reservations are
+    /// made in the base currency, so balances in other currencies are available in full.
+    fn withdrawable(
+        &self,
+        account: &Account,
+        currency: Option<&Currency>,
+    ) -> PaymentEngineResult<Decimal> {
+        match currency {
+            Some(currency) => Ok(account.balances_in(Some(currency)).available),
+            None => model::checked_sub(
+                account.available,
+                self.reservations
+                    .reserved(account.client_id, self.clock.now())?,
+            ),
+        }
+    }
+
+    /// Currency whose balances `transaction` moves, or `None` for the base currency.
+    fn balance_currency(&self, transaction: &Transaction) -> PaymentEngineResult<Option<Currency>> {
+        self.balance_currency_of(transaction.currency.as_ref())
+    }
+
+    fn balance_currency_of(
+        &self,
+        currency: Option<&Currency>,
+    ) -> PaymentEngineResult<Option<Currency>> {
+        match (currency, &self.config.base_currency) {
+            (None, _) => Ok(None),
+            (Some(currency), Some(base)) if currency == base => Ok(None),
+            (Some(currency), Some(_)) => Ok(Some(currency.clone())),
+            (Some(_), None) => Err(PaymentEngineError::CurrencyNotEnabled),
+        }
+    }
+
+    /// Rate from `currency` to the base currency at which funds credited in it are booked, none
+    /// for the base currency or without a rate in the rate table.
+    fn booking_rate(&self, currency: Option<&Currency>) -> Option<Decimal> {
+        let base = self.config.base_currency.as_ref()?;
+
+        self.config.rates.rate(currency?, base).ok()
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Account> {
+        match self.datastore.retrieve_account(client_id)? {
+            None => Ok(Account::new(client_id)),
+            Some(account) => Ok(account),
+        }
+    }
+
+    fn report_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        let mut accounts = self.datastore.retrieve_all_accounts()?;
+
+        if self.config.report_mode == ReportMode::Changed {
+            accounts.retain(|account| self.changed_accounts.contains(&account.client_id));
+        }
+
+        Ok(accounts)
+    }
+
+    /// Accounts to report, one row per currency with a base currency, before rounding.
+    fn report_rows(&self) -> PaymentEngineResult<Vec<Account>> {
+        let accounts = self.report_accounts()?;
+
+        Ok(match &self.config.base_currency {
+            Some(base) => accounts
+                .iter()
+                .flat_map(|account| account.by_currency(base))
+                .collect(),
+            None => accounts,
+        })
+    }
+
+    /// Account report of the run so far in the configured format, kept in memory instead of
+    /// written, so the reports of two runs can be compared byte for byte.
+    pub fn render_report(&self) -> PaymentEngineResult<Vec<u8>> {
+        let rows = self.report_rows()?;
+
+        report::render_accounts(
+            self.config.report_format,
+            self.config.csv,
+            self.config.report_hash,
+            rows.iter()
+                .map(|account| self.config.rounding.round_output(account)),
+        )
+    }
+
+    fn write_accounts(&mut self) -> PaymentEngineResult<()> {
+        let _span = profiling::span("write_accounts");
+        let rows = self.report_rows()?;
+
+        self.check_balance_anomalies()?;
+
+        let config = &self.config;
+        let stage = config
+            .pipeline_depth
+            .filter(|_| self.reporter.is_none())
+            .map(|depth| {
+                ReportStage::start(
+                    config.report_format,
+                    config.csv,
+                    config.report_path.clone(),
+                    config.report_hash,
+                    depth,
+                )
+            });
+        let mut reported = Vec::with_capacity(rows.len());
+
+        // Rounding drift is only tracked in the base currency.
+        for account in rows {
+            let rounded = self.config.rounding.round_output(&account);
+            let adjustment = model::checked_sub(account.total, rounded.total)?;
+            let in_base_currency = account.currency == self.config.base_currency;
+
+            if in_base_currency && !adjustment.is_zero() {
+                self.rounding_drift
+                    .record_output(account.client_id, adjustment)?;
+            }
+
+            if let Some(stage) = &stage {
+                stage.write(rounded.clone());
+            }
+            reported.push(rounded);
+        }
+
+        match (self.reporter.as_mut(), stage) {
+            (Some(reporter), _) => reporter.report(&reported)?,
+            (None, Some(stage)) => stage.finish()?,
+            (None, None) => FileReporter {
+                format: self.config.report_format,
+                csv: self.config.csv,
+                path: self.config.report_path.clone(),
+                fingerprint: self.config.report_hash,
+            }
+            .report(&reported)?,
+        }
+        if self.reporter.is_none() {
+            self.outputs.extend(self.config.report_path.clone());
+        }
+        self.deliver_report(&reported)?;
+        self.report_rounding_drift()?;
+        self.write_risk_report()?;
+        self.write_analytics()?;
+        self.write_rejects()?;
+        self.write_dispute_liability()?;
+        self.write_revaluation()?;
+        self.write_stats()
+    }
+
+    /// Settings in effect, including any reloaded during the run.
+    pub fn config(&self) -> &ServiceConfig {
+        &self.config
+    }
+
+    /// Rows processed so far, by outcome.
+    pub fn run_counts(&self) -> RunCounts {
+        self.run_counts
+    }
+
+    /// Figures of the run so far: rows by outcome and transaction type, dispute steps applied
+    /// and throughput.
+    pub fn summary(&self) -> SummaryStats {
+        self.summary.stats(self.run_counts)
+    }
+
+    /// Rows processed so far by input source, in processing order.
+    pub fn source_counts(&self) -> &[SourceCounts] {
+        &self.source_counts
+    }
+
+    /// Files written so far: the account report, its deliveries and the side reports.
+    pub fn outputs(&self) -> &[PathBuf] {
+        &self.outputs
+    }
+
+    fn write_analytics(&mut self) -> PaymentEngineResult<()> {
+        if let (Some(analytics), Some(path)) = (&self.analytics, &self.config.analytics_path) {
+            analytics.write(path)?;
+            self.outputs.push(path.clone());
+        }
+
+        Ok(())
+    }
+
+    fn write_rejects(&mut self) -> PaymentEngineResult<()> {
+        if let (Some(rejects), Some(path)) = (&self.rejects, &self.config.rejects_path) {
+            rejects.write(path)?;
+            self.outputs.push(path.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Writes the balances of every account in other currencies than the base currency,
+    /// revalued at the end-of-day rates, to the revaluation report.
+    fn write_revaluation(&mut self) -> PaymentEngineResult<()> {
+        let path = match &self.config.revaluation_path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let revaluation = match &self.config.base_currency {
+            Some(base) => Revaluation::new(
+                base,
+                &self.datastore.retrieve_all_accounts()?,
+                &self.config.rates,
+                &self.config.eod_rates,
+                &self.config.rounding,
+            )?,
+            None => Revaluation::default(),
+        };
+
+        revaluation.write(&path)?;
+        self.outputs.push(path);
+
+        Ok(())
+    }
+
+    /// Writes the funds held for every dispute still open, which may have been opened in an
+    /// earlier run, to the dispute liability report.
+    fn write_dispute_liability(&mut self) -> PaymentEngineResult<()> {
+        let path = match &self.config.dispute_liability_path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let now = self.clock.now();
+        let mut liability = DisputeLiability::default();
+        let client_ids: BTreeSet<u16> = self
+            .datastore
+            .retrieve_all_accounts()?
+            .iter()
+            .map(|account| account.client_id)
+            .collect();
+
+        for client_id in client_ids {
+            for transaction in self.datastore.retrieve_client_transactions(client_id)? {
+                if !transaction.disputed {
+                    continue;
+                }
+
+                let chain = self
+                    .datastore
+                    .retrieve_dispute_chain(transaction.transaction_id)?;
+                let dispute = match chain
+                    .iter()
+                    .rev()
+                    .find(|record| record.r#type == TransactionType::Dispute)
+                {
+                    Some(dispute) => dispute,
+                    None => continue,
+                };
+                let holder = match transaction.r#type {
+                    TransactionType::Transfer => transaction.to_client.unwrap_or(client_id),
+                    _ => client_id,
+                };
+
+                liability.record(
+                    holder,
+                    self.balance_currency(&transaction)?,
+                    dispute.reason_code.as_deref(),
+                    now - dispute.recorded_at,
+                    dispute.amount,
+                )?;
+            }
+        }
+
+        liability.write(&path)?;
+        self.outputs.push(path);
+
+        Ok(())
+    }
+
+    /// Writes the summary of the run to the stats file, or logs it without one.
+    fn write_stats(&mut self) -> PaymentEngineResult<()> {
+        let stats = self.summary();
+
+        match &self.config.stats_path {
+            Some(path) => {
+                stats.write(path)?;
+                self.outputs.push(path.clone());
+            }
+            None => info!(
+                "{} rows in {:.3}s ({:.0} rows/s): {} applied, {} rejected ({} invalid), {} parked, \
+                 {} disputes opened, {} resolved, {} charged back",
+                stats.counts.rows,
+                stats.elapsed_seconds,
+                stats.rows_per_second,
+                stats.counts.applied,
+                stats.counts.rejected,
+                stats.invalid_rows,
+                stats.counts.parked,
+                stats.disputes_opened,
+                stats.disputes_resolved,
+                stats.chargebacks
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Delivers the account report to every destination configured for the tenant of the run.
+    fn deliver_report(&mut self, accounts: &[Account]) -> PaymentEngineResult<()> {
+        let run_at = self.clock.now();
+        let config = &self.config;
+        let deliveries = config
+            .deliveries
+            .iter()
+            .filter(|delivery| config.tenant.as_deref() == Some(delivery.tenant.as_str()));
+
+        for delivery in deliveries {
+            let path = delivery.deliver(accounts, run_at, config.csv)?;
+
+            info!("Delivered account report to {}", path.display());
+            self.outputs.push(path);
+        }
+
+        Ok(())
+    }
+
+    /// Logs the rounding adjustments of the run and writes them per client, when there are any.
+    fn report_rounding_drift(&mut self) -> PaymentEngineResult<()> {
+        if self.rounding_drift.is_empty() {
+            return Ok(());
+        }
+
+        let (input, output) = self.rounding_drift.total()?;
+
+        info!(
+            "Rounding drift of the run: input {}, output {}",
+            input, output
+        );
+
+        if let Some(path) = &self.config.rounding_drift_path {
+            self.rounding_drift.write(path)?;
+            self.outputs.push(path.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Compares the balance changes of the run with earlier runs when anomaly detection is
+    /// configured, before anything is reported. Flagged accounts are logged and written to the
+    /// anomaly report. A run failed by `fail_run` leaves the history as it was, so the rerun
+    /// with corrected input is compared with the same runs.
+    fn check_balance_anomalies(&mut self) -> PaymentEngineResult<()> {
+        let (config, history_path) = match (&self.config.anomaly, &self.config.balance_history_path)
+        {
+            (Some(config), Some(history_path)) => (config, history_path),
+            _ => return Ok(()),
+        };
+        let mut history = BalanceHistory::load(history_path)?;
+        let mut changed = self.datastore.retrieve_all_accounts()?;
+
+        changed.retain(|account| self.changed_accounts.contains(&account.client_id));
+        changed.sort_by_key(|account| account.client_id);
+
+        let anomalies = history.observe(&changed, config)?;
+
+        for anomaly in &anomalies {
+            warn!(
+                "Balance of client {} changed by {}, {:.1} standard deviations from its usual {:.4}",
+                anomaly.client, anomaly.change, anomaly.deviations, anomaly.mean
+            );
+        }
+
+        if let Some(path) = &self.config.balance_anomalies_path {
+            if !anomalies.is_empty() {
+                anomaly::write_anomalies(path, &anomalies)?;
+                self.outputs.push(path.clone());
+            }
+        }
+
+        if config.fail_run && !anomalies.is_empty() {
+            return Err(PaymentEngineError::BalanceAnomalies {
+                clients: anomalies.len(),
+            });
+        }
+
+        history.save(history_path)
+    }
+
+    fn write_risk_report(&mut self) -> PaymentEngineResult<()> {
+        match &self.config.risk_report_path {
+            Some(path) if !self.risk_report.is_empty() => {
+                self.risk_report.write(path)?;
+                self.outputs.push(path.clone());
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Amount a dispute step moves for the transaction: all of it, less what refunds gave back
+/// of a deposit.
+fn disputable_amount(transaction: &Transaction) -> PaymentEngineResult<Decimal> {
+    match transaction.amount {
+        Some(amount) if amount > transaction.refunded => {
+            model::checked_sub(amount, transaction.refunded)
+        }
+        _ => Err(PaymentEngineError::NoAmount),
+    }
+}
+
+/// Name the handler of the transaction type is profiled under.
+fn handler_name(r#type: &TransactionType) -> &'static str {
+    match r#type {
+        TransactionType::Deposit => "handle_deposit",
+        TransactionType::Withdrawal => "handle_withdrawal",
+        TransactionType::Transfer => "handle_transfer",
+        TransactionType::Dispute => "handle_dispute",
+        TransactionType::Resolve => "handle_resolve",
+        TransactionType::Chargeback => "handle_chargeback",
+        TransactionType::Representment => "handle_representment",
+        TransactionType::Convert => "handle_convert",
+        TransactionType::Refund => "handle_refund",
+        TransactionType::Authorize => "handle_authorize",
+        TransactionType::Capture => "handle_capture",
+        TransactionType::Release => "handle_release",
+        TransactionType::Correction => "handle_correction",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::approvals::{AdminAction, ApprovalBook};
+    use crate::checkpoint::{Checkpoint, CheckpointPolicy};
+    use crate::clock::FixedClock;
+    use crate::config::{ReportMode, ServiceConfig};
+    use crate::datastore::{DatastoreOperations, InMemoryDatastore, PickleDatastore};
+    use crate::erasure;
+    use crate::error::{PaymentEngineError, PaymentEngineResult};
+    use crate::flags::{FeatureFlags, Rollout};
+    use crate::ids::IdConfig;
+    use crate::limits::RunLimits;
+    use crate::locked::{LockedAction, LockedDisputePolicy};
+    use crate::manifest::RunCounts;
+    use crate::model::{
+        Account, DisputeEvidence, DisputeRecord, Documents, Settlement, Transaction,
+        TransactionType,
+    };
+    use crate::payment_service::PaymentService;
+    use crate::rates::RateTable;
+    use crate::rounding::RoundingConfig;
+    use crate::wal::WriteAheadLog;
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal::prelude::*;
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::num::{NonZeroU64, NonZeroUsize};
+    use std::sync::mpsc::channel;
+    use tempfile::{NamedTempFile, TempDir};
+
+    struct MockDatastore {
+        accounts: HashMap<u16, Account>,
+        transactions: Vec<Transaction>,
+        pending_transactions: Vec<Transaction>,
+        dispute_records: Vec<DisputeRecord>,
+    }
+
+    impl MockDatastore {
+        pub fn new(accounts: HashMap<u16, Account>, transactions: Vec<Transaction>) -> Self {
+            MockDatastore {
+                accounts,
+                transactions,
+                pending_transactions: vec![],
+                dispute_records: vec![],
+            }
+        }
+    }
+
+    impl DatastoreOperations for MockDatastore {
+        fn retrieve_transaction(
+            &mut self,
+            transaction_id: u32,
+        ) -> PaymentEngineResult<Option<Transaction>> {
+            Ok(self
+                .transactions
+                .iter()
+                .find(|t| t.transaction_id == transaction_id)
+                .cloned())
+        }
+
+        fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+            self.transactions
+                .retain(|t| t.transaction_id != transaction.transaction_id);
+            self.transactions.push(transaction);
+            Ok(())
+        }
+
+        fn retrieve_client_transactions(
+            &mut self,
+            client_id: u16,
+        ) -> PaymentEngineResult<Vec<Transaction>> {
+            Ok(self
+                .transactions
+                .iter()
+                .filter(|t| t.client_id == client_id)
+                .cloned()
+                .collect())
+        }
+
+        fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+            Ok(self.accounts.get(&client_id).cloned())
+        }
+
+        fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+            self.accounts.insert(account.client_id, account);
+
+            Ok(())
+        }
+
+        fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+            Ok(self.accounts.values().cloned().collect())
+        }
+
+        fn set_transaction_disputed(
+            &mut self,
+            transaction_id: u32,
+            disputed: bool,
+        ) -> PaymentEngineResult<()> {
+            let transaction = self
+                .transactions
+                .iter_mut()
+                .find(|t| t.transaction_id == transaction_id)
+                .unwrap();
+
+            transaction.disputed = disputed;
+
+            Ok(())
+        }
+
+        fn remove_transaction_from_cache(
+            &mut self,
+            _transaction_id: u32,
+        ) -> PaymentEngineResult<()> {
+            Ok(())
+        }
+
+        fn save_pending_transaction(
+            &mut self,
+            transaction: Transaction,
+        ) -> PaymentEngineResult<()> {
+            self.pending_transactions.push(transaction);
+
+            Ok(())
+        }
+
+        fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+            Ok(self.pending_transactions.clone())
+        }
+
+        fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+            self.pending_transactions
+                .retain(|t| t.transaction_id != transaction_id);
+
+            Ok(())
+        }
+
+        fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+            self.dispute_records.push(record);
+
+            Ok(())
+        }
+
+        fn retrieve_dispute_chain(
+            &self,
+            transaction_id: u32,
+        ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+            Ok(self
+                .dispute_records
+                .iter()
+                .filter(|r| r.transaction_id == transaction_id)
+                .cloned()
+                .collect())
+        }
+    }
+
+    #[test]
+    pub fn should_deposit_account() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let client_id = 1;
+
+        let transaction = Transaction::new(
+            TransactionType::Deposit,
+            client_id,
+            1,
+            Option::from(Decimal::from(500)),
+        );
+
+        let mut account = Account {
+            client_id,
+            currency: None,
+            available: Default::default(),
+            held: Default::default(),
+            total: Default::default(),
+            locked: false,
+            balances: Default::default(),
+        };
+
+        service.handle_deposit(&transaction, &mut account).unwrap();
+
+        let account = service.retrieve_account(1).unwrap();
+
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(500));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    pub fn should_withdraw_account() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let client_id = 2;
+
+        let transaction = Transaction::new(
+            TransactionType::Withdrawal,
+            client_id,
+            2,
+            Option::from(Decimal::from(500)),
+        );
+
+        let mut account = Account {
+            client_id,
+            currency: None,
+            available: Decimal::from(1000),
+            held: Default::default(),
+            total: Decimal::from(1000),
+            locked: false,
+            balances: Default::default(),
+        };
+
+        service
+            .handle_withdrawal(&transaction, &mut account)
+            .unwrap();
+
+        let account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(500));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    pub fn should_dispute_transaction_deposit_with_resolution() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let client_id = 3;
+
+        let transaction = Transaction::new(
+            TransactionType::Deposit,
+            client_id,
+            333,
+            Option::from(Decimal::from(500)),
+        );
+
+        let mut action_transaction =
+            Transaction::new(TransactionType::Dispute, client_id, 333, None);
+
+        let mut account = Account {
+            client_id,
+            currency: None,
+            available: Decimal::from(1000),
+            held: Default::default(),
+            total: Decimal::from(1000),
+            locked: false,
+            balances: Default::default(),
+        };
+
+        service.handle_deposit(&transaction, &mut account).unwrap();
+
+        let mut account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.available, Decimal::from(1500));
+        assert_eq!(account.total, Decimal::from(1500));
+
+        service
+            .handle_dispute(&action_transaction, &mut account)
+            .unwrap();
+
+        let mut account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.available, Decimal::from(1000));
+        assert_eq!(account.total, Decimal::from(1500));
+        assert_eq!(account.held, Decimal::from(500));
+
+        action_transaction.r#type = TransactionType::Resolve;
+
+        service
+            .handle_resolve(&action_transaction, &mut account)
+            .unwrap();
+
+        let account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.available, Decimal::from(1500));
+        assert_eq!(account.total, Decimal::from(1500));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    pub fn should_dispute_transaction_withdrawal_with_resolution() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let client_id = 3;
+
+        let transaction = Transaction::new(
+            TransactionType::Withdrawal,
+            client_id,
+            455,
+            Option::from(Decimal::from(500)),
+        );
+
+        let mut action_transaction =
+            Transaction::new(TransactionType::Dispute, client_id, 455, None);
+
+        let mut account = Account {
+            client_id,
+            currency: None,
+            available: Decimal::from(1000),
+            held: Default::default(),
+            total: Decimal::from(1000),
+            locked: false,
+            balances: Default::default(),
+        };
+
+        service
+            .handle_withdrawal(&transaction, &mut account)
+            .unwrap();
+
+        let mut account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(500));
+
+        service
+            .handle_dispute(&action_transaction, &mut account)
+            .unwrap();
+
+        let mut account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(1000));
+        assert_eq!(account.held, Decimal::from(500));
+
+        action_transaction.r#type = TransactionType::Resolve;
+
+        service
+            .handle_resolve(&action_transaction, &mut account)
+            .unwrap();
+
+        let account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.available, Decimal::from(1000));
+        assert_eq!(account.total, Decimal::from(1000));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    pub fn should_chargeback_account() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let client_id = 3;
+
+        let transaction = Transaction::new(
+            TransactionType::Withdrawal,
+            client_id,
+            455,
+            Option::from(Decimal::from(500)),
+        );
+
+        let mut action_transaction =
+            Transaction::new(TransactionType::Dispute, client_id, 455, None);
+
+        let account = Account {
+            client_id,
+            currency: None,
+            available: Decimal::from(1000),
+            held: Default::default(),
+            total: Decimal::from(1000),
+            locked: false,
+            balances: Default::default(),
+        };
+
+        service
+            .handle_withdrawal(&transaction, &mut account.clone())
+            .unwrap();
+
+        let mut account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(500));
+
+        service
+            .handle_dispute(&action_transaction, &mut account)
+            .unwrap();
+
+        let mut account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(1000));
+        assert_eq!(account.held, Decimal::from(500));
+
+        action_transaction.r#type = TransactionType::Chargeback;
+
+        service
+            .handle_chargeback(&action_transaction, &mut account)
+            .unwrap();
+
+        let account = service.retrieve_account(client_id).unwrap();
+
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(500));
+        assert_eq!(account.held, Decimal::ZERO);
+        assert!(account.locked);
+    }
+
+    #[test]
+    pub fn should_process_transactions_from_csv() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+
+        if let Err(e) = service.run("test.csv") {
+            panic!("{}", e)
+        };
+
+        let account = service.retrieve_account(1).unwrap();
+
+        assert_eq!(account.available, from_str_to_decimal("400.9699"));
+        assert_eq!(account.held, from_str_to_decimal("600"));
+        assert_eq!(account.total, from_str_to_decimal("1000.9699"));
+        assert!(!account.locked);
+
+        let account = service.retrieve_account(2).unwrap();
+
+        assert_eq!(account.available, from_str_to_decimal("5600"));
+        assert_eq!(account.held, from_str_to_decimal("0"));
+        assert_eq!(account.total, from_str_to_decimal("5600"));
+        assert!(account.locked);
+
+        let account = service.retrieve_account(3).unwrap();
+
+        assert_eq!(account.available, from_str_to_decimal("0"));
+        assert_eq!(account.held, from_str_to_decimal("500"));
+        assert_eq!(account.total, from_str_to_decimal("500"));
+        assert!(!account.locked);
+
+        let account = service.retrieve_account(33).unwrap();
+
+        assert_eq!(account.available, from_str_to_decimal("2500"));
+        assert_eq!(account.held, from_str_to_decimal("300"));
+        assert_eq!(account.total, from_str_to_decimal("2800"));
+        assert!(!account.locked);
+
+        let account = service.retrieve_account(99).unwrap();
+
+        assert_eq!(account.available, from_str_to_decimal("1000"));
+        assert_eq!(account.held, from_str_to_decimal("500"));
+        assert_eq!(account.total, from_str_to_decimal("1500"));
+        assert!(!account.locked);
+    }
+
+    #[test]
+    pub fn should_process_archive_members_in_name_order() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("daily.zip");
+        let mut archive = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let members = [
+            (
+                "b.csv",
+                "type,client,tx,amount\nwithdrawal,1,2,30\nwithdrawal,1,3,90\n",
+            ),
+            ("notes.txt", "not transactions"),
+            ("a.csv", "type,client,tx,amount\ndeposit,1,1,100\n"),
+        ];
+
+        for (name, content) in members.iter() {
+            archive
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            archive.write_all(content.as_bytes()).unwrap();
+        }
+        archive.finish().unwrap();
+
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let path = path.to_str().unwrap();
+
+        service.run(path).unwrap();
+
+        let account = service.retrieve_account(1).unwrap();
+        let sources: Vec<(String, RunCounts)> = service
+            .source_counts()
+            .iter()
+            .map(|source| (source.source.clone(), source.counts))
+            .collect();
+
+        assert_eq!(account.available, from_str_to_decimal("70"));
+        assert_eq!(
+            sources,
+            vec![
+                (
+                    format!("{}!a.csv", path),
+                    RunCounts {
+                        rows: 1,
+                        applied: 1,
+                        rejected: 0,
+                        parked: 0
+                    }
+                ),
+                (
+                    format!("{}!b.csv", path),
+                    RunCounts {
+                        rows: 2,
+                        applied: 1,
+                        rejected: 1,
+                        parked: 0
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn should_refund_deposit_up_to_its_amount() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let input = NamedTempFile::new().unwrap();
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+
+        std::fs::write(
+            input.path(),
+            "type,client,tx,amount\n\
+             deposit,1,1,100\n\
+             deposit,1,2,50\n\
+             refund,1,1,30\n\
+             refund,1,1,80\n\
+             refund,1,2,20\n\
+             dispute,1,1,\n\
+             refund,1,1,10\n",
+        )
+        .unwrap();
+        service.run(input.path().to_str().unwrap()).unwrap();
+
+        let account = service.retrieve_account(1).unwrap();
+        let deposit = service.datastore.retrieve_transaction(1).unwrap().unwrap();
+
+        assert_eq!(service.run_counts().rejected, 2);
+        assert_eq!(deposit.refunded, Decimal::from(30));
+        assert_eq!(account.available, Decimal::from(30));
+        assert_eq!(account.held, Decimal::from(70));
+        assert_eq!(account.total, Decimal::from(100));
+    }
+
+    #[test]
+    pub fn should_abort_strict_run_on_first_rejected_or_invalid_row() {
+        let input = NamedTempFile::new().unwrap();
+        let path = input.path().to_str().unwrap();
+        let strict = || ServiceConfig {
+            strict: true,
+            ..ServiceConfig::default()
+        };
+
+        std::fs::write(
+            input.path(),
+            "type,client,tx,amount\n\
+             deposit,1,1,100\n\
+             withdrawal,1,2,150\n\
+             deposit,1,3,50\n",
+        )
+        .unwrap();
+
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), strict());
+        let error = service.run(path).unwrap_err();
+
+        assert!(matches!(
+            error,
+            PaymentEngineError::StrictRowRejected { row: 2, .. }
+        ));
+        assert_eq!(service.run_counts().rows, 2);
+        assert_eq!(
+            service.retrieve_account(1).unwrap().total,
+            Decimal::from(100)
+        );
+
+        std::fs::write(
+            input.path(),
+            "type,client,tx,amount\n\
+             deposit,1,1,100\n\
+             deposit,1,2,ten\n",
+        )
+        .unwrap();
+
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), strict());
+
+        assert!(matches!(
+            service.run(path),
+            Err(PaymentEngineError::StrictRowRejected { row: 2, .. })
+        ));
+    }
+
+    #[test]
+    pub fn should_hold_authorized_funds_until_capture_or_release() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let input = NamedTempFile::new().unwrap();
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+
+        std::fs::write(
+            input.path(),
+            "type,client,tx,amount\n\
+             deposit,1,1,100\n\
+             authorize,1,2,40\n\
+             capture,1,2,30\n\
+             release,1,2,\n\
+             authorize,1,3,20\n\
+             release,1,3,\n\
+             authorize,1,4,60\n\
+             withdrawal,1,5,40\n",
+        )
+        .unwrap();
+        service.run(input.path().to_str().unwrap()).unwrap();
+
+        let account = service.retrieve_account(1).unwrap();
+        let capture = service.datastore.retrieve_transaction(2).unwrap().unwrap();
+
+        assert_eq!(service.run_counts().rejected, 2);
+        assert_eq!(capture.settled, Some(Settlement::Captured));
+        assert_eq!(account.available, Decimal::from(10));
+        assert_eq!(account.held, Decimal::from(60));
+        assert_eq!(account.total, Decimal::from(70));
+    }
+
+    #[test]
+    pub fn should_correct_amounts_of_undisputed_transactions() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let audit_log = directory.path().join("audit.log");
+        let config = ServiceConfig {
+            audit_log_path: Some(audit_log.clone()),
+            ..ServiceConfig::default()
+        };
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), config);
+
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             deposit,1,1,100\n\
+             withdrawal,1,2,20\n\
+             correction,1,1,120\n\
+             correction,1,1,120\n\
+             correction,1,2,50\n\
+             dispute,1,1,\n\
+             correction,1,1,10\n",
+        )
+        .unwrap();
+        service.run(input.to_str().unwrap()).unwrap();
+
+        let account = service.retrieve_account(1).unwrap();
+        let audit = std::fs::read_to_string(&audit_log).unwrap();
+        let corrections: Vec<&str> = audit
+            .lines()
+            .filter(|line| line.contains("transaction_corrected"))
+            .collect();
+
+        assert_eq!(service.run_counts().rejected, 1);
+        assert_eq!(account.available, Decimal::from(-50));
+        assert_eq!(account.held, Decimal::from(120));
+        assert_eq!(account.total, Decimal::from(70));
+        assert_eq!(corrections.len(), 2);
+        assert!(corrections[0].contains("amount 100 -> 120"));
+        assert!(corrections[1].contains("amount 20 -> 50"));
+    }
+
+    #[test]
+    pub fn should_erase_personal_fields_unless_client_is_under_legal_hold() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let audit_log = directory.path().join("audit.log");
+        let config = ServiceConfig {
+            audit_log_path: Some(audit_log.clone()),
+            legal_holds_path: Some(directory.path().join("holds.db")),
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(InMemoryDatastore::default()), config);
+
+        std::fs::write(
+            &input,
+            "type,client,tx,amount,memo,counterparty\n\
+             deposit,1,1,100,rent,Jane Doe\n\
+             withdrawal,1,2,20,,\n\
+             deposit,2,3,5,gift,John Roe\n",
+        )
+        .unwrap();
+        service.run(input.to_str().unwrap()).unwrap();
+        service.place_legal_hold(1, "case 42", "alice").unwrap();
+
+        assert!(matches!(
+            service.erase_client(1, "bob"),
+            Err(PaymentEngineError::ClientUnderLegalHold { client_id: 1 })
+        ));
+
+        service.release_legal_hold(1, "alice").unwrap();
+
+        let account = service.retrieve_account(1).unwrap();
+        let summary = service.erase_client(1, "bob").unwrap();
+        let erased = service.datastore.retrieve_transaction(1).unwrap().unwrap();
+        let other = service.datastore.retrieve_transaction(3).unwrap().unwrap();
+        let audit = std::fs::read_to_string(&audit_log).unwrap();
+
+        assert_eq!(summary.transactions, 1);
+        assert_eq!(service.erase_client(1, "bob").unwrap().transactions, 0);
+        assert_eq!(erased.memo.as_deref(), Some(erasure::ERASED));
+        assert_eq!(erased.counterparty.as_deref(), Some(erasure::ERASED));
+        assert_eq!(erased.amount, Some(Decimal::from(100)));
+        assert_eq!(other.counterparty.as_deref(), Some("John Roe"));
+        assert_eq!(service.retrieve_account(1).unwrap(), account);
+        assert!(audit.contains("erasure_blocked"));
+        assert!(audit.contains("legal_hold_released"));
+        assert!(audit.contains("client_erased"));
+    }
+
+    #[test]
+    pub fn should_report_only_changed_accounts() {
+        let mut accounts = HashMap::default();
+
+        for client_id in [1, 2] {
+            accounts.insert(
+                client_id,
+                Account {
+                    available: Decimal::from(100),
+                    total: Decimal::from(100),
+                    ..Account::new(client_id)
+                },
+            );
+        }
+
+        let datastore = MockDatastore::new(accounts, vec![]);
+        let config = ServiceConfig {
+            report_mode: ReportMode::Changed,
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(datastore), config);
+        let file = NamedTempFile::new().unwrap();
+
+        std::fs::write(
+            file.path(),
+            "type,client,tx,amount\ndeposit,2,1,5\nwithdrawal,1,2,500\ndeposit,3,3,1\n",
+        )
+        .unwrap();
+        service.process_file(file.path().to_str().unwrap()).unwrap();
+
+        let mut changed: Vec<u16> = service
+            .report_accounts()
+            .unwrap()
+            .iter()
+            .map(|account| account.client_id)
+            .collect();
+        changed.sort_unstable();
+
+        assert_eq!(changed, vec![2, 3]);
+    }
+
+    #[test]
+    pub fn should_not_panic_on_generated_input() {
+        let types = [
+            "deposit",
+            "withdrawal",
+            "dispute",
+            "resolve",
+            "chargeback",
+            "refund",
+        ];
+        let amounts = [
+            "1",
+            "0.0001",
+            "99999.99995",
+            "79228162514264337593543950335",
+            "-79228162514264337593543950335",
+            "-1",
+            "",
+            "1e5",
+            "abc",
+        ];
+        let mut seed: u64 = 42;
+        let mut next = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) as usize % bound
+        };
+        let mut csv = String::from("type,client,tx,amount\n");
+
+        for _ in 0..5000 {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                types[next(types.len())],
+                next(4),
+                next(200),
+                amounts[next(amounts.len())]
+            ));
+        }
+
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), csv).unwrap();
+
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+
+        assert!(service.process_file(file.path().to_str().unwrap()).is_ok());
+        assert!(!service.report_accounts().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn should_apply_batch_only_when_every_transaction_succeeds() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let transaction = |r#type, transaction_id, amount: i64| {
+            Transaction::new(r#type, 1, transaction_id, Some(Decimal::from(amount)))
+        };
+
+        let result = service
+            .process_batch(vec![
+                transaction(TransactionType::Deposit, 1, 100),
+                transaction(TransactionType::Withdrawal, 2, 150),
+                transaction(TransactionType::Withdrawal, 3, 50),
+            ])
+            .unwrap();
+
+        assert!(!result.applied);
+        assert!(result.outcomes[0].is_ok());
+        assert!(matches!(
+            result.outcomes[1],
+            Err(PaymentEngineError::InsufficientAccountFunds)
+        ));
+        assert!(result.outcomes[2].is_ok());
+        assert_eq!(service.retrieve_account(1).unwrap(), Account::new(1));
+
+        let result = service
+            .process_batch(vec![
+                transaction(TransactionType::Deposit, 1, 100),
+                transaction(TransactionType::Withdrawal, 3, 50),
+            ])
+            .unwrap();
+
+        assert!(result.applied);
+        assert_eq!(
+            service.retrieve_account(1).unwrap().available,
+            Decimal::from(50)
+        );
+    }
+
+    #[test]
+    pub fn should_close_disputes_resolved_in_a_batch_in_the_disputed_index() {
+        let directory = TempDir::new().unwrap();
+        let datastore = PickleDatastore::open(directory.path(), false);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let transaction = |r#type, transaction_id, amount: Option<i64>| {
+            Transaction::new(r#type, 1, transaction_id, amount.map(Decimal::from))
+        };
+
+        for batch in [
+            vec![
+                transaction(TransactionType::Deposit, 1, Some(100)),
+                transaction(TransactionType::Dispute, 1, None),
+            ],
+            vec![transaction(TransactionType::Resolve, 1, None)],
+        ] {
+            assert!(service.process_batch(batch).unwrap().applied);
+        }
+
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Resolve, 1, None)),
+            Err(PaymentEngineError::TransactionNotDisputed)
+        ));
+
+        let account = service.retrieve_account(1).unwrap();
+
+        assert_eq!(account.available, Decimal::from(100));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    pub fn should_audit_parked_transactions_of_applied_stages_only() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let audit_log = directory.path().join("audit.log");
+        let config = ServiceConfig {
+            approval_threshold: Some(Decimal::from(50)),
+            audit_log_path: Some(audit_log.clone()),
+            report_path: Some(directory.path().join("accounts.csv")),
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(InMemoryDatastore::default()), config);
+        let parked = || {
+            std::fs::read_to_string(&audit_log)
+                .unwrap_or_default()
+                .lines()
+                .filter(|line| line.contains("transaction_parked"))
+                .count()
+        };
+
+        std::fs::write(&input, "type,client,tx,amount\ndeposit,1,1,100\n").unwrap();
+        service.stage(input.to_str().unwrap()).unwrap();
+        service.discard_staged();
+
+        assert_eq!(parked(), 0);
+        assert!(service.pending_transactions().unwrap().is_empty());
+
+        service.stage(input.to_str().unwrap()).unwrap();
+        service.apply_staged().unwrap();
+
+        assert_eq!(parked(), 1);
+        assert_eq!(service.pending_transactions().unwrap().len(), 1);
+    }
+
+    #[test]
+    pub fn should_log_transactions_ahead_and_rebuild_accounts_from_the_log() {
+        let wal = NamedTempFile::new().unwrap();
+        let config = ServiceConfig {
+            wal_path: Some(wal.path().to_path_buf()),
+            ..ServiceConfig::default()
+        };
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), config);
+        let transaction = |r#type, transaction_id, amount: i64| {
+            Transaction::new(r#type, 1, transaction_id, Some(Decimal::from(amount)))
+        };
+
+        service
+            .process(&transaction(TransactionType::Deposit, 1, 100))
+            .unwrap();
+        assert!(service
+            .process(&transaction(TransactionType::Withdrawal, 2, 500))
+            .is_err());
+        // A rejected batch never reaches the datastore, so it is not logged either.
+        assert!(
+            !service
+                .process_batch(vec![
+                    transaction(TransactionType::Deposit, 3, 10),
+                    transaction(TransactionType::Withdrawal, 4, 1000),
+                ])
+                .unwrap()
+                .applied
+        );
+        assert!(
+            service
+                .process_batch(vec![transaction(TransactionType::Withdrawal, 5, 30)])
+                .unwrap()
+                .applied
+        );
+
+        let logged = WriteAheadLog::read(wal.path()).unwrap();
+        let ids: Vec<u32> = logged.iter().map(|t| t.transaction_id).collect();
+
+        assert_eq!(ids, vec![1, 2, 5]);
+
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut replayed = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+
+        for transaction in &logged {
+            let _ = replayed.process(transaction);
+        }
+
+        assert_eq!(
+            replayed.retrieve_account(1).unwrap(),
+            service.retrieve_account(1).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn should_resume_from_checkpoint_without_applying_rows_twice() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("large.csv");
+        let input = input.to_str().unwrap();
+        let mut csv = String::from("type,client,tx,amount\n");
+
+        for transaction_id in 1..=5 {
+            csv.push_str(&format!(
+                "deposit,1,{},{}\n",
+                transaction_id, transaction_id
+            ));
+        }
+
+        std::fs::write(input, csv).unwrap();
+
+        let config = |max_rows| ServiceConfig {
+            limits: RunLimits {
+                max_rows,
+                ..RunLimits::default()
+            },
+            checkpoints: Some(CheckpointPolicy {
+                every_rows: NonZeroU64::new(2).unwrap(),
+                directory: directory.path().to_path_buf(),
+            }),
+            report_path: Some(directory.path().join("report.csv")),
+            ..ServiceConfig::default()
+        };
+        let datastore = PickleDatastore::open(directory.path(), false);
+        let mut service = PaymentService::new(Box::new(datastore), config(Some(3)));
+
+        // The run fails on its fourth row, after the checkpoint of the second one.
+        assert!(matches!(
+            service.run(input),
+            Err(PaymentEngineError::RowLimitExceeded)
+        ));
+        drop(service);
+
+        let checkpoint = Checkpoint::restore(directory.path()).unwrap();
+
+        assert_eq!(checkpoint.counts.applied, 2);
+
+        let datastore = PickleDatastore::open(directory.path(), true);
+        let mut service = PaymentService::new(Box::new(datastore), config(None));
+
+        service.resume_from_checkpoint(input, &checkpoint).unwrap();
+
+        assert_eq!(
+            service.retrieve_account(1).unwrap().available,
+            Decimal::from(15)
+        );
+        assert_eq!(service.run_counts().applied, 5);
+        assert!(Checkpoint::restore(directory.path()).is_err());
+    }
+
+    #[test]
+    pub fn should_report_the_same_accounts_through_the_pipeline() {
+        let directory = TempDir::new().unwrap();
+        let run = |pipeline_depth, report| {
+            let config = ServiceConfig {
+                pipeline_depth,
+                report_path: Some(directory.path().join(report)),
+                ..ServiceConfig::default()
+            };
+            let mut service = PaymentService::new(Box::new(InMemoryDatastore::default()), config);
+
+            service.run("test.csv").unwrap();
+
+            (
+                service.run_counts(),
+                std::fs::read_to_string(directory.path().join(report)).unwrap(),
+            )
+        };
+
+        // A depth of one row makes every stage wait for the next one.
+        assert_eq!(
+            run(NonZeroUsize::new(1), "pipelined.csv"),
+            run(None, "sequential.csv")
+        );
+    }
+
+    #[test]
+    pub fn should_link_dispute_chain_steps() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let transaction = |r#type, amount| Transaction::new(r#type, 1, 1, amount);
+
+        let result = service
+            .process_batch(vec![
+                transaction(TransactionType::Deposit, Some(Decimal::from(100))),
+                transaction(TransactionType::Dispute, None),
+                transaction(TransactionType::Resolve, None),
+                transaction(TransactionType::Dispute, None),
+                transaction(TransactionType::Chargeback, None),
+            ])
+            .unwrap();
+        let chain: Vec<(u32, TransactionType, Option<u32>)> = service
+            .dispute_chain(1)
+            .unwrap()
+            .into_iter()
+            .map(|record: DisputeRecord| (record.sequence, record.r#type, record.closes))
+            .collect();
+
+        assert!(result.applied);
+        assert_eq!(
+            chain,
+            vec![
+                (1, TransactionType::Dispute, None),
+                (2, TransactionType::Resolve, Some(1)),
+                (3, TransactionType::Dispute, None),
+                (4, TransactionType::Chargeback, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn should_recredit_represented_chargeback_once() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let transaction = |r#type, amount| Transaction {
+            reason_code: Some("fraud".to_string()),
+            ..Transaction::new(r#type, 1, 1, amount)
+        };
+
+        service
+            .process(&transaction(
+                TransactionType::Deposit,
+                Some(Decimal::from(30)),
+            ))
+            .unwrap();
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Representment, None)),
+            Err(PaymentEngineError::RepresentmentNotAllowed)
+        ));
+
+        for r#type in [TransactionType::Dispute, TransactionType::Chargeback] {
+            service.process(&transaction(r#type, None)).unwrap();
+        }
+
+        let account = service
+            .process(&transaction(TransactionType::Representment, None))
+            .unwrap();
+        let last_step = service.dispute_chain(1).unwrap().pop().unwrap();
+
+        assert_eq!(account.available, Decimal::from(30));
+        assert_eq!(account.total, Decimal::from(30));
+        assert!(account.locked);
+        assert_eq!(
+            (last_step.r#type, last_step.closes),
+            (TransactionType::Representment, Some(2))
+        );
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Representment, None)),
+            Err(PaymentEngineError::RepresentmentNotAllowed)
+        ));
+    }
+
+    #[test]
+    pub fn should_resolve_disputes_left_open_past_deadline() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let config = ServiceConfig {
+            dispute_deadline_days: Some(30),
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(datastore), config);
+        let opened_at = Utc::now() - Duration::days(40);
+        let transaction = |r#type, transaction_id, amount| Transaction {
+            timestamp: Some(opened_at),
+            ..Transaction::new(r#type, 1, transaction_id, amount)
+        };
+
+        for transaction_id in [1, 2] {
+            service
+                .process(&transaction(
+                    TransactionType::Deposit,
+                    transaction_id,
+                    Some(Decimal::from(10)),
+                ))
+                .unwrap();
+            service
+                .process(&transaction(TransactionType::Dispute, transaction_id, None))
+                .unwrap();
+        }
+        service
+            .process(&transaction(TransactionType::Chargeback, 2, None))
+            .unwrap();
+
+        assert_eq!(service.run_due_timers(Utc::now()).unwrap(), 2);
+        assert_eq!(
+            service.dispute_chain(1).unwrap().pop().unwrap().r#type,
+            TransactionType::Resolve
+        );
+        assert_eq!(service.dispute_chain(2).unwrap().len(), 2);
+        assert_eq!(service.accounts().unwrap()[0].available, Decimal::from(10));
+        assert!(service.timers().is_empty());
+    }
+
+    #[test]
+    pub fn should_date_disputes_and_expire_reservations_with_the_injected_clock() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let config = ServiceConfig {
+            dispute_deadline_days: Some(30),
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(datastore), config);
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let clock = FixedClock::new(start);
+        let transaction =
+            |r#type, transaction_id, amount| Transaction::new(r#type, 1, transaction_id, amount);
+
+        service.set_clock(Box::new(clock.clone()));
+        for transaction_id in [1, 2] {
+            service
+                .process(&transaction(
+                    TransactionType::Deposit,
+                    transaction_id,
+                    Some(Decimal::from(100)),
+                ))
+                .unwrap();
+        }
+        service
+            .process(&transaction(TransactionType::Dispute, 1, None))
+            .unwrap();
+
+        assert_eq!(service.dispute_chain(1).unwrap()[0].recorded_at, start);
+        assert_eq!(service.timers()[0].due_at, start + Duration::days(30));
+
+        let reservation = service
+            .reserve(1, Decimal::from(40), Duration::minutes(15))
+            .unwrap();
+
+        clock.advance(Duration::minutes(16));
+
+        assert!(matches!(
+            service.commit_reservation(&reservation.token, Some(3)),
+            Err(PaymentEngineError::ReservationExpired)
+        ));
+    }
+
+    #[test]
+    pub fn should_reject_disputes_over_open_dispute_limit() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let config = ServiceConfig {
+            max_open_disputes: Some(1),
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(datastore), config);
+        let transaction =
+            |r#type, transaction_id, amount| Transaction::new(r#type, 1, transaction_id, amount);
+
+        for transaction_id in [1, 2] {
+            service
+                .process(&transaction(
+                    TransactionType::Deposit,
+                    transaction_id,
+                    Some(Decimal::from(10)),
+                ))
+                .unwrap();
+        }
+        service
+            .process(&transaction(TransactionType::Dispute, 1, None))
+            .unwrap();
+
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Dispute, 2, None)),
+            Err(PaymentEngineError::OpenDisputeLimitExceeded)
+        ));
+
+        service
+            .process(&transaction(TransactionType::Resolve, 1, None))
+            .unwrap();
+        let account = service
+            .process(&transaction(TransactionType::Dispute, 2, None))
+            .unwrap();
+
+        assert_eq!(account.held, Decimal::from(10));
+    }
+
+    #[test]
+    pub fn should_transfer_funds_and_charge_transfer_back() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let transaction = |r#type, transaction_id, amount, to_client| Transaction {
+            to_client,
+            ..Transaction::new(r#type, 1, transaction_id, amount)
+        };
+        let balances = |service: &PaymentService, client_id| {
+            let account = service.retrieve_account(client_id).unwrap();
+
+            (account.available, account.held, account.total)
+        };
+
+        service
+            .process(&transaction(
+                TransactionType::Deposit,
+                1,
+                Some(Decimal::from(50)),
+                None,
+            ))
+            .unwrap();
+        assert!(matches!(
+            service.process(&transaction(
+                TransactionType::Transfer,
+                2,
+                Some(Decimal::from(60)),
+                Some(2)
+            )),
+            Err(PaymentEngineError::InsufficientAccountFunds)
+        ));
+        assert!(matches!(
+            service.process(&transaction(
+                TransactionType::Transfer,
+                2,
+                Some(Decimal::from(10)),
+                Some(1)
+            )),
+            Err(PaymentEngineError::InvalidTransferRecipient)
+        ));
+
+        service
+            .process(&transaction(
+                TransactionType::Transfer,
+                2,
+                Some(Decimal::from(20)),
+                Some(2),
+            ))
+            .unwrap();
+
+        let thirty = Decimal::from(30);
+        let twenty = Decimal::from(20);
+
+        assert_eq!(balances(&service, 1), (thirty, Decimal::ZERO, thirty));
+        assert_eq!(balances(&service, 2), (twenty, Decimal::ZERO, twenty));
+
+        service
+            .process(&transaction(TransactionType::Dispute, 2, None, None))
+            .unwrap();
+
+        assert_eq!(balances(&service, 2), (Decimal::ZERO, twenty, twenty));
+
+        let sender = service
+            .process(&transaction(TransactionType::Chargeback, 2, None, None))
+            .unwrap();
+
+        assert_eq!(
+            balances(&service, 1),
+            (Decimal::from(50), Decimal::ZERO, Decimal::from(50))
+        );
+        assert_eq!(
+            balances(&service, 2),
+            (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO)
+        );
+        assert!(sender.locked);
+    }
+
+    #[test]
+    pub fn should_keep_evidence_with_client_dispute() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let evidence = |documents: &[&str]| DisputeEvidence {
+            reason_code: Some("fraud".to_string()),
+            documents: documents
+                .iter()
+                .map(|document| document.to_string())
+                .collect(),
+        };
 
-            transaction.disputed = disputed;
+        service
+            .process(&Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::from(100)),
+            ))
+            .unwrap();
 
-            Ok(())
-        }
+        let record = service
+            .open_dispute(1, 1, evidence(&["s3://evidence/receipt.pdf", "ticket-42"]))
+            .unwrap();
 
-        fn remove_transaction_from_cache(
-            &mut self,
-            _transaction_id: u32,
-        ) -> PaymentEngineResult<()> {
-            Ok(())
-        }
+        assert_eq!(record.reason_code.as_deref(), Some("fraud"));
+        assert_eq!(service.dispute_chain(1).unwrap(), vec![record.clone()]);
+        assert_eq!(
+            String::from(record.documents),
+            "s3://evidence/receipt.pdf ticket-42"
+        );
+        assert_eq!(
+            Documents::from("a b".to_string()),
+            Documents(vec!["a".to_string(), "b".to_string()])
+        );
+        assert!(matches!(
+            service.open_dispute(1, 1, evidence(&["no spaces please"])),
+            Err(PaymentEngineError::InvalidDisputeEvidence)
+        ));
+        assert!(matches!(
+            service.open_dispute(1, 1, evidence(&[])),
+            Err(PaymentEngineError::TransactionAlreadyDisputed)
+        ));
+        assert_eq!(service.accounts().unwrap()[0].held, Decimal::from(100));
     }
 
     #[test]
-    pub fn should_deposit_account() {
+    pub fn should_abort_run_when_limit_is_exceeded() {
         let datastore = MockDatastore::new(HashMap::default(), vec![]);
-        let mut service = PaymentService::new(Box::new(datastore));
-        let client_id = 1;
-
-        let transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id,
-            transaction_id: 1,
-            amount: Option::from(Decimal::from(500)),
-            disputed: false,
+        let config = ServiceConfig {
+            limits: RunLimits {
+                max_total_deposits: Some(from_str_to_decimal("1000")),
+                ..RunLimits::default()
+            },
+            ..ServiceConfig::default()
         };
+        let mut service = PaymentService::new(Box::new(datastore), config);
 
-        let mut account = Account {
-            client_id,
-            available: Default::default(),
-            held: Default::default(),
-            total: Default::default(),
-            locked: false,
+        match service.run("test.csv") {
+            Err(PaymentEngineError::DepositLimitExceeded) => {}
+            result => panic!("Expected deposit limit error, got {:?}", result),
         };
 
-        service.handle_deposit(&transaction, &mut account).unwrap();
-
         let account = service.retrieve_account(1).unwrap();
 
-        assert_eq!(account.available, Decimal::from(500));
-        assert_eq!(account.total, Decimal::from(500));
-        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, from_str_to_decimal("400.9699"));
     }
 
     #[test]
-    pub fn should_withdraw_account() {
+    pub fn should_apply_staged_changes_only_after_approval() {
         let datastore = MockDatastore::new(HashMap::default(), vec![]);
-        let mut service = PaymentService::new(Box::new(datastore));
-        let client_id = 2;
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
 
-        let transaction = Transaction {
-            r#type: TransactionType::Withdrawal,
-            client_id,
-            transaction_id: 2,
-            amount: Option::from(Decimal::from(500)),
-            disputed: false,
-        };
+        let impact = service.stage("test.csv").unwrap();
 
-        let mut account = Account {
-            client_id,
-            available: Decimal::from(1000),
-            held: Default::default(),
-            total: Decimal::from(1000),
-            locked: false,
-        };
+        assert_eq!(impact.accounts_changed, 5);
+        assert_eq!(impact.accounts_locked, 1);
 
-        service
-            .handle_withdrawal(&transaction, &mut account)
-            .unwrap();
+        service.discard_staged();
 
-        let account = service.retrieve_account(client_id).unwrap();
+        assert_eq!(service.datastore.retrieve_all_accounts().unwrap().len(), 0);
 
-        assert_eq!(account.available, Decimal::from(500));
-        assert_eq!(account.total, Decimal::from(500));
-        assert_eq!(account.held, Decimal::ZERO);
+        service.stage("test.csv").unwrap();
+        service.apply_staged().unwrap();
+
+        let account = service.retrieve_account(2).unwrap();
+
+        assert_eq!(account.total, from_str_to_decimal("5600"));
+        assert!(account.locked);
     }
 
     #[test]
-    pub fn should_dispute_transaction_deposit_with_resolution() {
+    pub fn should_report_rejected_rows_of_dry_run_without_applying_them() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let rejects = directory.path().join("rejects.csv");
+        let report = directory.path().join("accounts.csv");
         let datastore = MockDatastore::new(HashMap::default(), vec![]);
-        let mut service = PaymentService::new(Box::new(datastore));
-        let client_id = 3;
-
-        let transaction = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id,
-            transaction_id: 333,
-            amount: Option::from(Decimal::from(500)),
-            disputed: false,
+        let config = ServiceConfig {
+            rejects_path: Some(rejects.clone()),
+            report_path: Some(report.clone()),
+            ..ServiceConfig::default()
         };
+        let mut service = PaymentService::new(Box::new(datastore), config);
 
-        let mut action_transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client_id,
-            transaction_id: 333,
-            amount: None,
-            disputed: false,
-        };
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             deposit,1,1,100\n\
+             dispute,1,7,\n\
+             withdrawal,1,2,\n",
+        )
+        .unwrap();
 
-        let mut account = Account {
-            client_id,
-            available: Decimal::from(1000),
-            held: Default::default(),
-            total: Decimal::from(1000),
-            locked: false,
+        let impact = service.dry_run(input.to_str().unwrap()).unwrap();
+
+        assert_eq!(impact.accounts_changed, 1);
+        assert_eq!(service.run_counts().rejected, 2);
+        assert_eq!(service.datastore.retrieve_all_accounts().unwrap().len(), 0);
+        assert_eq!(
+            std::fs::read_to_string(&rejects).unwrap().lines().count(),
+            3
+        );
+        assert!(!report.exists());
+    }
+
+    #[test]
+    pub fn should_park_transactions_above_approval_threshold() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let config = ServiceConfig {
+            approval_threshold: Some(from_str_to_decimal("2000")),
+            ..ServiceConfig::default()
         };
+        let mut service = PaymentService::new(Box::new(datastore), config);
 
-        service.handle_deposit(&transaction, &mut account).unwrap();
+        service.run("test.csv").unwrap();
 
-        let mut account = service.retrieve_account(client_id).unwrap();
+        let pending: Vec<u32> = service
+            .pending_transactions()
+            .unwrap()
+            .iter()
+            .map(|t| t.transaction_id)
+            .collect();
 
-        assert_eq!(account.held, Decimal::ZERO);
-        assert_eq!(account.available, Decimal::from(1500));
-        assert_eq!(account.total, Decimal::from(1500));
+        assert_eq!(pending, vec![4, 580]);
+        assert_eq!(
+            service.retrieve_account(2).unwrap().total,
+            from_str_to_decimal("600")
+        );
 
-        service
-            .handle_dispute(&action_transaction, &mut account)
-            .unwrap();
+        let account = service.approve_pending(4, "ops").unwrap().unwrap();
 
-        let mut account = service.retrieve_account(client_id).unwrap();
+        assert_eq!(account.total, from_str_to_decimal("5600"));
 
-        assert_eq!(account.available, Decimal::from(1000));
-        assert_eq!(account.total, Decimal::from(1500));
-        assert_eq!(account.held, Decimal::from(500));
+        assert!(service.reject_pending(580, "ops").unwrap());
 
-        action_transaction.r#type = TransactionType::Resolve;
+        assert!(service.pending_transactions().unwrap().is_empty());
+        assert!(service.reject_pending(580, "ops").is_err());
+    }
 
-        service
-            .handle_resolve(&action_transaction, &mut account)
-            .unwrap();
+    #[test]
+    pub fn should_apply_pending_only_after_second_principal_confirms() {
+        let directory = TempDir::new().unwrap();
+        let config = ServiceConfig {
+            approval_threshold: Some(from_str_to_decimal("2000")),
+            dual_control: true,
+            approvals_path: Some(directory.path().join("approvals.db")),
+            ..ServiceConfig::default()
+        };
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), config.clone());
 
-        let account = service.retrieve_account(client_id).unwrap();
+        service.run("test.csv").unwrap();
 
-        assert_eq!(account.available, Decimal::from(1500));
-        assert_eq!(account.total, Decimal::from(1500));
-        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(service.approve_pending(4, "alice").unwrap(), None);
+        assert!(matches!(
+            service.approve_pending(4, "alice"),
+            Err(PaymentEngineError::SamePrincipalConfirmation)
+        ));
+        assert!(!service.reject_pending(580, "bob").unwrap());
+        assert_eq!(service.approval_requests().len(), 2);
+
+        // The request survives until the second principal confirms it in a later run.
+        service.approvals = ApprovalBook::open(config.approvals_path.as_deref());
+
+        let account = service.approve_pending(4, "bob").unwrap().unwrap();
+
+        assert_eq!(account.total, from_str_to_decimal("5600"));
+        assert_eq!(
+            service.approval_requests()[0].action,
+            AdminAction::RejectPending {
+                transaction_id: 580
+            }
+        );
+        assert_eq!(
+            service
+                .pending_transactions()
+                .unwrap()
+                .iter()
+                .map(|t| t.transaction_id)
+                .collect::<Vec<_>>(),
+            vec![580]
+        );
     }
 
     #[test]
-    pub fn should_dispute_transaction_withdrawal_with_resolution() {
+    pub fn should_apply_reloaded_config_during_run() {
         let datastore = MockDatastore::new(HashMap::default(), vec![]);
-        let mut service = PaymentService::new(Box::new(datastore));
-        let client_id = 3;
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let (updates, receiver) = channel();
 
-        let transaction = Transaction {
-            r#type: TransactionType::Withdrawal,
-            client_id,
-            transaction_id: 455,
-            amount: Option::from(Decimal::from(500)),
-            disputed: false,
-        };
+        service.watch_config(receiver);
+        updates
+            .send(ServiceConfig {
+                approval_threshold: Some(from_str_to_decimal("2000")),
+                ..ServiceConfig::default()
+            })
+            .unwrap();
+        service.run("test.csv").unwrap();
 
-        let mut action_transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client_id,
-            transaction_id: 455,
-            amount: None,
-            disputed: false,
-        };
+        assert_eq!(service.pending_transactions().unwrap().len(), 2);
+        assert_eq!(
+            service.config.approval_threshold,
+            Some(from_str_to_decimal("2000"))
+        );
+    }
 
-        let mut account = Account {
-            client_id,
-            available: Decimal::from(1000),
-            held: Default::default(),
-            total: Decimal::from(1000),
-            locked: false,
+    #[test]
+    pub fn should_keep_run_settings_when_config_is_reloaded() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let report = directory.path().join("accounts.csv");
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let config = ServiceConfig {
+            report_path: Some(report.clone()),
+            report_mode: ReportMode::Changed,
+            strict: true,
+            ..ServiceConfig::default()
         };
+        let mut service = PaymentService::new(Box::new(datastore), config);
+        let (updates, receiver) = channel();
 
-        service
-            .handle_withdrawal(&transaction, &mut account)
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             deposit,1,1,100\n\
+             deposit,2,2,50\n",
+        )
+        .unwrap();
+        service.watch_config(receiver);
+        updates
+            .send(ServiceConfig {
+                approval_threshold: Some(from_str_to_decimal("2000")),
+                ..ServiceConfig::default()
+            })
             .unwrap();
+        service.run(input.to_str().unwrap()).unwrap();
 
-        let mut account = service.retrieve_account(client_id).unwrap();
+        assert_eq!(
+            service.config.approval_threshold,
+            Some(from_str_to_decimal("2000"))
+        );
+        assert_eq!(service.config.report_path, Some(report.clone()));
+        assert_eq!(service.config.report_mode, ReportMode::Changed);
+        assert!(service.config.strict);
+        assert_eq!(std::fs::read_to_string(&report).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    pub fn should_reject_withdrawal_disputes_when_flag_is_enabled() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let config = ServiceConfig {
+            flags: FeatureFlags {
+                deposit_only_disputes: Some(Rollout {
+                    client_ranges: vec![(30, 40)],
+                    ..Rollout::default()
+                }),
+                ..FeatureFlags::default()
+            },
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(datastore), config);
+
+        service.run("test.csv").unwrap();
+
+        let account = service.retrieve_account(33).unwrap();
 
         assert_eq!(account.held, Decimal::ZERO);
-        assert_eq!(account.available, Decimal::from(500));
-        assert_eq!(account.total, Decimal::from(500));
+        assert_eq!(account.total, from_str_to_decimal("2500"));
+        assert!(service.retrieve_account(2).unwrap().locked);
+    }
+
+    #[test]
+    pub fn should_reject_dispute_of_another_clients_transaction() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let transaction =
+            |r#type, client_id, amount| Transaction::new(r#type, client_id, 1, amount);
 
         service
-            .handle_dispute(&action_transaction, &mut account)
+            .process(&transaction(
+                TransactionType::Deposit,
+                1,
+                Some(Decimal::from(10)),
+            ))
             .unwrap();
 
-        let mut account = service.retrieve_account(client_id).unwrap();
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Dispute, 2, None)),
+            Err(PaymentEngineError::DisputeClientMismatch {
+                client_id: 2,
+                transaction_client_id: 1,
+            })
+        ));
+        assert_eq!(service.retrieve_account(1).unwrap().held, Decimal::ZERO);
+        assert_eq!(service.retrieve_account(2).unwrap(), Account::new(2));
+        service
+            .process(&transaction(TransactionType::Dispute, 1, None))
+            .unwrap();
+        assert_eq!(service.retrieve_account(1).unwrap().held, Decimal::from(10));
+    }
 
-        assert_eq!(account.available, Decimal::from(500));
-        assert_eq!(account.total, Decimal::from(1000));
-        assert_eq!(account.held, Decimal::from(500));
+    #[test]
+    pub fn should_keep_and_report_balances_per_currency() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let input = NamedTempFile::new().unwrap();
+        let report = NamedTempFile::new().unwrap();
+        let config = ServiceConfig {
+            base_currency: Some("EUR".parse().unwrap()),
+            report_path: Some(report.path().to_path_buf()),
+            ..ServiceConfig::default()
+        };
+        let mut service = PaymentService::new(Box::new(datastore), config);
 
-        action_transaction.r#type = TransactionType::Resolve;
+        std::fs::write(
+            input.path(),
+            "type,client,tx,amount,currency\n\
+             deposit,1,1,100,\n\
+             deposit,1,2,50,usd\n\
+             withdrawal,1,3,60,USD\n\
+             withdrawal,1,4,20,USD\n\
+             deposit,2,5,10,EUR\n\
+             dispute,1,2,,\n",
+        )
+        .unwrap();
+        service.run(input.path().to_str().unwrap()).unwrap();
 
-        service
-            .handle_resolve(&action_transaction, &mut account)
-            .unwrap();
+        let report = std::fs::read_to_string(report.path()).unwrap();
+        let mut rows: Vec<&str> = report.lines().collect();
 
-        let account = service.retrieve_account(client_id).unwrap();
+        // The mock datastore returns accounts in no particular order.
+        rows.sort_unstable();
 
-        assert_eq!(account.available, Decimal::from(1000));
-        assert_eq!(account.total, Decimal::from(1000));
-        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(
+            rows,
+            vec![
+                "1,EUR,100,0.0000,100,false",
+                "1,USD,-20,50,30,false",
+                "2,EUR,10,0.0000,10,false",
+                "client,currency,available,held,total,locked",
+            ]
+        );
     }
 
     #[test]
-    pub fn should_chargeback_account() {
-        let datastore = MockDatastore::new(HashMap::default(), vec![]);
-        let mut service = PaymentService::new(Box::new(datastore));
-        let client_id = 3;
+    pub fn should_compare_amounts_in_other_currencies_with_limits_in_base_currency() {
+        let directory = TempDir::new().unwrap();
+        let rates = directory.path().join("rates.csv");
+        let input = directory.path().join("in.csv");
 
-        let transaction = Transaction {
-            r#type: TransactionType::Withdrawal,
-            client_id,
-            transaction_id: 455,
-            amount: Option::from(Decimal::from(500)),
-            disputed: false,
-        };
+        std::fs::write(&rates, "from,to,rate\nJPY,EUR,0.006\n").unwrap();
+        std::fs::write(
+            &input,
+            "type,client,tx,amount,currency\n\
+             deposit,1,1,100000,JPY\n\
+             deposit,1,2,1200,\n\
+             deposit,2,3,200000,JPY\n\
+             deposit,2,4,100000,JPY\n",
+        )
+        .unwrap();
 
-        let mut action_transaction = Transaction {
-            r#type: TransactionType::Dispute,
-            client_id,
-            transaction_id: 455,
-            amount: None,
-            disputed: false,
+        let config = ServiceConfig {
+            base_currency: Some("EUR".parse().unwrap()),
+            rates: RateTable::load(&rates).unwrap(),
+            approval_threshold: Some(Decimal::from(1000)),
+            limits: RunLimits {
+                max_total_deposits: Some(Decimal::from(3500)),
+                ..RunLimits::default()
+            },
+            report_path: Some(directory.path().join("accounts.csv")),
+            ..ServiceConfig::default()
         };
+        let mut service = PaymentService::new(Box::new(InMemoryDatastore::default()), config);
 
-        let account = Account {
-            client_id,
-            available: Decimal::from(1000),
-            held: Default::default(),
-            total: Decimal::from(1000),
-            locked: false,
+        // 100000 JPY is 600 EUR, below the threshold, while 1200 EUR and 200000 JPY are parked.
+        // The deposits add up to 3000 EUR before the last one exceeds the cap.
+        assert!(matches!(
+            service.run(input.to_str().unwrap()),
+            Err(PaymentEngineError::DepositLimitExceeded)
+        ));
+        assert_eq!(
+            service
+                .pending_transactions()
+                .unwrap()
+                .iter()
+                .map(|t| t.transaction_id)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(
+            service
+                .retrieve_account(1)
+                .unwrap()
+                .balances_in(Some(&"JPY".parse().unwrap()))
+                .available,
+            Decimal::from(100000)
+        );
+    }
+
+    #[test]
+    pub fn should_convert_between_currencies_at_rounded_rate() {
+        let directory = TempDir::new().unwrap();
+        let rates = directory.path().join("rates.csv");
+        let audit_log = directory.path().join("audit.log");
+
+        std::fs::write(&rates, "from,to,rate\nUSD,EUR,0.9\n").unwrap();
+
+        let config = ServiceConfig {
+            base_currency: Some("EUR".parse().unwrap()),
+            rates: RateTable::load(&rates).unwrap(),
+            rounding: RoundingConfig {
+                input_decimals: 2,
+                ..RoundingConfig::default()
+            },
+            audit_log_path: Some(audit_log.clone()),
+            ..ServiceConfig::default()
         };
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), config);
+        let transaction =
+            |r#type, transaction_id, amount: &str, currency: &str, to: &str| Transaction {
+                currency: currency.parse().ok(),
+                to_currency: to.parse().ok(),
+                ..Transaction::new(r#type, 1, transaction_id, Some(from_str_to_decimal(amount)))
+            };
+        let usd = "USD".parse().unwrap();
 
         service
-            .handle_withdrawal(&transaction, &mut account.clone())
+            .process(&transaction(TransactionType::Deposit, 1, "100", "USD", ""))
+            .unwrap();
+        service
+            .process(&transaction(
+                TransactionType::Convert,
+                2,
+                "10.01",
+                "USD",
+                "EUR",
+            ))
             .unwrap();
 
-        let mut account = service.retrieve_account(client_id).unwrap();
+        let account = service
+            .process(&transaction(TransactionType::Convert, 3, "4.5", "", "USD"))
+            .unwrap();
 
-        assert_eq!(account.held, Decimal::ZERO);
-        assert_eq!(account.available, Decimal::from(500));
-        assert_eq!(account.total, Decimal::from(500));
+        assert_eq!(account.available, from_str_to_decimal("4.51"));
+        assert_eq!(
+            account.balances_in(Some(&usd)).available,
+            from_str_to_decimal("94.99")
+        );
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Convert, 4, "5", "", "USD")),
+            Err(PaymentEngineError::InsufficientAccountFunds)
+        ));
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Convert, 5, "1", "USD", "GBP")),
+            Err(PaymentEngineError::ExchangeRateNotFound { .. })
+        ));
+        assert!(std::fs::read_to_string(&audit_log)
+            .unwrap()
+            .contains("10.01 USD -> 9.01 EUR at 0.9"));
+    }
 
-        service
-            .handle_dispute(&action_transaction, &mut account)
-            .unwrap();
+    #[test]
+    pub fn should_reject_or_audit_activity_on_locked_accounts() {
+        let audit_log = NamedTempFile::new().unwrap();
+        let transaction =
+            |r#type, transaction_id, amount| Transaction::new(r#type, 1, transaction_id, amount);
+        let locked_service = |strict_locking| {
+            let config = ServiceConfig {
+                audit_log_path: Some(audit_log.path().to_path_buf()),
+                flags: FeatureFlags {
+                    strict_locking,
+                    ..FeatureFlags::default()
+                },
+                ..ServiceConfig::default()
+            };
+            let datastore = MockDatastore::new(HashMap::default(), vec![]);
+            let mut service = PaymentService::new(Box::new(datastore), config);
 
-        let mut account = service.retrieve_account(client_id).unwrap();
+            service
+                .process(&transaction(
+                    TransactionType::Deposit,
+                    1,
+                    Some(Decimal::from(10)),
+                ))
+                .unwrap();
+            for r#type in [TransactionType::Dispute, TransactionType::Chargeback] {
+                service.process(&transaction(r#type, 1, None)).unwrap();
+            }
 
-        assert_eq!(account.available, Decimal::from(500));
-        assert_eq!(account.total, Decimal::from(1000));
-        assert_eq!(account.held, Decimal::from(500));
+            service
+        };
+        let deposit = transaction(TransactionType::Deposit, 2, Some(Decimal::from(5)));
 
-        action_transaction.r#type = TransactionType::Chargeback;
+        let mut strict = locked_service(Some(Rollout {
+            enabled: true,
+            ..Rollout::default()
+        }));
 
-        service
-            .handle_chargeback(&action_transaction, &mut account)
-            .unwrap();
+        assert!(matches!(
+            strict.process(&deposit),
+            Err(PaymentEngineError::AccountLocked)
+        ));
 
-        let account = service.retrieve_account(client_id).unwrap();
+        let account = locked_service(None).process(&deposit).unwrap();
+        let audited = std::fs::read_to_string(audit_log.path()).unwrap();
 
-        assert_eq!(account.available, Decimal::from(500));
-        assert_eq!(account.total, Decimal::from(500));
-        assert_eq!(account.held, Decimal::ZERO);
-        assert_eq!(account.locked, true);
+        assert_eq!(account.available, Decimal::from(5));
+        assert_eq!(audited.matches("locked_account_activity").count(), 1);
     }
 
     #[test]
-    pub fn should_process_transactions_from_csv() {
+    pub fn should_queue_or_reject_dispute_steps_of_locked_accounts() {
+        let transaction = |r#type, transaction_id, amount: Option<i64>| {
+            Transaction::new(r#type, 1, transaction_id, amount.map(Decimal::from))
+        };
+        let config = ServiceConfig {
+            locked_disputes: LockedDisputePolicy {
+                dispute: LockedAction::Reject,
+                resolve: LockedAction::Queue,
+                chargeback: LockedAction::Allow,
+            },
+            ..ServiceConfig::default()
+        };
         let datastore = MockDatastore::new(HashMap::default(), vec![]);
-        let mut service = PaymentService::new(Box::new(datastore));
+        let mut service = PaymentService::new(Box::new(datastore), config);
 
-        match service.run("test.csv") {
-            Ok(_) => {}
-            Err(_) => {
-                assert!(false)
-            }
-        };
+        for (r#type, transaction_id, amount) in [
+            (TransactionType::Deposit, 1, Some(100)),
+            (TransactionType::Deposit, 2, Some(50)),
+            (TransactionType::Deposit, 3, Some(20)),
+            (TransactionType::Dispute, 2, None),
+            (TransactionType::Dispute, 3, None),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Chargeback, 1, None),
+            (TransactionType::Resolve, 2, None),
+            (TransactionType::Chargeback, 3, None),
+            (TransactionType::Deposit, 4, Some(10)),
+        ] {
+            service
+                .process(&transaction(r#type, transaction_id, amount))
+                .unwrap();
+        }
+
+        assert!(matches!(
+            service.process(&transaction(TransactionType::Dispute, 4, None)),
+            Err(PaymentEngineError::DisputeOnLockedAccount)
+        ));
 
         let account = service.retrieve_account(1).unwrap();
 
-        assert_eq!(account.available, from_str_to_decimal("400.9699"));
-        assert_eq!(account.held, from_str_to_decimal("600"));
-        assert_eq!(account.total, from_str_to_decimal("1000.9699"));
-        assert_eq!(account.locked, false);
+        assert!(account.locked);
+        assert_eq!(account.held, Decimal::from(50));
+        assert_eq!(service.locked_queue().len(), 1);
 
-        let account = service.retrieve_account(2).unwrap();
+        let account = service.unlock_account(1, "ops").unwrap();
 
-        assert_eq!(account.available, from_str_to_decimal("5600"));
-        assert_eq!(account.held, from_str_to_decimal("0"));
-        assert_eq!(account.total, from_str_to_decimal("5600"));
-        assert_eq!(account.locked, true);
+        assert!(!account.locked);
+        assert_eq!(account.available, Decimal::from(60));
+        assert_eq!(account.held, Decimal::ZERO);
+        assert!(service.locked_queue().is_empty());
+    }
 
-        let account = service.retrieve_account(3).unwrap();
+    #[test]
+    pub fn should_report_differences_of_shadow_policies() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let report = NamedTempFile::new().unwrap();
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let shadow_config = ServiceConfig {
+            flags: FeatureFlags {
+                deposit_only_disputes: Some(Rollout {
+                    enabled: true,
+                    ..Rollout::default()
+                }),
+                ..FeatureFlags::default()
+            },
+            ..ServiceConfig::default()
+        };
 
-        assert_eq!(account.available, from_str_to_decimal("0"));
-        assert_eq!(account.held, from_str_to_decimal("500"));
-        assert_eq!(account.total, from_str_to_decimal("500"));
-        assert_eq!(account.locked, false);
+        service.enable_shadow(shadow_config, report.path().to_path_buf());
+        service.run("test.csv").unwrap();
 
-        let account = service.retrieve_account(33).unwrap();
+        let shadow = service.shadow.as_ref().unwrap();
+        let transactions: Vec<Option<u32>> = shadow
+            .report
+            .differences()
+            .iter()
+            .map(|difference| difference.tx)
+            .collect();
 
-        assert_eq!(account.available, from_str_to_decimal("2500"));
-        assert_eq!(account.held, from_str_to_decimal("300"));
-        assert_eq!(account.total, from_str_to_decimal("2800"));
-        assert_eq!(account.locked, false);
+        assert_eq!(transactions, vec![Some(9), Some(9), Some(585), None, None]);
+        assert!(service.retrieve_account(2).unwrap().locked);
+        assert!(!shadow.service.retrieve_account(2).unwrap().locked);
+        assert_eq!(
+            std::fs::read_to_string(report.path())
+                .unwrap()
+                .lines()
+                .count(),
+            6
+        );
+    }
 
-        let account = service.retrieve_account(99).unwrap();
+    #[test]
+    pub fn should_hold_reserved_funds_until_commit() {
+        let datastore = MockDatastore::new(HashMap::default(), vec![]);
+        let mut service = PaymentService::new(Box::new(datastore), ServiceConfig::default());
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::from(100)));
+        let withdrawal = Transaction {
+            r#type: TransactionType::Withdrawal,
+            transaction_id: 2,
+            amount: Some(Decimal::from(50)),
+            ..deposit.clone()
+        };
+        let mut account = Account::new(1);
 
-        assert_eq!(account.available, from_str_to_decimal("1000"));
-        assert_eq!(account.held, from_str_to_decimal("500"));
-        assert_eq!(account.total, from_str_to_decimal("1500"));
-        assert_eq!(account.locked, false);
+        service.handle_deposit(&deposit, &mut account).unwrap();
+
+        let reservation = service
+            .reserve(1, Decimal::from(60), Duration::seconds(60))
+            .unwrap();
+
+        assert!(service
+            .reserve(1, Decimal::from(60), Duration::seconds(60))
+            .is_err());
+        assert!(service
+            .handle_withdrawal(&withdrawal, &mut account)
+            .is_err());
+
+        let account = service
+            .commit_reservation(&reservation.token, None)
+            .unwrap();
+        let internal_id = IdConfig::default().start;
+
+        assert_eq!(account.available, Decimal::from(40));
+        assert!(service
+            .datastore
+            .retrieve_transaction(internal_id)
+            .unwrap()
+            .is_some());
+        assert!(service
+            .commit_reservation(&reservation.token, Some(4))
+            .is_err());
+
+        let expired = service
+            .reserve(1, Decimal::from(40), Duration::seconds(-1))
+            .unwrap();
+
+        assert_eq!(
+            service.retrieve_account(1).unwrap().available,
+            Decimal::from(40)
+        );
+        assert!(service.commit_reservation(&expired.token, Some(5)).is_err());
+        assert!(service.reservations().is_empty());
     }
 
     fn from_str_to_decimal(amount: &str) -> Decimal {
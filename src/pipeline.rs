@@ -0,0 +1,136 @@
+use crate::error::PaymentEngineResult;
+use crate::input::{InputSource, RawRow};
+use crate::model::{Account, Transaction};
+use crate::report::{self, CsvStyle, ReportFormat};
+use crate::rows::{self, TransactionRows};
+use crossbeam_channel::{Receiver, Sender};
+use csv::{Position, StringRecord};
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+
+type Record = (Result<StringRecord, csv::Error>, Position);
+type ParsedRow = (Result<Transaction, csv::Error>, Position, StringRecord);
+
+/// Rows read and deserialized ahead of the processor: a reader stage reads raw records and a
+/// parser stage deserializes them, each on a thread of its own handing its rows on through a
+/// bounded channel. A stage blocks once `depth` rows wait for the stage after it, so a slow
+/// datastore holds back reading instead of filling memory with parsed rows.
+pub struct PipelinedRows {
+    source: String,
+    position: Position,
+    record: StringRecord,
+    rows: Receiver<ParsedRow>,
+}
+
+/// Writer stage of the account report: rows are serialized and written on a thread of their
+/// own while the processor rounds and records the next ones.
+pub struct ReportStage {
+    rows: Sender<Account>,
+    writer: JoinHandle<PaymentEngineResult<()>>,
+}
+
+impl PipelinedRows {
+    /// Starts the reader and parser stages of `rows`. Dropping the pipelined rows stops both
+    /// after the row they are handing on.
+    pub fn start<R: Read + Send + 'static>(rows: TransactionRows<R>, depth: NonZeroUsize) -> Self {
+        let source = rows.source().to_string();
+        let position = rows.position().clone();
+        let parser = rows.parser();
+        let (record_sender, records) = crossbeam_channel::bounded::<Record>(depth.get());
+        let (row_sender, parsed) = crossbeam_channel::bounded::<ParsedRow>(depth.get());
+
+        thread::spawn(move || {
+            let mut rows = rows;
+
+            while let Some(record) = rows.next_record() {
+                if record_sender
+                    .send((record, rows.position().clone()))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        thread::spawn(move || {
+            for (record, position) in records {
+                let (row, record) = match record {
+                    Ok(record) => (parser.parse(&record), record),
+                    Err(e) => (Err(e), StringRecord::new()),
+                };
+
+                if row_sender.send((row, position, record)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        PipelinedRows {
+            source,
+            position,
+            record: StringRecord::new(),
+            rows: parsed,
+        }
+    }
+}
+
+impl Iterator for PipelinedRows {
+    type Item = Result<Transaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (row, position, record) = self.rows.recv().ok()?;
+
+        self.position = position;
+        self.record = record;
+
+        Some(row)
+    }
+}
+
+impl InputSource<csv::Error> for PipelinedRows {
+    fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Position after the last row handed to the processor, not the one the reader is at.
+    fn position(&self) -> Option<&Position> {
+        Some(&self.position)
+    }
+
+    fn raw_row(&self) -> Option<RawRow> {
+        rows::raw_row(&self.record)
+    }
+}
+
+impl ReportStage {
+    pub fn start(
+        format: ReportFormat,
+        csv: CsvStyle,
+        path: Option<PathBuf>,
+        fingerprint: bool,
+        depth: NonZeroUsize,
+    ) -> Self {
+        let (rows, accounts) = crossbeam_channel::bounded(depth.get());
+        let writer = thread::spawn(move || {
+            report::write_accounts(format, csv, path.as_deref(), fingerprint, accounts)
+        });
+
+        ReportStage { rows, writer }
+    }
+
+    pub fn write(&self, account: Account) {
+        // A writer which failed has dropped its end of the channel, `finish` returns its error.
+        let _ = self.rows.send(account);
+    }
+
+    /// Waits for the writer to write the rows sent so far and finish the report.
+    pub fn finish(self) -> PaymentEngineResult<()> {
+        drop(self.rows);
+
+        match self.writer.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
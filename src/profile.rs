@@ -0,0 +1,433 @@
+use crate::echo::{self, CanonicalTransaction, EchoSummary};
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::Transaction;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use csv::{ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const PROFILES_DIR: &str = "profiles";
+const CANONICAL_COLUMNS: [&str; 10] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "memo",
+    "counterparty",
+    "to_client",
+    "currency",
+    "to_currency",
+];
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+/// What to do with columns of a partner file which the profile does not map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtraColumns {
+    #[default]
+    Ignore,
+    Fail,
+}
+
+/// What to do with a header which appears more than once in a partner file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateColumns {
+    /// The first of the columns is read, the others are ignored.
+    #[default]
+    First,
+    Fail,
+}
+
+/// How the sign of amounts is read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AmountSign {
+    /// Amounts are unsigned and the type column says what they are.
+    #[default]
+    Unsigned,
+    /// A single signed amount column replaces the type: negative amounts are withdrawals,
+    /// others deposits. Rows without an amount keep the type of their type column, if any.
+    TypeFromSign,
+}
+
+/// How the files of one partner differ from the canonical input schema, read from a TOML file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartnerProfile {
+    pub delimiter: char,
+    /// Partner column name by canonical column name. Unmapped columns keep their canonical name.
+    pub columns: HashMap<String, String>,
+    pub decimal_separator: char,
+    pub thousands_separator: Option<char>,
+    /// `chrono` format of naive timestamps, which are taken as UTC. RFC 3339 without it.
+    pub timestamp_format: Option<String>,
+    /// Canonical type by partner type, compared case insensitively.
+    pub type_aliases: HashMap<String, String>,
+    pub extra_columns: ExtraColumns,
+    pub duplicate_columns: DuplicateColumns,
+    pub amount_sign: AmountSign,
+}
+
+impl Default for PartnerProfile {
+    fn default() -> Self {
+        PartnerProfile {
+            delimiter: ',',
+            columns: HashMap::default(),
+            decimal_separator: '.',
+            thousands_separator: None,
+            timestamp_format: None,
+            type_aliases: HashMap::default(),
+            extra_columns: ExtraColumns::default(),
+            duplicate_columns: DuplicateColumns::default(),
+            amount_sign: AmountSign::default(),
+        }
+    }
+}
+
+impl PartnerProfile {
+    pub fn load(path: &Path) -> PaymentEngineResult<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| PaymentEngineError::ConfigRead { source })?;
+        let mut profile: PartnerProfile = toml::from_str(&text)?;
+
+        profile.type_aliases = profile
+            .type_aliases
+            .into_iter()
+            .map(|(alias, name)| (alias.to_lowercase(), name))
+            .collect();
+
+        Ok(profile)
+    }
+
+    /// Path of a profile given by name, `profiles/<name>.toml`, or the argument itself when it
+    /// is an existing file.
+    pub fn path(name: &str) -> PathBuf {
+        let path = PathBuf::from(name);
+
+        if path.is_file() {
+            path
+        } else {
+            Path::new(PROFILES_DIR).join(format!("{}.toml", name))
+        }
+    }
+
+    /// Partner column name of a canonical column.
+    fn column_name<'a>(&'a self, column: &'a str) -> &'a str {
+        self.columns.get(column).map_or(column, String::as_str)
+    }
+
+    /// Whether files have to have the canonical column. The type is derived from the sign of
+    /// the amount when the profile says so, so the amount is required instead.
+    fn is_required(&self, column: &str) -> bool {
+        match self.amount_sign {
+            AmountSign::Unsigned => REQUIRED_COLUMNS.contains(&column),
+            AmountSign::TypeFromSign => {
+                column == "amount" || (column != "type" && REQUIRED_COLUMNS.contains(&column))
+            }
+        }
+    }
+
+    /// Fails fast on columns the profile does not map or on duplicated headers when the
+    /// profile says so, before any row is read.
+    fn check_headers(&self, headers: &StringRecord) -> PaymentEngineResult<()> {
+        for (index, header) in headers.iter().enumerate() {
+            let mapped = CANONICAL_COLUMNS
+                .iter()
+                .any(|column| self.column_name(column) == header);
+
+            if self.extra_columns == ExtraColumns::Fail && !mapped {
+                return Err(PaymentEngineError::UnexpectedProfileColumn {
+                    column: header.to_string(),
+                });
+            }
+            if self.duplicate_columns == DuplicateColumns::Fail
+                && headers.iter().take(index).any(|earlier| earlier == header)
+            {
+                return Err(PaymentEngineError::DuplicateProfileColumn {
+                    column: header.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maps a partner row to the canonical columns.
+    fn normalize(
+        &self,
+        record: &StringRecord,
+        indices: &[Option<usize>],
+    ) -> PaymentEngineResult<StringRecord> {
+        let mut canonical = StringRecord::new();
+        let value = |column: &str| {
+            CANONICAL_COLUMNS
+                .iter()
+                .position(|canonical| *canonical == column)
+                .and_then(|position| indices[position])
+                .and_then(|index| record.get(index))
+                .unwrap_or("")
+        };
+        let (r#type, amount) = self.type_and_amount(value("type"), value("amount"));
+
+        for column in CANONICAL_COLUMNS {
+            match column {
+                "type" => canonical.push_field(r#type),
+                "amount" => canonical.push_field(&amount),
+                "timestamp" => canonical.push_field(&self.normalize_timestamp(value(column))?),
+                _ => canonical.push_field(value(column)),
+            }
+        }
+
+        Ok(canonical)
+    }
+
+    /// Canonical type and unsigned amount of a row, the type taken from the sign of the amount
+    /// when the profile says so.
+    fn type_and_amount<'a>(&'a self, r#type: &'a str, amount: &str) -> (&'a str, String) {
+        let amount = self.normalize_amount(amount);
+
+        match (self.amount_sign, amount.strip_prefix('-')) {
+            (AmountSign::TypeFromSign, Some(unsigned)) => ("withdrawal", unsigned.to_string()),
+            (AmountSign::TypeFromSign, None) if !amount.is_empty() => {
+                ("deposit", amount.trim_start_matches('+').to_string())
+            }
+            _ => (
+                self.type_aliases
+                    .get(&r#type.to_lowercase())
+                    .map_or(r#type, String::as_str),
+                amount,
+            ),
+        }
+    }
+
+    fn normalize_amount(&self, value: &str) -> String {
+        value
+            .chars()
+            .filter(|c| Some(*c) != self.thousands_separator)
+            .map(|c| if c == self.decimal_separator { '.' } else { c })
+            .collect()
+    }
+
+    fn normalize_timestamp(&self, value: &str) -> PaymentEngineResult<String> {
+        match &self.timestamp_format {
+            Some(format) if !value.is_empty() => NaiveDateTime::parse_from_str(value, format)
+                .map(|timestamp| Utc.from_utc_datetime(&timestamp).to_rfc3339())
+                .map_err(|_| PaymentEngineError::InvalidTimestamp),
+            _ => Ok(value.to_string()),
+        }
+    }
+}
+
+/// Applies the profile to a partner file and writes the transactions in the canonical schema.
+/// Rows are checked with the rules of a normal run; rejected rows are logged and left out.
+pub fn normalize_file(
+    profile: &PartnerProfile,
+    input_path: &Path,
+    output_path: &Path,
+) -> PaymentEngineResult<EchoSummary> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .trim(Trim::All)
+        .delimiter(profile.delimiter as u8)
+        .from_path(input_path)?;
+    let headers = reader.headers()?.clone();
+
+    profile.check_headers(&headers)?;
+
+    // `position` finds the first of duplicated columns.
+    let indices: Vec<Option<usize>> = CANONICAL_COLUMNS
+        .iter()
+        .map(|column| {
+            let name = profile.column_name(column);
+
+            headers.iter().position(|header| header == name)
+        })
+        .collect();
+
+    for (column, index) in CANONICAL_COLUMNS.iter().zip(&indices) {
+        if index.is_none() && profile.is_required(column) {
+            return Err(PaymentEngineError::MissingProfileColumn {
+                column: column.to_string(),
+            });
+        }
+    }
+
+    let canonical_headers = StringRecord::from(CANONICAL_COLUMNS.to_vec());
+    let mut writer = WriterBuilder::new().from_path(output_path)?;
+    let mut summary = EchoSummary::default();
+    let mut row = 1;
+
+    for record in reader.records() {
+        row += 1;
+
+        let transaction = record
+            .map_err(PaymentEngineError::from)
+            .and_then(|record| profile.normalize(&record, &indices))
+            .and_then(|canonical| {
+                let transaction: Transaction = canonical.deserialize(Some(&canonical_headers))?;
+
+                echo::validate(&transaction)?;
+
+                Ok(transaction)
+            });
+
+        match transaction {
+            Ok(transaction) => {
+                writer.serialize(CanonicalTransaction::from(&transaction))?;
+                summary.written += 1;
+            }
+            Err(e) => {
+                warn!("Row {} rejected: {}", row, e);
+                summary.rejected += 1;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::PaymentEngineError;
+    use crate::profile::{
+        normalize_file, AmountSign, DuplicateColumns, ExtraColumns, PartnerProfile,
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_normalize_partner_file_with_profile() {
+        let directory = TempDir::new().unwrap();
+        let profile_path = directory.path().join("partner.toml");
+        let input = directory.path().join("in.csv");
+        let output = directory.path().join("out.csv");
+
+        std::fs::write(
+            &profile_path,
+            r#"
+            delimiter = ";"
+            decimal_separator = ","
+            thousands_separator = "."
+            timestamp_format = "%d.%m.%Y %H:%M"
+
+            [columns]
+            type = "Art"
+            client = "Kunde"
+            tx = "Referenz"
+            amount = "Betrag"
+            timestamp = "Gebucht"
+
+            [type_aliases]
+            GUTSCHRIFT = "deposit"
+            Lastschrift = "withdrawal"
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            &input,
+            "Art;Kunde;Referenz;Betrag;Gebucht\nGutschrift;1;10;1.234,50;02.01.2024 09:30\n\
+             lastschrift;1;11;;02.01.2024 10:00\nLastschrift;1;12;0,5;\n",
+        )
+        .unwrap();
+
+        let profile = PartnerProfile::load(&profile_path).unwrap();
+        let summary = normalize_file(&profile, &input, &output).unwrap();
+
+        assert_eq!((summary.written, summary.rejected), (2, 1));
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "type,client,tx,amount,timestamp,memo,counterparty,to_client,currency,to_currency\n\
+             deposit,1,10,1234.50,2024-01-02T09:30:00Z,,,,,\n\
+             withdrawal,1,12,0.5,,,,,,\n"
+        );
+    }
+
+    #[test]
+    pub fn should_tolerate_or_reject_extra_and_duplicate_columns() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let output = directory.path().join("out.csv");
+
+        std::fs::write(
+            &input,
+            "type,client,tx,amount,note,amount\ndeposit,1,10,5,vip,7\n",
+        )
+        .unwrap();
+
+        let tolerant = PartnerProfile::default();
+        let summary = normalize_file(&tolerant, &input, &output).unwrap();
+
+        assert_eq!((summary.written, summary.rejected), (1, 0));
+        assert!(std::fs::read_to_string(&output)
+            .unwrap()
+            .ends_with("deposit,1,10,5,,,,,,\n"));
+
+        let strict_extra = PartnerProfile {
+            extra_columns: ExtraColumns::Fail,
+            ..PartnerProfile::default()
+        };
+        let strict_duplicates = PartnerProfile {
+            duplicate_columns: DuplicateColumns::Fail,
+            ..PartnerProfile::default()
+        };
+
+        assert!(matches!(
+            normalize_file(&strict_extra, &input, &output),
+            Err(PaymentEngineError::UnexpectedProfileColumn { column }) if column == "note"
+        ));
+        assert!(matches!(
+            normalize_file(&strict_duplicates, &input, &output),
+            Err(PaymentEngineError::DuplicateProfileColumn { column }) if column == "amount"
+        ));
+    }
+
+    #[test]
+    pub fn should_derive_type_from_sign_of_single_amount_column() {
+        let directory = TempDir::new().unwrap();
+        let profile_path = directory.path().join("signed.toml");
+        let input = directory.path().join("in.csv");
+        let output = directory.path().join("out.csv");
+
+        std::fs::write(
+            &profile_path,
+            r#"
+            amount_sign = "type-from-sign"
+
+            [columns]
+            amount = "value"
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            &input,
+            "client,tx,value\n1,10,25.5\n1,11,-4.25\n1,12,+3\n1,13,\n",
+        )
+        .unwrap();
+
+        let profile = PartnerProfile::load(&profile_path).unwrap();
+
+        assert_eq!(profile.amount_sign, AmountSign::TypeFromSign);
+
+        let summary = normalize_file(&profile, &input, &output).unwrap();
+
+        assert_eq!((summary.written, summary.rejected), (3, 1));
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "type,client,tx,amount,timestamp,memo,counterparty,to_client,currency,to_currency\n\
+             deposit,1,10,25.5,,,,,,\n\
+             withdrawal,1,11,4.25,,,,,,\n\
+             deposit,1,12,3,,,,,,\n"
+        );
+
+        std::fs::write(&input, "client,tx\n1,10\n").unwrap();
+
+        assert!(matches!(
+            normalize_file(&profile, &input, &output),
+            Err(PaymentEngineError::MissingProfileColumn { column }) if column == "amount"
+        ));
+    }
+}
@@ -0,0 +1,349 @@
+#[cfg(feature = "profiling")]
+use crate::datastore::CacheStats;
+use crate::datastore::DatastoreOperations;
+#[cfg(feature = "profiling")]
+use crate::error::PaymentEngineResult;
+#[cfg(feature = "profiling")]
+use crate::model::{Account, DisputeRecord, Transaction};
+#[cfg(feature = "profiling")]
+use std::cell::RefCell;
+#[cfg(feature = "profiling")]
+use std::collections::HashMap;
+#[cfg(feature = "profiling")]
+use std::io::Write;
+#[cfg(feature = "profiling")]
+use std::path::Path;
+#[cfg(feature = "profiling")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "profiling")]
+const HOTSPOTS_SHOWN: usize = 10;
+
+/// Times the code until it is dropped, as a frame on top of the spans still open. Does nothing
+/// unless built with the `profiling` feature and enabled for the run.
+#[must_use]
+pub struct Span {
+    #[cfg(feature = "profiling")]
+    open: bool,
+}
+
+/// Time spent in spans of one name over a run. `own` leaves out the time of nested spans.
+#[cfg(feature = "profiling")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hotspot {
+    pub calls: u64,
+    pub total: Duration,
+    pub own: Duration,
+}
+
+#[cfg(feature = "profiling")]
+struct Frame {
+    name: &'static str,
+    started: Instant,
+    nested: Duration,
+}
+
+/// Spans recorded on this thread since `enable`, by stack for the folded output and by name
+/// for the hotspots.
+#[cfg(feature = "profiling")]
+#[derive(Default)]
+struct Profiler {
+    open: Vec<Frame>,
+    stacks: HashMap<String, Duration>,
+    hotspots: HashMap<&'static str, Hotspot>,
+}
+
+#[cfg(feature = "profiling")]
+thread_local! {
+    static PROFILER: RefCell<Option<Profiler>> = const { RefCell::new(None) };
+}
+
+/// Datastore timing every call as a `datastore::` span.
+#[cfg(feature = "profiling")]
+struct ProfiledDatastore {
+    datastore: Box<dyn DatastoreOperations>,
+}
+
+/// Starts recording spans of this thread.
+#[cfg(feature = "profiling")]
+pub fn enable() {
+    PROFILER.with(|profiler| *profiler.borrow_mut() = Some(Profiler::default()));
+}
+
+#[cfg(feature = "profiling")]
+pub fn span(name: &'static str) -> Span {
+    PROFILER.with(|profiler| match profiler.borrow_mut().as_mut() {
+        Some(profiler) => {
+            profiler.open.push(Frame {
+                name,
+                started: Instant::now(),
+                nested: Duration::ZERO,
+            });
+            Span { open: true }
+        }
+        None => Span { open: false },
+    })
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn span(_name: &'static str) -> Span {
+    Span {}
+}
+
+#[cfg(feature = "profiling")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+
+        PROFILER.with(|profiler| {
+            if let Some(profiler) = profiler.borrow_mut().as_mut() {
+                profiler.close();
+            }
+        });
+    }
+}
+
+#[cfg(feature = "profiling")]
+impl Profiler {
+    fn close(&mut self) {
+        let frame = match self.open.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let total = frame.started.elapsed();
+        let own = total.saturating_sub(frame.nested);
+        let stack = self
+            .open
+            .iter()
+            .map(|open| open.name)
+            .chain(std::iter::once(frame.name))
+            .collect::<Vec<_>>()
+            .join(";");
+
+        *self.stacks.entry(stack).or_default() += own;
+
+        let hotspot = self.hotspots.entry(frame.name).or_default();
+
+        hotspot.calls += 1;
+        hotspot.total += total;
+        hotspot.own += own;
+
+        if let Some(parent) = self.open.last_mut() {
+            parent.nested += total;
+        }
+    }
+}
+
+/// Stops recording, writes the stacks in folded format with their own time in microseconds as
+/// the sample count, ready for `flamegraph.pl` or `inferno-flamegraph`, and prints the spans
+/// with the most own time to stderr. Returns the hotspots in that order.
+#[cfg(feature = "profiling")]
+pub fn finish(folded_path: Option<&Path>) -> PaymentEngineResult<Vec<(&'static str, Hotspot)>> {
+    let profiler = match PROFILER.with(|profiler| profiler.borrow_mut().take()) {
+        Some(profiler) => profiler,
+        None => return Ok(vec![]),
+    };
+
+    if let Some(folded_path) = folded_path {
+        let mut stacks: Vec<_> = profiler.stacks.iter().collect();
+
+        stacks.sort();
+        crate::sink::write_atomically(folded_path, |file| {
+            for (stack, own) in stacks {
+                writeln!(file, "{} {}", stack, own.as_micros())?;
+            }
+
+            Ok(())
+        })?;
+    }
+
+    let mut hotspots: Vec<_> = profiler.hotspots.into_iter().collect();
+
+    hotspots.sort_by(|(a_name, a), (b_name, b)| b.own.cmp(&a.own).then(a_name.cmp(b_name)));
+    eprintln!(
+        "{:<40} {:>10} {:>12} {:>12}",
+        "span", "calls", "total ms", "own ms"
+    );
+
+    for (name, hotspot) in hotspots.iter().take(HOTSPOTS_SHOWN) {
+        eprintln!(
+            "{:<40} {:>10} {:>12.3} {:>12.3}",
+            name,
+            hotspot.calls,
+            hotspot.total.as_secs_f64() * 1000.0,
+            hotspot.own.as_secs_f64() * 1000.0
+        );
+    }
+
+    Ok(hotspots)
+}
+
+/// Datastore timing its calls when profiling is built in, `datastore` itself otherwise.
+#[cfg(feature = "profiling")]
+pub fn profiled(datastore: Box<dyn DatastoreOperations>) -> Box<dyn DatastoreOperations> {
+    Box::new(ProfiledDatastore { datastore })
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn profiled(datastore: Box<dyn DatastoreOperations>) -> Box<dyn DatastoreOperations> {
+    datastore
+}
+
+#[cfg(feature = "profiling")]
+impl DatastoreOperations for ProfiledDatastore {
+    fn retrieve_transaction(
+        &mut self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        let _span = span("datastore::retrieve_transaction");
+        self.datastore.retrieve_transaction(transaction_id)
+    }
+
+    fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        let _span = span("datastore::save_transaction");
+        self.datastore.save_transaction(transaction)
+    }
+
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let _span = span("datastore::retrieve_client_transactions");
+        self.datastore.retrieve_client_transactions(client_id)
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        let _span = span("datastore::retrieve_account");
+        self.datastore.retrieve_account(client_id)
+    }
+
+    fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+        let _span = span("datastore::save_account");
+        self.datastore.save_account(account)
+    }
+
+    fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        let _span = span("datastore::retrieve_all_accounts");
+        self.datastore.retrieve_all_accounts()
+    }
+
+    fn retrieve_accounts_after(
+        &self,
+        after: Option<u16>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        let _span = span("datastore::retrieve_accounts_after");
+        self.datastore.retrieve_accounts_after(after, count)
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let _span = span("datastore::retrieve_transactions_after");
+        self.datastore.retrieve_transactions_after(after, count)
+    }
+
+    fn set_transaction_disputed(
+        &mut self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        let _span = span("datastore::set_transaction_disputed");
+        self.datastore
+            .set_transaction_disputed(transaction_id, disputed)
+    }
+
+    fn remove_transaction_from_cache(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        let _span = span("datastore::remove_transaction_from_cache");
+        self.datastore.remove_transaction_from_cache(transaction_id)
+    }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        let _span = span("datastore::save_pending_transaction");
+        self.datastore.save_pending_transaction(transaction)
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        let _span = span("datastore::retrieve_pending_transactions");
+        self.datastore.retrieve_pending_transactions()
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        let _span = span("datastore::remove_pending_transaction");
+        self.datastore.remove_pending_transaction(transaction_id)
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        let _span = span("datastore::save_dispute_record");
+        self.datastore.save_dispute_record(record)
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        let _span = span("datastore::retrieve_dispute_chain");
+        self.datastore.retrieve_dispute_chain(transaction_id)
+    }
+
+    fn count_open_disputes(&mut self, client_id: u16) -> PaymentEngineResult<usize> {
+        let _span = span("datastore::count_open_disputes");
+        self.datastore.count_open_disputes(client_id)
+    }
+
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        let _span = span("datastore::flush");
+        self.datastore.flush()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.datastore.cache_stats()
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use crate::profiling;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_record_nested_spans_as_folded_stacks() {
+        profiling::enable();
+
+        {
+            let _outer = profiling::span("process_transaction");
+
+            for _ in 0..2 {
+                let _inner = profiling::span("datastore::save_account");
+            }
+        }
+
+        let folded = NamedTempFile::new().unwrap();
+        let hotspots = profiling::finish(Some(folded.path())).unwrap();
+        let stacks: Vec<String> = std::fs::read_to_string(folded.path())
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0.to_string())
+            .collect();
+
+        assert_eq!(
+            stacks,
+            vec![
+                "process_transaction",
+                "process_transaction;datastore::save_account"
+            ]
+        );
+
+        let (_, save_account) = hotspots
+            .iter()
+            .find(|(name, _)| *name == "datastore::save_account")
+            .unwrap();
+
+        assert_eq!(save_account.calls, 2);
+        assert!(profiling::finish(None).unwrap().is_empty());
+    }
+}
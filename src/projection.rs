@@ -0,0 +1,290 @@
+use crate::event_store::AccountEvent;
+use crate::model::Account;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread::JoinHandle;
+
+/// Read model maintained from the account event stream.
+pub trait Projection: Send {
+    fn apply(&mut self, event: &AccountEvent);
+
+    /// Called before a sync completes and when the runner stops, for projections which buffer
+    /// their writes.
+    fn flush(&mut self) {}
+}
+
+enum ProjectionMessage {
+    Event(AccountEvent),
+    Sync(Sender<()>),
+    Stop,
+}
+
+/// Applies events to projections on a background thread, so queries against the read models
+/// never contend with the ingestion write path.
+pub struct ProjectionRunner {
+    sender: Sender<ProjectionMessage>,
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Clone)]
+pub struct ProjectionHandle {
+    sender: Sender<ProjectionMessage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Display)]
+#[display(
+    fmt = "{} accounts ({} locked), available {}, held {}, total {}",
+    accounts,
+    locked_accounts,
+    available,
+    held,
+    total
+)]
+pub struct Aggregates {
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+/// State of every account after the first `events` events of the stream.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccountsSnapshot {
+    pub events: u64,
+    pub accounts: HashMap<u16, Account>,
+}
+
+/// Shared handle to the latest accounts snapshot. Snapshots are copy-on-write: taking one only
+/// clones a pointer, and the projection copies the accounts on its next event only while an
+/// earlier snapshot is still held, so readers never block the projection for longer than that
+/// copy and always see the accounts of a single point of the stream.
+#[derive(Clone, Default)]
+pub struct AccountsView {
+    current: Arc<RwLock<Arc<AccountsSnapshot>>>,
+}
+
+/// Latest state of every account, queryable while ingestion is running.
+#[derive(Default)]
+pub struct AccountsProjection {
+    view: AccountsView,
+}
+
+/// Totals over all accounts.
+#[derive(Default)]
+pub struct AggregatesProjection {
+    locked: HashMap<u16, bool>,
+    aggregates: Arc<RwLock<Aggregates>>,
+}
+
+impl ProjectionRunner {
+    pub fn start(mut projections: Vec<Box<dyn Projection>>) -> Self {
+        let (sender, receiver) = channel();
+        let worker = std::thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    ProjectionMessage::Event(event) => {
+                        for projection in projections.iter_mut() {
+                            projection.apply(&event);
+                        }
+                    }
+                    ProjectionMessage::Sync(done) => {
+                        for projection in projections.iter_mut() {
+                            projection.flush();
+                        }
+                        let _ = done.send(());
+                    }
+                    ProjectionMessage::Stop => break,
+                }
+            }
+
+            for projection in projections.iter_mut() {
+                projection.flush();
+            }
+        });
+
+        ProjectionRunner {
+            sender,
+            worker: Some(worker),
+        }
+    }
+
+    pub fn publish(&self, event: AccountEvent) {
+        if self.sender.send(ProjectionMessage::Event(event)).is_err() {
+            warn!("Projection worker stopped, event not projected");
+        }
+    }
+
+    pub fn handle(&self) -> ProjectionHandle {
+        ProjectionHandle {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl Drop for ProjectionRunner {
+    fn drop(&mut self) {
+        let _ = self.sender.send(ProjectionMessage::Stop);
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl ProjectionHandle {
+    /// Blocks until every event published before this call has been projected.
+    pub fn sync(&self) {
+        let (done, wait) = channel();
+
+        if self.sender.send(ProjectionMessage::Sync(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+impl AccountsView {
+    pub fn snapshot(&self) -> Arc<AccountsSnapshot> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl AccountsProjection {
+    pub fn view(&self) -> AccountsView {
+        self.view.clone()
+    }
+}
+
+impl Projection for AccountsProjection {
+    fn apply(&mut self, event: &AccountEvent) {
+        let mut current = self
+            .view
+            .current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let snapshot = Arc::make_mut(&mut current);
+
+        snapshot.events += 1;
+
+        let account = snapshot
+            .accounts
+            .entry(event.client_id)
+            .or_insert_with(|| Account::new(event.client_id));
+
+        if let Err(e) = event.apply(account) {
+            warn!("Account {} not projected, {}", event.client_id, e);
+        }
+    }
+}
+
+impl AggregatesProjection {
+    pub fn view(&self) -> Arc<RwLock<Aggregates>> {
+        self.aggregates.clone()
+    }
+}
+
+impl Projection for AggregatesProjection {
+    fn apply(&mut self, event: &AccountEvent) {
+        let mut aggregates = self
+            .aggregates
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        match self.locked.insert(event.client_id, event.locked) {
+            None => {
+                aggregates.accounts += 1;
+                if event.locked {
+                    aggregates.locked_accounts += 1;
+                }
+            }
+            Some(false) if event.locked => aggregates.locked_accounts += 1,
+            Some(true) if !event.locked => aggregates.locked_accounts -= 1,
+            Some(_) => {}
+        }
+
+        match (
+            aggregates.available.checked_add(event.available),
+            aggregates.held.checked_add(event.held),
+            aggregates.total.checked_add(event.total),
+        ) {
+            (Some(available), Some(held), Some(total)) => {
+                aggregates.available = available;
+                aggregates.held = held;
+                aggregates.total = total;
+            }
+            _ => warn!("Aggregate balances overflowed, event not counted"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_store::AccountEvent;
+    use crate::projection::{
+        AccountsProjection, AggregatesProjection, Projection, ProjectionRunner,
+    };
+    use rust_decimal::Decimal;
+
+    fn event(client_id: u16, amount: i64, locked: bool) -> AccountEvent {
+        AccountEvent {
+            client_id,
+            sequence: 1,
+            transactions: vec![],
+            available: Decimal::from(amount),
+            held: Decimal::ZERO,
+            total: Decimal::from(amount),
+            locked,
+            recorded_at: None,
+            balances: Default::default(),
+        }
+    }
+
+    #[test]
+    pub fn should_project_events_in_background() {
+        let accounts = AccountsProjection::default();
+        let aggregates = AggregatesProjection::default();
+        let accounts_view = accounts.view();
+        let aggregates_view = aggregates.view();
+        let runner = ProjectionRunner::start(vec![Box::new(accounts), Box::new(aggregates)]);
+
+        runner.publish(event(1, 100, false));
+        runner.publish(event(2, 50, false));
+        runner.publish(event(1, -30, true));
+        runner.handle().sync();
+
+        let accounts = accounts_view.snapshot().accounts.clone();
+        let aggregates = aggregates_view.read().unwrap();
+
+        assert_eq!(accounts[&1].available, Decimal::from(70));
+        assert!(accounts[&1].locked);
+        assert_eq!(aggregates.accounts, 2);
+        assert_eq!(aggregates.locked_accounts, 1);
+        assert_eq!(aggregates.total, Decimal::from(120));
+    }
+
+    #[test]
+    pub fn should_keep_snapshots_unchanged_by_later_events() {
+        let mut accounts = AccountsProjection::default();
+        let view = accounts.view();
+
+        accounts.apply(&event(1, 100, false));
+
+        let snapshot = view.snapshot();
+
+        accounts.apply(&event(1, -40, false));
+        accounts.apply(&event(2, 10, false));
+
+        let latest = view.snapshot();
+
+        assert_eq!(snapshot.events, 1);
+        assert_eq!(snapshot.accounts.len(), 1);
+        assert_eq!(snapshot.accounts[&1].available, Decimal::from(100));
+        assert_eq!(latest.events, 3);
+        assert_eq!(latest.accounts[&1].available, Decimal::from(60));
+    }
+}
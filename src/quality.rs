@@ -0,0 +1,225 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{self, TransactionType};
+use crate::rows;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Value an input column has to parse to, for the columns the engine reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Type,
+    Client,
+    Transaction,
+    Amount,
+    Other,
+}
+
+/// Metrics of one column. Empty values count as nulls, values which do not parse to what the
+/// engine expects as malformed. `min` and `max` are given for `amount`, `distinct` for `client`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColumnQuality {
+    pub column: String,
+    pub nulls: u64,
+    pub null_rate: f64,
+    pub malformed: u64,
+    pub malformed_rate: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distinct: Option<u64>,
+}
+
+/// Data-quality metrics of the input files of a run, for deciding whether to apply them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QualityReport {
+    pub files: Vec<FileQuality>,
+}
+
+/// Data-quality metrics of one input file, computed without applying anything. Rows whose
+/// number of fields does not match the header count as `malformed_rows` and are left out of
+/// the column metrics.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileQuality {
+    pub path: String,
+    pub rows: u64,
+    pub malformed_rows: u64,
+    pub columns: Vec<ColumnQuality>,
+}
+
+#[derive(Debug, Default)]
+struct ColumnTally {
+    nulls: u64,
+    malformed: u64,
+    min: Option<Decimal>,
+    max: Option<Decimal>,
+    clients: HashSet<u16>,
+}
+
+impl ColumnKind {
+    fn of(header: &str) -> Self {
+        match header {
+            "type" => ColumnKind::Type,
+            "client" | "client_id" => ColumnKind::Client,
+            "tx" | "transaction_id" => ColumnKind::Transaction,
+            "amount" => ColumnKind::Amount,
+            _ => ColumnKind::Other,
+        }
+    }
+}
+
+impl ColumnTally {
+    fn record(&mut self, kind: ColumnKind, value: &str) {
+        if value.is_empty() {
+            self.nulls += 1;
+            return;
+        }
+
+        let well_formed = match kind {
+            ColumnKind::Type => TransactionType::from_name(value).is_some(),
+            ColumnKind::Client => match value.parse::<u16>() {
+                Ok(client_id) => {
+                    self.clients.insert(client_id);
+                    true
+                }
+                Err(_) => false,
+            },
+            ColumnKind::Transaction => value.parse::<u32>().is_ok(),
+            ColumnKind::Amount => match model::parse_amount(value) {
+                Some(amount) => {
+                    self.min = Some(self.min.map_or(amount, |min| min.min(amount)));
+                    self.max = Some(self.max.map_or(amount, |max| max.max(amount)));
+                    true
+                }
+                None => false,
+            },
+            ColumnKind::Other => true,
+        };
+
+        if !well_formed {
+            self.malformed += 1;
+        }
+    }
+}
+
+/// Fraction of `count` in `rows`, 0 for no rows.
+fn rate(count: u64, rows: u64) -> f64 {
+    if rows == 0 {
+        0.0
+    } else {
+        count as f64 / rows as f64
+    }
+}
+
+impl QualityReport {
+    pub fn of_files(paths: &[&str]) -> PaymentEngineResult<Self> {
+        let files = paths
+            .iter()
+            .map(|path| FileQuality::of_file(Path::new(path)))
+            .collect::<PaymentEngineResult<_>>()?;
+
+        Ok(QualityReport { files })
+    }
+}
+
+impl FileQuality {
+    pub fn of_file(path: &Path) -> PaymentEngineResult<Self> {
+        let mut reader = rows::reader_builder().from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let kinds: Vec<ColumnKind> = headers.iter().map(ColumnKind::of).collect();
+        let mut tallies: Vec<ColumnTally> = kinds.iter().map(|_| ColumnTally::default()).collect();
+        let mut rows = 0;
+        let mut malformed_rows = 0;
+
+        for record in reader.records() {
+            rows += 1;
+
+            let record = match record {
+                Ok(record) if record.len() == headers.len() => record,
+                _ => {
+                    malformed_rows += 1;
+                    continue;
+                }
+            };
+
+            for ((tally, kind), value) in tallies.iter_mut().zip(&kinds).zip(record.iter()) {
+                tally.record(*kind, value);
+            }
+        }
+
+        let parsed_rows = rows - malformed_rows;
+        let columns = headers
+            .iter()
+            .zip(&kinds)
+            .zip(tallies)
+            .map(|((column, kind), tally)| ColumnQuality {
+                column: column.to_string(),
+                nulls: tally.nulls,
+                null_rate: rate(tally.nulls, parsed_rows),
+                malformed: tally.malformed,
+                malformed_rate: rate(tally.malformed, parsed_rows),
+                min: tally.min,
+                max: tally.max,
+                distinct: match kind {
+                    ColumnKind::Client => Some(tally.clients.len() as u64),
+                    _ => None,
+                },
+            })
+            .collect();
+
+        Ok(FileQuality {
+            path: path.to_string_lossy().into_owned(),
+            rows,
+            malformed_rows,
+            columns,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quality::FileQuality;
+    use rust_decimal::Decimal;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_measure_nulls_malformed_values_and_ranges() {
+        let input = NamedTempFile::new().unwrap();
+
+        std::fs::write(
+            input.path(),
+            "type,client,tx,amount\n\
+             deposit,1,1,10.5\n\
+             deposit,2,2,abc\n\
+             dispute,1,1,\n\
+             refill,x,4,-3\n\
+             withdrawal,2\n",
+        )
+        .unwrap();
+
+        let quality = FileQuality::of_file(input.path()).unwrap();
+        let column = |name: &str| {
+            quality
+                .columns
+                .iter()
+                .find(|column| column.column == name)
+                .unwrap()
+                .clone()
+        };
+
+        assert_eq!(quality.rows, 5);
+        assert_eq!(quality.malformed_rows, 1);
+        assert_eq!(column("type").malformed, 1);
+        assert_eq!(column("client").malformed, 1);
+        assert_eq!(column("client").distinct, Some(2));
+        assert_eq!(column("amount").nulls, 1);
+        assert_eq!(column("amount").null_rate, 0.25);
+        assert_eq!(column("amount").malformed, 1);
+        assert_eq!(column("amount").min, Some(Decimal::from(-3)));
+        assert_eq!(column("amount").max, Some(Decimal::new(105, 1)));
+        assert_eq!(column("tx").distinct, None);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::Currency;
+use csv::{ReaderBuilder, Trim};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Row of a rate file: one unit of `from` is worth `rate` units of `to`.
+#[derive(Debug, Deserialize)]
+struct RateRow {
+    from: Currency,
+    to: Currency,
+    rate: Decimal,
+}
+
+/// Exchange rates used by conversions, read from a CSV file with `from`, `to` and `rate`
+/// columns. A pair without a rate of its own is converted with the inverse of the opposite
+/// pair.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateTable {
+    rates: BTreeMap<(Currency, Currency), Decimal>,
+}
+
+impl RateTable {
+    pub fn load(path: &Path) -> PaymentEngineResult<Self> {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(true)
+            .trim(Trim::All)
+            .from_path(path)?;
+        let mut rates = BTreeMap::new();
+
+        for row in reader.deserialize() {
+            let row: RateRow = row?;
+
+            if row.rate <= Decimal::ZERO || row.from == row.to {
+                return Err(PaymentEngineError::InvalidExchangeRate {
+                    from: row.from.to_string(),
+                    to: row.to.to_string(),
+                });
+            }
+
+            rates.insert((row.from, row.to), row.rate);
+        }
+
+        Ok(RateTable { rates })
+    }
+
+    pub fn rate(&self, from: &Currency, to: &Currency) -> PaymentEngineResult<Decimal> {
+        let direct = self.rates.get(&(from.clone(), to.clone())).copied();
+        let inverse = || {
+            self.rates
+                .get(&(to.clone(), from.clone()))
+                .and_then(|rate| Decimal::ONE.checked_div(*rate))
+        };
+
+        direct
+            .or_else(inverse)
+            .ok_or_else(|| PaymentEngineError::ExchangeRateNotFound {
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+    }
+}
@@ -0,0 +1,132 @@
+use crate::model::{Account, DisputeRecord, Transaction};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+/// Client whose rebuilt account differs from the persisted one. Deltas are rebuilt minus
+/// persisted values; a client missing on one side counts as an empty account there.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountMismatch {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Stored transactions and dispute steps in the order to replay them: each transaction once, as
+/// first stored and without its disputed flag, and each dispute step as a dispute, resolve or
+/// chargeback. Sorted by timestamp, then id, so replaying them yields the account state of the
+/// runs which stored them.
+pub fn replay_order(
+    transactions: Vec<Transaction>,
+    dispute_records: Vec<DisputeRecord>,
+) -> Vec<Transaction> {
+    let mut seen = HashSet::new();
+    let mut history: Vec<(u32, Transaction)> = transactions
+        .into_iter()
+        .filter(|transaction| seen.insert(transaction.transaction_id))
+        .map(|transaction| {
+            (
+                0,
+                Transaction {
+                    disputed: false,
+                    ..transaction
+                },
+            )
+        })
+        .collect();
+
+    for record in dispute_records {
+        history.push((
+            record.sequence,
+            Transaction {
+                timestamp: Some(record.recorded_at),
+                reason_code: record.reason_code,
+                ..Transaction::new(record.r#type, record.client_id, record.transaction_id, None)
+            },
+        ));
+    }
+
+    history.sort_by_key(|(sequence, transaction)| {
+        (transaction.timestamp, transaction.transaction_id, *sequence)
+    });
+
+    history
+        .into_iter()
+        .map(|(_, transaction)| transaction)
+        .collect()
+}
+
+/// Compares rebuilt accounts with persisted ones and returns the mismatches by client id.
+pub fn compare_accounts(rebuilt: &[Account], persisted: &[Account]) -> Vec<AccountMismatch> {
+    let mut pairs: BTreeMap<u16, (Account, Account)> = BTreeMap::new();
+    let empty = |client_id| (Account::new(client_id), Account::new(client_id));
+
+    for account in rebuilt {
+        pairs
+            .entry(account.client_id)
+            .or_insert_with(|| empty(account.client_id))
+            .0 = account.clone();
+    }
+
+    for account in persisted {
+        pairs
+            .entry(account.client_id)
+            .or_insert_with(|| empty(account.client_id))
+            .1 = account.clone();
+    }
+
+    pairs
+        .into_iter()
+        .filter(|(_, (rebuilt, persisted))| rebuilt != persisted)
+        .map(|(client, (rebuilt, persisted))| AccountMismatch {
+            client,
+            available: rebuilt.available - persisted.available,
+            held: rebuilt.held - persisted.held,
+            total: rebuilt.total - persisted.total,
+            locked: rebuilt.locked != persisted.locked,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Account;
+    use crate::rebuild::{compare_accounts, AccountMismatch};
+    use rust_decimal::Decimal;
+
+    #[test]
+    pub fn should_list_mismatched_clients_with_delta() {
+        let account = |client_id, total: i64| Account {
+            available: Decimal::from(total),
+            total: Decimal::from(total),
+            ..Account::new(client_id)
+        };
+
+        let mismatches = compare_accounts(
+            &[account(1, 10), account(2, 5)],
+            &[account(1, 10), account(2, 7), account(3, 1)],
+        );
+
+        assert_eq!(
+            mismatches,
+            vec![
+                AccountMismatch {
+                    client: 2,
+                    available: Decimal::from(-2),
+                    held: Decimal::ZERO,
+                    total: Decimal::from(-2),
+                    locked: false,
+                },
+                AccountMismatch {
+                    client: 3,
+                    available: Decimal::from(-1),
+                    held: Decimal::ZERO,
+                    total: Decimal::from(-1),
+                    locked: false,
+                },
+            ]
+        );
+    }
+}
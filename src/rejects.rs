@@ -0,0 +1,117 @@
+use crate::error::PaymentEngineResult;
+use crate::input::RawRow;
+use crate::sink;
+use csv::WriterBuilder;
+use serde::Serialize;
+use std::path::Path;
+
+const HEADERS: [&str; 5] = ["source", "line", "row", "error", "message"];
+
+/// Rejected row: where it came from, its content as read and why it was rejected. `error` is
+/// the name of the `PaymentEngineError`, or `InvalidRow` for rows which cannot be read as a
+/// transaction. `line` and `row` are empty for rows the source cannot give back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RejectedRow {
+    pub source: String,
+    pub line: Option<u64>,
+    pub row: Option<String>,
+    pub error: String,
+    pub message: String,
+}
+
+/// Rows rejected during a run, written to the `--rejects` file at its end so operations can
+/// correct them and submit them again.
+#[derive(Debug, Default)]
+pub struct RejectsReport {
+    rows: Vec<RejectedRow>,
+}
+
+impl RejectsReport {
+    pub fn record(&mut self, source: &str, raw_row: Option<RawRow>, error: &str, message: String) {
+        let (line, row) = match raw_row {
+            Some(raw_row) => (Some(raw_row.line), Some(raw_row.content)),
+            None => (None, None),
+        };
+
+        self.rows.push(RejectedRow {
+            source: source.to_string(),
+            line,
+            row,
+            error: error.to_string(),
+            message,
+        });
+    }
+
+    pub fn rows(&self) -> &[RejectedRow] {
+        &self.rows
+    }
+
+    /// Writes the rows as CSV, with a header even when nothing was rejected.
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |sink| {
+            let mut writer = WriterBuilder::new().has_headers(false).from_writer(sink);
+
+            writer.write_record(HEADERS)?;
+
+            for row in &self.rows {
+                writer.serialize(row)?;
+            }
+
+            writer.flush()?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::payment_service::PaymentService;
+    use std::num::NonZeroUsize;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_write_rejected_rows_with_line_content_and_error() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("in.csv");
+        let rejects = directory.path().join("rejects.csv");
+
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,50.0\n\
+             deposit,1,3,ten\n\
+             deposit,1,4,\"1,5\"\n",
+        )
+        .unwrap();
+
+        for pipeline_depth in [None, NonZeroUsize::new(2)] {
+            let mut service = PaymentService::new(
+                Box::new(InMemoryDatastore::default()),
+                ServiceConfig {
+                    rejects_path: Some(rejects.clone()),
+                    pipeline_depth,
+                    report_path: Some(directory.path().join("accounts.csv")),
+                    ..ServiceConfig::default()
+                },
+            );
+
+            service.run(input.to_str().unwrap()).unwrap();
+
+            let written = std::fs::read_to_string(&rejects).unwrap();
+            let lines: Vec<&str> = written.lines().collect();
+
+            assert_eq!(lines[0], "source,line,row,error,message");
+            assert!(lines[1].starts_with(&format!(
+                "{},3,\"withdrawal,1,2,50.0\",InsufficientAccountFunds,",
+                input.display()
+            )));
+            assert!(lines[2].contains(",4,\"deposit,1,3,ten\",InvalidRow,"));
+            assert!(lines[3].contains(",5,\"deposit,1,4,\"\"1,5\"\"\",InvalidRow,"));
+            assert_eq!(lines.len(), 4);
+        }
+    }
+}
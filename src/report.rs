@@ -0,0 +1,411 @@
+use crate::error::PaymentEngineResult;
+use crate::model::Account;
+use crate::sink;
+use csv::{QuoteStyle, ReaderBuilder, StringRecord, Terminator, Writer, WriterBuilder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const COLUMN_GAP: &str = "  ";
+
+/// Format of the account report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    Json,
+    /// Columns aligned for reading in a terminal, numbers to the right.
+    Table,
+}
+
+/// Which fields of CSV reports are quoted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CsvQuote {
+    /// Only fields containing a delimiter, quote or line break.
+    #[default]
+    Necessary,
+    /// Every field, amounts included.
+    Always,
+    /// Every field which is not a number.
+    NonNumeric,
+}
+
+/// Line ending of CSV reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CsvTerminator {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+/// How CSV reports are written, the `[csv]` table of the configuration, for loaders which
+/// need quoted fields or CRLF line endings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvStyle {
+    pub quote: CsvQuote,
+    pub terminator: CsvTerminator,
+}
+
+/// Destination of the accounts a run reports, called once at the end of every run with the
+/// rounded accounts in client order. `PaymentService::set_reporter` replaces the default
+/// `FileReporter`, e.g. by one writing to a database or posting to a service.
+pub trait Reporter: Send {
+    fn report(&mut self, accounts: &[Account]) -> PaymentEngineResult<()>;
+}
+
+/// Writes the report in `format` to the file at `path`, or to stdout without one, which is what
+/// runs do unless another reporter is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileReporter {
+    pub format: ReportFormat,
+    pub csv: CsvStyle,
+    pub path: Option<PathBuf>,
+    pub fingerprint: bool,
+}
+
+/// Writes report rows in one of the formats. Tables are only written by `finish`, once the
+/// width of every column is known.
+pub struct ReportWriter<W: Write> {
+    output: Output<W>,
+}
+
+enum Output<W: Write> {
+    Csv(Box<Writer<W>>),
+    Json(W),
+    Table {
+        writer: W,
+        rows: Box<Writer<Vec<u8>>>,
+    },
+}
+
+impl ReportFormat {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "csv" => Some(ReportFormat::Csv),
+            "json" => Some(ReportFormat::Json),
+            "table" => Some(ReportFormat::Table),
+            _ => None,
+        }
+    }
+}
+
+impl CsvStyle {
+    /// Builder of CSV writers in this style.
+    pub fn builder(&self) -> WriterBuilder {
+        let mut builder = WriterBuilder::new();
+
+        builder.quote_style(match self.quote {
+            CsvQuote::Necessary => QuoteStyle::Necessary,
+            CsvQuote::Always => QuoteStyle::Always,
+            CsvQuote::NonNumeric => QuoteStyle::NonNumeric,
+        });
+        builder.terminator(match self.terminator {
+            CsvTerminator::Lf => Terminator::Any(b'\n'),
+            CsvTerminator::Crlf => Terminator::CRLF,
+        });
+        builder
+    }
+}
+
+impl<W: Write> ReportWriter<W> {
+    pub fn new(format: ReportFormat, writer: W) -> Self {
+        ReportWriter::with_csv_style(format, CsvStyle::default(), writer)
+    }
+
+    /// Writer whose CSV output is written in `csv` style. JSON lines and tables are not CSV, so
+    /// the style does not apply to them.
+    pub fn with_csv_style(format: ReportFormat, csv: CsvStyle, writer: W) -> Self {
+        let output = match format {
+            ReportFormat::Csv => Output::Csv(Box::new(csv.builder().from_writer(writer))),
+            ReportFormat::Json => Output::Json(writer),
+            ReportFormat::Table => Output::Table {
+                writer,
+                rows: Box::new(WriterBuilder::new().from_writer(vec![])),
+            },
+        };
+
+        ReportWriter { output }
+    }
+
+    pub fn write<T: Serialize>(&mut self, row: &T) -> PaymentEngineResult<()> {
+        match &mut self.output {
+            Output::Csv(writer) => writer.serialize(row)?,
+            Output::Json(writer) => {
+                serde_json::to_writer(&mut *writer, row)?;
+                writeln!(writer)?;
+            }
+            Output::Table { rows, .. } => rows.serialize(row)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> PaymentEngineResult<()> {
+        match self.output {
+            Output::Csv(mut writer) => writer.flush()?,
+            Output::Json(mut writer) => writer.flush()?,
+            Output::Table { mut writer, rows } => {
+                let csv = rows
+                    .into_inner()
+                    .map_err(|e| std::io::Error::from(e.error().kind()))?;
+
+                write_table(&mut writer, &csv)?;
+                writer.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the account report to the file at `path`, or to stdout without one, with the
+/// fingerprint of every row when `fingerprint` is set.
+pub fn write_accounts<I>(
+    format: ReportFormat,
+    csv: CsvStyle,
+    path: Option<&Path>,
+    fingerprint: bool,
+    accounts: I,
+) -> PaymentEngineResult<()>
+where
+    I: IntoIterator,
+    I::Item: Borrow<Account>,
+{
+    let mut sink = sink::open(path)?;
+
+    write_rows(
+        ReportWriter::with_csv_style(format, csv, &mut sink),
+        fingerprint,
+        accounts,
+    )?;
+    sink.finish()
+}
+
+/// Account report as `write_accounts` would write it, kept in memory.
+pub fn render_accounts<I>(
+    format: ReportFormat,
+    csv: CsvStyle,
+    fingerprint: bool,
+    accounts: I,
+) -> PaymentEngineResult<Vec<u8>>
+where
+    I: IntoIterator,
+    I::Item: Borrow<Account>,
+{
+    let mut rendered = vec![];
+
+    write_rows(
+        ReportWriter::with_csv_style(format, csv, &mut rendered),
+        fingerprint,
+        accounts,
+    )?;
+
+    Ok(rendered)
+}
+
+fn write_rows<W, I>(
+    mut writer: ReportWriter<W>,
+    fingerprint: bool,
+    accounts: I,
+) -> PaymentEngineResult<()>
+where
+    W: Write,
+    I: IntoIterator,
+    I::Item: Borrow<Account>,
+{
+    for account in accounts {
+        let account = account.borrow();
+
+        if fingerprint {
+            writer.write(&account.with_fingerprint())?;
+        } else {
+            writer.write(account)?;
+        }
+    }
+
+    writer.finish()
+}
+
+impl Reporter for FileReporter {
+    fn report(&mut self, accounts: &[Account]) -> PaymentEngineResult<()> {
+        write_accounts(
+            self.format,
+            self.csv,
+            self.path.as_deref(),
+            self.fingerprint,
+            accounts,
+        )
+    }
+}
+
+fn write_table<W: Write>(writer: &mut W, csv: &[u8]) -> PaymentEngineResult<()> {
+    let records = ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(csv)
+        .into_records()
+        .collect::<Result<Vec<StringRecord>, _>>()?;
+    let columns = records.first().map_or(0, StringRecord::len);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            records
+                .iter()
+                .map(|record| record.get(column).map_or(0, |value| value.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let numeric: Vec<bool> = (0..columns)
+        .map(|column| {
+            records.len() > 1
+                && records[1..]
+                    .iter()
+                    .all(|record| Decimal::from_str(record.get(column).unwrap_or("")).is_ok())
+        })
+        .collect();
+
+    for record in &records {
+        let cells: Vec<String> = record
+            .iter()
+            .enumerate()
+            .map(|(column, value)| match numeric[column] {
+                true => format!("{:>width$}", value, width = widths[column]),
+                false => format!("{:<width$}", value, width = widths[column]),
+            })
+            .collect();
+
+        writeln!(writer, "{}", cells.join(COLUMN_GAP).trim_end())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::error::PaymentEngineResult;
+    use crate::model::Account;
+    use crate::payment_service::PaymentService;
+    use crate::report::{CsvQuote, CsvStyle, CsvTerminator, ReportFormat, ReportWriter, Reporter};
+    use rust_decimal::Decimal;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tempfile::NamedTempFile;
+
+    struct CollectingReporter(Arc<Mutex<Vec<Account>>>);
+
+    impl Reporter for CollectingReporter {
+        fn report(&mut self, accounts: &[Account]) -> PaymentEngineResult<()> {
+            self.0.lock().unwrap().extend_from_slice(accounts);
+            Ok(())
+        }
+    }
+
+    fn render(format: ReportFormat) -> String {
+        let mut output = vec![];
+        let mut writer = ReportWriter::new(format, &mut output);
+
+        for (client_id, available) in [(1, Decimal::new(15, 1)), (12, Decimal::from(100))] {
+            writer
+                .write(&Account {
+                    available,
+                    total: available,
+                    ..Account::new(client_id)
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    pub fn should_write_accounts_in_every_format() {
+        assert_eq!(
+            render(ReportFormat::Csv),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n12,100,0,100,false\n"
+        );
+        assert_eq!(
+            render(ReportFormat::Json).lines().nth(1).unwrap(),
+            "{\"client\":12,\"available\":\"100\",\"held\":\"0\",\"total\":\"100\",\"locked\":false}"
+        );
+        assert_eq!(
+            render(ReportFormat::Table),
+            "client  available  held  total  locked\n\
+             \x20    1        1.5     0    1.5  false\n\
+             \x20   12        100     0    100  false\n"
+        );
+    }
+
+    #[test]
+    pub fn should_hand_accounts_of_run_to_reporter() {
+        let mut input = NamedTempFile::new().unwrap();
+        let reported = Arc::new(Mutex::new(vec![]));
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig::default(),
+        );
+
+        writeln!(
+            input,
+            "type,client,tx,amount\ndeposit,2,1,3.5\ndeposit,1,2,1.0"
+        )
+        .unwrap();
+        service.set_reporter(Box::new(CollectingReporter(reported.clone())));
+        service.run(input.path().to_str().unwrap()).unwrap();
+
+        let reported = reported.lock().unwrap();
+
+        assert_eq!(
+            reported.iter().map(|a| a.client_id).collect::<Vec<u16>>(),
+            vec![1, 2]
+        );
+        assert_eq!(reported[1].available, Decimal::new(35, 1));
+        assert!(service.outputs().is_empty());
+    }
+
+    #[test]
+    pub fn should_write_csv_report_in_configured_style() {
+        let config: ServiceConfig = toml::from_str(
+            r#"
+            [csv]
+            quote = "always"
+            terminator = "crlf"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.csv,
+            CsvStyle {
+                quote: CsvQuote::Always,
+                terminator: CsvTerminator::Crlf,
+            }
+        );
+
+        let mut output = vec![];
+        let mut writer = ReportWriter::with_csv_style(ReportFormat::Csv, config.csv, &mut output);
+
+        writer
+            .write(&Account {
+                available: Decimal::new(15, 1),
+                total: Decimal::new(15, 1),
+                ..Account::new(1)
+            })
+            .unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\r\n\
+             \"1\",\"1.5\",\"0\",\"1.5\",\"false\"\r\n"
+        );
+    }
+}
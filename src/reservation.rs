@@ -0,0 +1,113 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model;
+use chrono::{DateTime, Duration, Utc};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const RESERVATIONS_DB_PATH: &str = "pe_reservations.db";
+pub const DEFAULT_RESERVATION_TTL_SECONDS: i64 = 900;
+
+/// Funds set aside for an external authorization flow. Reserved funds stay in the account but
+/// cannot be withdrawn by anything other than committing the reservation, until it expires.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub token: String,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub amount: Decimal,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Open reservations by token, optionally persisted so they survive between runs.
+pub struct ReservationBook {
+    db: Option<PickleDb>,
+    reservations: HashMap<String, Reservation>,
+}
+
+impl Reservation {
+    /// Reservation made at `now`, expiring `ttl` later.
+    pub fn new(client_id: u16, amount: Decimal, now: DateTime<Utc>, ttl: Duration) -> Self {
+        Reservation {
+            token: uuid::Uuid::new_v4().to_string(),
+            client_id,
+            amount,
+            expires_at: now + ttl,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+impl ReservationBook {
+    pub fn open(path: Option<&Path>) -> Self {
+        let db = path.map(|path| {
+            PickleDb::load(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                .unwrap_or_else(|_| {
+                    PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                })
+        });
+        let reservations = match &db {
+            Some(db) => db
+                .iter()
+                .filter_map(|item| item.get_value::<String>())
+                .filter_map(|json| serde_json::from_str::<Reservation>(&json).ok())
+                .map(|reservation| (reservation.token.clone(), reservation))
+                .collect(),
+            None => HashMap::default(),
+        };
+
+        ReservationBook { db, reservations }
+    }
+
+    /// Sum of the client's reservations which have not expired by `now`.
+    pub fn reserved(&self, client_id: u16, now: DateTime<Utc>) -> PaymentEngineResult<Decimal> {
+        self.reservations
+            .values()
+            .filter(|r| r.client_id == client_id && !r.is_expired(now))
+            .try_fold(Decimal::ZERO, |reserved, r| {
+                model::checked_add(reserved, r.amount)
+            })
+    }
+
+    pub fn list(&self) -> Vec<Reservation> {
+        let mut reservations: Vec<Reservation> = self.reservations.values().cloned().collect();
+
+        reservations.sort_by_key(|r| r.expires_at);
+
+        reservations
+    }
+
+    pub fn insert(&mut self, reservation: Reservation) -> PaymentEngineResult<()> {
+        if let Some(db) = self.db.as_mut() {
+            db.set(&reservation.token, &serde_json::to_string(&reservation)?)?;
+        }
+        self.reservations
+            .insert(reservation.token.clone(), reservation);
+
+        Ok(())
+    }
+
+    /// Removes the reservation, failing when it does not exist or has expired by `now`. Expired
+    /// reservations are removed as well.
+    pub fn take(&mut self, token: &str, now: DateTime<Utc>) -> PaymentEngineResult<Reservation> {
+        let reservation = match self.reservations.remove(token) {
+            Some(reservation) => reservation,
+            None => return Err(PaymentEngineError::ReservationNotFound),
+        };
+
+        if let Some(db) = self.db.as_mut() {
+            db.rem(token)?;
+        }
+
+        if reservation.is_expired(now) {
+            return Err(PaymentEngineError::ReservationExpired);
+        }
+
+        Ok(reservation)
+    }
+}
@@ -0,0 +1,220 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{Account, Currency};
+use crate::rates::RateTable;
+use crate::rounding::RoundingConfig;
+use crate::sink;
+use csv::WriterBuilder;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::path::Path;
+
+/// Total balance of one client in one currency other than the base currency, revalued at the
+/// end-of-day rate. `exposure` and `unrealized_pnl` are in the base currency, and `booked_rate`
+/// is the average rate its funds were credited at.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RevaluationRow {
+    pub client: u16,
+    pub currency: Currency,
+    pub total: Decimal,
+    pub booked_rate: Decimal,
+    pub eod_rate: Decimal,
+    pub exposure: Decimal,
+    pub unrealized_pnl: Decimal,
+}
+
+/// Currency exposure of the accounts at the end of a run, which treasury revalues at month-end.
+/// Balances are taken at the base currency cost they were credited at, or at the rates of the
+/// run, `--rates`, when their cost is unknown, and revalued at the end-of-day rates,
+/// `--eod-rates`; the difference is the unrealized gain or loss.
+#[derive(Debug, Default)]
+pub struct Revaluation {
+    rows: Vec<RevaluationRow>,
+}
+
+impl Revaluation {
+    /// Revalues the balances in other currencies than `base` of `accounts`, in the order of the
+    /// accounts and then by currency code. Amounts in the base currency are rounded like
+    /// reported balances and written with the decimals of reported balances.
+    pub fn new(
+        base: &Currency,
+        accounts: &[Account],
+        booked: &RateTable,
+        eod: &RateTable,
+        rounding: &RoundingConfig,
+    ) -> PaymentEngineResult<Self> {
+        let mut rows = vec![];
+
+        for account in accounts {
+            for (currency, balances) in &account.balances {
+                if currency == base {
+                    continue;
+                }
+
+                let value = |rate: Decimal| {
+                    balances
+                        .total
+                        .checked_mul(rate)
+                        .ok_or(PaymentEngineError::AmountOverflow)
+                };
+                let booked_rate = balances
+                    .cost
+                    .and_then(|cost| cost.checked_div(balances.total))
+                    .map(|rate| rate.normalize());
+                let (booked_rate, cost) = match (booked_rate, balances.cost) {
+                    (Some(booked_rate), Some(cost)) => (booked_rate, cost),
+                    _ => {
+                        let booked_rate = booked.rate(currency, base)?;
+
+                        (booked_rate, value(booked_rate)?)
+                    }
+                };
+                let eod_rate = eod.rate(currency, base)?;
+                let exposure = value(eod_rate)?;
+                let unrealized_pnl = exposure
+                    .checked_sub(cost)
+                    .ok_or(PaymentEngineError::AmountOverflow)?;
+                let reported = |value: Decimal| {
+                    let mut value = rounding.round_balance(value);
+
+                    value.rescale(rounding.output_decimals);
+                    value
+                };
+
+                rows.push(RevaluationRow {
+                    client: account.client_id,
+                    currency: currency.clone(),
+                    total: balances.total,
+                    booked_rate,
+                    eod_rate,
+                    exposure: reported(exposure),
+                    unrealized_pnl: reported(unrealized_pnl),
+                });
+            }
+        }
+
+        Ok(Revaluation { rows })
+    }
+
+    pub fn rows(&self) -> &[RevaluationRow] {
+        &self.rows
+    }
+
+    /// Writes the rows as CSV, with a header even when no account holds another currency.
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |sink| {
+            let mut writer = WriterBuilder::new().has_headers(false).from_writer(sink);
+
+            writer.write_record([
+                "client",
+                "currency",
+                "total",
+                "booked_rate",
+                "eod_rate",
+                "exposure",
+                "unrealized_pnl",
+            ])?;
+
+            for row in &self.rows {
+                writer.serialize(row)?;
+            }
+
+            writer.flush()?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::model::{Account, Currency};
+    use crate::payment_service::PaymentService;
+    use crate::rates::RateTable;
+    use crate::revaluation::Revaluation;
+    use crate::rounding::RoundingConfig;
+    use rust_decimal::Decimal;
+    use std::io::Write;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    pub fn should_report_exposure_and_unrealized_pnl_at_end_of_day_rates() {
+        let directory = TempDir::new().unwrap();
+        let revaluation = directory.path().join("revaluation.csv");
+        let booked = directory.path().join("rates.csv");
+        let eod = directory.path().join("eod.csv");
+        let mut file = NamedTempFile::new().unwrap();
+
+        std::fs::write(&booked, "from,to,rate\nUSD,EUR,0.9\nGBP,EUR,1.15\n").unwrap();
+        std::fs::write(&eod, "from,to,rate\nEUR,USD,1.25\nGBP,EUR,1.2\n").unwrap();
+        write!(
+            file,
+            "type,client,tx,amount,currency\n\
+             deposit,1,1,100.0,\n\
+             deposit,1,2,50.0,USD\n\
+             deposit,2,3,10.0,GBP\n"
+        )
+        .unwrap();
+
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig {
+                base_currency: Some("EUR".parse().unwrap()),
+                rates: RateTable::load(&booked).unwrap(),
+                eod_rates: RateTable::load(&eod).unwrap(),
+                revaluation_path: Some(revaluation.clone()),
+                report_path: Some(directory.path().join("accounts.csv")),
+                ..ServiceConfig::default()
+            },
+        );
+
+        service.run(file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&revaluation).unwrap(),
+            "client,currency,total,booked_rate,eod_rate,exposure,unrealized_pnl\n\
+             1,USD,50.0,0.9,0.8,40.0000,-5.0000\n\
+             2,GBP,10.0,1.15,1.2,12.0000,0.5000\n"
+        );
+        assert!(service.outputs().contains(&revaluation));
+    }
+
+    #[test]
+    pub fn should_revalue_balances_at_the_rates_they_were_credited_at() {
+        let directory = TempDir::new().unwrap();
+        let eod = directory.path().join("eod.csv");
+        let usd: Currency = "USD".parse().unwrap();
+        let mut account = Account::new(1);
+
+        std::fs::write(&eod, "from,to,rate\nUSD,EUR,1.0\n").unwrap();
+        account
+            .book_in(Some(&usd), Decimal::from(50), Some(Decimal::new(9, 1)))
+            .unwrap();
+        account
+            .book_in(Some(&usd), Decimal::from(50), Some(Decimal::new(8, 1)))
+            .unwrap();
+        account
+            .adjust_in(
+                Some(&usd),
+                Decimal::from(-20),
+                Decimal::ZERO,
+                Decimal::from(-20),
+            )
+            .unwrap();
+
+        let revaluation = Revaluation::new(
+            &"EUR".parse().unwrap(),
+            &[account],
+            &RateTable::default(),
+            &RateTable::load(&eod).unwrap(),
+            &RoundingConfig::default(),
+        )
+        .unwrap();
+        let row = &revaluation.rows()[0];
+
+        assert_eq!(row.booked_rate, Decimal::new(85, 2));
+        assert_eq!(row.exposure.to_string(), "80.0000");
+        assert_eq!(row.unrealized_pnl.to_string(), "12.0000");
+    }
+}
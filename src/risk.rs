@@ -0,0 +1,176 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{self, DisputeRecord, TransactionType};
+use crate::sink;
+use csv::WriterBuilder;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const RISK_REPORT_PATH: &str = "pe_risk_report.csv";
+const DEFAULT_REASON_CODES: [&str; 7] = [
+    "fraud",
+    "product-not-received",
+    "product-not-as-described",
+    "duplicate",
+    "credit-not-processed",
+    "unrecognized",
+    "other",
+];
+
+/// Reason codes disputes and chargebacks may carry, the `[reason_codes]` table of the
+/// configuration. Rows with any other code are rejected.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReasonCodes {
+    pub allowed: Vec<String>,
+}
+
+/// Disputes, chargebacks and representments with one reason code and the amounts they concern. Steps without a
+/// reason code are counted under an empty one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReasonStats {
+    pub reason_code: String,
+    pub disputes: u64,
+    pub disputed_amount: Decimal,
+    pub chargebacks: u64,
+    pub charged_back_amount: Decimal,
+    pub representments: u64,
+    pub represented_amount: Decimal,
+}
+
+/// Dispute steps of a run by reason code, for chargeback analytics.
+#[derive(Debug, Default)]
+pub struct RiskReport {
+    reasons: BTreeMap<String, ReasonStats>,
+}
+
+impl Default for ReasonCodes {
+    fn default() -> Self {
+        ReasonCodes {
+            allowed: DEFAULT_REASON_CODES
+                .iter()
+                .map(|code| code.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl ReasonCodes {
+    pub fn is_valid(&self) -> bool {
+        self.allowed
+            .iter()
+            .all(|code| !code.is_empty() && !code.contains(char::is_whitespace))
+    }
+
+    pub fn contains(&self, code: &str) -> bool {
+        self.allowed.iter().any(|allowed| allowed == code)
+    }
+}
+
+impl RiskReport {
+    pub fn record(&mut self, record: &DisputeRecord) -> PaymentEngineResult<()> {
+        if record.r#type == TransactionType::Resolve {
+            return Ok(());
+        }
+
+        let reason_code = record.reason_code.clone().unwrap_or_default();
+        let stats = self
+            .reasons
+            .entry(reason_code.clone())
+            .or_insert_with(|| ReasonStats {
+                reason_code,
+                ..ReasonStats::default()
+            });
+
+        match record.r#type {
+            TransactionType::Dispute => {
+                stats.disputes += 1;
+                stats.disputed_amount = model::checked_add(stats.disputed_amount, record.amount)?;
+            }
+            TransactionType::Chargeback => {
+                stats.chargebacks += 1;
+                stats.charged_back_amount =
+                    model::checked_add(stats.charged_back_amount, record.amount)?;
+            }
+            _ => {
+                stats.representments += 1;
+                stats.represented_amount =
+                    model::checked_add(stats.represented_amount, record.amount)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reasons.is_empty()
+    }
+
+    /// Writes one row per reason code, ordered by code.
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |sink| {
+            let mut writer = WriterBuilder::new().from_writer(sink);
+
+            for stats in self.reasons.values() {
+                writer.serialize(stats)?;
+            }
+
+            writer.flush()?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{DisputeRecord, Documents, TransactionType};
+    use crate::risk::{ReasonCodes, RiskReport};
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_aggregate_disputes_and_chargebacks_by_reason() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("risk.csv");
+        let record = |r#type, amount: i64, reason_code: Option<&str>| DisputeRecord {
+            transaction_id: 1,
+            sequence: 1,
+            r#type,
+            client_id: 1,
+            amount: Decimal::from(amount),
+            closes: None,
+            recorded_at: Utc::now(),
+            reason_code: reason_code.map(str::to_string),
+            documents: Documents::default(),
+        };
+        let mut report = RiskReport::default();
+
+        for step in [
+            record(TransactionType::Dispute, 10, Some("fraud")),
+            record(TransactionType::Chargeback, 10, Some("fraud")),
+            record(TransactionType::Dispute, 5, Some("fraud")),
+            record(TransactionType::Resolve, 5, Some("fraud")),
+            record(TransactionType::Representment, 10, Some("fraud")),
+            record(TransactionType::Dispute, 7, None),
+        ] {
+            report.record(&step).unwrap();
+        }
+        report.write(&path).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "reason_code,disputes,disputed_amount,chargebacks,charged_back_amount,\
+             representments,represented_amount\n\
+             ,1,7,0,0,0,0\n\
+             fraud,2,15,1,10,1,10\n"
+        );
+        assert!(ReasonCodes::default().contains("product-not-received"));
+        assert!(!ReasonCodes {
+            allowed: vec!["bad code".to_string()]
+        }
+        .is_valid());
+    }
+}
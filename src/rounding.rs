@@ -0,0 +1,222 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{self, Account};
+use crate::sink;
+use csv::WriterBuilder;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const ROUNDING_DRIFT_PATH: &str = "pe_rounding_drift.csv";
+const MAX_DECIMALS: u32 = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Ties go to the even digit, also known as banker's rounding.
+    HalfEven,
+    /// Ties go away from zero.
+    HalfUp,
+    /// Extra digits are cut off.
+    Down,
+}
+
+/// Decimal places amounts are rounded to when they enter the engine, and balances when they
+/// are reported, the `[rounding]` table of the configuration. Balances are kept at full
+/// precision in between, so reporting never feeds rounding back into later computations.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoundingConfig {
+    pub input_decimals: u32,
+    pub input_mode: RoundingMode,
+    pub output_decimals: u32,
+    pub output_mode: RoundingMode,
+}
+
+/// Rounding adjustments of one client, original minus rounded values: `input` of the amounts of
+/// applied transactions, `output` of the reported total balance.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientDrift {
+    pub client: u16,
+    pub input: Decimal,
+    pub output: Decimal,
+}
+
+/// Rounding adjustments accumulated over a run, per client and overall, so finance can post a
+/// rounding difference journal entry.
+#[derive(Debug, Default)]
+pub struct RoundingDrift {
+    clients: BTreeMap<u16, ClientDrift>,
+}
+
+impl RoundingMode {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Down => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+impl Default for RoundingConfig {
+    fn default() -> Self {
+        RoundingConfig {
+            input_decimals: 4,
+            input_mode: RoundingMode::HalfEven,
+            output_decimals: 4,
+            output_mode: RoundingMode::HalfEven,
+        }
+    }
+}
+
+impl RoundingConfig {
+    pub fn is_valid(&self) -> bool {
+        self.input_decimals <= MAX_DECIMALS && self.output_decimals <= MAX_DECIMALS
+    }
+
+    /// Amount of a transaction as the engine applies it.
+    pub fn round_input(&self, amount: Decimal) -> Decimal {
+        amount.round_dp_with_strategy(self.input_decimals, self.input_mode.strategy())
+    }
+
+    /// Account as written to reports.
+    pub fn round_output(&self, account: &Account) -> Account {
+        Account {
+            available: self.round_balance(account.available),
+            held: self.round_balance(account.held),
+            total: self.round_balance(account.total),
+            ..account.clone()
+        }
+    }
+
+    /// Balance or other amount as written to reports.
+    pub fn round_balance(&self, value: Decimal) -> Decimal {
+        value.round_dp_with_strategy(self.output_decimals, self.output_mode.strategy())
+    }
+}
+
+impl RoundingDrift {
+    pub fn record_input(&mut self, client_id: u16, adjustment: Decimal) -> PaymentEngineResult<()> {
+        let drift = self.client(client_id);
+
+        drift.input = model::checked_add(drift.input, adjustment)?;
+
+        Ok(())
+    }
+
+    pub fn record_output(
+        &mut self,
+        client_id: u16,
+        adjustment: Decimal,
+    ) -> PaymentEngineResult<()> {
+        let drift = self.client(client_id);
+
+        drift.output = model::checked_add(drift.output, adjustment)?;
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Sum of the input and output adjustments of all clients.
+    pub fn total(&self) -> PaymentEngineResult<(Decimal, Decimal)> {
+        self.clients
+            .values()
+            .try_fold((Decimal::ZERO, Decimal::ZERO), |(input, output), drift| {
+                Ok((
+                    model::checked_add(input, drift.input)?,
+                    model::checked_add(output, drift.output)?,
+                ))
+            })
+    }
+
+    /// Writes the adjustments of every client with any, by client id.
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |sink| {
+            let mut writer = WriterBuilder::new().from_writer(sink);
+
+            for drift in self.clients.values() {
+                writer.serialize(drift)?;
+            }
+
+            writer.flush()?;
+
+            Ok(())
+        })
+    }
+
+    fn client(&mut self, client_id: u16) -> &mut ClientDrift {
+        self.clients
+            .entry(client_id)
+            .or_insert_with(|| ClientDrift {
+                client: client_id,
+                input: Decimal::ZERO,
+                output: Decimal::ZERO,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Account;
+    use crate::rounding::{RoundingConfig, RoundingDrift, RoundingMode};
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    #[test]
+    pub fn should_round_input_and_output_separately() {
+        let config = RoundingConfig {
+            input_decimals: 6,
+            output_decimals: 2,
+            output_mode: RoundingMode::HalfUp,
+            ..RoundingConfig::default()
+        };
+        let amount = config.round_input(Decimal::from_str("0.0012345").unwrap());
+        let mut account = Account::new(1);
+
+        for _ in 0..3 {
+            account.adjust(amount, Decimal::ZERO, amount).unwrap();
+        }
+        account.held = Decimal::from_str("0.125").unwrap();
+
+        let reported = config.round_output(&account);
+
+        assert_eq!(amount, Decimal::from_str("0.001234").unwrap());
+        assert_eq!(account.total, Decimal::from_str("0.003702").unwrap());
+        assert_eq!(reported.total, Decimal::ZERO);
+        assert_eq!(reported.held, Decimal::from_str("0.13").unwrap());
+    }
+
+    #[test]
+    pub fn should_sum_rounding_drift_per_client_and_run() {
+        let mut drift = RoundingDrift::default();
+        let directory = tempfile::TempDir::new().unwrap();
+        let path = directory.path().join("drift.csv");
+
+        drift
+            .record_input(1, Decimal::from_str("0.00006").unwrap())
+            .unwrap();
+        drift
+            .record_input(2, Decimal::from_str("-0.00004").unwrap())
+            .unwrap();
+        drift
+            .record_output(1, Decimal::from_str("0.00001").unwrap())
+            .unwrap();
+        drift.write(&path).unwrap();
+
+        assert_eq!(
+            drift.total().unwrap(),
+            (
+                Decimal::from_str("0.00002").unwrap(),
+                Decimal::from_str("0.00001").unwrap()
+            )
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,input,output\n1,0.00006,0.00001\n2,-0.00004,0\n"
+        );
+    }
+}
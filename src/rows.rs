@@ -0,0 +1,233 @@
+use crate::error::PaymentEngineResult;
+use crate::input::{InputSource, RawRow};
+use crate::model::{Provenance, Transaction};
+use csv::{Position, Reader, ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use std::fs::File;
+use std::io::{Read, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+const STDIN_PATH: &str = "-";
+const STDIN_SOURCE: &str = "stdin";
+
+/// Reads transactions row by row into a single record buffer which is reused for every row,
+/// so the only allocations per row are the ones a transaction itself needs (memo and
+/// counterparty).
+pub struct TransactionRows<R: Read> {
+    reader: Reader<R>,
+    parser: RecordParser,
+    record: StringRecord,
+}
+
+/// Deserializes records read with `headers`. Rows without a `provenance` column are attributed
+/// to their line of `path`.
+#[derive(Clone)]
+pub(crate) struct RecordParser {
+    headers: StringRecord,
+    path: Arc<str>,
+}
+
+impl TransactionRows<Box<dyn Read + Send>> {
+    /// Rows of the file at `path`, or of standard input when `path` is `-`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> PaymentEngineResult<Self> {
+        let path = path.as_ref();
+        let (input, source): (Box<dyn Read + Send>, _) = if path == Path::new(STDIN_PATH) {
+            (Box::new(std::io::stdin()), Arc::from(STDIN_SOURCE))
+        } else {
+            (
+                Box::new(File::open(path).map_err(csv::Error::from)?),
+                Arc::from(path.to_string_lossy().as_ref()),
+            )
+        };
+
+        TransactionRows::new(reader_builder().from_reader(input), source)
+    }
+}
+
+impl TransactionRows<File> {
+    /// Rows of the file at `path` from `position` on, e.g. the one of a checkpoint.
+    pub fn from_position<P: AsRef<Path>>(path: P, position: Position) -> PaymentEngineResult<Self> {
+        let path = path.as_ref();
+        let mut reader = reader_builder().from_path(path)?;
+
+        // The headers are read before seeking, so they are kept for the rows after it.
+        reader.headers()?;
+        reader.seek_raw(SeekFrom::Start(position.byte()), position)?;
+
+        TransactionRows::new(reader, Arc::from(path.to_string_lossy().as_ref()))
+    }
+}
+
+impl<R: Read> TransactionRows<R> {
+    /// Rows read from `input`, attributed to `source` where they have no provenance of their
+    /// own.
+    pub fn from_reader(input: R, source: &str) -> PaymentEngineResult<Self> {
+        TransactionRows::new(reader_builder().from_reader(input), Arc::from(source))
+    }
+
+    fn new(mut reader: Reader<R>, path: Arc<str>) -> PaymentEngineResult<Self> {
+        let headers = reader.headers()?.clone();
+
+        Ok(TransactionRows {
+            reader,
+            parser: RecordParser { headers, path },
+            record: StringRecord::new(),
+        })
+    }
+
+    /// Name the rows are attributed to: the file path, `stdin`, or the archive member.
+    pub fn source(&self) -> &str {
+        &self.parser.path
+    }
+
+    /// Position after the last row read.
+    pub fn position(&self) -> &Position {
+        self.reader.position()
+    }
+
+    /// Next row, `None` at the end of the input. A row which cannot be read or deserialized is
+    /// returned as an error, and reading continues with the row after it. Rows without a
+    /// `provenance` column are attributed to their line of the file.
+    pub fn next_transaction(&mut self) -> Option<Result<Transaction, csv::Error>> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => Some(self.parser.parse(&self.record)),
+            Ok(false) => None,
+            Err(e) => {
+                self.record.clear();
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Next row without deserializing it, for a parser on another thread.
+    pub(crate) fn next_record(&mut self) -> Option<Result<StringRecord, csv::Error>> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => Some(Ok(self.record.clone())),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    pub(crate) fn parser(&self) -> RecordParser {
+        self.parser.clone()
+    }
+}
+
+impl RecordParser {
+    pub(crate) fn parse(&self, record: &StringRecord) -> Result<Transaction, csv::Error> {
+        record
+            .deserialize::<Transaction>(Some(&self.headers))
+            .map(|mut transaction| {
+                if transaction.provenance.is_none() {
+                    transaction.provenance = Some(Provenance::File {
+                        path: self.path.clone(),
+                        line: record.position().map_or(0, |p| p.line()),
+                    });
+                }
+                transaction
+            })
+    }
+}
+
+impl<R: Read> InputSource<csv::Error> for TransactionRows<R> {
+    fn source(&self) -> &str {
+        TransactionRows::source(self)
+    }
+
+    fn position(&self) -> Option<&Position> {
+        Some(TransactionRows::position(self))
+    }
+
+    fn raw_row(&self) -> Option<RawRow> {
+        raw_row(&self.record)
+    }
+}
+
+impl<R: Read> Iterator for TransactionRows<R> {
+    type Item = Result<Transaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_transaction()
+    }
+}
+
+/// Row of the record as it would be written to a CSV file, none for a record which could not be
+/// read.
+pub(crate) fn raw_row(record: &StringRecord) -> Option<RawRow> {
+    if record.is_empty() {
+        return None;
+    }
+
+    let line = record.position()?.line();
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+    writer.write_record(record).ok()?;
+
+    let content = String::from_utf8(writer.into_inner().ok()?).ok()?;
+
+    Some(RawRow {
+        line,
+        content: content.trim_end().to_string(),
+    })
+}
+
+pub(crate) fn reader_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+
+    builder.has_headers(true).trim(Trim::All);
+
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::TransactionType;
+    use crate::rows::TransactionRows;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_read_rows_with_shared_buffer() {
+        let input =
+            "type, client, tx, amount\ndeposit, 1, 1, 1.5\nbogus, 1, 2, 1\nwithdrawal, 2, 3, 0.5\n";
+        let file = NamedTempFile::new().unwrap();
+
+        std::fs::write(file.path(), input).unwrap();
+
+        let rows: Vec<_> = TransactionRows::from_path(file.path()).unwrap().collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].as_ref().unwrap().client_id, 1);
+        assert!(rows[1].is_err());
+        assert_eq!(
+            rows[2].as_ref().unwrap().r#type,
+            TransactionType::Withdrawal
+        );
+    }
+
+    #[test]
+    pub fn should_attribute_rows_to_file_lines() {
+        let file = NamedTempFile::new().unwrap();
+
+        std::fs::write(
+            file.path(),
+            "type,client,tx,amount,provenance
+deposit,1,1,1.5,
+             deposit,1,2,1,file:day1.csv:7
+",
+        )
+        .unwrap();
+
+        let rows: Vec<_> = TransactionRows::from_path(file.path())
+            .unwrap()
+            .map(|row| row.unwrap().provenance.unwrap().to_string())
+            .collect();
+
+        assert_eq!(
+            rows,
+            vec![
+                format!("file:{}:2", file.path().display()),
+                "file:day1.csv:7".to_string()
+            ]
+        );
+    }
+}
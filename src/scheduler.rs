@@ -0,0 +1,432 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::export::{ExportProgress, TransactionFilter};
+use chrono::{DateTime, Duration, Utc};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const JOBS_DB_PATH: &str = "pe_jobs.db";
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RETRY_DELAY_SECONDS: i64 = 60;
+const FILE_URI_PREFIX: &str = "file://";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Work done by a job. Jobs stored before exports could be queued are batch runs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobKind {
+    /// Batch run of the input file, writing the account report to the output.
+    #[default]
+    Run,
+    /// Full history export of the event store's transactions to the output, as JSON lines.
+    /// Exports continue after their last checkpoint when they are retried or the scheduler
+    /// restarts.
+    Export { filter: TransactionFilter },
+}
+
+/// Job as one row of `jobs list`.
+#[derive(Debug, Serialize)]
+pub struct JobSummary<'a> {
+    pub id: u64,
+    pub kind: &'static str,
+    pub input: &'a str,
+    pub event_store: Option<&'a str>,
+    pub output: &'a str,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub run_after: DateTime<Utc>,
+    pub error: Option<&'a str>,
+}
+
+/// Batch run or export waiting in the queue of the scheduler. Jobs sharing an event store belong to the
+/// same tenant and always run one after the other; jobs without one share the `pickledb` store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    #[serde(default)]
+    pub kind: JobKind,
+    pub input: String,
+    pub event_store: Option<String>,
+    pub output: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub run_after: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+/// Job as stored by the scheduler, with the transactions an unfinished export has written.
+#[derive(Debug, Serialize)]
+pub struct JobPoll {
+    #[serde(flatten)]
+    pub job: Job,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exported: Option<usize>,
+}
+
+/// Jobs by id, persisted so the queue survives restarts of the scheduler.
+pub struct JobQueue {
+    db: PickleDb,
+    jobs: BTreeMap<u64, Job>,
+}
+
+/// How long after a failure a job is run again. Only retryable errors, e.g. storage which
+/// could not be read, reschedule a job, and the wait grows with every attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub delay: Duration,
+}
+
+impl Job {
+    /// Job reading `input`, a path or a `file://` URI.
+    pub fn new(
+        input: &str,
+        event_store: Option<&str>,
+        output: Option<&str>,
+        max_attempts: u32,
+    ) -> PaymentEngineResult<Self> {
+        let input = match input.split_once("://") {
+            None => input,
+            Some(_) => input.strip_prefix(FILE_URI_PREFIX).ok_or_else(|| {
+                PaymentEngineError::UnsupportedInputUri {
+                    uri: input.to_string(),
+                }
+            })?,
+        };
+
+        Ok(Job {
+            id: 0,
+            kind: JobKind::Run,
+            input: input.to_string(),
+            event_store: event_store.map(str::to_string),
+            output: output.unwrap_or_default().to_string(),
+            status: JobStatus::Queued,
+            attempts: 0,
+            max_attempts,
+            run_after: Utc::now(),
+            error: None,
+        })
+    }
+
+    /// Job exporting the transactions of the event store at `event_store` which match `filter`.
+    pub fn export(
+        event_store: &str,
+        filter: TransactionFilter,
+        output: Option<&str>,
+        max_attempts: u32,
+    ) -> Self {
+        Job {
+            id: 0,
+            kind: JobKind::Export { filter },
+            input: String::new(),
+            event_store: Some(event_store.to_string()),
+            output: output.unwrap_or_default().to_string(),
+            status: JobStatus::Queued,
+            attempts: 0,
+            max_attempts,
+            run_after: Utc::now(),
+            error: None,
+        }
+    }
+
+    pub fn summary(&self) -> JobSummary<'_> {
+        JobSummary {
+            id: self.id,
+            kind: match self.kind {
+                JobKind::Run => "run",
+                JobKind::Export { .. } => "export",
+            },
+            input: &self.input,
+            event_store: self.event_store.as_deref(),
+            output: &self.output,
+            status: self.status,
+            attempts: self.attempts,
+            max_attempts: self.max_attempts,
+            run_after: self.run_after,
+            error: self.error.as_deref(),
+        }
+    }
+
+    fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.status == JobStatus::Queued && self.run_after <= now
+    }
+
+    fn finish(self, result: PaymentEngineResult<()>, retry: RetryPolicy) -> Job {
+        let attempts = self.attempts + 1;
+
+        match result {
+            Ok(_) => {
+                info!("Job {} succeeded", self.id);
+
+                Job {
+                    status: JobStatus::Succeeded,
+                    attempts,
+                    error: None,
+                    ..self
+                }
+            }
+            Err(e) if e.is_retryable() && attempts < self.max_attempts => {
+                let run_after = Utc::now() + retry.delay * attempts as i32;
+
+                warn!(
+                    "Job {} failed, retrying after {}: {}",
+                    self.id, run_after, e
+                );
+
+                Job {
+                    status: JobStatus::Queued,
+                    attempts,
+                    run_after,
+                    error: Some(e.to_string()),
+                    ..self
+                }
+            }
+            Err(e) => {
+                error!("Job {} failed: {}", self.id, e);
+
+                Job {
+                    status: JobStatus::Failed,
+                    attempts,
+                    error: Some(e.to_string()),
+                    ..self
+                }
+            }
+        }
+    }
+}
+
+impl JobPoll {
+    /// Job `job_id` of the queue at `path` as last saved, read without opening the queue, so a
+    /// job a running scheduler works on shows as running.
+    pub fn load(path: &Path, job_id: u64) -> PaymentEngineResult<Self> {
+        let job: Job = PickleDb::load_read_only(path, SerializationMethod::Bin)
+            .ok()
+            .and_then(|db| db.get::<String>(&job_id.to_string()))
+            .map(|json| serde_json::from_str(&json))
+            .transpose()?
+            .ok_or(PaymentEngineError::JobNotFound { job_id })?;
+        let exported = match job.kind {
+            JobKind::Export { .. } => ExportProgress::load(Path::new(&job.output))?,
+            JobKind::Run => None,
+        };
+
+        Ok(JobPoll {
+            job,
+            exported: exported.map(|progress| progress.exported),
+        })
+    }
+}
+
+impl JobQueue {
+    /// Opens the queue. Jobs left running by a scheduler which stopped are queued again.
+    pub fn open(path: &Path) -> Self {
+        let db = PickleDb::load(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+            .unwrap_or_else(|_| {
+                PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+            });
+        let jobs = db
+            .iter()
+            .filter_map(|item| item.get_value::<String>())
+            .filter_map(|json| serde_json::from_str::<Job>(&json).ok())
+            .map(|mut job| {
+                if job.status == JobStatus::Running {
+                    job.status = JobStatus::Queued;
+                }
+                (job.id, job)
+            })
+            .collect();
+
+        JobQueue { db, jobs }
+    }
+
+    /// Adds the job with the next free id. Jobs without an output get a file named after their
+    /// id.
+    pub fn submit(&mut self, mut job: Job) -> PaymentEngineResult<u64> {
+        job.id = self.jobs.keys().next_back().map_or(1, |id| id + 1);
+
+        if job.output.is_empty() {
+            job.output = match job.kind {
+                JobKind::Run => format!("job-{}.accounts.csv", job.id),
+                JobKind::Export { .. } => format!("job-{}.export.jsonl", job.id),
+            };
+        }
+
+        let id = job.id;
+
+        self.save(job)?;
+
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.values().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Job> {
+        self.jobs.get(&id)
+    }
+
+    /// Runs the queued jobs which are due, grouped by tenant. Each tenant's jobs run in
+    /// submission order; up to `max_parallel` tenants run at the same time.
+    pub fn run_due<F>(
+        &mut self,
+        max_parallel: usize,
+        retry: RetryPolicy,
+        run: F,
+    ) -> PaymentEngineResult<usize>
+    where
+        F: Fn(&Job) -> PaymentEngineResult<()> + Sync,
+    {
+        let now = Utc::now();
+        let mut tenants: BTreeMap<Option<String>, Vec<Job>> = BTreeMap::new();
+
+        for job in self.jobs.values().filter(|job| job.is_due(now)) {
+            tenants
+                .entry(job.event_store.clone())
+                .or_default()
+                .push(job.clone());
+        }
+
+        let tenants: Vec<Vec<Job>> = tenants.into_values().collect();
+        let mut finished = 0;
+
+        for batch in tenants.chunks(max_parallel.max(1)) {
+            for job in batch.iter().flatten() {
+                self.save(Job {
+                    status: JobStatus::Running,
+                    ..job.clone()
+                })?;
+            }
+
+            let results: Vec<(Job, PaymentEngineResult<()>)> = std::thread::scope(|scope| {
+                let handles: Vec<_> = batch
+                    .iter()
+                    .map(|jobs| {
+                        let run = &run;
+
+                        scope.spawn(move || {
+                            jobs.iter()
+                                .map(|job| (job.clone(), run(job)))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().expect("Job thread panicked"))
+                    .collect()
+            });
+
+            for (job, result) in results {
+                finished += 1;
+                self.save(job.finish(result, retry))?;
+            }
+        }
+
+        Ok(finished)
+    }
+
+    fn save(&mut self, job: Job) -> PaymentEngineResult<()> {
+        self.db
+            .set(&job.id.to_string(), &serde_json::to_string(&job)?)?;
+        self.jobs.insert(job.id, job);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::PaymentEngineError;
+    use crate::scheduler::{Job, JobPoll, JobQueue, JobStatus, RetryPolicy};
+    use chrono::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_retry_retryable_failures_and_keep_tenants_apart() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("jobs.db");
+        let mut queue = JobQueue::open(&path);
+        let retry = RetryPolicy {
+            delay: Duration::zero(),
+        };
+
+        for (input, tenant) in [("a.csv", "a.log"), ("b.csv", "b.log"), ("bad.csv", "a.log")] {
+            queue
+                .submit(Job::new(input, Some(tenant), None, 2).unwrap())
+                .unwrap();
+        }
+
+        let run = |job: &Job| match job.input.as_str() {
+            "bad.csv" => Err(PaymentEngineError::EventLog {
+                source: std::io::Error::other("disk unavailable"),
+            }),
+            _ => Ok(()),
+        };
+
+        assert_eq!(queue.run_due(2, retry, run).unwrap(), 3);
+        assert_eq!(queue.run_due(2, retry, run).unwrap(), 1);
+
+        let jobs = JobQueue::open(&path).list();
+        let statuses: Vec<JobStatus> = jobs.iter().map(|job| job.status).collect();
+
+        assert_eq!(
+            statuses,
+            vec![
+                JobStatus::Succeeded,
+                JobStatus::Succeeded,
+                JobStatus::Failed
+            ]
+        );
+        assert_eq!(jobs[2].attempts, 2);
+        assert_eq!(jobs[0].output, "job-1.accounts.csv");
+        assert!(Job::new("https://example.com/in.csv", None, None, 1).is_err());
+        assert_eq!(
+            Job::new("file:///data/in.csv", None, None, 1)
+                .unwrap()
+                .input,
+            "/data/in.csv"
+        );
+    }
+
+    #[test]
+    pub fn should_poll_jobs_as_the_running_scheduler_saved_them() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("jobs.db");
+        let mut queue = JobQueue::open(&path);
+        let retry = RetryPolicy {
+            delay: Duration::zero(),
+        };
+        let job_id = queue
+            .submit(Job::new("a.csv", None, None, 1).unwrap())
+            .unwrap();
+
+        queue
+            .run_due(1, retry, |job| {
+                let poll = JobPoll::load(&path, job.id)?;
+
+                assert_eq!(poll.job.status, JobStatus::Running);
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            JobPoll::load(&path, job_id).unwrap().job.status,
+            JobStatus::Succeeded
+        );
+        assert!(matches!(
+            JobPoll::load(&path, 2),
+            Err(PaymentEngineError::JobNotFound { job_id: 2 })
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::error::PaymentEngineResult;
+use crate::event_store::AccountEvent;
+use crate::model::Transaction;
+use crate::projection::Projection;
+use std::path::Path;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, Value, INDEXED, STORED, TEXT};
+use tantivy::{doc, Index, IndexWriter, TantivyDocument, TantivyError, Term};
+
+pub const SEARCH_INDEX_PATH: &str = "pe_search_index";
+
+const WRITER_MEMORY_BUDGET: usize = 50_000_000;
+
+#[derive(Clone, Copy)]
+struct SearchFields {
+    transaction_id: Field,
+    memo: Field,
+    counterparty: Field,
+    transaction: Field,
+}
+
+/// Full-text index over transaction memos and counterparties, kept up to date from the account
+/// event stream. Documents are committed when the projections are synced.
+pub struct SearchProjection {
+    writer: IndexWriter,
+    fields: SearchFields,
+    uncommitted: bool,
+}
+
+fn open_index(path: &Path) -> PaymentEngineResult<(Index, SearchFields)> {
+    let mut builder = Schema::builder();
+    let fields = SearchFields {
+        transaction_id: builder.add_u64_field("transaction_id", INDEXED),
+        memo: builder.add_text_field("memo", TEXT),
+        counterparty: builder.add_text_field("counterparty", TEXT),
+        transaction: builder.add_text_field("transaction", STORED),
+    };
+
+    std::fs::create_dir_all(path).map_err(TantivyError::from)?;
+
+    let directory = MmapDirectory::open(path).map_err(TantivyError::from)?;
+    let index = Index::open_or_create(directory, builder.build())?;
+
+    Ok((index, fields))
+}
+
+impl SearchProjection {
+    pub fn open(path: &Path) -> PaymentEngineResult<Self> {
+        let (index, fields) = open_index(path)?;
+
+        Ok(SearchProjection {
+            writer: index.writer(WRITER_MEMORY_BUDGET)?,
+            fields,
+            uncommitted: false,
+        })
+    }
+
+    fn index_transaction(&mut self, transaction: &Transaction) -> PaymentEngineResult<()> {
+        let transaction_id = u64::from(transaction.transaction_id);
+
+        self.writer.delete_term(Term::from_field_u64(
+            self.fields.transaction_id,
+            transaction_id,
+        ));
+        self.writer.add_document(doc!(
+            self.fields.transaction_id => transaction_id,
+            self.fields.memo => transaction.memo.clone().unwrap_or_default(),
+            self.fields.counterparty => transaction.counterparty.clone().unwrap_or_default(),
+            self.fields.transaction => serde_json::to_string(transaction)?,
+        ))?;
+        self.uncommitted = true;
+
+        Ok(())
+    }
+}
+
+impl Projection for SearchProjection {
+    fn apply(&mut self, event: &AccountEvent) {
+        for transaction in &event.transactions {
+            if transaction.memo.is_none() && transaction.counterparty.is_none() {
+                continue;
+            }
+
+            if let Err(e) = self.index_transaction(transaction) {
+                warn!(
+                    "Transaction {} not indexed, {}",
+                    transaction.transaction_id, e
+                );
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.uncommitted {
+            return;
+        }
+
+        match self.writer.commit() {
+            Ok(_) => self.uncommitted = false,
+            Err(e) => warn!("Search index not committed, {}", e),
+        }
+    }
+}
+
+/// Returns transactions whose memo or counterparty contain all the words of the query, best
+/// matches first.
+pub fn search_text(
+    path: &Path,
+    query: &str,
+    limit: usize,
+) -> PaymentEngineResult<Vec<Transaction>> {
+    let (index, fields) = open_index(path)?;
+    let searcher = index.reader()?.searcher();
+    let mut query_parser = QueryParser::for_index(&index, vec![fields.memo, fields.counterparty]);
+
+    query_parser.set_conjunction_by_default();
+
+    let (query, _) = query_parser.parse_query_lenient(query);
+    let mut transactions = vec![];
+
+    for (_, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+        let document: TantivyDocument = searcher.doc(address)?;
+
+        if let Some(json) = document
+            .get_first(fields.transaction)
+            .and_then(|value| value.as_str())
+        {
+            transactions.push(serde_json::from_str(json)?);
+        }
+    }
+
+    Ok(transactions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event_store::AccountEvent;
+    use crate::model::{Transaction, TransactionType};
+    use crate::projection::Projection;
+    use crate::search::{search_text, SearchProjection};
+    use rust_decimal::Decimal;
+    use tempfile::TempDir;
+
+    fn transaction(transaction_id: u32, memo: &str, counterparty: &str) -> Transaction {
+        Transaction {
+            memo: Some(memo.to_string()),
+            counterparty: Some(counterparty.to_string()),
+            ..Transaction::new(
+                TransactionType::Deposit,
+                1,
+                transaction_id,
+                Some(Decimal::from(10)),
+            )
+        }
+    }
+
+    #[test]
+    pub fn should_find_transactions_by_memo_and_counterparty() {
+        let directory = TempDir::new().unwrap();
+        let mut projection = SearchProjection::open(directory.path()).unwrap();
+        let mut disputed = transaction(2, "Chargeback for invoice 4711", "ACME Ltd");
+
+        projection.apply(&AccountEvent {
+            client_id: 1,
+            sequence: 1,
+            transactions: vec![
+                transaction(1, "invoice 4711", "ACME Ltd"),
+                disputed.clone(),
+                transaction(3, "chargeback invoice 4712", "Globex"),
+            ],
+            available: Decimal::from(30),
+            held: Decimal::ZERO,
+            total: Decimal::from(30),
+            locked: false,
+            recorded_at: None,
+            balances: Default::default(),
+        });
+        disputed.disputed = true;
+        projection.apply(&AccountEvent {
+            client_id: 1,
+            sequence: 2,
+            transactions: vec![disputed.clone()],
+            available: Decimal::from(-10),
+            held: Decimal::from(10),
+            total: Decimal::ZERO,
+            locked: false,
+            recorded_at: None,
+            balances: Default::default(),
+        });
+        projection.flush();
+
+        let found = search_text(directory.path(), "chargeback invoice 4711", 10).unwrap();
+        assert_eq!(found, vec![disputed]);
+
+        let found = search_text(directory.path(), "acme", 10).unwrap();
+        assert_eq!(found.len(), 2);
+    }
+}
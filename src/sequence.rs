@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+pub enum SequenceIssue {
+    #[display(
+        fmt = "Sequence gap for client {}, missing transactions {}-{}",
+        client_id,
+        from,
+        to
+    )]
+    Gap { client_id: u16, from: u32, to: u32 },
+    #[display(
+        fmt = "Out of order transaction for client {}, received {} after {}",
+        client_id,
+        received,
+        last
+    )]
+    OutOfOrder {
+        client_id: u16,
+        last: u32,
+        received: u32,
+    },
+}
+
+/// Tracks the last seen transaction id per client, assuming partners send monotonically
+/// increasing ids for each client.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    last_transaction_ids: HashMap<u16, u32>,
+    issues: Vec<SequenceIssue>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        SequenceTracker::default()
+    }
+
+    pub fn observe(&mut self, client_id: u16, transaction_id: u32) -> Option<SequenceIssue> {
+        let issue = match self.last_transaction_ids.get(&client_id) {
+            Some(&last) if transaction_id <= last => Some(SequenceIssue::OutOfOrder {
+                client_id,
+                last,
+                received: transaction_id,
+            }),
+            Some(&last) if transaction_id > last + 1 => Some(SequenceIssue::Gap {
+                client_id,
+                from: last + 1,
+                to: transaction_id - 1,
+            }),
+            _ => None,
+        };
+
+        if !matches!(issue, Some(SequenceIssue::OutOfOrder { .. })) {
+            self.last_transaction_ids.insert(client_id, transaction_id);
+        }
+
+        if let Some(issue) = &issue {
+            self.issues.push(issue.clone());
+        }
+
+        issue
+    }
+
+    pub fn issues(&self) -> &[SequenceIssue] {
+        &self.issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sequence::{SequenceIssue, SequenceTracker};
+
+    #[test]
+    pub fn should_report_missing_range() {
+        let mut tracker = SequenceTracker::new();
+
+        assert_eq!(tracker.observe(1, 1), None);
+        assert_eq!(tracker.observe(1, 2), None);
+        assert_eq!(
+            tracker.observe(1, 6),
+            Some(SequenceIssue::Gap {
+                client_id: 1,
+                from: 3,
+                to: 5
+            })
+        );
+        assert_eq!(tracker.observe(2, 3), None);
+        assert_eq!(tracker.observe(1, 7), None);
+        assert_eq!(tracker.issues().len(), 1);
+    }
+
+    #[test]
+    pub fn should_report_out_of_order_transaction() {
+        let mut tracker = SequenceTracker::new();
+
+        tracker.observe(1, 10);
+
+        assert_eq!(
+            tracker.observe(1, 4),
+            Some(SequenceIssue::OutOfOrder {
+                client_id: 1,
+                last: 10,
+                received: 4
+            })
+        );
+        assert_eq!(tracker.observe(1, 11), None);
+    }
+}
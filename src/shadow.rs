@@ -0,0 +1,95 @@
+use crate::error::PaymentEngineResult;
+use crate::model::{Account, Transaction};
+use crate::sink;
+use csv::WriterBuilder;
+use serde::Serialize;
+use std::path::PathBuf;
+
+pub const SHADOW_REPORT_PATH: &str = "pe_shadow_report.csv";
+
+/// Row of the comparison report. Rows with a transaction id describe a transaction whose
+/// outcome differs, rows without one describe an account whose final balances differ.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShadowDifference {
+    pub client: u16,
+    pub tx: Option<u32>,
+    pub production: String,
+    pub shadow: String,
+}
+
+#[derive(Debug)]
+pub struct ShadowReport {
+    path: PathBuf,
+    differences: Vec<ShadowDifference>,
+}
+
+impl ShadowReport {
+    pub fn new(path: PathBuf) -> Self {
+        ShadowReport {
+            path,
+            differences: vec![],
+        }
+    }
+
+    pub fn differences(&self) -> &[ShadowDifference] {
+        &self.differences
+    }
+
+    pub fn compare_outcomes(
+        &mut self,
+        transaction: &Transaction,
+        production: &PaymentEngineResult<()>,
+        shadow: &PaymentEngineResult<()>,
+    ) {
+        let production = describe_outcome(production);
+        let shadow = describe_outcome(shadow);
+
+        if production != shadow {
+            self.differences.push(ShadowDifference {
+                client: transaction.client_id,
+                tx: Some(transaction.transaction_id),
+                production,
+                shadow,
+            });
+        }
+    }
+
+    pub fn compare_accounts(&mut self, production: &Account, shadow: &Account) {
+        if production != shadow {
+            self.differences.push(ShadowDifference {
+                client: production.client_id,
+                tx: None,
+                production: describe_account(production),
+                shadow: describe_account(shadow),
+            });
+        }
+    }
+
+    pub fn write(&self) -> PaymentEngineResult<()> {
+        sink::write_atomically(&self.path, |sink| {
+            let mut writer = WriterBuilder::new().from_writer(sink);
+
+            for difference in &self.differences {
+                writer.serialize(difference)?;
+            }
+
+            writer.flush()?;
+
+            Ok(())
+        })
+    }
+}
+
+fn describe_outcome(outcome: &PaymentEngineResult<()>) -> String {
+    match outcome {
+        Ok(_) => "applied".to_string(),
+        Err(e) => format!("rejected: {}", e),
+    }
+}
+
+fn describe_account(account: &Account) -> String {
+    format!(
+        "available {}, held {}, total {}, locked {}",
+        account.available, account.held, account.total, account.locked
+    )
+}
@@ -0,0 +1,326 @@
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::event_store::EventSourcedDatastore;
+use crate::model::Account;
+use csv::{ReaderBuilder, Trim, Writer, WriterBuilder};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+pub const CLIENT_COLUMNS: [&str; 2] = ["client", "client_id"];
+pub const TRANSACTION_ID_COLUMNS: [&str; 2] = ["tx", "transaction_id"];
+const TYPE_COLUMN: &str = "type";
+const TO_CLIENT_COLUMN: &str = "to_client";
+const TRANSFER_TYPE: &str = "transfer";
+
+/// Shard of a client, spread with multiplicative hashing so consecutive ids land in different
+/// shards.
+pub fn shard_of(client_id: u16, shards: usize) -> usize {
+    (u32::from(client_id).wrapping_mul(2_654_435_761) >> 16) as usize % shards
+}
+
+/// Partitions a CSV file into `shards` files in `output_dir` by client, so each shard can be
+/// processed by a separate run. Disputes, resolves and chargebacks follow the transaction they
+/// reference when it was seen earlier in the file, even if their client column differs.
+///
+/// A transfer changes the accounts of two clients at once, which only one run can do atomically.
+/// Clients linked by transfers, directly or through other clients, therefore form a group whose
+/// rows all go to the shard of its lowest client id, so no shard ever holds one side of a
+/// transfer without the other. The file is read twice when it has a `to_client` column.
+pub fn split_by_client(
+    csv_path: &Path,
+    shards: usize,
+    output_dir: &Path,
+) -> PaymentEngineResult<Vec<PathBuf>> {
+    if shards == 0 {
+        return Err(PaymentEngineError::InvalidShardCount);
+    }
+
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .trim(Trim::All)
+        .from_path(csv_path)?;
+    let headers = reader.headers()?.clone();
+    let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h));
+    let client_index = column(&CLIENT_COLUMNS).ok_or(PaymentEngineError::MissingClientColumn)?;
+    let transaction_index = column(&TRANSACTION_ID_COLUMNS);
+    let type_index = column(&[TYPE_COLUMN]);
+    let groups = match (type_index, column(&[TO_CLIENT_COLUMN])) {
+        (Some(type_index), Some(to_client_index)) => {
+            transfer_groups(csv_path, client_index, type_index, to_client_index)?
+        }
+        _ => TransferGroups::default(),
+    };
+
+    let stem = csv_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let paths: Vec<PathBuf> = (0..shards)
+        .map(|shard| output_dir.join(format!("{}.shard{}.csv", stem, shard)))
+        .collect();
+    let mut writers: Vec<Writer<File>> = Vec::with_capacity(shards);
+
+    for path in &paths {
+        let mut writer = WriterBuilder::new().from_path(path)?;
+
+        writer.write_record(&headers)?;
+        writers.push(writer);
+    }
+
+    let mut transaction_shards: HashMap<u32, usize> = HashMap::default();
+
+    for record in reader.records() {
+        let record = record?;
+        let client_shard = match record.get(client_index).and_then(|c| c.parse::<u16>().ok()) {
+            Some(client_id) => shard_of(groups.group_of(client_id), shards),
+            None => {
+                warn!("Invalid client in row {:?}, written to shard 0", record);
+                0
+            }
+        };
+        let transaction_id = transaction_index
+            .and_then(|index| record.get(index))
+            .and_then(|tx| tx.parse::<u32>().ok());
+        let is_reference = type_index
+            .and_then(|index| record.get(index))
+            .map(|t| {
+                !matches!(
+                    t.to_lowercase().as_str(),
+                    "deposit" | "withdrawal" | TRANSFER_TYPE
+                )
+            })
+            .unwrap_or(false);
+
+        let shard = match transaction_id {
+            Some(transaction_id) if is_reference => transaction_shards
+                .get(&transaction_id)
+                .copied()
+                .unwrap_or(client_shard),
+            Some(transaction_id) => {
+                transaction_shards.insert(transaction_id, client_shard);
+                client_shard
+            }
+            None => client_shard,
+        };
+
+        writers[shard].write_record(&record)?;
+    }
+
+    for writer in writers.iter_mut() {
+        writer.flush()?;
+    }
+
+    Ok(paths)
+}
+
+/// Clients linked by transfers, each pointing towards the lowest client id of its group.
+#[derive(Debug, Default)]
+struct TransferGroups {
+    parents: HashMap<u16, u16>,
+}
+
+impl TransferGroups {
+    fn group_of(&self, client_id: u16) -> u16 {
+        let mut client_id = client_id;
+
+        while let Some(parent) = self.parents.get(&client_id).filter(|p| **p != client_id) {
+            client_id = *parent;
+        }
+
+        client_id
+    }
+
+    fn link(&mut self, a: u16, b: u16) {
+        let (a, b) = (self.group_of(a), self.group_of(b));
+
+        if a != b {
+            self.parents.insert(a.max(b), a.min(b));
+        }
+    }
+
+    /// Points every client straight at its group, so lookups while writing take one step.
+    fn flatten(mut self) -> Self {
+        let clients: Vec<u16> = self.parents.keys().copied().collect();
+
+        for client_id in clients {
+            let group = self.group_of(client_id);
+
+            self.parents.insert(client_id, group);
+        }
+
+        self
+    }
+}
+
+fn transfer_groups(
+    csv_path: &Path,
+    client_index: usize,
+    type_index: usize,
+    to_client_index: usize,
+) -> PaymentEngineResult<TransferGroups> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .trim(Trim::All)
+        .from_path(csv_path)?;
+    let mut groups = TransferGroups::default();
+
+    for record in reader.records() {
+        let record = record?;
+        let is_transfer = record
+            .get(type_index)
+            .is_some_and(|t| t.eq_ignore_ascii_case(TRANSFER_TYPE));
+        let client_id = record.get(client_index).and_then(|c| c.parse::<u16>().ok());
+        let to_client = record
+            .get(to_client_index)
+            .and_then(|c| c.parse::<u16>().ok());
+
+        if let (true, Some(client_id), Some(to_client)) = (is_transfer, client_id, to_client) {
+            groups.link(client_id, to_client);
+        }
+    }
+
+    Ok(groups.flatten())
+}
+
+/// Combines the accounts of several shards into one report ordered by client. Inputs ending in
+/// `.csv` are account reports, anything else is read as an event store log. A client found in
+/// more than one shard means the shards overlap, and is reported as an error.
+pub fn merge_shards(paths: &[&Path]) -> PaymentEngineResult<Vec<Account>> {
+    let mut accounts: BTreeMap<u16, Account> = BTreeMap::new();
+
+    for path in paths {
+        for account in read_shard_accounts(path)? {
+            let client_id = account.client_id;
+
+            if accounts.insert(client_id, account).is_some() {
+                return Err(PaymentEngineError::ClientInMultipleShards { client_id });
+            }
+        }
+    }
+
+    Ok(accounts.into_values().collect())
+}
+
+/// Reads accounts back from a report written by a run.
+pub fn read_account_report(path: &Path) -> PaymentEngineResult<Vec<Account>> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .trim(Trim::All)
+        .from_path(path)?;
+
+    Ok(reader.deserialize().collect::<Result<Vec<Account>, _>>()?)
+}
+
+fn read_shard_accounts(path: &Path) -> PaymentEngineResult<Vec<Account>> {
+    if path.extension().is_some_and(|extension| extension == "csv") {
+        read_account_report(path)
+    } else {
+        EventSourcedDatastore::open(path, vec![])?.retrieve_all_accounts()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::PaymentEngineError;
+    use crate::shard::{merge_shards, shard_of, split_by_client};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_split_by_client_keeping_references_with_their_transaction() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("big.csv");
+        let (a, b) = (1..100)
+            .flat_map(|a| (1..100).map(move |b| (a, b)))
+            .find(|(a, b)| shard_of(*a, 2) == 0 && shard_of(*b, 2) == 1)
+            .unwrap();
+
+        writeln!(
+            std::fs::File::create(&input).unwrap(),
+            "type,client,tx,amount\ndeposit,{a},1,10\ndeposit,{b},2,10\ndispute,{b},1,\nresolve,{a},1,",
+            a = a,
+            b = b
+        )
+        .unwrap();
+
+        let paths = split_by_client(&input, 2, directory.path()).unwrap();
+        let shard_0 = std::fs::read_to_string(&paths[0]).unwrap();
+        let shard_1 = std::fs::read_to_string(&paths[1]).unwrap();
+
+        assert!(paths[0].ends_with("big.shard0.csv"));
+        assert_eq!(shard_0.lines().count(), 4);
+        assert!(shard_0.contains(&format!("dispute,{},1,", b)));
+        assert_eq!(shard_1.lines().count(), 2);
+    }
+
+    #[test]
+    pub fn should_keep_clients_linked_by_transfers_in_one_shard() {
+        let directory = TempDir::new().unwrap();
+        let input = directory.path().join("transfers.csv");
+        let (a, b) = (1..100)
+            .flat_map(|a| (a + 1..100).map(move |b| (a, b)))
+            .find(|(a, b)| shard_of(*a, 2) != shard_of(*b, 2))
+            .unwrap();
+        let shard = shard_of(a, 2);
+        let other = (b + 1..200).find(|c| shard_of(*c, 2) != shard).unwrap();
+
+        writeln!(
+            std::fs::File::create(&input).unwrap(),
+            "type,client,tx,amount,to_client\n\
+             deposit,{b},1,10,\n\
+             deposit,{other},2,10,\n\
+             Transfer,{b},3,4,{other}\n\
+             transfer,{other},4,2,{a}\n\
+             dispute,{b},3,,",
+            a = a,
+            b = b,
+            other = other
+        )
+        .unwrap();
+
+        let paths = split_by_client(&input, 2, directory.path()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&paths[shard])
+                .unwrap()
+                .lines()
+                .count(),
+            6
+        );
+        assert_eq!(
+            std::fs::read_to_string(&paths[1 - shard])
+                .unwrap()
+                .lines()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    pub fn should_merge_shard_reports_with_distinct_clients() {
+        let directory = TempDir::new().unwrap();
+        let report = |name: &str, content: &str| {
+            let path = directory.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        let header = "client,available,held,total,locked\n";
+        let first = report("first.csv", &format!("{}2,1.5,0,1.5,false\n", header));
+        let second = report("second.csv", &format!("{}1,3,1,4,true\n", header));
+        let overlapping = report("third.csv", &format!("{}2,0,0,0,false\n", header));
+
+        let accounts = merge_shards(&[&first, &second]).unwrap();
+
+        assert_eq!(
+            accounts.iter().map(|a| a.client_id).collect::<Vec<u16>>(),
+            vec![1, 2]
+        );
+        assert!(accounts[0].locked);
+
+        match merge_shards(&[&first, &second, &overlapping]) {
+            Err(PaymentEngineError::ClientInMultipleShards { client_id: 2 }) => {}
+            result => panic!("Expected overlapping shards error, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::error::PaymentEngineResult;
+use std::io::{Stdout, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// Destination of the account report. Nothing written needs to be visible to readers before
+/// `finish` is called.
+pub trait ReportSink: Write {
+    fn finish(self: Box<Self>) -> PaymentEngineResult<()>;
+}
+
+pub struct StdoutSink(Stdout);
+
+/// File written under a temporary name next to its destination and renamed over it by
+/// `finish`, so readers see either the previous report or the complete new one. When the sink
+/// is dropped without finishing, e.g. after a failed run, the temporary file is removed.
+pub struct AtomicFileSink {
+    file: NamedTempFile,
+    path: PathBuf,
+}
+
+/// Sink writing to the file at `path`, or to stdout without one.
+pub fn open(path: Option<&Path>) -> PaymentEngineResult<Box<dyn ReportSink>> {
+    match path {
+        Some(path) => Ok(Box::new(AtomicFileSink::create(path)?)),
+        None => Ok(Box::new(StdoutSink(std::io::stdout()))),
+    }
+}
+
+/// Writes the file at `path` through an `AtomicFileSink`, so a run failing half way through
+/// leaves the previous file in place instead of a truncated one.
+pub fn write_atomically<F>(path: &Path, write: F) -> PaymentEngineResult<()>
+where
+    F: FnOnce(&mut AtomicFileSink) -> PaymentEngineResult<()>,
+{
+    let mut sink = Box::new(AtomicFileSink::create(path)?);
+
+    write(&mut sink)?;
+    sink.finish()
+}
+
+impl Write for StdoutSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ReportSink for StdoutSink {
+    fn finish(mut self: Box<Self>) -> PaymentEngineResult<()> {
+        self.0.flush()?;
+
+        Ok(())
+    }
+}
+
+impl AtomicFileSink {
+    pub fn create(path: &Path) -> PaymentEngineResult<Self> {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        Ok(AtomicFileSink {
+            file: NamedTempFile::new_in(directory)?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Write for AtomicFileSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl ReportSink for AtomicFileSink {
+    fn finish(mut self: Box<Self>) -> PaymentEngineResult<()> {
+        self.file.flush()?;
+        self.file.as_file().sync_all()?;
+        self.file.persist(&self.path).map_err(|e| e.error)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::PaymentEngineError;
+    use crate::sink::{self, AtomicFileSink};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_replace_report_only_when_finished() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("accounts.csv");
+
+        std::fs::write(&path, "previous").unwrap();
+
+        let mut failed = AtomicFileSink::create(&path).unwrap();
+        write!(failed, "partial").unwrap();
+        drop(failed);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+
+        let mut finished = sink::open(Some(&path)).unwrap();
+        write!(finished, "complete").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+
+        finished.finish().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "complete");
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    pub fn should_keep_previous_file_when_writing_fails() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("risk_report.csv");
+
+        std::fs::write(&path, "previous").unwrap();
+
+        let result = sink::write_atomically(&path, |sink| {
+            write!(sink, "partial")?;
+            Err(PaymentEngineError::RowLimitExceeded)
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+
+        sink::write_atomically(&path, |sink| Ok(write!(sink, "complete")?)).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "complete");
+    }
+}
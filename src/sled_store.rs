@@ -0,0 +1,220 @@
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::migrate::MigrationSource;
+use crate::model::{Account, DisputeRecord, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ops::Bound;
+use std::path::Path;
+
+pub const SLED_DB_PATH: &str = "pe_datastore.sled";
+
+/// Datastore keeping transactions, accounts and dispute chains in a `sled` database, one tree
+/// per kind with the same JSON as the other stores, keyed by big-endian ids so iteration follows
+/// id order. Like SQLite, accounts are persisted between runs.
+pub struct SledDatastore {
+    db: sled::Db,
+    transactions: sled::Tree,
+    /// Client id followed by transaction id, for the transactions of a client.
+    client_transactions: sled::Tree,
+    pending_transactions: sled::Tree,
+    accounts: sled::Tree,
+    /// Transaction id followed by sequence.
+    dispute_records: sled::Tree,
+}
+
+impl SledDatastore {
+    pub fn open(path: &Path) -> PaymentEngineResult<Self> {
+        let db = sled::open(path)?;
+
+        Ok(SledDatastore {
+            transactions: db.open_tree("transactions")?,
+            client_transactions: db.open_tree("client_transactions")?,
+            pending_transactions: db.open_tree("pending_transactions")?,
+            accounts: db.open_tree("accounts")?,
+            dispute_records: db.open_tree("dispute_records")?,
+            db,
+        })
+    }
+}
+
+fn get<T: DeserializeOwned>(tree: &sled::Tree, key: &[u8]) -> PaymentEngineResult<Option<T>> {
+    match tree.get(key)? {
+        Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        None => Ok(None),
+    }
+}
+
+fn insert<T: Serialize>(tree: &sled::Tree, key: &[u8], value: &T) -> PaymentEngineResult<()> {
+    tree.insert(key, serde_json::to_vec(value)?)?;
+
+    Ok(())
+}
+
+fn values<T: DeserializeOwned>(
+    entries: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+) -> PaymentEngineResult<Vec<T>> {
+    entries
+        .map(|entry| Ok(serde_json::from_slice(&entry?.1)?))
+        .collect()
+}
+
+/// Entries of `tree` in key order, starting after `after`.
+fn after_key<K: AsRef<[u8]>>(tree: &sled::Tree, after: Option<K>) -> sled::Iter {
+    match after {
+        Some(after) => tree.range((Bound::Excluded(after), Bound::Unbounded)),
+        None => tree.iter(),
+    }
+}
+
+fn client_transaction_key(client_id: u16, transaction_id: u32) -> Vec<u8> {
+    [
+        &client_id.to_be_bytes()[..],
+        &transaction_id.to_be_bytes()[..],
+    ]
+    .concat()
+}
+
+fn dispute_record_key(record: &DisputeRecord) -> Vec<u8> {
+    [
+        &record.transaction_id.to_be_bytes()[..],
+        &record.sequence.to_be_bytes()[..],
+    ]
+    .concat()
+}
+
+impl DatastoreOperations for SledDatastore {
+    fn retrieve_transaction(
+        &mut self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        get(&self.transactions, &transaction_id.to_be_bytes())
+    }
+
+    fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.client_transactions.insert(
+            client_transaction_key(transaction.client_id, transaction.transaction_id),
+            &[],
+        )?;
+        insert(
+            &self.transactions,
+            &transaction.transaction_id.to_be_bytes(),
+            &transaction,
+        )
+    }
+
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transactions = vec![];
+
+        for entry in self
+            .client_transactions
+            .scan_prefix(client_id.to_be_bytes())
+        {
+            let (key, _) = entry?;
+
+            if let Some(transaction) = get(&self.transactions, &key[2..])? {
+                transactions.push(transaction);
+            }
+        }
+
+        Ok(transactions)
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        get(&self.accounts, &client_id.to_be_bytes())
+    }
+
+    fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+        insert(&self.accounts, &account.client_id.to_be_bytes(), &account)
+    }
+
+    fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        values(self.accounts.iter())
+    }
+
+    fn retrieve_accounts_after(
+        &self,
+        after: Option<u16>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        values(after_key(&self.accounts, after.map(u16::to_be_bytes)).take(count))
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        values(after_key(&self.transactions, after.map(u32::to_be_bytes)).take(count))
+    }
+
+    fn set_transaction_disputed(
+        &mut self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        match self.retrieve_transaction(transaction_id)? {
+            Some(transaction) => self.save_transaction(Transaction {
+                disputed,
+                ..transaction
+            }),
+            None => Err(PaymentEngineError::DisputedValueChange),
+        }
+    }
+
+    fn remove_transaction_from_cache(&mut self, _transaction_id: u32) -> PaymentEngineResult<()> {
+        Ok(())
+    }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        insert(
+            &self.pending_transactions,
+            &transaction.transaction_id.to_be_bytes(),
+            &transaction,
+        )
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        values(self.pending_transactions.iter())
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.pending_transactions
+            .remove(transaction_id.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        insert(&self.dispute_records, &dispute_record_key(&record), &record)
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        values(
+            self.dispute_records
+                .scan_prefix(transaction_id.to_be_bytes()),
+        )
+    }
+
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        self.db.flush()?;
+
+        Ok(())
+    }
+}
+
+impl MigrationSource for SledDatastore {
+    fn all_transactions(&mut self) -> PaymentEngineResult<Vec<Transaction>> {
+        values(self.transactions.iter())
+    }
+
+    fn all_dispute_records(&mut self) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        values(self.dispute_records.iter())
+    }
+}
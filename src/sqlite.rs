@@ -0,0 +1,281 @@
+use crate::datastore::DatastoreOperations;
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::migrate::MigrationSource;
+use crate::model::{Account, DisputeRecord, Transaction};
+use rusqlite::{params, Connection, OptionalExtension, Params};
+use serde::de::DeserializeOwned;
+use std::path::Path;
+
+pub const SQLITE_DB_PATH: &str = "pe_datastore.sqlite";
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS transactions (
+        id INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        json TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS transactions_client ON transactions (client);
+    CREATE TABLE IF NOT EXISTS pending_transactions (
+        id INTEGER PRIMARY KEY,
+        json TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER PRIMARY KEY,
+        json TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS dispute_records (
+        tx INTEGER NOT NULL,
+        sequence INTEGER NOT NULL,
+        json TEXT NOT NULL,
+        PRIMARY KEY (tx, sequence)
+    );
+";
+
+/// Datastore keeping transactions, accounts and dispute chains in one SQLite file. Rows hold
+/// the same JSON as the `pickledb` store, keyed by id. Unlike there, accounts are persisted, so
+/// a later run continues from the balances of the previous one.
+pub struct SqliteDatastore {
+    connection: Connection,
+}
+
+impl SqliteDatastore {
+    pub fn open(path: &Path) -> PaymentEngineResult<Self> {
+        let connection = Connection::open(path)?;
+
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteDatastore { connection })
+    }
+
+    fn query_one<T: DeserializeOwned, P: Params>(
+        &self,
+        sql: &str,
+        params: P,
+    ) -> PaymentEngineResult<Option<T>> {
+        let json: Option<String> = self
+            .connection
+            .query_row(sql, params, |row| row.get(0))
+            .optional()?;
+
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn query_all<T: DeserializeOwned, P: Params>(
+        &self,
+        sql: &str,
+        params: P,
+    ) -> PaymentEngineResult<Vec<T>> {
+        let mut statement = self.connection.prepare_cached(sql)?;
+        let rows = statement.query_map(params, |row| row.get::<_, String>(0))?;
+        let mut values = vec![];
+
+        for json in rows {
+            values.push(serde_json::from_str(&json?)?);
+        }
+
+        Ok(values)
+    }
+}
+
+impl DatastoreOperations for SqliteDatastore {
+    fn retrieve_transaction(
+        &mut self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        self.query_one(
+            "SELECT json FROM transactions WHERE id = ?1",
+            [transaction_id],
+        )
+    }
+
+    fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO transactions (id, client, json) VALUES (?1, ?2, ?3)",
+            params![
+                transaction.transaction_id,
+                transaction.client_id,
+                serde_json::to_string(&transaction)?
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.query_all(
+            "SELECT json FROM transactions WHERE client = ?1 ORDER BY id",
+            [client_id],
+        )
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        self.query_one("SELECT json FROM accounts WHERE client = ?1", [client_id])
+    }
+
+    fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO accounts (client, json) VALUES (?1, ?2)",
+            params![account.client_id, serde_json::to_string(&account)?],
+        )?;
+
+        Ok(())
+    }
+
+    fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        self.query_all("SELECT json FROM accounts ORDER BY client", [])
+    }
+
+    fn retrieve_accounts_after(
+        &self,
+        after: Option<u16>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        self.query_all(
+            "SELECT json FROM accounts WHERE client > ?1 ORDER BY client LIMIT ?2",
+            params![after.map_or(-1, i64::from), count as i64],
+        )
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        self.query_all(
+            "SELECT json FROM transactions WHERE id > ?1 ORDER BY id LIMIT ?2",
+            params![after.map_or(-1, i64::from), count as i64],
+        )
+    }
+
+    fn set_transaction_disputed(
+        &mut self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        match self.retrieve_transaction(transaction_id)? {
+            Some(transaction) => self.save_transaction(Transaction {
+                disputed,
+                ..transaction
+            }),
+            None => Err(PaymentEngineError::DisputedValueChange),
+        }
+    }
+
+    fn remove_transaction_from_cache(&mut self, _transaction_id: u32) -> PaymentEngineResult<()> {
+        Ok(())
+    }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO pending_transactions (id, json) VALUES (?1, ?2)",
+            params![
+                transaction.transaction_id,
+                serde_json::to_string(&transaction)?
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        self.query_all("SELECT json FROM pending_transactions ORDER BY id", [])
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        self.connection.execute(
+            "DELETE FROM pending_transactions WHERE id = ?1",
+            params![transaction_id],
+        )?;
+
+        Ok(())
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        self.connection.execute(
+            "INSERT INTO dispute_records (tx, sequence, json) VALUES (?1, ?2, ?3)",
+            params![
+                record.transaction_id,
+                record.sequence,
+                serde_json::to_string(&record)?
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        self.query_all(
+            "SELECT json FROM dispute_records WHERE tx = ?1 ORDER BY sequence",
+            [transaction_id],
+        )
+    }
+}
+
+impl MigrationSource for SqliteDatastore {
+    fn all_transactions(&mut self) -> PaymentEngineResult<Vec<Transaction>> {
+        self.query_all("SELECT json FROM transactions ORDER BY id", [])
+    }
+
+    fn all_dispute_records(&mut self) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        self.query_all("SELECT json FROM dispute_records ORDER BY tx, sequence", [])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::export::TransactionFilter;
+    use crate::model::{Transaction, TransactionType};
+    use crate::payment_service::PaymentService;
+    use crate::sqlite::SqliteDatastore;
+    use rust_decimal::Decimal;
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_continue_from_persisted_state() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("store.sqlite");
+        let transaction = |r#type, amount| Transaction::new(r#type, 3, 9, amount);
+        let open = || {
+            PaymentService::new(
+                Box::new(SqliteDatastore::open(&path).unwrap()),
+                ServiceConfig::default(),
+            )
+        };
+
+        open()
+            .process(&transaction(
+                TransactionType::Deposit,
+                Some(Decimal::from(40)),
+            ))
+            .unwrap();
+
+        let mut service = open();
+        let account = service
+            .process(&transaction(TransactionType::Dispute, None))
+            .unwrap();
+
+        assert_eq!(account.held, Decimal::from(40));
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(service.dispute_chain(9).unwrap().len(), 1);
+        assert_eq!(
+            service
+                .write_transactions_page(vec![], &TransactionFilter::default(), None, 1024)
+                .unwrap()
+                .items,
+            1
+        );
+        assert_eq!(open().accounts().unwrap(), vec![account]);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{Account, DisputeRecord, Transaction};
+use chrono::Utc;
+use printpdf::{
+    BuiltinFont, IndirectFontRef, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference,
+};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 20.0;
+const LINE_HEIGHT: f32 = 6.0;
+const TABLE_COLUMNS: [f32; 6] = [0.0, 22.0, 50.0, 80.0, 100.0, 120.0];
+
+/// Branding of client statements, read from a TOML file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatementTemplate {
+    pub title: String,
+    pub company: String,
+    pub address: Vec<String>,
+    pub footer: String,
+    pub font_size: f32,
+}
+
+impl Default for StatementTemplate {
+    fn default() -> Self {
+        StatementTemplate {
+            title: "Account statement".to_string(),
+            company: "Payment Engine".to_string(),
+            address: vec![],
+            footer: String::new(),
+            font_size: 10.0,
+        }
+    }
+}
+
+impl StatementTemplate {
+    pub fn load(path: &Path) -> PaymentEngineResult<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| PaymentEngineError::ConfigRead { source })?;
+
+        Ok(toml::from_str(&text)?)
+    }
+}
+
+/// Writes lines top to bottom, starting a new page when the current one is full.
+struct PageWriter<'a> {
+    document: &'a PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+    font_size: f32,
+    y: f32,
+    pages: usize,
+}
+
+impl<'a> PageWriter<'a> {
+    fn line(&mut self, columns: &[(f32, &str)], bold: bool) {
+        if self.y < MARGIN + LINE_HEIGHT {
+            let (page, layer) = self.document.add_page(
+                Mm(PAGE_WIDTH),
+                Mm(PAGE_HEIGHT),
+                format!("Page {}", self.pages + 1),
+            );
+
+            self.layer = self.document.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT - MARGIN;
+            self.pages += 1;
+        }
+
+        let font = if bold { &self.bold } else { &self.regular };
+
+        for (x, text) in columns {
+            self.layer
+                .use_text(*text, self.font_size, Mm(MARGIN + x), Mm(self.y), font);
+        }
+
+        self.y -= LINE_HEIGHT;
+    }
+
+    fn skip(&mut self) {
+        self.y -= LINE_HEIGHT;
+    }
+}
+
+/// Renders balances and transaction history of one client into a PDF file. The dispute chain of
+/// a contested transaction is listed below it.
+pub fn write_statement_pdf(
+    path: &Path,
+    template: &StatementTemplate,
+    account: &Account,
+    transactions: &[Transaction],
+    disputes: &[DisputeRecord],
+) -> PaymentEngineResult<()> {
+    let (document, page, layer) = PdfDocument::new(
+        template.title.as_str(),
+        Mm(PAGE_WIDTH),
+        Mm(PAGE_HEIGHT),
+        "Page 1",
+    );
+    let mut writer = PageWriter {
+        document: &document,
+        layer: document.get_page(page).get_layer(layer),
+        regular: document.add_builtin_font(BuiltinFont::Helvetica)?,
+        bold: document.add_builtin_font(BuiltinFont::HelveticaBold)?,
+        font_size: template.font_size,
+        y: PAGE_HEIGHT - MARGIN,
+        pages: 1,
+    };
+
+    writer.line(&[(0.0, &template.company)], true);
+    for line in &template.address {
+        writer.line(&[(0.0, line)], false);
+    }
+    writer.skip();
+    writer.line(&[(0.0, &template.title)], true);
+    writer.line(
+        &[
+            (0.0, &format!("Client {}", account.client_id)),
+            (
+                100.0,
+                &format!("Generated {}", Utc::now().format("%Y-%m-%d %H:%M UTC")),
+            ),
+        ],
+        false,
+    );
+    writer.skip();
+    writer.line(
+        &[
+            (0.0, &format!("Available {}", account.available)),
+            (50.0, &format!("Held {}", account.held)),
+            (100.0, &format!("Total {}", account.total)),
+            (150.0, if account.locked { "Locked" } else { "Active" }),
+        ],
+        true,
+    );
+    writer.skip();
+
+    let header = ["Tx", "Type", "Amount", "Disputed", "Date", "Memo"];
+    writer.line(&columns(&header), true);
+
+    for transaction in transactions {
+        let cells = [
+            transaction.transaction_id.to_string(),
+            format!("{:?}", transaction.r#type),
+            transaction
+                .amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+            if transaction.disputed { "yes" } else { "" }.to_string(),
+            transaction
+                .timestamp
+                .map(|timestamp| timestamp.format("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+            transaction.memo.clone().unwrap_or_default(),
+        ];
+        let cells: Vec<&str> = cells.iter().map(String::as_str).collect();
+
+        writer.line(&columns(&cells), false);
+
+        for record in disputes
+            .iter()
+            .filter(|record| record.transaction_id == transaction.transaction_id)
+        {
+            writer.line(&[(TABLE_COLUMNS[1], &describe_dispute(record))], false);
+        }
+    }
+
+    if !template.footer.is_empty() {
+        writer.skip();
+        writer.line(&[(0.0, &template.footer)], false);
+    }
+
+    let file =
+        File::create(path).map_err(|source| PaymentEngineError::StatementWrite { source })?;
+
+    document.save(&mut BufWriter::new(file))?;
+
+    Ok(())
+}
+
+fn describe_dispute(record: &DisputeRecord) -> String {
+    let step = format!(
+        "#{} {:?} {} on {}",
+        record.sequence,
+        record.r#type,
+        record.amount,
+        record.recorded_at.format("%Y-%m-%d")
+    );
+
+    match record.closes {
+        Some(closes) => format!("{}, closes #{}", step, closes),
+        None => step,
+    }
+}
+
+fn columns<'a>(cells: &[&'a str]) -> Vec<(f32, &'a str)> {
+    TABLE_COLUMNS
+        .iter()
+        .copied()
+        .zip(cells.iter().copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{Account, DisputeRecord, Documents, Transaction, TransactionType};
+    use crate::statement::{write_statement_pdf, StatementTemplate};
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_write_statement_pdf() {
+        let file = NamedTempFile::new().unwrap();
+        let transactions: Vec<Transaction> = (1..=100)
+            .map(|transaction_id| Transaction {
+                memo: Some("invoice".to_string()),
+                ..Transaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    transaction_id,
+                    Some(Decimal::from(10)),
+                )
+            })
+            .collect();
+        let account = Account {
+            available: Decimal::from(1000),
+            total: Decimal::from(1000),
+            ..Account::new(1)
+        };
+        let disputes = vec![DisputeRecord {
+            transaction_id: 7,
+            sequence: 1,
+            r#type: TransactionType::Dispute,
+            client_id: 1,
+            amount: Decimal::from(10),
+            closes: None,
+            recorded_at: Utc::now(),
+            reason_code: None,
+            documents: Documents::default(),
+        }];
+
+        write_statement_pdf(
+            file.path(),
+            &StatementTemplate::default(),
+            &account,
+            &transactions,
+            &disputes,
+        )
+        .unwrap();
+
+        let pdf = std::fs::read(file.path()).unwrap();
+
+        assert!(pdf.starts_with(b"%PDF"));
+    }
+}
@@ -0,0 +1,166 @@
+use crate::error::PaymentEngineResult;
+use crate::manifest::RunCounts;
+use crate::model::TransactionType;
+use crate::sink;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Instant;
+
+/// What became of a row read as a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Applied,
+    Rejected,
+    Parked,
+}
+
+/// Rows of one transaction type by outcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TypeCounts {
+    pub applied: u64,
+    pub rejected: u64,
+    pub parked: u64,
+}
+
+/// Outcomes of a run by transaction type, counted as its rows are processed.
+#[derive(Debug)]
+pub struct RunSummary {
+    started: Instant,
+    types: BTreeMap<&'static str, TypeCounts>,
+}
+
+/// Figures of a run at its end, written to the `--stats` file or logged without one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SummaryStats {
+    #[serde(flatten)]
+    pub counts: RunCounts,
+    /// Rejected rows which could not be read as a transaction, so have no type.
+    pub invalid_rows: u64,
+    pub types: BTreeMap<&'static str, TypeCounts>,
+    pub disputes_opened: u64,
+    pub disputes_resolved: u64,
+    pub chargebacks: u64,
+    pub elapsed_seconds: f64,
+    pub rows_per_second: f64,
+}
+
+impl Default for RunSummary {
+    fn default() -> Self {
+        RunSummary {
+            started: Instant::now(),
+            types: BTreeMap::new(),
+        }
+    }
+}
+
+impl RunSummary {
+    pub fn record(&mut self, r#type: &TransactionType, outcome: Outcome) {
+        let counts = self.types.entry(r#type.name()).or_default();
+
+        match outcome {
+            Outcome::Applied => counts.applied += 1,
+            Outcome::Rejected => counts.rejected += 1,
+            Outcome::Parked => counts.parked += 1,
+        }
+    }
+
+    /// Figures of the run so far, the row counts taken from `counts`.
+    pub fn stats(&self, counts: RunCounts) -> SummaryStats {
+        let applied = |r#type: &TransactionType| {
+            self.types
+                .get(r#type.name())
+                .map_or(0, |counts| counts.applied)
+        };
+        let typed: u64 = self
+            .types
+            .values()
+            .map(|counts| counts.applied + counts.rejected + counts.parked)
+            .sum();
+        let elapsed_seconds = self.started.elapsed().as_secs_f64();
+
+        SummaryStats {
+            counts,
+            invalid_rows: counts.rows.saturating_sub(typed),
+            types: self.types.clone(),
+            disputes_opened: applied(&TransactionType::Dispute),
+            disputes_resolved: applied(&TransactionType::Resolve),
+            chargebacks: applied(&TransactionType::Chargeback),
+            elapsed_seconds,
+            rows_per_second: match elapsed_seconds {
+                seconds if seconds > 0.0 => counts.rows as f64 / seconds,
+                _ => 0.0,
+            },
+        }
+    }
+}
+
+impl SummaryStats {
+    pub fn write(&self, path: &Path) -> PaymentEngineResult<()> {
+        sink::write_atomically(path, |sink| {
+            serde_json::to_writer_pretty(sink, self)?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::payment_service::PaymentService;
+    use std::io::Write;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    pub fn should_count_outcomes_by_type_and_dispute_steps() {
+        let directory = TempDir::new().unwrap();
+        let stats_path = directory.path().join("stats.json");
+        let mut file = NamedTempFile::new().unwrap();
+
+        write!(
+            file,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,5.0\n\
+             withdrawal,1,3,50.0\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n\
+             dispute,1,2,\n\
+             chargeback,1,2,\n\
+             deposit,1,4,ten\n"
+        )
+        .unwrap();
+
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig {
+                stats_path: Some(stats_path.clone()),
+                report_path: Some(directory.path().join("accounts.csv")),
+                ..ServiceConfig::default()
+            },
+        );
+
+        service.run(file.path().to_str().unwrap()).unwrap();
+
+        let stats = service.summary();
+
+        assert_eq!(stats.counts.rows, 8);
+        assert_eq!(stats.counts.rejected, 2);
+        assert_eq!(stats.invalid_rows, 1);
+        assert_eq!(stats.types["deposit"].applied, 2);
+        assert_eq!(stats.types["withdrawal"].rejected, 1);
+        assert_eq!(stats.disputes_opened, 2);
+        assert_eq!(stats.disputes_resolved, 1);
+        assert_eq!(stats.chargebacks, 1);
+
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&stats_path).unwrap()).unwrap();
+
+        assert_eq!(written["rows"], 8);
+        assert_eq!(written["types"]["dispute"]["applied"], 2);
+        assert_eq!(written["chargebacks"], 1);
+        assert!(service.outputs().contains(&stats_path));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::error::PaymentEngineResult;
+use chrono::{DateTime, Utc};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const TIMERS_DB_PATH: &str = "pe_timers.db";
+
+/// Follow-up action created while processing, executed once it is due.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum TimerAction {
+    /// Releases the funds of a reservation which was neither committed nor cancelled.
+    ExpireReservation { token: String },
+    /// Resolves a dispute which is still open when its deadline passes.
+    DisputeDeadline {
+        client_id: u16,
+        transaction_id: u32,
+        sequence: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timer {
+    pub id: u64,
+    pub due_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub action: TimerAction,
+}
+
+/// Scheduled timers ordered by due time, optionally persisted so they survive restarts.
+pub struct TimerWheel {
+    db: Option<PickleDb>,
+    timers: BTreeMap<(DateTime<Utc>, u64), Timer>,
+}
+
+impl TimerWheel {
+    pub fn open(path: Option<&Path>) -> Self {
+        let db = path.map(|path| {
+            PickleDb::load(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                .unwrap_or_else(|_| {
+                    PickleDb::new(path, PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
+                })
+        });
+        let timers = match &db {
+            Some(db) => db
+                .iter()
+                .filter_map(|item| item.get_value::<String>())
+                .filter_map(|json| serde_json::from_str::<Timer>(&json).ok())
+                .map(|timer| ((timer.due_at, timer.id), timer))
+                .collect(),
+            None => BTreeMap::default(),
+        };
+
+        TimerWheel { db, timers }
+    }
+
+    pub fn schedule(
+        &mut self,
+        due_at: DateTime<Utc>,
+        action: TimerAction,
+    ) -> PaymentEngineResult<()> {
+        let id = self
+            .timers
+            .values()
+            .map(|timer| timer.id)
+            .max()
+            .map_or(1, |id| id + 1);
+        let timer = Timer { id, due_at, action };
+
+        if let Some(db) = self.db.as_mut() {
+            db.set(&id.to_string(), &serde_json::to_string(&timer)?)?;
+        }
+        self.timers.insert((due_at, id), timer);
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<Timer> {
+        self.timers.values().cloned().collect()
+    }
+
+    /// Removes and returns the timers due at `now`, earliest first.
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> PaymentEngineResult<Vec<Timer>> {
+        let pending = self.timers.split_off(&(now, u64::MAX));
+        let due = std::mem::replace(&mut self.timers, pending);
+
+        if let Some(db) = self.db.as_mut() {
+            for timer in due.values() {
+                db.rem(&timer.id.to_string())?;
+            }
+        }
+
+        Ok(due.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timers::{TimerAction, TimerWheel};
+    use chrono::{Duration, Utc};
+    use tempfile::TempDir;
+
+    #[test]
+    pub fn should_keep_timers_across_restarts_and_fire_in_due_order() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("timers.db");
+        let now = Utc::now();
+        let expire = |token: &str| TimerAction::ExpireReservation {
+            token: token.to_string(),
+        };
+        let mut wheel = TimerWheel::open(Some(&path));
+
+        wheel
+            .schedule(now - Duration::minutes(1), expire("late"))
+            .unwrap();
+        wheel
+            .schedule(now - Duration::minutes(5), expire("early"))
+            .unwrap();
+        wheel
+            .schedule(now + Duration::days(1), expire("future"))
+            .unwrap();
+
+        let mut wheel = TimerWheel::open(Some(&path));
+        let due: Vec<TimerAction> = wheel
+            .take_due(now)
+            .unwrap()
+            .into_iter()
+            .map(|timer| timer.action)
+            .collect();
+
+        assert_eq!(due, vec![expire("early"), expire("late")]);
+        assert_eq!(TimerWheel::open(Some(&path)).list().len(), 1);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::monitor::{RunMonitor, RunStats};
+use log::LevelFilter;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::io::{self, Stderr};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Time between redraws, which is also the period throughput is measured over.
+const TICK: Duration = Duration::from_millis(500);
+/// Throughput samples shown, two minutes at the default tick.
+const THROUGHPUT_SAMPLES: usize = 240;
+const TOP_CLIENTS: usize = 10;
+
+/// Full-screen dashboard of a run on standard error, so the report can still go to standard
+/// output: throughput, rejects by error, the busiest clients, the transaction cache hit rate and
+/// how far the datastore files lag behind. Log messages would scroll over it, so logging is
+/// paused while it is on screen. `q` closes the dashboard and lets the run go on; Ctrl-C stops
+/// the process.
+pub struct Dashboard {
+    monitor: RunMonitor,
+    thread: JoinHandle<io::Result<()>>,
+    log_level: LevelFilter,
+}
+
+/// Rows per second of the recent ticks, newest last.
+#[derive(Debug, Default)]
+struct Throughput {
+    samples: VecDeque<u64>,
+    last: Option<(Instant, u64)>,
+}
+
+impl Dashboard {
+    pub fn start(monitor: RunMonitor) -> PaymentEngineResult<Self> {
+        let log_level = log::max_level();
+        let mut terminal = open().map_err(|source| PaymentEngineError::Tui { source })?;
+
+        log::set_max_level(LevelFilter::Off);
+
+        let shown = monitor.clone();
+        let thread = std::thread::spawn(move || {
+            let result = show(&mut terminal, &shown);
+
+            restore(&mut terminal);
+            result
+        });
+
+        Ok(Dashboard {
+            monitor,
+            thread,
+            log_level,
+        })
+    }
+
+    /// Marks the run as finished, waits for the dashboard to close and resumes logging.
+    pub fn finish(self) -> PaymentEngineResult<()> {
+        self.monitor.finish();
+
+        let result = self.thread.join().expect("Dashboard thread does not panic");
+
+        log::set_max_level(self.log_level);
+        result.map_err(|source| PaymentEngineError::Tui { source })
+    }
+}
+
+fn open() -> io::Result<Terminal<CrosstermBackend<Stderr>>> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+
+    Ok(terminal)
+}
+
+fn show(terminal: &mut Terminal<CrosstermBackend<Stderr>>, monitor: &RunMonitor) -> io::Result<()> {
+    let mut throughput = Throughput::default();
+
+    loop {
+        let stats = monitor.snapshot();
+        let now = Instant::now();
+
+        throughput.observe(stats.counts.rows, now);
+        terminal.draw(|frame| render(frame, &stats, &throughput, now))?;
+
+        if stats.finished {
+            return Ok(());
+        }
+
+        if !event::poll(TICK)? {
+            continue;
+        }
+
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    restore(terminal);
+                    std::process::exit(130);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn restore(terminal: &mut Terminal<CrosstermBackend<Stderr>>) {
+    let _ = disable_raw_mode();
+    let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+    let _ = terminal.show_cursor();
+}
+
+impl Throughput {
+    fn observe(&mut self, rows: u64, now: Instant) {
+        if let Some((at, before)) = self.last {
+            let elapsed = now.saturating_duration_since(at).as_secs_f64();
+
+            if elapsed > 0.0 {
+                self.samples
+                    .push_back((rows.saturating_sub(before) as f64 / elapsed) as u64);
+            }
+
+            while self.samples.len() > THROUGHPUT_SAMPLES {
+                self.samples.pop_front();
+            }
+        }
+
+        self.last = Some((now, rows));
+    }
+
+    fn current(&self) -> u64 {
+        self.samples.back().copied().unwrap_or_default()
+    }
+}
+
+fn render(frame: &mut Frame, stats: &RunStats, throughput: &Throughput, now: Instant) {
+    let [summary, chart, details] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(8),
+        Constraint::Min(6),
+    ])
+    .areas(frame.area());
+    let [rejects, clients, datastore] = Layout::horizontal([
+        Constraint::Percentage(40),
+        Constraint::Percentage(25),
+        Constraint::Percentage(35),
+    ])
+    .areas(details);
+    let elapsed = now.saturating_duration_since(stats.started);
+    let average = stats.counts.rows as f64 / elapsed.as_secs_f64().max(1.0);
+    let state = if stats.finished {
+        "finished"
+    } else {
+        "running"
+    };
+
+    frame.render_widget(
+        Paragraph::new(format!(
+            "{} rows, {} applied, {} rejected, {} parked in {}s, {:.0} rows/s on average",
+            stats.counts.rows,
+            stats.counts.applied,
+            stats.counts.rejected,
+            stats.counts.parked,
+            elapsed.as_secs(),
+            average
+        ))
+        .block(Block::bordered().title(format!(" Run {} (q closes) ", state))),
+        summary,
+    );
+
+    // The newest samples which fit the chart.
+    let width = usize::from(chart.width.saturating_sub(2));
+    let samples: Vec<u64> = throughput
+        .samples
+        .iter()
+        .skip(throughput.samples.len().saturating_sub(width))
+        .copied()
+        .collect();
+
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(format!(" Throughput {} rows/s ", throughput.current())))
+            .data(&samples),
+        chart,
+    );
+
+    let reject_rows = stats
+        .rejects
+        .iter()
+        .map(|(error, count)| Row::new(vec![error.clone(), count.to_string()]));
+
+    frame.render_widget(
+        Table::new(reject_rows, [Constraint::Min(20), Constraint::Length(10)])
+            .header(Row::new(vec!["Error", "Rows"]))
+            .block(Block::bordered().title(" Rejects ")),
+        rejects,
+    );
+
+    let client_rows = stats
+        .top_clients(TOP_CLIENTS)
+        .into_iter()
+        .map(|(client_id, rows)| Row::new(vec![client_id.to_string(), rows.to_string()]));
+
+    frame.render_widget(
+        Table::new(client_rows, [Constraint::Length(8), Constraint::Min(8)])
+            .header(Row::new(vec!["Client", "Rows"]))
+            .block(Block::bordered().title(" Top clients ")),
+        clients,
+    );
+
+    let hit_rate = match stats.cache.and_then(|cache| cache.hit_rate()) {
+        Some(hit_rate) => format!("{:.1}%", hit_rate * 100.0),
+        None => "n/a".to_string(),
+    };
+
+    frame.render_widget(
+        Paragraph::new(format!(
+            "Cache hit rate  {}\n\
+             Flush lag       {}s\n\
+             Unflushed rows  {}\n\
+             Flushes         {}\n\
+             Last flush      {}ms",
+            hit_rate,
+            stats.flush_lag(now).as_secs(),
+            stats.unflushed_rows,
+            stats.flushes,
+            stats.last_flush_duration.as_millis()
+        ))
+        .block(Block::bordered().title(" Datastore ")),
+        datastore,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::monitor::RunMonitor;
+    use crate::tui::{render, Throughput};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    pub fn should_render_throughput_rejects_and_clients() {
+        let monitor = RunMonitor::default();
+        let mut throughput = Throughput::default();
+        let started = Instant::now();
+
+        monitor.record_row(Default::default(), None, Some(7), None);
+        monitor.record_row(Default::default(), None, Some(7), Some("AccountLocked"));
+        throughput.observe(0, started);
+        throughput.observe(500, started + Duration::from_secs(1));
+
+        let stats = monitor.snapshot();
+        let mut terminal = Terminal::new(TestBackend::new(120, 24)).unwrap();
+
+        terminal
+            .draw(|frame| render(frame, &stats, &throughput, Instant::now()))
+            .unwrap();
+
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+
+        assert!(screen.contains("Throughput 500 rows/s"));
+        assert!(screen.contains("AccountLocked"));
+        assert!(screen.contains("Cache hit rate  n/a"));
+    }
+}
@@ -0,0 +1,374 @@
+use crate::datastore::{CacheStats, DatastoreOperations};
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::{Account, DisputeRecord, Transaction};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Default)]
+struct PendingChanges {
+    transactions: HashMap<u32, Transaction>,
+    accounts: HashMap<u16, Account>,
+    removed_from_cache: HashSet<u32>,
+    parked_transactions: Vec<Transaction>,
+    dispute_records: Vec<DisputeRecord>,
+}
+
+/// Wraps a datastore and buffers all writes in memory between `begin` and `commit`, so a group
+/// of transactions can be inspected before it is applied or thrown away with `rollback`. Outside
+/// of a unit of work, calls are passed straight to the wrapped datastore.
+pub struct UnitOfWork {
+    datastore: Box<dyn DatastoreOperations>,
+    pending: Option<PendingChanges>,
+}
+
+impl UnitOfWork {
+    pub fn new(datastore: Box<dyn DatastoreOperations>) -> Self {
+        UnitOfWork {
+            datastore,
+            pending: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn begin(&mut self) {
+        self.pending = Some(PendingChanges::default());
+    }
+
+    pub fn commit(&mut self) -> PaymentEngineResult<()> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let mut transactions_by_client: HashMap<u16, Vec<Transaction>> = HashMap::default();
+
+        for (_, transaction) in pending.transactions {
+            transactions_by_client
+                .entry(transaction.client_id)
+                .or_default()
+                .push(transaction);
+        }
+
+        for (client_id, account) in pending.accounts {
+            if let Some(transactions) = transactions_by_client.remove(&client_id) {
+                self.commit_transactions(transactions)?;
+            }
+
+            self.datastore.save_account(account)?;
+        }
+
+        for (_, transactions) in transactions_by_client {
+            self.commit_transactions(transactions)?;
+        }
+
+        for transaction_id in pending.removed_from_cache {
+            self.datastore
+                .remove_transaction_from_cache(transaction_id)?;
+        }
+
+        for transaction in pending.parked_transactions {
+            self.datastore.save_pending_transaction(transaction)?;
+        }
+
+        for record in pending.dispute_records {
+            self.datastore.save_dispute_record(record)?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the transactions, telling the datastore of every one whose disputed flag changed
+    /// in the unit of work, so disputes opened or closed in it reach its dispute index.
+    fn commit_transactions(&mut self, transactions: Vec<Transaction>) -> PaymentEngineResult<()> {
+        for transaction in transactions {
+            let transaction_id = transaction.transaction_id;
+            let disputed = transaction.disputed;
+            let was_disputed = self
+                .datastore
+                .retrieve_transaction(transaction_id)?
+                .is_some_and(|stored| stored.disputed);
+
+            self.datastore.save_transaction(transaction)?;
+
+            if disputed != was_disputed {
+                self.datastore
+                    .set_transaction_disputed(transaction_id, disputed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn rollback(&mut self) {
+        self.pending = None;
+    }
+
+    /// Returns account state before and after the pending changes for every account touched by
+    /// the current unit of work.
+    pub fn pending_account_changes(&self) -> PaymentEngineResult<Vec<(Account, Account)>> {
+        let pending = match &self.pending {
+            Some(pending) => pending,
+            None => return Ok(vec![]),
+        };
+        let mut changes = Vec::with_capacity(pending.accounts.len());
+
+        for (client_id, account) in &pending.accounts {
+            let before = self
+                .datastore
+                .retrieve_account(*client_id)?
+                .unwrap_or_else(|| Account::new(*client_id));
+
+            changes.push((before, account.clone()));
+        }
+
+        changes.sort_by_key(|(before, _)| before.client_id);
+
+        Ok(changes)
+    }
+}
+
+impl DatastoreOperations for UnitOfWork {
+    fn retrieve_transaction(
+        &mut self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Option<Transaction>> {
+        if let Some(transaction) = self
+            .pending
+            .as_ref()
+            .and_then(|pending| pending.transactions.get(&transaction_id))
+        {
+            return Ok(Some(transaction.clone()));
+        }
+
+        self.datastore.retrieve_transaction(transaction_id)
+    }
+
+    fn save_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending
+                    .transactions
+                    .insert(transaction.transaction_id, transaction);
+
+                Ok(())
+            }
+            None => self.datastore.save_transaction(transaction),
+        }
+    }
+
+    fn retrieve_client_transactions(
+        &mut self,
+        client_id: u16,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transactions = self.datastore.retrieve_client_transactions(client_id)?;
+
+        if let Some(pending) = &self.pending {
+            transactions.retain(|t| !pending.transactions.contains_key(&t.transaction_id));
+            transactions.extend(
+                pending
+                    .transactions
+                    .values()
+                    .filter(|t| t.client_id == client_id)
+                    .cloned(),
+            );
+        }
+
+        Ok(transactions)
+    }
+
+    fn retrieve_account(&self, client_id: u16) -> PaymentEngineResult<Option<Account>> {
+        if let Some(account) = self
+            .pending
+            .as_ref()
+            .and_then(|pending| pending.accounts.get(&client_id))
+        {
+            return Ok(Some(account.clone()));
+        }
+
+        self.datastore.retrieve_account(client_id)
+    }
+
+    fn save_account(&mut self, account: Account) -> PaymentEngineResult<()> {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.accounts.insert(account.client_id, account);
+
+                Ok(())
+            }
+            None => self.datastore.save_account(account),
+        }
+    }
+
+    fn retrieve_all_accounts(&self) -> PaymentEngineResult<Vec<Account>> {
+        let mut accounts = self.datastore.retrieve_all_accounts()?;
+
+        if let Some(pending) = &self.pending {
+            accounts.retain(|account| !pending.accounts.contains_key(&account.client_id));
+            accounts.extend(pending.accounts.values().cloned());
+        }
+
+        Ok(accounts)
+    }
+
+    /// The first `count` of the stored accounts after `after` hold every stored account the
+    /// page can take, so the pending ones are merged into those.
+    fn retrieve_accounts_after(
+        &self,
+        after: Option<u16>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Account>> {
+        let mut accounts = self.datastore.retrieve_accounts_after(after, count)?;
+
+        if let Some(pending) = &self.pending {
+            accounts.retain(|account| !pending.accounts.contains_key(&account.client_id));
+            accounts.extend(
+                pending
+                    .accounts
+                    .values()
+                    .filter(|account| after.is_none_or(|after| account.client_id > after))
+                    .cloned(),
+            );
+            accounts.sort_by_key(|account| account.client_id);
+            accounts.truncate(count);
+        }
+
+        Ok(accounts)
+    }
+
+    fn retrieve_transactions_after(
+        &mut self,
+        after: Option<u32>,
+        count: usize,
+    ) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transactions = self.datastore.retrieve_transactions_after(after, count)?;
+
+        if let Some(pending) = &self.pending {
+            transactions.retain(|t| !pending.transactions.contains_key(&t.transaction_id));
+            transactions.extend(
+                pending
+                    .transactions
+                    .values()
+                    .filter(|t| after.is_none_or(|after| t.transaction_id > after))
+                    .cloned(),
+            );
+            transactions.sort_by_key(|t| t.transaction_id);
+            transactions.truncate(count);
+        }
+
+        Ok(transactions)
+    }
+
+    fn set_transaction_disputed(
+        &mut self,
+        transaction_id: u32,
+        disputed: bool,
+    ) -> PaymentEngineResult<()> {
+        if self.pending.is_none() {
+            return self
+                .datastore
+                .set_transaction_disputed(transaction_id, disputed);
+        }
+
+        match self.retrieve_transaction(transaction_id)? {
+            Some(mut transaction) => {
+                transaction.disputed = disputed;
+                self.save_transaction(transaction)
+            }
+            None => Err(PaymentEngineError::DisputedValueChange),
+        }
+    }
+
+    fn remove_transaction_from_cache(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.removed_from_cache.insert(transaction_id);
+
+                Ok(())
+            }
+            None => self.datastore.remove_transaction_from_cache(transaction_id),
+        }
+    }
+
+    fn save_pending_transaction(&mut self, transaction: Transaction) -> PaymentEngineResult<()> {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.parked_transactions.push(transaction);
+
+                Ok(())
+            }
+            None => self.datastore.save_pending_transaction(transaction),
+        }
+    }
+
+    fn retrieve_pending_transactions(&self) -> PaymentEngineResult<Vec<Transaction>> {
+        let mut transactions = self.datastore.retrieve_pending_transactions()?;
+
+        if let Some(pending) = &self.pending {
+            transactions.extend(pending.parked_transactions.iter().cloned());
+        }
+
+        Ok(transactions)
+    }
+
+    fn remove_pending_transaction(&mut self, transaction_id: u32) -> PaymentEngineResult<()> {
+        if let Some(pending) = self.pending.as_mut() {
+            pending
+                .parked_transactions
+                .retain(|t| t.transaction_id != transaction_id);
+        }
+
+        self.datastore.remove_pending_transaction(transaction_id)
+    }
+
+    fn save_dispute_record(&mut self, record: DisputeRecord) -> PaymentEngineResult<()> {
+        match self.pending.as_mut() {
+            Some(pending) => {
+                pending.dispute_records.push(record);
+
+                Ok(())
+            }
+            None => self.datastore.save_dispute_record(record),
+        }
+    }
+
+    fn retrieve_dispute_chain(
+        &self,
+        transaction_id: u32,
+    ) -> PaymentEngineResult<Vec<DisputeRecord>> {
+        let mut chain = self.datastore.retrieve_dispute_chain(transaction_id)?;
+
+        if let Some(pending) = &self.pending {
+            chain.extend(
+                pending
+                    .dispute_records
+                    .iter()
+                    .filter(|r| r.transaction_id == transaction_id)
+                    .cloned(),
+            );
+        }
+
+        Ok(chain)
+    }
+
+    fn count_open_disputes(&mut self, client_id: u16) -> PaymentEngineResult<usize> {
+        if self.pending.is_none() {
+            return self.datastore.count_open_disputes(client_id);
+        }
+
+        Ok(self
+            .retrieve_client_transactions(client_id)?
+            .iter()
+            .filter(|t| t.disputed)
+            .count())
+    }
+
+    fn flush(&mut self) -> PaymentEngineResult<()> {
+        self.datastore.flush()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.datastore.cache_stats()
+    }
+}
@@ -0,0 +1,74 @@
+use crate::error::{PaymentEngineError, PaymentEngineResult};
+use crate::model::Transaction;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Write-ahead log of the transactions handed to the engine, one JSON object per line. Every
+/// transaction is on disk before any balance changes, so replaying the log through a service
+/// with the same configuration rebuilds the state a crashed run left behind.
+#[derive(Debug, Clone)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+}
+
+impl WriteAheadLog {
+    pub fn new(path: PathBuf) -> Self {
+        WriteAheadLog { path }
+    }
+
+    /// Empties the log, for a run which starts from an empty datastore.
+    pub fn reset(path: &Path) -> PaymentEngineResult<()> {
+        File::create(path)
+            .map(|_| ())
+            .map_err(|source| PaymentEngineError::WriteAheadLog { source })
+    }
+
+    /// Appends the transactions and waits until they reached the disk.
+    pub fn append(&self, transactions: &[Transaction]) -> PaymentEngineResult<()> {
+        let mut lines = String::new();
+
+        for transaction in transactions {
+            lines.push_str(&serde_json::to_string(transaction)?);
+            lines.push('\n');
+        }
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(lines.as_bytes())?;
+                file.sync_data()
+            })
+            .map_err(|source| PaymentEngineError::WriteAheadLog { source })
+    }
+
+    /// Transactions of the log in the order they were written. A last line cut off by the crash
+    /// was never applied and is left out.
+    pub fn read(path: &Path) -> PaymentEngineResult<Vec<Transaction>> {
+        let file =
+            File::open(path).map_err(|source| PaymentEngineError::WriteAheadLog { source })?;
+        let lines = BufReader::new(file)
+            .lines()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|source| PaymentEngineError::WriteAheadLog { source })?;
+        let mut transactions = Vec::with_capacity(lines.len());
+
+        for (index, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(transaction) => transactions.push(transaction),
+                Err(e) if index + 1 == lines.len() => {
+                    warn!(
+                        "Skipping incomplete last entry of {}: {}",
+                        path.display(),
+                        e
+                    )
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(transactions)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::input::RawRow;
+
+/// Row of a run which was rejected, handed to the channel set with
+/// `PaymentService::send_warnings` so embedding programs can handle rejects themselves instead
+/// of reading them from the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    /// Input the row was read from, e.g. a file path or `archive.zip!member.csv`.
+    pub source: String,
+    /// Position of the row in the run, counting from 1 across all its sources.
+    pub row: u64,
+    /// Name of the `PaymentEngineError`, or `InvalidRow` for rows which cannot be read as a
+    /// transaction.
+    pub code: String,
+    pub message: String,
+    /// The row as read with the line it starts on, none for sources which cannot give it back.
+    pub record: Option<RawRow>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServiceConfig;
+    use crate::datastore::InMemoryDatastore;
+    use crate::input::RawRow;
+    use crate::payment_service::PaymentService;
+    use crate::warning::Warning;
+    use std::io::Write;
+    use std::sync::mpsc::channel;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    pub fn should_send_a_warning_for_every_rejected_row() {
+        let mut file = NamedTempFile::new().unwrap();
+
+        write!(
+            file,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,50.0\n\
+             deposit,1,3,ten\n"
+        )
+        .unwrap();
+
+        let source = file.path().to_str().unwrap();
+        let (sender, warnings) = channel();
+        let mut service = PaymentService::new(
+            Box::new(InMemoryDatastore::default()),
+            ServiceConfig::default(),
+        );
+
+        service.send_warnings(sender);
+        service.run(source).unwrap();
+
+        let warnings: Vec<Warning> = warnings.try_iter().collect();
+
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].source, source);
+        assert_eq!(warnings[0].row, 2);
+        assert_eq!(warnings[0].code, "InsufficientAccountFunds");
+        assert_eq!(
+            warnings[0].record,
+            Some(RawRow {
+                line: 3,
+                content: "withdrawal,1,2,50.0".to_string(),
+            })
+        );
+        assert_eq!(warnings[1].row, 3);
+        assert_eq!(warnings[1].code, "InvalidRow");
+        assert_eq!(
+            warnings[1].record.as_ref().map(|record| record.line),
+            Some(4)
+        );
+    }
+}
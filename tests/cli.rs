@@ -0,0 +1,112 @@
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+fn payment_engine(directory: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .current_dir(directory)
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?} failed: {:?}", args, output);
+
+    output
+}
+
+#[test]
+fn should_keep_stored_accounts_when_inspecting_them() {
+    let directory = TempDir::new().unwrap();
+    let input = directory.path().join("in.csv");
+    let empty = directory.path().join("empty.csv");
+
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         dispute,1,1,\n",
+    )
+    .unwrap();
+    std::fs::write(&empty, "type,client,tx,amount\n").unwrap();
+
+    payment_engine(directory.path(), &["in.csv"]);
+
+    let chain = payment_engine(directory.path(), &["dispute-chain", "1"]);
+
+    assert_eq!(String::from_utf8(chain.stdout).unwrap().lines().count(), 2);
+
+    let report = payment_engine(directory.path(), &["empty.csv", "--resume"]);
+
+    assert_eq!(
+        String::from_utf8(report.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.0000,10.0,10.0,false\n"
+    );
+}
+
+#[test]
+fn should_keep_stored_accounts_when_listing_pending_transactions() {
+    let directory = TempDir::new().unwrap();
+
+    std::fs::write(
+        directory.path().join("in.csv"),
+        "type,client,tx,amount\ndeposit,1,1,10.0\n",
+    )
+    .unwrap();
+    std::fs::write(
+        directory.path().join("empty.csv"),
+        "type,client,tx,amount\n",
+    )
+    .unwrap();
+
+    payment_engine(directory.path(), &["in.csv"]);
+    payment_engine(directory.path(), &["pending", "list"]);
+
+    let report = payment_engine(directory.path(), &["empty.csv", "--resume"]);
+
+    assert_eq!(
+        String::from_utf8(report.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10.0,0.0000,10.0,false\n"
+    );
+}
+
+#[test]
+fn should_leave_no_files_behind_with_in_memory_datastore() {
+    let directory = TempDir::new().unwrap();
+
+    std::fs::write(
+        directory.path().join("in.csv"),
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\n",
+    )
+    .unwrap();
+
+    payment_engine(directory.path(), &["in.csv", "--datastore", "memory"]);
+
+    let files: Vec<_> = std::fs::read_dir(directory.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+
+    assert_eq!(files, vec!["in.csv"]);
+}
+
+#[test]
+fn should_refuse_to_verify_determinism_of_a_resumed_run() {
+    let directory = TempDir::new().unwrap();
+
+    std::fs::write(
+        directory.path().join("in.csv"),
+        "type,client,tx,amount\ndeposit,1,1,10.0\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .current_dir(directory.path())
+        .args(["in.csv", "--resume", "--verify-determinism"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("cannot be used with"));
+}